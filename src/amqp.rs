@@ -0,0 +1,477 @@
+//! Publishing received messages to an AMQP 0-9-1 exchange, e.g. of RabbitMQ, for test tooling
+//! that is built around queues.
+//!
+//! This is a minimal client: it logs in with `PLAIN`, opens one channel in confirm mode and
+//! publishes the JSON summary of every message as a persistent, mandatory message with the
+//! message UUID as message ID. Every message waits for the broker to confirm it, so messages
+//! that cannot be routed to any queue or are rejected are logged. There is no TLS.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::Session;
+
+/// How long to wait for the broker to answer, e.g. with the confirmation of a message
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const FRAME_METHOD: u8 = 1;
+const FRAME_HEADER: u8 = 2;
+const FRAME_BODY: u8 = 3;
+const FRAME_HEARTBEAT: u8 = 8;
+const FRAME_END: u8 = 0xce;
+
+/// Size of the frame type, channel, size and frame end around a payload
+const FRAME_OVERHEAD: usize = 8;
+
+const CONNECTION_START: (u16, u16) = (10, 10);
+const CONNECTION_START_OK: (u16, u16) = (10, 11);
+const CONNECTION_TUNE: (u16, u16) = (10, 30);
+const CONNECTION_TUNE_OK: (u16, u16) = (10, 31);
+const CONNECTION_OPEN: (u16, u16) = (10, 40);
+const CONNECTION_OPEN_OK: (u16, u16) = (10, 41);
+const CONNECTION_CLOSE: (u16, u16) = (10, 50);
+const CHANNEL_OPEN: (u16, u16) = (20, 10);
+const CHANNEL_OPEN_OK: (u16, u16) = (20, 11);
+const CHANNEL_CLOSE: (u16, u16) = (20, 40);
+const BASIC_PUBLISH: (u16, u16) = (60, 40);
+const BASIC_RETURN: (u16, u16) = (60, 50);
+const BASIC_ACK: (u16, u16) = (60, 80);
+const BASIC_NACK: (u16, u16) = (60, 120);
+const CONFIRM_SELECT: (u16, u16) = (85, 10);
+const CONFIRM_SELECT_OK: (u16, u16) = (85, 11);
+
+/// Class of the content header of published messages
+const BASIC_CLASS: u16 = 60;
+
+/// Content header properties that are set: content type, delivery mode and message ID
+const PROPERTY_FLAGS: u16 = 0x8000 | 0x1000 | 0x0010;
+
+/// Delivery mode of messages that survive a restart of the broker
+const PERSISTENT: u8 = 2;
+
+/// Where to publish and how to log in
+#[derive(Clone)]
+pub struct Sink {
+    /// Address of the broker as host:port
+    pub server: String,
+    pub vhost: String,
+    pub user: String,
+    pub password: String,
+    /// Exchange to publish to, the empty default exchange routes to the queue named like the
+    /// routing key
+    pub exchange: String,
+    pub routing_key: String,
+}
+
+/// Publish the messages of every session received until the channel closes.
+/// Messages that fail to publish are logged and dropped.
+pub fn run(sink: Sink, server: Option<String>, sessions: Receiver<Arc<Session>>) {
+    let mut connection: Option<Connection> = None;
+    for session in sessions {
+        let smtp = &session.connection;
+        let (Some(sender_domain), Some(messages)) = (smtp.get_sender_domain(), smtp.get_messages())
+        else {
+            continue;
+        };
+        for message in messages {
+            let summary = crate::message_json(&session, sender_domain, message, server.as_deref())
+                .to_string();
+            let published = match connection.as_mut() {
+                Some(connection) => Ok(connection),
+                None => Connection::open(&sink).map(|open| connection.insert(open)),
+            }
+            .and_then(|connection| connection.publish(&sink, message.get_id(), summary.as_bytes()));
+            if let Err(e) = published {
+                tracing::error!(
+                    "Publishing to AMQP exchange '{}' failed: {}",
+                    sink.exchange,
+                    e
+                );
+                // A failed channel is closed by the broker, so the next message connects again
+                connection = None;
+            }
+        }
+    }
+}
+
+/// A connection to a broker with one channel in confirm mode
+struct Connection {
+    stream: TcpStream,
+    /// Largest frame the broker accepts
+    frame_max: usize,
+}
+
+impl Connection {
+    fn open(sink: &Sink) -> Result<Connection, Error> {
+        let stream = TcpStream::connect(&sink.server)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut connection = Connection {
+            stream,
+            frame_max: 4096,
+        };
+        connection.stream.write_all(b"AMQP\x00\x00\x09\x01")?;
+
+        let start = connection.expect(0, CONNECTION_START)?;
+        let mut start = Decoder(&start);
+        // Protocol version and server properties
+        start.take::<2>()?;
+        start.long_string()?;
+        let mechanisms = String::from_utf8_lossy(start.long_string()?).into_owned();
+        if !mechanisms.split(' ').any(|mechanism| mechanism == "PLAIN") {
+            return Err(Error::other(format!(
+                "broker does not support PLAIN login, only {}",
+                mechanisms
+            )));
+        }
+        let mut start_ok = Encoder::method(CONNECTION_START_OK);
+        start_ok.table(&[("product", "rust-smtp-server")]);
+        start_ok.short_string("PLAIN");
+        start_ok.long_string(format!("\0{}\0{}", sink.user, sink.password).as_bytes());
+        start_ok.short_string("en_US");
+        connection.send(FRAME_METHOD, 0, &start_ok.0)?;
+
+        let tune = connection.expect(0, CONNECTION_TUNE)?;
+        let mut tune = Decoder(&tune);
+        let channel_max = tune.u16()?;
+        let frame_max = tune.u32()?;
+        if frame_max != 0 {
+            connection.frame_max = frame_max as usize;
+        }
+        let mut tune_ok = Encoder::method(CONNECTION_TUNE_OK);
+        tune_ok.u16(channel_max);
+        tune_ok.u32(frame_max);
+        // No heartbeats, the connection is checked by every confirmation
+        tune_ok.u16(0);
+        connection.send(FRAME_METHOD, 0, &tune_ok.0)?;
+
+        let mut open = Encoder::method(CONNECTION_OPEN);
+        open.short_string(&sink.vhost);
+        // Reserved capabilities and insist
+        open.short_string("");
+        open.0.push(0);
+        connection.send(FRAME_METHOD, 0, &open.0)?;
+        connection.expect(0, CONNECTION_OPEN_OK)?;
+
+        let mut channel_open = Encoder::method(CHANNEL_OPEN);
+        channel_open.short_string("");
+        connection.send(FRAME_METHOD, 1, &channel_open.0)?;
+        connection.expect(1, CHANNEL_OPEN_OK)?;
+
+        let mut select = Encoder::method(CONFIRM_SELECT);
+        // Wait for the answer
+        select.0.push(0);
+        connection.send(FRAME_METHOD, 1, &select.0)?;
+        connection.expect(1, CONFIRM_SELECT_OK)?;
+        Ok(connection)
+    }
+
+    /// Publish a message and wait for the broker to confirm it
+    fn publish(&mut self, sink: &Sink, id: &str, body: &[u8]) -> Result<(), Error> {
+        let mut publish = Encoder::method(BASIC_PUBLISH);
+        // Reserved ticket
+        publish.u16(0);
+        publish.short_string(&sink.exchange);
+        publish.short_string(&sink.routing_key);
+        // Mandatory, so that unroutable messages are returned
+        publish.0.push(1);
+
+        let mut header = Encoder::default();
+        header.u16(BASIC_CLASS);
+        // Weight
+        header.u16(0);
+        header
+            .0
+            .extend_from_slice(&(body.len() as u64).to_be_bytes());
+        header.u16(PROPERTY_FLAGS);
+        header.short_string("application/json");
+        header.0.push(PERSISTENT);
+        header.short_string(id);
+
+        let mut frames = frame(FRAME_METHOD, 1, &publish.0);
+        frames.extend(frame(FRAME_HEADER, 1, &header.0));
+        for chunk in body.chunks(self.frame_max - FRAME_OVERHEAD) {
+            frames.extend(frame(FRAME_BODY, 1, chunk));
+        }
+        self.stream.write_all(&frames)?;
+
+        let mut returned = None;
+        loop {
+            let (frame_type, _, payload) = self.receive()?;
+            if frame_type != FRAME_METHOD {
+                // The content of a returned message
+                continue;
+            }
+            let mut method = Decoder(&payload);
+            match (method.u16()?, method.u16()?) {
+                BASIC_ACK => return returned.map_or(Ok(()), Err),
+                BASIC_NACK => return Err(Error::other("broker rejected the message")),
+                BASIC_RETURN => {
+                    let code = method.u16()?;
+                    let text = String::from_utf8_lossy(method.short_string()?).into_owned();
+                    returned = Some(Error::other(format!(
+                        "broker returned the message: {} {}",
+                        code, text
+                    )));
+                }
+                id => return Err(unexpected(id, &payload)),
+            }
+        }
+    }
+
+    /// Send a frame
+    fn send(&mut self, frame_type: u8, channel: u16, payload: &[u8]) -> Result<(), Error> {
+        self.stream.write_all(&frame(frame_type, channel, payload))
+    }
+
+    /// Receive a frame other than a heartbeat, as type, channel and payload
+    fn receive(&mut self) -> Result<(u8, u16, Vec<u8>), Error> {
+        loop {
+            let mut header = [0; 7];
+            self.stream.read_exact(&mut header)?;
+            let size = u32::from_be_bytes(header[3..7].try_into().unwrap()) as usize;
+            let mut payload = vec![0; size + 1];
+            self.stream.read_exact(&mut payload)?;
+            if payload.pop() != Some(FRAME_END) {
+                return Err(invalid("frame without end"));
+            }
+            if header[0] != FRAME_HEARTBEAT {
+                let channel = u16::from_be_bytes([header[1], header[2]]);
+                return Ok((header[0], channel, payload));
+            }
+        }
+    }
+
+    /// Receive a method frame and return its arguments if it is the expected method
+    fn expect(&mut self, channel: u16, expected: (u16, u16)) -> Result<Vec<u8>, Error> {
+        let (frame_type, received_channel, payload) = self.receive()?;
+        let mut method = Decoder(&payload);
+        let id = (method.u16()?, method.u16()?);
+        if frame_type != FRAME_METHOD || received_channel != channel || id != expected {
+            return Err(unexpected(id, &payload));
+        }
+        Ok(method.0.to_vec())
+    }
+}
+
+/// Make a frame
+fn frame(frame_type: u8, channel: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + FRAME_OVERHEAD);
+    frame.push(frame_type);
+    frame.extend_from_slice(&channel.to_be_bytes());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame.push(FRAME_END);
+    frame
+}
+
+/// The error for an unexpected method, with the reason if the broker closed the channel or
+/// connection
+fn unexpected(id: (u16, u16), payload: &[u8]) -> Error {
+    if id == CHANNEL_CLOSE || id == CONNECTION_CLOSE {
+        let mut close = Decoder(&payload[4..]);
+        if let (Ok(code), Ok(text)) = (close.u16(), close.short_string()) {
+            return Error::other(format!(
+                "broker closed the {}: {} {}",
+                if id == CHANNEL_CLOSE {
+                    "channel"
+                } else {
+                    "connection"
+                },
+                code,
+                String::from_utf8_lossy(text)
+            ));
+        }
+    }
+    invalid(&format!("method {}.{}", id.0, id.1))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected answer from AMQP broker: {}", message),
+    )
+}
+
+/// Writes values in the encoding of AMQP
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    /// Start the payload of a method frame
+    fn method((class, method): (u16, u16)) -> Encoder {
+        let mut encoder = Encoder::default();
+        encoder.u16(class);
+        encoder.u16(method);
+        encoder
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn short_string(&mut self, value: &str) {
+        self.0.push(value.len().min(255) as u8);
+        self.0
+            .extend_from_slice(&value.as_bytes()[..value.len().min(255)]);
+    }
+
+    fn long_string(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+
+    /// Write a field table with string values
+    fn table(&mut self, fields: &[(&str, &str)]) {
+        let mut table = Encoder::default();
+        for (name, value) in fields {
+            table.short_string(name);
+            table.0.push(b'S');
+            table.long_string(value.as_bytes());
+        }
+        self.long_string(&table.0);
+    }
+}
+
+/// Reads values in the encoding of AMQP
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.slice(N)?.try_into().unwrap())
+    }
+
+    fn slice(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < length {
+            return Err(invalid("frame too short"));
+        }
+        let (value, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        self.take().map(u16::from_be_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        self.take().map(u32::from_be_bytes)
+    }
+
+    fn short_string(&mut self) -> Result<&'a [u8], Error> {
+        let [length] = self.take()?;
+        self.slice(length as usize)
+    }
+
+    fn long_string(&mut self) -> Result<&'a [u8], Error> {
+        let length = self.u32()?;
+        self.slice(length as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Read a frame as the broker and return its type and payload
+    fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0; 7];
+        stream.read_exact(&mut header).unwrap();
+        let size = u32::from_be_bytes(header[3..7].try_into().unwrap()) as usize;
+        let mut payload = vec![0; size + 1];
+        stream.read_exact(&mut payload).unwrap();
+        assert_eq!(payload.pop(), Some(FRAME_END));
+        (header[0], payload)
+    }
+
+    #[test]
+    fn publish_with_confirmation() {
+        // Given
+        let broker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sink = Sink {
+            server: broker.local_addr().unwrap().to_string(),
+            vhost: "/".to_string(),
+            user: "guest".to_string(),
+            password: "secret".to_string(),
+            exchange: "mail".to_string(),
+            routing_key: "received".to_string(),
+        };
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = broker.accept().unwrap();
+            let mut protocol = [0; 8];
+            stream.read_exact(&mut protocol).unwrap();
+            assert_eq!(&protocol, b"AMQP\x00\x00\x09\x01");
+
+            let mut start = Encoder::method(CONNECTION_START);
+            start.0.extend_from_slice(&[0, 9]);
+            start.table(&[]);
+            start.long_string(b"AMQPLAIN PLAIN");
+            start.long_string(b"en_US");
+            stream.write_all(&frame(FRAME_METHOD, 0, &start.0)).unwrap();
+            let (_, start_ok) = read_frame(&mut stream);
+
+            let mut tune = Encoder::method(CONNECTION_TUNE);
+            tune.u16(2047);
+            // The smallest frame size, so the body is split
+            tune.u32(4096);
+            tune.u16(60);
+            stream.write_all(&frame(FRAME_METHOD, 0, &tune.0)).unwrap();
+            read_frame(&mut stream);
+            read_frame(&mut stream);
+            let mut answers = frame(FRAME_METHOD, 0, &Encoder::method(CONNECTION_OPEN_OK).0);
+            let mut channel_open_ok = Encoder::method(CHANNEL_OPEN_OK);
+            channel_open_ok.long_string(b"");
+            answers.extend(frame(FRAME_METHOD, 1, &channel_open_ok.0));
+            answers.extend(frame(
+                FRAME_METHOD,
+                1,
+                &Encoder::method(CONFIRM_SELECT_OK).0,
+            ));
+            stream.write_all(&answers).unwrap();
+            read_frame(&mut stream);
+            read_frame(&mut stream);
+
+            let (_, publish) = read_frame(&mut stream);
+            let (_, header) = read_frame(&mut stream);
+            let mut body = Vec::new();
+            let mut body_frames = 0;
+            while body.len() < 5000 {
+                let (frame_type, chunk) = read_frame(&mut stream);
+                assert_eq!(frame_type, FRAME_BODY);
+                body.extend(chunk);
+                body_frames += 1;
+            }
+            let mut ack = Encoder::method(BASIC_ACK);
+            ack.0.extend_from_slice(&1u64.to_be_bytes());
+            ack.0.push(0);
+            stream.write_all(&frame(FRAME_HEARTBEAT, 0, &[])).unwrap();
+            stream.write_all(&frame(FRAME_METHOD, 1, &ack.0)).unwrap();
+            (start_ok, publish, header, body_frames)
+        });
+
+        // When
+        let mut connection = Connection::open(&sink).unwrap();
+        let published = connection.publish(&sink, "some-id", &[b'x'; 5000]);
+
+        // Then
+        published.unwrap();
+        let (start_ok, publish, header, body_frames) = broker.join().unwrap();
+        assert!(start_ok.ends_with(b"\x05PLAIN\x00\x00\x00\x0d\x00guest\x00secret\x05en_US"));
+        assert_eq!(publish, b"\x00\x3c\x00\x28\x00\x00\x04mail\x08received\x01");
+        assert_eq!(
+            header,
+            b"\x00\x3c\x00\x00\x00\x00\x00\x00\x00\x00\x13\x88\x90\x10\
+              \x10application/json\x02\x07some-id"
+        );
+        assert_eq!(body_frames, 2);
+    }
+}
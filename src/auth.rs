@@ -0,0 +1,82 @@
+//! Checking the credentials of clients that log in with AUTH against a configured user, instead
+//! of accepting any credentials.
+
+use std::sync::Arc;
+
+use crate::smtp::{self, Message, Verdict};
+
+/// The user clients have to log in as
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+/// Accepts only the configured credentials in a session
+pub struct Policy(pub Arc<Credentials>);
+
+impl smtp::Policy for Policy {
+    fn check_recipient(&mut self, _sender: &str, _recipient: &str) -> Option<String> {
+        None
+    }
+
+    fn check_message(&mut self, _message: &mut Message) -> Verdict {
+        Verdict::Accept
+    }
+
+    fn check_credentials(&mut self, user: &str, password: &str) -> bool {
+        user == self.0.user && password == self.0.password
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::io::BufReader;
+
+    #[test]
+    fn log_in_with_plain_and_login() {
+        // Given
+        let request = "EHLO localhost\n\
+                       AUTH PLAIN AHVzZXIAd3Jvbmc=\n\
+                       AUTH LOGIN\n\
+                       dXNlcg==\n\
+                       c2VjcmV0\n\
+                       AUTH PLAIN\n\
+                       MAIL FROM: tester@localhost\n\
+                       RCPT TO: admin@localhost\n\
+                       DATA\n\
+                       Hello\n\
+                       .\n\
+                       QUIT\n";
+        let mut policy = Policy(Arc::new(Credentials {
+            user: "user".to_string(),
+            password: "secret".to_string(),
+        }));
+        let mut response = Vec::new();
+
+        // When
+        let connection = Connection::handle_with_policy(
+            &mut BufReader::new(request.as_bytes()),
+            &mut response,
+            &mut policy,
+        )
+        .unwrap();
+
+        // Then
+        let response = String::from_utf8(response).unwrap();
+        let replies: Vec<&str> = response.lines().collect();
+        assert_eq!(replies[7], "250 AUTH PLAIN LOGIN");
+        assert_eq!(replies[8], "535 Authentication credentials invalid");
+        assert_eq!(
+            replies[9..12],
+            [
+                "334 VXNlcm5hbWU6",
+                "334 UGFzc3dvcmQ6",
+                "235 Authentication succeeded"
+            ]
+        );
+        assert_eq!(replies[12], "503 Already authenticated");
+        assert_eq!(connection.get_user(), Some("user"));
+    }
+}
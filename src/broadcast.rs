@@ -0,0 +1,135 @@
+//! Fan-out of events to any number of subscribers.
+
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// How many events a subscriber may be behind before further events are dropped for it
+const CAPACITY: usize = 1000;
+
+/// Delivers every published event to all current subscribers.
+///
+/// Subscribers receive events through their own bounded channel, so a slow subscriber never
+/// blocks the publisher. Events for a subscriber that is too far behind are dropped and logged,
+/// so it cannot pile up memory. Subscribers that dropped their receiver are removed on the next
+/// publish. A panic while holding the lock cannot leave the list inconsistent, so a poisoned
+/// lock is ignored.
+pub struct Broadcaster<T: Clone> {
+    subscribers: Mutex<Vec<Subscriber<T>>>,
+    capacity: usize,
+}
+
+struct Subscriber<T> {
+    /// What the subscriber does with events, for the log
+    name: &'static str,
+    sender: SyncSender<T>,
+    /// Events dropped since the subscriber last kept up
+    dropped: u64,
+}
+
+impl<T: Clone> Broadcaster<T> {
+    pub fn new() -> Broadcaster<T> {
+        Broadcaster::with_capacity(CAPACITY)
+    }
+
+    fn with_capacity(capacity: usize) -> Broadcaster<T> {
+        Broadcaster {
+            subscribers: Mutex::new(Vec::new()),
+            capacity,
+        }
+    }
+
+    /// Receive all events published from now on, as long as the subscriber keeps up
+    pub fn subscribe(&self, name: &'static str) -> Receiver<T> {
+        let (sender, receiver) = mpsc::sync_channel(self.capacity);
+        self.subscribers().push(Subscriber {
+            name,
+            sender,
+            dropped: 0,
+        });
+        receiver
+    }
+
+    pub fn publish(&self, event: T) {
+        self.subscribers().retain_mut(|subscriber| {
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => {
+                    if subscriber.dropped > 0 {
+                        tracing::warn!(
+                            "{} caught up after {} sessions were dropped for it",
+                            subscriber.name,
+                            subscriber.dropped
+                        );
+                        subscriber.dropped = 0;
+                    }
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    // Once per backlog, so a stuck subscriber does not flood the log
+                    if subscriber.dropped == 0 {
+                        tracing::warn!(
+                            "{} is behind, dropping sessions for it until it catches up",
+                            subscriber.name
+                        );
+                    }
+                    subscriber.dropped += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Drop all subscribers, whose receivers end after the events published so far
+    pub fn close(&self) {
+        self.subscribers().clear();
+    }
+
+    fn subscribers(&self) -> MutexGuard<'_, Vec<Subscriber<T>>> {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_to_subscribers() {
+        let broadcaster = Broadcaster::new();
+        let first = broadcaster.subscribe("first");
+        let second = broadcaster.subscribe("second");
+
+        broadcaster.publish(1);
+        drop(second);
+        broadcaster.publish(2);
+
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(broadcaster.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn drop_events_for_slow_subscribers() {
+        // Given
+        let broadcaster = Broadcaster::with_capacity(2);
+        let slow = broadcaster.subscribe("slow");
+
+        // When
+        for event in 1..=4 {
+            broadcaster.publish(event);
+        }
+        let behind: Vec<i32> = slow.try_iter().collect();
+        let dropped = broadcaster.subscribers().first().map(|s| s.dropped);
+        broadcaster.publish(5);
+
+        // Then
+        assert_eq!(behind, vec![1, 2]);
+        assert_eq!(dropped, Some(2));
+        assert_eq!(slow.try_iter().collect::<Vec<_>>(), vec![5]);
+        assert_eq!(
+            broadcaster.subscribers().first().map(|s| s.dropped),
+            Some(0)
+        );
+    }
+}
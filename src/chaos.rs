@@ -0,0 +1,146 @@
+//! Injecting random failures into sessions, to test how clients cope with a flaky server.
+//!
+//! Every behavior has its own probability, from 0 for never to 1 for always, and is decided anew
+//! each time it can happen. Recipients are rejected temporarily or permanently, connections are
+//! dropped after the reply to DATA, and the greeting or the replies to commands are delayed.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::smtp::{self, Interference, Message, Stage, Verdict};
+
+/// Reply to recipients that are rejected temporarily
+const MSG_TEMPORARY_FAILURE: &str = "451 Temporary failure, try again later";
+/// Reply to recipients that are rejected permanently
+const MSG_MAILBOX_UNAVAILABLE: &str = "550 Mailbox unavailable";
+
+/// The probabilities of the failures of a server
+pub struct Chaos {
+    pub rcpt_tempfail: f64,
+    pub rcpt_reject: f64,
+    /// Dropping the connection instead of reading the content of a message
+    pub drop_data: f64,
+    /// Delaying the reply to a command
+    pub slow_reply: f64,
+    pub slow_greeting: f64,
+    /// How long slow replies and greetings are delayed
+    pub delay: Duration,
+}
+
+/// Injects failures into a session
+pub struct Policy(pub Arc<Chaos>);
+
+impl smtp::Policy for Policy {
+    fn check_recipient(&mut self, _sender: &str, _recipient: &str) -> Option<String> {
+        if happens(self.0.rcpt_tempfail) {
+            Some(MSG_TEMPORARY_FAILURE.to_string())
+        } else if happens(self.0.rcpt_reject) {
+            Some(MSG_MAILBOX_UNAVAILABLE.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn check_message(&mut self, _message: &mut Message) -> Verdict {
+        Verdict::Accept
+    }
+
+    fn interfere(&mut self, stage: &Stage) -> Interference {
+        let probability = match stage {
+            Stage::Greeting => self.0.slow_greeting,
+            Stage::Command(_) => self.0.slow_reply,
+            Stage::Data if happens(self.0.drop_data) => return Interference::Disconnect,
+            Stage::Data => return Interference::Proceed,
+        };
+        if happens(probability) {
+            Interference::Delay(self.0.delay)
+        } else {
+            Interference::Proceed
+        }
+    }
+}
+
+/// Decide randomly whether something with a probability happens
+fn happens(probability: f64) -> bool {
+    // Every RandomState has new keys, so the hash of nothing is a random number
+    let random = RandomState::new().build_hasher().finish() >> 11;
+    (random as f64 / (1u64 << 53) as f64) < probability
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::io::{BufReader, ErrorKind};
+    use std::time::Instant;
+
+    fn chaos() -> Chaos {
+        Chaos {
+            rcpt_tempfail: 0.0,
+            rcpt_reject: 0.0,
+            drop_data: 0.0,
+            slow_reply: 0.0,
+            slow_greeting: 0.0,
+            delay: Duration::from_millis(50),
+        }
+    }
+
+    #[test]
+    fn reject_recipients_after_slow_greeting() {
+        // Given
+        let request = "HELO localhost\n\
+                       MAIL FROM: tester@localhost\n\
+                       RCPT TO: admin@localhost\n\
+                       QUIT\n";
+        let mut policy = Policy(Arc::new(Chaos {
+            rcpt_reject: 1.0,
+            slow_greeting: 1.0,
+            ..chaos()
+        }));
+        let mut response = Vec::new();
+        let start = Instant::now();
+
+        // When
+        let _ = Connection::handle_with_policy(
+            &mut BufReader::new(request.as_bytes()),
+            &mut response,
+            &mut policy,
+        );
+
+        // Then
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(response.lines().nth(3), Some(MSG_MAILBOX_UNAVAILABLE));
+    }
+
+    #[test]
+    fn drop_connection_during_data() {
+        // Given
+        let request = "HELO localhost\n\
+                       MAIL FROM: tester@localhost\n\
+                       RCPT TO: admin@localhost\n\
+                       DATA\n\
+                       Hello\n\
+                       .\n\
+                       QUIT\n";
+        let mut policy = Policy(Arc::new(Chaos {
+            drop_data: 1.0,
+            ..chaos()
+        }));
+        let mut response = Vec::new();
+
+        // When
+        let result = Connection::handle_with_policy(
+            &mut BufReader::new(request.as_bytes()),
+            &mut response,
+            &mut policy,
+        );
+
+        // Then
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::ConnectionAborted);
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(response.lines().last(), Some("354 Send message content"));
+    }
+}
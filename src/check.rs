@@ -0,0 +1,194 @@
+//! Validation of the server configuration without starting the server.
+//!
+//! Every check runs even if an earlier one fails, so that all problems are reported at once.
+
+use std::fs::OpenOptions;
+use std::net::TcpListener;
+use std::path::Path;
+
+use clap::{App, SubCommand};
+
+use crate::error::Error;
+use crate::Config;
+
+/// Name of the subcommand
+pub const SUBCOMMAND_NAME: &str = "check";
+
+/// The command line definition of the subcommand, taking the same settings as the server
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Check the server settings and that its addresses can be bound, then exit")
+        .args(&crate::server_args())
+}
+
+/// Run all checks for the main server and the virtual servers and print their outcome on stdout
+pub fn run(configs: &[Config]) -> Result<(), Error> {
+    let mut problems = 0;
+    let mut report = |subject: &str, outcome: Result<(), String>| match outcome {
+        Ok(()) => println!("{}: ok", subject),
+        Err(e) => {
+            problems += 1;
+            println!("{}: {}", subject, e);
+        }
+    };
+
+    for config in configs {
+        let server = match &config.name {
+            Some(name) => format!("server {} ", name),
+            None => String::new(),
+        };
+        for address in &config.bind_addresses {
+            report(&format!("{}{}", server, address), check_address(address));
+        }
+        for path in &config.socket_paths {
+            report(&format!("{}unix:{}", server, path), check_socket_path(path));
+        }
+        if let Some(queue) = config.relay.as_ref().and_then(|relay| relay.queue.as_ref()) {
+            report(
+                &format!("{}relay queue {}", server, queue.path.display()),
+                check_directory(&queue.path),
+            );
+        }
+        if let Some(path) = config.store.as_ref().and_then(|store| store.path()) {
+            report(
+                &format!("{}storage {}", server, path.display()),
+                check_directory(path),
+            );
+        }
+        if let Some(path) = &config.record {
+            report(
+                &format!("{}recordings {}", server, path.display()),
+                check_directory(path),
+            );
+        }
+    }
+
+    // The settings of the whole process are those of the main server
+    let config = &configs[0];
+    if config.daemon {
+        report("daemon mode", check_daemon());
+    }
+    if let Some(path) = &config.pid_file {
+        report(&format!("pid file {}", path), check_file(path));
+    }
+    if let Some(path) = &config.log_file {
+        report(&format!("log file {}", path), check_file(path));
+    }
+
+    match problems {
+        0 => Ok(()),
+        problems => Err(Error::Check { problems }),
+    }
+}
+
+/// Check that a TCP address can be bound
+fn check_address(address: &str) -> Result<(), String> {
+    TcpListener::bind(address)
+        .map(drop)
+        .map_err(|e| format!("cannot be bound: {}", e))
+}
+
+/// Check that a unix domain socket can be bound at the path.
+/// A socket file left behind by an earlier run is replaced by the server, one in use is not.
+#[cfg(unix)]
+fn check_socket_path(path: &str) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            Err("exists and is not a socket".to_string())
+        }
+        Ok(_) if UnixStream::connect(path).is_ok() => Err("in use by a running server".to_string()),
+        Ok(_) => Ok(()),
+        Err(_) => {
+            // Bind a socket to find out whether the directory allows it, then remove it again
+            UnixListener::bind(path).map_err(|e| format!("cannot be bound: {}", e))?;
+            std::fs::remove_file(path).map_err(|e| format!("cannot be removed: {}", e))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn check_socket_path(_path: &str) -> Result<(), String> {
+    Err("unix domain sockets are not supported on this platform".to_string())
+}
+
+fn check_daemon() -> Result<(), String> {
+    if cfg!(unix) {
+        Ok(())
+    } else {
+        Err("not supported on this platform".to_string())
+    }
+}
+
+/// Check that the daemon will be able to write a file, without changing it
+fn check_file(path: &str) -> Result<(), String> {
+    let path = Path::new(path);
+    if path.exists() {
+        return OpenOptions::new()
+            .append(true)
+            .open(path)
+            .map(drop)
+            .map_err(|e| format!("cannot be written: {}", e));
+    }
+
+    match path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        Some(parent) if !parent.is_dir() => {
+            Err(format!("directory {} does not exist", parent.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check that a directory can be written to, or does not exist yet and is created when needed
+fn check_directory(path: &Path) -> Result<(), String> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => Err("exists and is not a directory".to_string()),
+        Ok(metadata) if metadata.permissions().readonly() => Err("is read-only".to_string()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Settings;
+
+    #[test]
+    #[cfg(unix)]
+    fn report_every_problem() {
+        // Given
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port().to_string();
+        let args = [
+            "",
+            "--host=127.0.0.1",
+            "--smtp-port",
+            &port,
+            "--listen=127.0.0.1:0",
+            "--daemon",
+            "--pid-file=/nonexistent/smtp.pid",
+        ];
+        let settings = Settings::from_args(
+            crate::serve_subcommand,
+            args.iter().map(|arg| arg.to_string()).collect(),
+        )
+        .unwrap();
+        let config = crate::config(&settings, None).unwrap();
+
+        // When
+        let result = run(&[config]);
+
+        // Then
+        // The taken port and the pid file, but not the free port and the daemon
+        assert!(matches!(result, Err(Error::Check { problems: 2 })));
+        assert_eq!(
+            check_file("/nonexistent/smtp.pid"),
+            Err("directory /nonexistent does not exist".to_string())
+        );
+    }
+}
@@ -0,0 +1,442 @@
+//! A minimal SMTP client, which can encrypt the connection with STARTTLS or from the start.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, StreamOwned};
+
+use crate::tls;
+
+/// How long to wait for connecting, sending and every reply, which servers may take a while with
+/// after the content
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A reply from the server, which is also the error when it is not the expected one
+#[derive(Debug)]
+pub struct Reply {
+    pub code: u16,
+    pub text: String,
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected reply: {} {}", self.code, self.text)
+    }
+}
+
+impl std::error::Error for Reply {}
+
+/// Whether an error is a 5xx reply, which the server would give again on another attempt
+pub fn is_permanent(e: &Error) -> bool {
+    e.get_ref()
+        .and_then(|e| e.downcast_ref::<Reply>())
+        .is_some_and(|reply| reply.code / 100 == 5)
+}
+
+/// How the connection to a server is encrypted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encryption {
+    None,
+    /// Switching to TLS with STARTTLS after the greeting, usually on port 587
+    StartTls,
+    /// TLS from the start, usually on port 465
+    Tls,
+}
+
+impl Encryption {
+    pub const NAMES: [&'static str; 3] = ["none", "starttls", "tls"];
+
+    pub fn from_name(name: &str) -> Option<Encryption> {
+        match name {
+            "none" => Some(Encryption::None),
+            "starttls" => Some(Encryption::StartTls),
+            "tls" => Some(Encryption::Tls),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encryption::None => "none",
+            Encryption::StartTls => "starttls",
+            Encryption::Tls => "tls",
+        }
+    }
+}
+
+/// A client session with an SMTP server
+pub struct Client {
+    stream: BufReader<Stream>,
+}
+
+/// The connection to a server, which is written to underneath the reading buffer
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Client {
+    /// Connect to a server, wait for its greeting and introduce ourselves as the given domain
+    pub fn connect(address: &str, domain: &str) -> Result<Client, Error> {
+        Client::connect_with(address, domain, Encryption::None, None)
+    }
+
+    /// Connect to a server, encrypt the connection, introduce ourselves as the given domain and
+    /// log in with AUTH PLAIN if there are credentials
+    pub fn connect_with(
+        address: &str,
+        domain: &str,
+        encryption: Encryption,
+        credentials: Option<(&str, &str)>,
+    ) -> Result<Client, Error> {
+        Client::establish(
+            address,
+            domain,
+            encryption,
+            credentials,
+            tls::client_config(),
+        )
+    }
+
+    /// Start a session, verifying the certificate of the server with the given settings
+    fn establish(
+        address: &str,
+        domain: &str,
+        encryption: Encryption,
+        credentials: Option<(&str, &str)>,
+        config: Arc<ClientConfig>,
+    ) -> Result<Client, Error> {
+        let stream = connect(address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let stream = match encryption {
+            Encryption::Tls => encrypt(stream, address, config.clone())?,
+            _ => Stream::Plain(stream),
+        };
+        let mut client = Client {
+            stream: BufReader::new(stream),
+        };
+        client.expect(2)?;
+        if encryption == Encryption::None && credentials.is_none() {
+            client.command(&format!("HELO {}", domain), 2)?;
+            return Ok(client);
+        }
+
+        // STARTTLS and AUTH are extensions, so the server has to be greeted with EHLO
+        let greeting = format!("EHLO {}", domain);
+        client.command(&greeting, 2)?;
+        if encryption == Encryption::StartTls {
+            client.command("STARTTLS", 2)?;
+            let Stream::Plain(stream) = client.stream.into_inner() else {
+                unreachable!("only plain connections switch to TLS");
+            };
+            client = Client {
+                stream: BufReader::new(encrypt(stream, address, config)?),
+            };
+            // The session starts over, as if we had just connected
+            client.command(&greeting, 2)?;
+        }
+        if let Some((user, password)) = credentials {
+            let credentials = format!("\0{}\0{}", user, password);
+            client.command(&format!("AUTH PLAIN {}", base64(credentials.as_bytes())), 2)?;
+        }
+        Ok(client)
+    }
+
+    /// Send a message with a complete mail transaction.
+    /// The data is dot-stuffed and terminated as needed.
+    pub fn send(&mut self, sender: &str, recipients: &[String], data: &[u8]) -> Result<(), Error> {
+        self.command(&format!("MAIL FROM:<{}>", sender), 2)?;
+        for recipient in recipients {
+            self.command(&format!("RCPT TO:<{}>", recipient), 2)?;
+        }
+        self.command("DATA", 3)?;
+
+        let mut content = Vec::with_capacity(data.len() + 5);
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if line.starts_with(b".") {
+                content.push(b'.');
+            }
+            content.extend_from_slice(line);
+        }
+        if !content.is_empty() && !content.ends_with(b"\n") {
+            content.extend_from_slice(b"\r\n");
+        }
+        content.extend_from_slice(b".\r\n");
+        self.write(&content)?;
+
+        self.expect(2)?;
+        Ok(())
+    }
+
+    /// Reset the session, which also checks that the server still answers before the connection
+    /// is used again
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.command("RSET", 2)?;
+        Ok(())
+    }
+
+    /// End the session
+    pub fn quit(mut self) -> Result<(), Error> {
+        self.command("QUIT", 2)?;
+        // Tell the server that the session ended on purpose, not by a truncation attack
+        if let Stream::Tls(stream) = self.stream.get_mut() {
+            stream.conn.send_close_notify();
+            let _ = stream.conn.complete_io(&mut stream.sock);
+        }
+        Ok(())
+    }
+
+    /// Send a command and check that the reply has the expected class, e.g. 2 for 2xx
+    fn command(&mut self, line: &str, class: u16) -> Result<Reply, Error> {
+        // One write per command, so the command is not split over several packets
+        self.write(format!("{}\r\n", line).as_bytes())?;
+        self.expect(class)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let stream = self.stream.get_mut();
+        stream.write_all(data)?;
+        stream.flush()
+    }
+
+    fn expect(&mut self, class: u16) -> Result<Reply, Error> {
+        let reply = self.read_reply()?;
+        if reply.code / 100 == class {
+            Ok(reply)
+        } else {
+            Err(Error::other(reply))
+        }
+    }
+
+    /// Read a possibly multi-line reply
+    fn read_reply(&mut self) -> Result<Reply, Error> {
+        let mut text = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed by server",
+                ));
+            }
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse::<u16>().ok())
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, format!("invalid reply: {}", line))
+                })?;
+            text.push(line.get(4..).unwrap_or("").to_string());
+
+            // Continuation lines have a dash after the code
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(Reply {
+                    code,
+                    text: text.join("\n"),
+                });
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Do the TLS handshake with the server at a host:port, which needs a certificate for the host
+fn encrypt(stream: TcpStream, address: &str, config: Arc<ClientConfig>) -> Result<Stream, Error> {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let connection = ClientConnection::new(config, name).map_err(Error::other)?;
+    let mut stream = StreamOwned::new(connection, stream);
+    // The handshake is done right away, so that it fails here and not on the next command
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    Ok(Stream::Tls(Box::new(stream)))
+}
+
+/// Connect to the first address of a host:port that answers in time
+fn connect(address: &str) -> Result<TcpStream, Error> {
+    let mut last_error = Error::new(ErrorKind::NotFound, format!("no address for {}", address));
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Encode data in base64 with padding
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::{AcceptAll, Connection, Transport};
+    use crate::tls::tests::{test_acceptor, test_client_config};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn send_message() {
+        // Given
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            Connection::handle(&mut reader, &mut stream).unwrap()
+        });
+
+        // When
+        let mut client = Client::connect(&address, "localhost").unwrap();
+        client
+            .send(
+                "tester@localhost",
+                &["admin@localhost".to_string()],
+                b"Hello\r\n.hidden dot\r\nBye",
+            )
+            .unwrap();
+        client.quit().unwrap();
+
+        // Then
+        let result = server.join().unwrap();
+        let message = &result.get_messages().unwrap()[0];
+        assert_eq!(message.get_sender(), "<tester@localhost>");
+        assert_eq!(message.get_recipients().join(", "), "<admin@localhost>");
+        assert_eq!(message.get_data(), "Hello\n.hidden dot\nBye");
+    }
+
+    /// Server side of a session that can switch to TLS
+    struct Encrypted {
+        reader: BufReader<tls::Stream<TcpStream>>,
+        writer: tls::Stream<TcpStream>,
+        acceptor: tls::Acceptor,
+    }
+
+    impl Transport for Encrypted {
+        fn reader(&mut self) -> &mut dyn BufRead {
+            &mut self.reader
+        }
+
+        fn writer(&mut self) -> &mut dyn Write {
+            &mut self.writer
+        }
+
+        fn can_start_tls(&self) -> bool {
+            true
+        }
+
+        fn start_tls(&mut self) -> Result<(), Error> {
+            self.writer.start_tls(&self.acceptor)
+        }
+    }
+
+    #[test]
+    fn send_message_over_tls() {
+        // Given
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // The name of the test certificate
+        let address = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let server = thread::spawn(move || {
+            [false, true].map(|from_start| {
+                let (stream, _) = listener.accept().unwrap();
+                let stream = tls::Stream::new(stream);
+                let mut transport = Encrypted {
+                    reader: BufReader::new(stream.clone()),
+                    writer: stream,
+                    acceptor: test_acceptor(),
+                };
+                if from_start {
+                    transport.start_tls().unwrap();
+                }
+                Connection::handle_transport(&mut transport, &mut AcceptAll).unwrap()
+            })
+        });
+
+        // When
+        for encryption in [Encryption::StartTls, Encryption::Tls] {
+            let mut client = Client::establish(
+                &address,
+                "localhost",
+                encryption,
+                Some(("tester", "secret")),
+                test_client_config(),
+            )
+            .unwrap();
+            client
+                .send("tester@localhost", &["admin@localhost".to_string()], b"Hi")
+                .unwrap();
+            client.quit().unwrap();
+        }
+
+        // Then
+        let [starttls, tls] = server.join().unwrap();
+        assert!(starttls.is_encrypted());
+        for result in [starttls, tls] {
+            assert_eq!(result.get_user(), Some("tester"));
+            assert_eq!(result.get_messages().unwrap()[0].get_data(), "Hi");
+        }
+    }
+
+    #[test]
+    fn encode_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"\0user\0secret"), "AHVzZXIAc2VjcmV0");
+    }
+}
@@ -0,0 +1,105 @@
+//! The server's notion of the current time, which can be shifted or frozen so that behavior that
+//! depends on time, like timestamps and relay retries, can be tested deterministically.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where the current time comes from
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// The current time in seconds since the epoch
+    fn unix_time(&self) -> u64 {
+        self.now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs())
+    }
+}
+
+/// The time of the system, shifted by an offset that is usually zero
+pub struct SystemClock {
+    /// Seconds to add to the time of the system, negative to go back
+    pub offset: i64,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        let offset = Duration::from_secs(self.offset.unsigned_abs());
+        if self.offset < 0 {
+            SystemTime::now() - offset
+        } else {
+            SystemTime::now() + offset
+        }
+    }
+}
+
+/// A time that stands still
+pub struct FrozenClock(pub SystemTime);
+
+impl FrozenClock {
+    /// Freeze the time at seconds since the epoch
+    pub fn at(unix_time: u64) -> FrozenClock {
+        FrozenClock(UNIX_EPOCH + Duration::from_secs(unix_time))
+    }
+}
+
+impl Clock for FrozenClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+/// Convert days since the epoch into a year, month and day, after Howard Hinnant's algorithm
+/// with years starting in March
+pub fn civil_date(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    (year_of_era + era * 400 + u64::from(month <= 2), month, day)
+}
+
+/// Convert a year, month and day into days since the epoch, the reverse of [`civil_date`]. Dates
+/// before the epoch become day 0.
+pub fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era).saturating_sub(719_468)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shift_and_freeze_time() {
+        let now = SystemClock { offset: 0 }.unix_time();
+        let past = SystemClock { offset: -86400 }.unix_time();
+        let future = SystemClock { offset: 3600 }.unix_time();
+        assert!((86399..=86401).contains(&(now - past)));
+        assert!((3599..=3601).contains(&(future - now)));
+
+        assert_eq!(FrozenClock::at(1_700_000_000).unix_time(), 1_700_000_000);
+    }
+
+    #[test]
+    fn convert_civil_dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(19_675), (2023, 11, 14));
+        assert_eq!(days_from_civil(2023, 11, 14), 19_675);
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+        assert_eq!(civil_date(19_782), (2024, 2, 29));
+    }
+}
@@ -0,0 +1,97 @@
+//! Shell completions and a man page generated from the command line definition.
+
+use std::io::{self, Write};
+
+use clap::{App, Arg, ArgMatches, Shell, SubCommand};
+
+/// Name of the subcommand
+pub const SUBCOMMAND_NAME: &str = "completions";
+
+const TARGET_ARG_NAME: &str = "target";
+
+/// Target that generates a man page instead of shell completions
+const MAN_TARGET: &str = "man";
+
+/// Name of the installed executable
+const BIN_NAME: &str = "rust-smtp-server";
+
+/// What to generate
+pub enum Target {
+    Shell(Shell),
+    Man,
+}
+
+/// The command line definition of the subcommand
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Print shell completions or a man page on stdout")
+        .arg(
+            Arg::with_name(TARGET_ARG_NAME)
+                .help("Shell to complete for, or man for a man page")
+                .possible_values(&Shell::variants())
+                .possible_value(MAN_TARGET)
+                .required(true),
+        )
+}
+
+/// Get the target from the parsed subcommand arguments
+pub fn target(matches: &ArgMatches) -> Target {
+    match matches.value_of(TARGET_ARG_NAME).unwrap() {
+        MAN_TARGET => Target::Man,
+        shell => Target::Shell(shell.parse().unwrap()),
+    }
+}
+
+pub fn run(target: Target) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    match target {
+        // The zsh completions use the name as an identifier, so it must not contain spaces
+        Target::Shell(shell) => crate::app()
+            .name(BIN_NAME)
+            .gen_completions_to(BIN_NAME, shell, &mut out),
+        Target::Man => write_man_page(&mut out)?,
+    }
+    out.flush()
+}
+
+/// Write a man page with the help texts of the program and all subcommands
+fn write_man_page(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, ".TH {} 1", BIN_NAME.to_uppercase())?;
+    writeln!(out, ".SH NAME")?;
+    writeln!(
+        out,
+        "{} \\- Simple SMTP server that prints received messages",
+        BIN_NAME
+    )?;
+
+    writeln!(out, ".SH DESCRIPTION")?;
+    write_preformatted(out, &help(&[]))?;
+    for subcommand in crate::SUBCOMMAND_NAMES {
+        writeln!(out, ".SH {}", subcommand.to_uppercase())?;
+        write_preformatted(out, &help(&[subcommand]))?;
+    }
+    Ok(())
+}
+
+/// Get the help text of the program or a subcommand as printed by --help
+fn help(subcommand: &[&str]) -> String {
+    let args = [BIN_NAME].iter().chain(subcommand).chain(&["--help"]);
+    match crate::app().get_matches_from_safe(args) {
+        Err(e) => e.message,
+        Ok(_) => String::new(),
+    }
+}
+
+/// Write text in a block that roff leaves as is
+fn write_preformatted(out: &mut dyn Write, text: &str) -> io::Result<()> {
+    writeln!(out, ".nf")?;
+    for line in text.lines() {
+        let line = line.replace('\\', "\\e");
+        // Lines starting with these characters would be taken as requests
+        if line.starts_with('.') || line.starts_with('\'') {
+            write!(out, "\\&")?;
+        }
+        writeln!(out, "{}", line)?;
+    }
+    writeln!(out, ".fi")
+}
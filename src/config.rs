@@ -0,0 +1,384 @@
+//! Server settings from environment variables and a TOML configuration file.
+//!
+//! The keys of the file are the long names of the command line flags, e.g. `buffer-size = 16384`.
+//! Environment variables use the same names in upper case with a prefix, e.g.
+//! `SMTP_SERVER_BUFFER_SIZE=16384`, and settings that can be given more than once take a list
+//! separated by commas. Both are turned into command line arguments and parsed with
+//! the same definitions as the real command line, so all sources are validated alike.
+//!
+//! A profile is a named set of settings in the configuration file format. Some are built in,
+//! more can be defined in tables under `[profiles]` in the configuration file.
+//!
+//! The command line takes precedence over the environment, then come the selected profile, the
+//! file and the defaults.
+//!
+//! Tables under `[servers]` in the configuration file define virtual servers that run in the same
+//! process next to the main one. Their settings come only from their own table and the defaults,
+//! so nothing given for the main server leaks into them.
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+
+use clap::{App, AppSettings, ArgMatches, ArgSettings, Values};
+use toml::{Table, Value};
+
+use crate::error::Error;
+
+/// Prefix of environment variables with settings
+const ENV_PREFIX: &str = "SMTP_SERVER_";
+
+/// Key of the table with profiles in the configuration file
+const PROFILES_KEY: &str = "profiles";
+
+/// Key of the table with virtual servers in the configuration file
+const SERVERS_KEY: &str = "servers";
+
+/// Profiles that are always available
+const BUILTIN_PROFILES: &str = r#"
+# Continuous integration: a line of JSON per message for the job log
+[ci]
+print = "summary"
+print-format = "jsonl"
+
+# Load tests: nothing printed, larger reads
+[load]
+print = "none"
+buffer-size = 65536
+
+# Like a picky production server: clients log in, messages are checked and limited
+[strict]
+auth-required = true
+verify = true
+max-message-size = 10485760
+max-recipients = 100
+max-connections-per-ip = 10
+max-commands-per-second = 50
+
+# Retry logic of clients: some of every kind of failure, with short delays
+[chaos]
+chaos-rcpt-tempfail = 0.1
+chaos-rcpt-reject = 0.02
+chaos-drop-data = 0.05
+chaos-slow-reply = 0.1
+chaos-slow-greeting = 0.1
+chaos-delay = 2
+"#;
+
+/// Environment variables with the prefix that are not settings
+#[cfg(unix)]
+const INTERNAL_VARS: &[&str] = &[crate::handoff::LISTEN_FDS_VAR, crate::handoff::READY_FD_VAR];
+#[cfg(not(unix))]
+const INTERNAL_VARS: &[&str] = &[];
+
+/// Settings from the command line, falling back to the environment, the profile, the
+/// configuration file and then to defaults
+pub struct Settings<'a> {
+    command_line: ArgMatches<'a>,
+    environment: ArgMatches<'a>,
+    profile: Option<ArgMatches<'a>>,
+    file: Option<ArgMatches<'a>>,
+    /// Settings of the virtual servers by name
+    servers: Vec<(String, Settings<'a>)>,
+}
+
+impl<'a> Settings<'a> {
+    /// Combine the command line with the environment, the configuration file and the profile
+    /// named by any of the earlier ones
+    pub fn load<'b>(
+        app: fn() -> App<'a, 'b>,
+        command_line: ArgMatches<'a>,
+    ) -> Result<Settings<'a>, Error> {
+        let lists: Vec<&str> = crate::server_args()
+            .iter()
+            .filter(|arg| arg.is_set(ArgSettings::Multiple))
+            .map(|arg| arg.b.name)
+            .collect();
+        let environment = parse_args(app(), environment_args(env::vars_os(), &lists))
+            .map_err(Error::Environment)?;
+        let mut settings = Settings {
+            command_line,
+            environment,
+            profile: None,
+            file: None,
+            servers: Vec::new(),
+        };
+
+        let mut profiles = BUILTIN_PROFILES
+            .parse::<Table>()
+            .expect("built-in profiles are valid");
+        if let Some(path) = settings
+            .value_of(crate::CONFIG_ARG_NAME)
+            .map(str::to_string)
+        {
+            let invalid = |message: String| Error::Config {
+                path: path.clone(),
+                message,
+            };
+            let mut table = read_file(&path).map_err(invalid)?;
+            match table.remove(PROFILES_KEY) {
+                Some(Value::Table(file_profiles)) => profiles.extend(file_profiles),
+                Some(_) => return Err(invalid(format!("{} must be a table", PROFILES_KEY))),
+                None => {}
+            }
+            match table.remove(SERVERS_KEY) {
+                Some(Value::Table(servers)) => {
+                    for (name, server) in servers {
+                        let invalid = |message: String| {
+                            invalid(format!("{}.{}: {}", SERVERS_KEY, name, message))
+                        };
+                        let Value::Table(server) = server else {
+                            return Err(invalid("not a table".to_string()));
+                        };
+                        let defaults = parse_args(app(), vec![String::new()]).map_err(invalid)?;
+                        let file = parse_args(app(), file_args(&server).map_err(invalid)?)
+                            .map_err(invalid)?;
+                        let server = Settings {
+                            command_line: defaults.clone(),
+                            environment: defaults,
+                            profile: None,
+                            file: Some(file),
+                            servers: Vec::new(),
+                        };
+                        settings.servers.push((name, server));
+                    }
+                }
+                Some(_) => return Err(invalid(format!("{} must be a table", SERVERS_KEY))),
+                None => {}
+            }
+            settings.file =
+                Some(parse_args(app(), file_args(&table).map_err(invalid)?).map_err(invalid)?);
+        }
+
+        if let Some(name) = settings
+            .value_of(crate::PROFILE_ARG_NAME)
+            .map(str::to_string)
+        {
+            let invalid = |message: String| Error::Profile {
+                name: name.clone(),
+                message,
+            };
+            let table = match profiles.get(&name) {
+                Some(Value::Table(table)) => table,
+                Some(_) => return Err(invalid("not a table".to_string())),
+                None => return Err(invalid("no such profile".to_string())),
+            };
+            settings.profile =
+                Some(parse_args(app(), file_args(table).map_err(invalid)?).map_err(invalid)?);
+        }
+        Ok(settings)
+    }
+
+    /// Take the settings from arguments only, without the environment or a configuration file,
+    /// e.g. for a server embedded in another program. The first argument stands in for the
+    /// program name.
+    pub fn from_args<'b>(
+        app: fn() -> App<'a, 'b>,
+        args: Vec<String>,
+    ) -> Result<Settings<'a>, String> {
+        Ok(Settings {
+            command_line: parse_args(app(), args)?,
+            environment: parse_args(app(), vec![String::new()])?,
+            profile: None,
+            file: None,
+            servers: Vec::new(),
+        })
+    }
+
+    /// The settings of the virtual servers from the configuration file, by name
+    pub fn servers(&self) -> &[(String, Settings<'a>)] {
+        &self.servers
+    }
+
+    /// The layer that sets the setting with the given name and its description, if any does
+    fn layer(&self, name: &str) -> Option<(&ArgMatches<'a>, &'static str)> {
+        let layers = [
+            (Some(&self.command_line), "command line"),
+            (Some(&self.environment), "environment"),
+            (self.profile.as_ref(), "profile"),
+            (self.file.as_ref(), "configuration file"),
+        ];
+        layers.iter().find_map(|&(matches, origin)| {
+            matches
+                .filter(|matches| matches.occurrences_of(name) > 0)
+                .map(|matches| (matches, origin))
+        })
+    }
+
+    /// The matches that decide the setting with the given name
+    fn source(&self, name: &str) -> &ArgMatches<'a> {
+        // Every layer has the defaults
+        self.layer(name)
+            .map_or(&self.command_line, |(matches, _)| matches)
+    }
+
+    /// Describe where the setting with the given name comes from
+    pub fn origin(&self, name: &str) -> &'static str {
+        self.layer(name).map_or("default", |(_, origin)| origin)
+    }
+
+    pub fn value_of(&self, name: &str) -> Option<&str> {
+        self.source(name).value_of(name)
+    }
+
+    pub fn values_of(&self, name: &str) -> Option<Values<'_>> {
+        self.source(name).values_of(name)
+    }
+
+    pub fn is_present(&self, name: &str) -> bool {
+        self.source(name).is_present(name)
+    }
+}
+
+/// Read a configuration file
+fn read_file(path: &str) -> Result<Table, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    content
+        .parse::<Table>()
+        .map_err(|e| e.message().to_string())
+}
+
+/// Parse arguments that were not given on the command line
+fn parse_args<'a>(app: App<'a, '_>, args: Vec<String>) -> Result<ArgMatches<'a>, String> {
+    app.setting(AppSettings::ColorNever)
+        .get_matches_from_safe(args)
+        .map_err(|e| {
+            // Only the first line describes the problem, the rest is command line usage
+            let message = e.message.lines().next().unwrap_or_default();
+            message.trim_start_matches("error: ").to_string()
+        })
+}
+
+/// Turn the prefixed environment variables into command line arguments.
+/// Flags are set with `true` and the values of the settings named in `lists` are separated by
+/// commas. Other values are taken as they are, so they may contain commas.
+fn environment_args(
+    vars: impl Iterator<Item = (OsString, OsString)>,
+    lists: &[&str],
+) -> Vec<String> {
+    let mut args = vec![String::new()];
+    for (name, value) in vars {
+        // Variables that are not unicode cannot be settings
+        let (Ok(name), Ok(value)) = (name.into_string(), value.into_string()) else {
+            continue;
+        };
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        if INTERNAL_VARS.contains(&name.as_str()) {
+            continue;
+        }
+
+        let key = key.to_lowercase().replace('_', "-");
+        match value.as_str() {
+            "true" => args.push(format!("--{}", key)),
+            "false" | "" => {}
+            _ if lists.contains(&key.as_str()) => {
+                args.extend(value.split(',').map(|value| format!("--{}={}", key, value)))
+            }
+            _ => args.push(format!("--{}={}", key, value)),
+        }
+    }
+    args
+}
+
+/// Turn the settings of a configuration file into command line arguments.
+/// The first argument stands in for the program name.
+fn file_args(table: &Table) -> Result<Vec<String>, String> {
+    let mut args = vec![String::new()];
+    for (key, value) in table {
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::String(s) => args.push(format!("--{}={}", key, s)),
+                Value::Integer(n) => args.push(format!("--{}={}", key, n)),
+                Value::Float(n) => args.push(format!("--{}={}", key, n)),
+                Value::Boolean(true) => args.push(format!("--{}", key)),
+                Value::Boolean(false) => {}
+                _ => return Err(format!("unsupported value for {}", key)),
+            }
+        }
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_profiles_are_valid() {
+        let profiles = BUILTIN_PROFILES.parse::<Table>().unwrap();
+        for (name, profile) in &profiles {
+            let args = file_args(profile.as_table().unwrap()).unwrap();
+            if let Err(e) = parse_args(crate::serve_subcommand(), args) {
+                panic!("Profile {} is invalid: {}", name, e);
+            }
+        }
+    }
+
+    #[test]
+    fn convert_file_to_args() {
+        // Given
+        let table = "smtp-port = 25\n\
+                     daemon = true\n\
+                     listen = [\"[::1]:25\", \"127.0.0.2:25\"]\n\
+                     log-file = \"/var/log/smtp.log\"\n"
+            .parse::<Table>()
+            .unwrap();
+
+        // When
+        let args = file_args(&table).unwrap();
+
+        // Then
+        assert_eq!(
+            args,
+            vec![
+                "",
+                "--daemon",
+                "--listen=[::1]:25",
+                "--listen=127.0.0.2:25",
+                "--log-file=/var/log/smtp.log",
+                "--smtp-port=25",
+            ]
+        );
+    }
+
+    #[test]
+    fn convert_environment_to_args() {
+        // Given
+        let vars = vec![
+            ("SMTP_SERVER_BUFFER_SIZE", "16384"),
+            ("SMTP_SERVER_LISTEN", "[::1]:25,127.0.0.2:25"),
+            ("SMTP_SERVER_DAEMON", "true"),
+            ("SMTP_SERVER_LOG_FILE", ""),
+            ("SMTP_SERVER_RELAY_PASSWORD", "se,cret"),
+            ("SMTP_SERVER_WEBHOOK_URL", "http://localhost/hook?to=a,b"),
+            ("PATH", "/usr/bin"),
+        ];
+
+        // When
+        let args = environment_args(
+            vars.into_iter()
+                .map(|(name, value)| (OsString::from(name), OsString::from(value))),
+            &["listen"],
+        );
+
+        // Then
+        assert_eq!(
+            args,
+            vec![
+                "",
+                "--buffer-size=16384",
+                "--listen=[::1]:25",
+                "--listen=127.0.0.2:25",
+                "--daemon",
+                "--relay-password=se,cret",
+                "--webhook-url=http://localhost/hook?to=a,b",
+            ]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Running the server as a classic unix daemon.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, Result};
+use std::os::unix::io::AsRawFd;
+use std::process;
+
+/// Fork twice and detach from the controlling terminal.
+///
+/// Standard output and standard error are redirected to the log file, or discarded if there is
+/// none. The PID of the daemon is written to the PID file. Must be called before any threads are
+/// started, since only the calling thread survives a fork.
+pub fn daemonize(pid_file: Option<&str>, log_file: Option<&str>) -> Result<()> {
+    // Open files before forking so that errors are still reported on the terminal
+    let stdin = File::open("/dev/null")?;
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file.unwrap_or("/dev/null"))?;
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(Error::last_os_error());
+    }
+    // The session leader exits as well so that the daemon can never reacquire a terminal
+    fork_and_exit_parent()?;
+
+    if let Some(path) = pid_file {
+        fs::write(path, format!("{}\n", process::id()))?;
+    }
+
+    redirect(&stdin, libc::STDIN_FILENO)?;
+    redirect(&log, libc::STDOUT_FILENO)?;
+    redirect(&log, libc::STDERR_FILENO)?;
+
+    // Do not keep the working directory busy
+    env::set_current_dir("/")
+}
+
+fn fork_and_exit_parent() -> Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::last_os_error()),
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
+
+fn redirect(file: &File, fd: libc::c_int) -> Result<()> {
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn detach_into_background() {
+        // Given
+        let directory = env::temp_dir();
+        let pid_file = directory.join(format!("daemon-test-{}.pid", process::id()));
+        let log_file = directory.join(format!("daemon-test-{}.log", process::id()));
+        let (pid_path, log_path) = (pid_file.to_str().unwrap(), log_file.to_str().unwrap());
+
+        // When
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            // Only the daemon gets past this, its parents exit
+            let outcome = daemonize(Some(pid_path), Some(log_path));
+            let _ = writeln!(std::io::stdout(), "daemonized: {:?}", outcome.is_ok());
+            let _ = std::io::stdout().flush();
+            unsafe { libc::_exit(0) };
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(child, &mut status, 0) };
+        let mut log = String::new();
+        for _ in 0..100 {
+            log = fs::read_to_string(&log_file).unwrap_or_default();
+            if !log.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let pid = fs::read_to_string(&pid_file).unwrap_or_default();
+        let _ = (fs::remove_file(&pid_file), fs::remove_file(&log_file));
+
+        // Then
+        // The first parent exits as soon as it forked
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        assert_eq!(log, "daemonized: true\n");
+        let pid: libc::pid_t = pid.trim().parse().unwrap();
+        assert_ne!(pid, child);
+        assert_ne!(pid, process::id() as libc::pid_t);
+    }
+}
@@ -0,0 +1,175 @@
+//! Reading the message content sent after the DATA command.
+
+use std::io::{BufRead, Error, ErrorKind};
+
+/// Position of the scanner relative to the current line
+#[derive(Clone, Copy)]
+enum Scan {
+    /// At the first character of a line
+    LineStart,
+    /// After a dot at the start of a line
+    Dot,
+    /// After a dot and a carriage return at the start of a line
+    DotCr,
+    /// Within a line
+    Text,
+}
+
+/// Incrementally scans message content for the terminating line consisting of a single dot.
+///
+/// Input is processed a buffer at a time, as returned by `BufRead::fill_buf`, so the size of the
+/// reader's buffer determines the chunk size. Dot-stuffed lines are unstuffed. Both CRLF and bare
+/// LF line endings are accepted, line endings are kept as received.
+pub struct DataReader {
+    scan: Scan,
+    data: Vec<u8>,
+    /// Size of the largest content that is kept
+    limit: usize,
+    /// Whether the content exceeded the limit, after which it is discarded
+    exceeded: bool,
+}
+
+impl DataReader {
+    pub fn new() -> DataReader {
+        DataReader::limited(usize::MAX)
+    }
+
+    pub fn limited(limit: usize) -> DataReader {
+        DataReader {
+            scan: Scan::LineStart,
+            data: Vec::new(),
+            limit,
+            exceeded: false,
+        }
+    }
+
+    /// Read message content until the terminating dot line and return it, or None if it was
+    /// larger than the limit
+    pub fn read(mut self, reader: &mut dyn BufRead) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            let buffer = reader.fill_buf()?;
+            if buffer.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed during DATA",
+                ));
+            }
+
+            let (consumed, done) = self.feed(buffer);
+            reader.consume(consumed);
+            if self.data.len() > self.limit {
+                // The rest still has to be read to find the end of the content
+                self.exceeded = true;
+                self.data.clear();
+            }
+            if done {
+                return Ok((!self.exceeded).then_some(self.data));
+            }
+        }
+    }
+
+    /// Scan a chunk of input.
+    /// Returns the number of bytes consumed and whether the terminating line was found.
+    fn feed(&mut self, buffer: &[u8]) -> (usize, bool) {
+        let mut position = 0;
+
+        while position < buffer.len() {
+            let byte = buffer[position];
+            match self.scan {
+                Scan::Text => {
+                    // Copy the rest of the line in one go
+                    match buffer[position..].iter().position(|&b| b == b'\n') {
+                        Some(newline) => {
+                            let end = position + newline + 1;
+                            self.data.extend_from_slice(&buffer[position..end]);
+                            self.scan = Scan::LineStart;
+                            position = end;
+                        }
+                        None => {
+                            self.data.extend_from_slice(&buffer[position..]);
+                            position = buffer.len();
+                        }
+                    }
+                    continue;
+                }
+                Scan::LineStart if byte == b'.' => self.scan = Scan::Dot,
+                Scan::LineStart => {
+                    self.data.push(byte);
+                    if byte != b'\n' {
+                        self.scan = Scan::Text;
+                    }
+                }
+                Scan::Dot if byte == b'\n' => return (position + 1, true),
+                Scan::Dot if byte == b'\r' => self.scan = Scan::DotCr,
+                // A leading dot followed by more characters is removed
+                Scan::Dot => {
+                    self.data.push(byte);
+                    self.scan = Scan::Text;
+                }
+                Scan::DotCr if byte == b'\n' => return (position + 1, true),
+                Scan::DotCr => {
+                    self.data.push(b'\r');
+                    self.scan = Scan::Text;
+                    // Process this byte again as part of the line
+                    continue;
+                }
+            }
+            position += 1;
+        }
+
+        (position, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    fn read_with_buffer_size(input: &str, capacity: usize) -> (Vec<u8>, Vec<u8>) {
+        let mut reader = BufReader::with_capacity(capacity, input.as_bytes());
+        let data = DataReader::new().read(&mut reader).unwrap().unwrap();
+        let mut rest = Vec::new();
+        std::io::Read::read_to_end(&mut reader, &mut rest).unwrap();
+        (data, rest)
+    }
+
+    #[test]
+    fn read_data_in_chunks() {
+        let input = "Subject: test\r\n\r\n..leading dot\r\n.\r\nQUIT\r\n";
+
+        // A buffer of one byte splits every line, a large one reads the input at once
+        for capacity in &[1, 2, 3, 7, 8192] {
+            let (data, rest) = read_with_buffer_size(input, *capacity);
+            assert_eq!(
+                String::from_utf8(data).unwrap(),
+                "Subject: test\r\n\r\n.leading dot\r\n"
+            );
+            assert_eq!(String::from_utf8(rest).unwrap(), "QUIT\r\n");
+        }
+    }
+
+    #[test]
+    fn read_data_with_bare_line_feeds() {
+        let (data, rest) = read_with_buffer_size("first\n.\rnot the end\n.\nQUIT\n", 4);
+        assert_eq!(String::from_utf8(data).unwrap(), "first\n\rnot the end\n");
+        assert_eq!(String::from_utf8(rest).unwrap(), "QUIT\n");
+    }
+
+    #[test]
+    fn discard_data_over_limit() {
+        let mut reader = BufReader::with_capacity(4, "0123456789\n.\nQUIT\n".as_bytes());
+        let data = DataReader::limited(10).read(&mut reader).unwrap();
+        assert_eq!(data, None);
+        let mut rest = String::new();
+        reader.read_line(&mut rest).unwrap();
+        assert_eq!(rest, "QUIT\n");
+    }
+
+    #[test]
+    fn fail_on_early_eof() {
+        let mut reader = BufReader::new("unterminated\r\n".as_bytes());
+        let error = DataReader::new().read(&mut reader).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,336 @@
+//! A minimal DNS client for the TXT, address and MX lookups of SPF and DKIM verification.
+//!
+//! Queries go to one recursive resolver over UDP, announcing a large payload size with EDNS so
+//! that long TXT records like DKIM keys fit, and over TCP if an answer is truncated anyway.
+//! Names that do not exist have no records rather than failing.
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+/// How long to wait for an answer
+const TIMEOUT: Duration = Duration::from_secs(3);
+/// UDP payload size announced with EDNS
+const UDP_PAYLOAD_SIZE: u16 = 4096;
+
+const TYPE_A: u16 = 1;
+const TYPE_MX: u16 = 15;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+/// Recursion desired
+const FLAGS_QUERY: u16 = 0x0100;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const RCODE_NAME_ERROR: u16 = 3;
+
+/// The lookups of SPF and DKIM, so that tests can answer them without a resolver
+pub trait Lookup {
+    /// The TXT records of a name, each with its strings joined
+    fn txt(&self, name: &str) -> Result<Vec<String>, Error>;
+
+    /// The IPv4 and IPv6 addresses of a name
+    fn addresses(&self, name: &str) -> Result<Vec<IpAddr>, Error>;
+
+    /// The mail exchangers of a name, in no particular order
+    fn mx(&self, name: &str) -> Result<Vec<String>, Error>;
+}
+
+/// A record of an answer
+enum Record {
+    Address(IpAddr),
+    Mx(String),
+    Txt(String),
+}
+
+/// A recursive resolver
+pub struct Resolver {
+    pub server: SocketAddr,
+}
+
+impl Resolver {
+    /// The first nameserver of `/etc/resolv.conf`, if there is one
+    pub fn system() -> Option<Resolver> {
+        let config = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+        config
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .find_map(|address| address.trim().parse::<IpAddr>().ok())
+            .map(|ip| Resolver {
+                server: SocketAddr::new(ip, 53),
+            })
+    }
+
+    fn query(&self, name: &str, record_type: u16) -> Result<Vec<Record>, Error> {
+        let id = RandomState::new().build_hasher().finish() as u16;
+        let query = encode_query(id, name, record_type)?;
+        let local: SocketAddr = if self.server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_read_timeout(Some(TIMEOUT))?;
+        socket.connect(self.server)?;
+        socket.send(&query)?;
+        let mut buffer = vec![0; usize::from(UDP_PAYLOAD_SIZE)];
+        let length = loop {
+            let length = socket.recv(&mut buffer)?;
+            // Late answers to earlier queries are ignored
+            if length >= 2 && buffer[..2] == id.to_be_bytes() {
+                break length;
+            }
+        };
+        match decode_response(&buffer[..length], record_type)? {
+            Some(records) => Ok(records),
+            None => self.query_tcp(&query, record_type),
+        }
+    }
+
+    /// Repeat a query over TCP, which has no size limit
+    fn query_tcp(&self, query: &[u8], record_type: u16) -> Result<Vec<Record>, Error> {
+        let mut stream = TcpStream::connect_timeout(&self.server, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        stream.write_all(&message)?;
+        let mut length = [0; 2];
+        stream.read_exact(&mut length)?;
+        let mut response = vec![0; usize::from(u16::from_be_bytes(length))];
+        stream.read_exact(&mut response)?;
+        decode_response(&response, record_type)?
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "truncated DNS answer over TCP"))
+    }
+}
+
+impl Lookup for Resolver {
+    fn txt(&self, name: &str) -> Result<Vec<String>, Error> {
+        let records = self.query(name, TYPE_TXT)?;
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Txt(text) => Some(text),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn addresses(&self, name: &str) -> Result<Vec<IpAddr>, Error> {
+        let mut records = self.query(name, TYPE_A)?;
+        records.extend(self.query(name, TYPE_AAAA)?);
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Address(ip) => Some(ip),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn mx(&self, name: &str) -> Result<Vec<String>, Error> {
+        let records = self.query(name, TYPE_MX)?;
+        Ok(records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Mx(exchange) => Some(exchange),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+/// Encode a recursive query for one name and type, with an EDNS record for larger answers
+fn encode_query(id: u16, name: &str, record_type: u16) -> Result<Vec<u8>, Error> {
+    let mut query = Vec::new();
+    // One question and one additional record
+    for value in [id, FLAGS_QUERY, 1, 0, 0, 1] {
+        query.extend_from_slice(&value.to_be_bytes());
+    }
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid domain name {}", name),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    for value in [record_type, CLASS_IN] {
+        query.extend_from_slice(&value.to_be_bytes());
+    }
+    // The OPT record has the root name, the payload size as its class and no data
+    query.push(0);
+    for value in [TYPE_OPT, UDP_PAYLOAD_SIZE, 0, 0, 0] {
+        query.extend_from_slice(&value.to_be_bytes());
+    }
+    Ok(query)
+}
+
+/// Decode the records of a type in the answer of a response, or None if it is truncated
+fn decode_response(response: &[u8], record_type: u16) -> Result<Option<Vec<Record>>, Error> {
+    let malformed = || Error::new(ErrorKind::InvalidData, "malformed DNS answer");
+    let number = |position: usize| -> Result<u16, Error> {
+        let bytes = response.get(position..position + 2).ok_or_else(malformed)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let flags = number(2)?;
+    if flags & FLAG_TRUNCATED != 0 {
+        return Ok(None);
+    }
+    match flags & 0x000f {
+        0 => {}
+        RCODE_NAME_ERROR => return Ok(Some(Vec::new())),
+        code => {
+            return Err(Error::other(format!(
+                "DNS server answered with error code {}",
+                code
+            )))
+        }
+    }
+
+    let (questions, answers) = (number(4)?, number(6)?);
+    let mut position = 12;
+    for _ in 0..questions {
+        position = decode_name(response, position)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        position = decode_name(response, position)?.1;
+        let (answer_type, length) = (number(position)?, usize::from(number(position + 8)?));
+        let start = position + 10;
+        let data = response.get(start..start + length).ok_or_else(malformed)?;
+        position = start + length;
+        // Other types are e.g. the CNAMEs that led to the records
+        if answer_type != record_type {
+            continue;
+        }
+        match (answer_type, data.len()) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = data.try_into().unwrap();
+                records.push(Record::Address(IpAddr::from(octets)));
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into().unwrap();
+                records.push(Record::Address(IpAddr::from(octets)));
+            }
+            (TYPE_MX, _) => {
+                // The preference comes before the exchange
+                records.push(Record::Mx(decode_name(response, start + 2)?.0));
+            }
+            (TYPE_TXT, _) => {
+                // Character strings, each with its length first
+                let mut text = Vec::new();
+                let mut rest = data;
+                while let Some((&length, tail)) = rest.split_first() {
+                    let string = tail.get(..usize::from(length)).ok_or_else(malformed)?;
+                    text.extend_from_slice(string);
+                    rest = &tail[string.len()..];
+                }
+                records.push(Record::Txt(String::from_utf8_lossy(&text).into_owned()));
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(records))
+}
+
+/// Decode a name that may be compressed, and get the position after it
+fn decode_name(message: &[u8], position: usize) -> Result<(String, usize), Error> {
+    let malformed = || Error::new(ErrorKind::InvalidData, "malformed name in DNS answer");
+    let mut labels = Vec::new();
+    let (mut current, mut end) = (position, None);
+    // Every pointer goes to an earlier name, but a bound keeps loops from malicious answers out
+    for _ in 0..128 {
+        let length = *message.get(current).ok_or_else(malformed)?;
+        match length {
+            0 => {
+                let name = labels.join(".");
+                return Ok((name, end.unwrap_or(current + 1)));
+            }
+            length if length & 0xc0 == 0xc0 => {
+                let low = *message.get(current + 1).ok_or_else(malformed)?;
+                end.get_or_insert(current + 2);
+                current = usize::from(length & 0x3f) << 8 | usize::from(low);
+            }
+            length => {
+                let label = message
+                    .get(current + 1..current + 1 + usize::from(length))
+                    .ok_or_else(malformed)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                current += 1 + usize::from(length);
+            }
+        }
+    }
+    Err(malformed())
+}
+
+/// Answers from memory, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct Answers {
+    pub txt: std::collections::HashMap<String, Vec<String>>,
+    pub addresses: std::collections::HashMap<String, Vec<IpAddr>>,
+    pub mx: std::collections::HashMap<String, Vec<String>>,
+}
+
+#[cfg(test)]
+impl Lookup for Answers {
+    fn txt(&self, name: &str) -> Result<Vec<String>, Error> {
+        Ok(self.txt.get(name).cloned().unwrap_or_default())
+    }
+
+    fn addresses(&self, name: &str) -> Result<Vec<IpAddr>, Error> {
+        Ok(self.addresses.get(name).cloned().unwrap_or_default())
+    }
+
+    fn mx(&self, name: &str) -> Result<Vec<String>, Error> {
+        Ok(self.mx.get(name).cloned().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn look_up_txt_records() {
+        // Given
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resolver = Resolver {
+            server: server.local_addr().unwrap(),
+        };
+        let answering = thread::spawn(move || {
+            let mut query = [0; 512];
+            let (length, client) = server.recv_from(&mut query).unwrap();
+            // The question without the OPT record, which is 11 bytes
+            let question = &query[12..length - 11];
+            let mut response = query[..2].to_vec();
+            for value in [0x8180, 1, 2, 0, 0] {
+                response.extend_from_slice(&u16::to_be_bytes(value));
+            }
+            response.extend_from_slice(question);
+            // A CNAME to a compressed name, then the TXT record in two strings
+            response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+            response.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0, 16]);
+            response.extend_from_slice(b"\x08v=spf1 -\x06all -a");
+            server.send_to(&response, client).unwrap();
+            question.to_vec()
+        });
+
+        // When
+        let records = resolver.txt("example.com").unwrap();
+
+        // Then
+        assert_eq!(records, ["v=spf1 -all -a"]);
+        assert_eq!(
+            answering.join().unwrap(),
+            b"\x07example\x03com\x00\x00\x10\x00\x01"
+        );
+    }
+}
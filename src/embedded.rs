@@ -0,0 +1,276 @@
+//! Running a server inside another program, e.g. to receive the mail of an application in its
+//! integration tests.
+//!
+//! An embedded server prints nothing and keeps received messages in memory unless told otherwise.
+//! It takes its settings only from the builder, not from the environment or a configuration file.
+
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::store::MessageStore;
+use crate::{config, Listener, Server};
+
+/// Settings that differ from the command line for embedded servers
+const DEFAULTS: [(&str, &str); 3] = [
+    (crate::PRINT_ARG_NAME, "none"),
+    (crate::STORAGE_ARG_NAME, "memory"),
+    (crate::BIND_PORT_ARG_NAME, "0"),
+];
+
+/// An SMTP server to run inside another program
+pub struct SmtpServer;
+
+impl SmtpServer {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+}
+
+/// The settings of an embedded server
+#[derive(Default)]
+pub struct Builder {
+    addresses: Vec<String>,
+    settings: Vec<(String, String)>,
+}
+
+impl Builder {
+    /// Listen on an address as host:port, may be called multiple times. Port 0 picks a free port.
+    /// Without an address, the server listens on a free port of localhost.
+    pub fn bind(mut self, address: &str) -> Builder {
+        self.addresses.push(address.to_string());
+        self
+    }
+
+    /// Set a setting of the serve subcommand by its long name, e.g. `setting("tls-cert",
+    /// "cert.pem")`. Flags are set with `true`, like in the environment.
+    pub fn setting(mut self, name: &str, value: &str) -> Builder {
+        self.settings.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Bind the listeners and start accepting connections. Settings that are invalid or
+    /// contradict each other are an [`Error::Settings`].
+    pub fn start(self) -> Result<Handle, Error> {
+        let mut args = vec![String::new()];
+        for (name, value) in DEFAULTS {
+            if !self.settings.iter().any(|(setting, _)| setting == name) {
+                args.push(format!("--{}={}", name, value));
+            }
+        }
+        for (name, value) in &self.settings {
+            match value.as_str() {
+                "true" => args.push(format!("--{}", name)),
+                "false" => {}
+                _ => args.push(format!("--{}={}", name, value)),
+            }
+        }
+        let settings = config::Settings::from_args(crate::serve_subcommand, args)
+            .map_err(|message| crate::settings_error(&message, clap::ErrorKind::InvalidValue))?;
+        let mut config = crate::config(&settings, None)?;
+        if !self.addresses.is_empty() {
+            config.bind_addresses = self.addresses;
+        }
+
+        let listeners = crate::bind_configured(&config)?;
+        let addresses = listeners.iter().map(Listener::local_address).collect();
+        Ok(Handle {
+            addresses,
+            store: config.store.clone(),
+            server: crate::start(&config, listeners),
+        })
+    }
+}
+
+/// A running embedded server
+pub struct Handle {
+    addresses: Vec<String>,
+    store: Option<Arc<dyn MessageStore>>,
+    server: Server,
+}
+
+impl Handle {
+    /// The addresses the server listens on, with the ports chosen for port 0
+    pub fn addresses(&self) -> &[String] {
+        &self.addresses
+    }
+
+    /// The port of the first address the server listens on
+    pub fn port(&self) -> u16 {
+        self.addresses
+            .first()
+            .and_then(|address| address.parse::<SocketAddr>().ok())
+            .map_or(0, |address| address.port())
+    }
+
+    /// Where received messages are kept. They show up shortly after their session ended.
+    pub fn store(&self) -> Option<&Arc<dyn MessageStore>> {
+        self.store.as_ref()
+    }
+
+    /// Stop accepting connections and wait for the sessions in progress to end
+    pub fn shutdown(mut self) {
+        let drain = &self.server.drain;
+        drain.started.store(true, Ordering::SeqCst);
+        drain.stopped.store(true, Ordering::SeqCst);
+        // Acceptors wait for a connection, so each gets one to notice the shutdown
+        for address in &self.addresses {
+            #[cfg(unix)]
+            if let Some(path) = address.strip_prefix("unix:") {
+                let _ = UnixStream::connect(path);
+                continue;
+            }
+            if let Ok(mut address) = address.parse::<SocketAddr>() {
+                match address.ip() {
+                    IpAddr::V4(ip) if ip.is_unspecified() => {
+                        address.set_ip(Ipv4Addr::LOCALHOST.into())
+                    }
+                    IpAddr::V6(ip) if ip.is_unspecified() => {
+                        address.set_ip(Ipv6Addr::LOCALHOST.into())
+                    }
+                    _ => {}
+                }
+                let _ = TcpStream::connect(address);
+            }
+        }
+        for acceptor in mem::take(&mut self.server.acceptors) {
+            // Acceptors contain session panics, so a failed join leaves nothing to clean up
+            let _ = acceptor.join();
+        }
+        self.server.drain(None);
+        // Messages of the last sessions are in the store once this returns
+        self.server.flush(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use std::io::{BufRead, BufReader, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn receive_and_shut_down() {
+        // Given
+        let server = SmtpServer::builder().bind("127.0.0.1:0").start().unwrap();
+        let address = format!("127.0.0.1:{}", server.port());
+
+        // When
+        let mut client = Client::connect(&address, "localhost").unwrap();
+        client
+            .send(
+                "tester@localhost",
+                &["admin@localhost".to_string()],
+                b"Hello",
+            )
+            .unwrap();
+        client.quit().unwrap();
+
+        // Then
+        let store = server.store().unwrap().clone();
+        let mut entries = Vec::new();
+        for _ in 0..50 {
+            entries = store.entries().unwrap();
+            if !entries.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].summary["from"], "<tester@localhost>");
+        assert_eq!(
+            store.content(&entries[0].id).unwrap().unwrap(),
+            b"Hello\r\n"
+        );
+
+        server.shutdown();
+        assert!(TcpStream::connect(&address).is_err());
+    }
+
+    #[test]
+    fn close_idle_sessions() {
+        // Given
+        let server = SmtpServer::builder()
+            .bind("127.0.0.1:0")
+            .setting("idle-timeout", "1")
+            .start()
+            .unwrap();
+        let stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+
+        // When
+        let mut reply = String::new();
+        reader.read_line(&mut reply).unwrap();
+
+        // Then
+        assert_eq!(reply, "421 Idle timeout, closing connection\n");
+        let mut rest = String::new();
+        assert_eq!(reader.read_line(&mut rest).unwrap(), 0);
+        server.shutdown();
+    }
+
+    #[test]
+    fn limit_connections_per_client_behind_proxy() {
+        // Given
+        let server = SmtpServer::builder()
+            .bind("127.0.0.1:0")
+            .setting("proxy-protocol", "true")
+            .setting("max-connections-per-ip", "1")
+            .setting("concurrency", "4")
+            .start()
+            .unwrap();
+        let connect = |client: &str| {
+            let mut stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            let header = format!("PROXY TCP4 {} 192.0.2.100 4000 25\r\n", client);
+            stream.write_all(header.as_bytes()).unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut greeting = String::new();
+            reader.read_line(&mut greeting).unwrap();
+            (reader, greeting)
+        };
+
+        // When
+        let (first_stream, first) = connect("192.0.2.1");
+        let (second_stream, second) = connect("192.0.2.2");
+        let (_, third) = connect("192.0.2.1");
+
+        // Then
+        assert!(first.starts_with("220 "), "{}", first);
+        assert!(second.starts_with("220 "), "{}", second);
+        assert_eq!(third.trim_end(), crate::limits::MSG_TOO_MANY_CONNECTIONS);
+        drop((first_stream, second_stream));
+        server.shutdown();
+    }
+
+    #[test]
+    fn return_invalid_settings() {
+        // When
+        let conflicting = SmtpServer::builder()
+            .setting("pid-file", "/tmp/smtp.pid")
+            .start();
+        let unknown = SmtpServer::builder()
+            .setting("no-such-setting", "1")
+            .start();
+
+        // Then
+        let Err(Error::Settings(e)) = conflicting else {
+            panic!("conflicting settings were accepted");
+        };
+        assert_eq!(e.kind, clap::ErrorKind::MissingRequiredArgument);
+        assert!(matches!(unknown, Err(Error::Settings(_))));
+    }
+}
@@ -0,0 +1,102 @@
+//! Errors that stop the server.
+//!
+//! Failures of a single client session never end up here. They are logged and only end that
+//! session.
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A listening socket could not be bound
+    Bind { address: String, source: io::Error },
+    /// A configuration file could not be read or contains invalid settings
+    Config { path: String, message: String },
+    /// The selected profile does not exist or contains invalid settings
+    Profile { name: String, message: String },
+    /// Environment variables contain invalid settings
+    Environment(String),
+    /// The settings contradict each other, or those of an embedded server are invalid
+    Settings(clap::Error),
+    /// Checking the configuration found problems, which have been reported already
+    Check { problems: usize },
+    /// A requested feature is not available on this platform
+    #[cfg_attr(unix, allow(dead_code))]
+    Unsupported(&'static str),
+    /// The sockets handed over by a previous server process do not fit the configured servers
+    #[cfg(unix)]
+    Handoff { expected: usize, received: usize },
+    /// A startup or supervision step failed
+    Io {
+        action: &'static str,
+        source: io::Error,
+    },
+    #[cfg(windows)]
+    Service(windows_service::Error),
+}
+
+impl Error {
+    /// Wrap an I/O error with the action that failed, for use with `map_err`
+    pub fn io(action: &'static str) -> impl FnOnce(io::Error) -> Error {
+        move |source| Error::Io { action, source }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Bind { address, source } => {
+                write!(f, "Binding to {} failed: {}", address, source)
+            }
+            Error::Config { path, message } => {
+                write!(f, "Invalid configuration file {}: {}", path, message)
+            }
+            Error::Profile { name, message } => write!(f, "Invalid profile {}: {}", name, message),
+            Error::Environment(message) => {
+                write!(f, "Invalid settings in the environment: {}", message)
+            }
+            Error::Settings(e) => write!(
+                f,
+                "Invalid server settings: {}",
+                e.message.trim_start_matches("error: ")
+            ),
+            Error::Check { problems: 1 } => f.write_str("1 check failed"),
+            Error::Check { problems } => write!(f, "{} checks failed", problems),
+            Error::Unsupported(message) => f.write_str(message),
+            #[cfg(unix)]
+            Error::Handoff { expected, received } => write!(
+                f,
+                "Taking over the sockets failed: the servers need {} but {} were handed over",
+                expected, received
+            ),
+            Error::Io { action, source } => write!(f, "{} failed: {}", action, source),
+            #[cfg(windows)]
+            Error::Service(e) => write!(f, "Windows service operation failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind { source, .. } | Error::Io { source, .. } => Some(source),
+            Error::Settings(e) => Some(e),
+            Error::Config { .. }
+            | Error::Profile { .. }
+            | Error::Environment(_)
+            | Error::Check { .. }
+            | Error::Unsupported(_) => None,
+            #[cfg(unix)]
+            Error::Handoff { .. } => None,
+            #[cfg(windows)]
+            Error::Service(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<windows_service::Error> for Error {
+    fn from(e: windows_service::Error) -> Error {
+        Error::Service(e)
+    }
+}
@@ -0,0 +1,202 @@
+//! Running an external command for every received message, for custom processing without
+//! changing the server.
+//!
+//! The command is run by the shell with the content of the message on stdin and the envelope in
+//! environment variables. Its stdout goes to stderr, so it does not mix with the printed
+//! messages. Hooks run on a pool of their own, and a hook that runs longer than the timeout is
+//! killed.
+
+use std::io::{self, Error, ErrorKind, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use threadpool::ThreadPool;
+
+use crate::relay::path_address;
+use crate::smtp::Message;
+use crate::Session;
+
+/// How often a running hook is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What to run and how
+#[derive(Clone)]
+pub struct Hook {
+    /// Command line, run by the shell
+    pub command: String,
+    /// Number of hooks that may run at the same time
+    pub concurrency: usize,
+    pub timeout: Duration,
+}
+
+/// Run the hook for the messages of every session received until the channel closes.
+/// Hooks that fail, exit with an error or time out are logged.
+pub fn run(hook: Hook, server: Option<String>, sessions: Receiver<Arc<Session>>) {
+    let pool = ThreadPool::new(hook.concurrency);
+    for session in sessions {
+        let messages = session
+            .connection
+            .get_messages()
+            .map_or(0, |messages| messages.len());
+        for index in 0..messages {
+            let (hook, server, session) = (hook.clone(), server.clone(), session.clone());
+            pool.execute(move || {
+                let message = &session.connection.get_messages().unwrap()[index];
+                let sender_domain = session.connection.get_sender_domain().unwrap_or_default();
+                match execute(
+                    &hook,
+                    &session.client_address,
+                    sender_domain,
+                    message,
+                    server.as_deref(),
+                ) {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => {
+                        tracing::error!("Hook for message {} failed: {}", message.get_id(), status)
+                    }
+                    Err(e) => {
+                        tracing::error!("Hook for message {} failed: {}", message.get_id(), e)
+                    }
+                }
+            });
+        }
+    }
+    pool.join();
+}
+
+/// Run the hook for a message and wait for it to exit
+fn execute(
+    hook: &Hook,
+    client_address: &str,
+    sender_domain: &str,
+    message: &Message,
+    server: Option<&str>,
+) -> Result<ExitStatus, Error> {
+    let recipients: Vec<&str> = message
+        .get_recipients()
+        .iter()
+        .map(|recipient| path_address(recipient))
+        .collect();
+    let mut command = shell(&hook.command);
+    command
+        .env("SMTP_MESSAGE_ID", message.get_id())
+        .env("SMTP_CLIENT", client_address)
+        .env("SMTP_SENDER_DOMAIN", sender_domain)
+        .env("SMTP_FROM", path_address(message.get_sender()))
+        .env("SMTP_TO", recipients.join(","))
+        .env("SMTP_SIZE", message.get_size().to_string())
+        .env("SMTP_SERVER", server.unwrap_or_default())
+        .stdin(Stdio::piped())
+        .stdout(io::stderr());
+    let mut child = command.spawn()?;
+
+    // The hook may exit without reading all of its input, so writing must not hold up waiting
+    let mut stdin = child.stdin.take().unwrap();
+    let content = message.get_content().to_vec();
+    thread::spawn(move || {
+        if let Err(e) = stdin.write_all(&content) {
+            if e.kind() != ErrorKind::BrokenPipe {
+                tracing::error!("Writing to a hook failed: {}", e);
+            }
+        }
+    });
+    wait(&mut child, hook.timeout)
+}
+
+/// Wait for a child process to exit, killing it after the timeout
+fn wait(child: &mut Child, timeout: Duration) -> Result<ExitStatus, Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("killed after {} seconds", timeout.as_secs_f32()),
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::io::BufReader;
+
+    fn received() -> Connection {
+        let request = "HELO client.example\n\
+                       MAIL FROM: <tester@localhost>\n\
+                       RCPT TO: <admin@localhost>\n\
+                       RCPT TO: <root@localhost>\n\
+                       DATA\n\
+                       Subject: test\n\
+                       .\n\
+                       QUIT\n";
+        Connection::handle(&mut BufReader::new(request.as_bytes()), &mut Vec::new()).unwrap()
+    }
+
+    #[test]
+    fn pass_message_to_command() {
+        // Given
+        let path = std::env::temp_dir().join(format!("hook-{}", std::process::id()));
+        let hook = Hook {
+            command: format!(
+                "(echo \"$SMTP_FROM $SMTP_TO $SMTP_SERVER\"; cat) > {}",
+                path.display()
+            ),
+            concurrency: 1,
+            timeout: Duration::from_secs(10),
+        };
+        let connection = received();
+
+        // When
+        let message = &connection.get_messages().unwrap()[0];
+        let status = execute(&hook, "127.0.0.1:1", "client.example", message, Some("qa"));
+
+        // Then
+        assert!(status.unwrap().success());
+        let output = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            output,
+            "tester@localhost admin@localhost,root@localhost qa\nSubject: test\n"
+        );
+    }
+
+    #[test]
+    fn kill_after_timeout() {
+        let hook = Hook {
+            command: "sleep 10".to_string(),
+            concurrency: 1,
+            timeout: Duration::from_millis(100),
+        };
+        let connection = received();
+
+        let message = &connection.get_messages().unwrap()[0];
+        let status = execute(&hook, "127.0.0.1:1", "client.example", message, None);
+
+        assert_eq!(status.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,160 @@
+//! Handing the listening sockets over to a new server process for restarts without downtime.
+//!
+//! On SIGUSR2 the server starts its executable again with the same arguments. The new process
+//! inherits the listening sockets and the write end of a pipe, both announced in environment
+//! variables. Once it accepts connections, it writes to the pipe. The old process then finishes
+//! its active sessions and exits. If the new process fails to start, the old one keeps running.
+
+use std::env;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use crate::signals::{clear_close_on_exec, set_close_on_exec};
+use crate::Listener;
+
+/// Comma separated listening socket descriptors
+pub const LISTEN_FDS_VAR: &str = "SMTP_SERVER_HANDOFF_FDS";
+/// Descriptor the new process reports readiness on
+pub const READY_FD_VAR: &str = "SMTP_SERVER_HANDOFF_READY_FD";
+
+/// How long the old process waits for the new one to become ready
+const READY_TIMEOUT_MS: libc::c_int = 30_000;
+
+/// Whether this process was started by a previous server process to take over its sockets
+pub fn is_taking_over() -> bool {
+    env::var_os(LISTEN_FDS_VAR).is_some()
+}
+
+/// Take the listening sockets handed over by a previous server process, if any
+pub fn take_listeners() -> Vec<Listener> {
+    let fds = env::var(LISTEN_FDS_VAR).unwrap_or_default();
+    env::remove_var(LISTEN_FDS_VAR);
+
+    fds.split(',')
+        .filter_map(|fd| fd.parse::<RawFd>().ok())
+        .map(|fd| {
+            // Inherited descriptors must not leak into further child processes
+            let _ = set_close_on_exec(fd);
+            unsafe { Listener::from_raw_fd(fd) }
+        })
+        .collect()
+}
+
+/// Tell the previous server process that this one accepts connections
+pub fn notify_ready() {
+    let fd = env::var(READY_FD_VAR)
+        .ok()
+        .and_then(|fd| fd.parse::<RawFd>().ok());
+    env::remove_var(READY_FD_VAR);
+
+    if let Some(fd) = fd {
+        let mut pipe = unsafe { File::from_raw_fd(fd) };
+        if let Err(e) = pipe.write_all(b"1") {
+            tracing::error!("Notifying the previous server process failed: {}", e);
+        }
+    }
+}
+
+/// Start a new server process with the listening sockets and wait until it is ready
+pub fn hand_over(listener_fds: &[RawFd]) -> Result<()> {
+    let mut fds = [0 as RawFd; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    let (mut ready, ready_write_fd) = (unsafe { File::from_raw_fd(fds[0]) }, fds[1]);
+    let ready_write = unsafe { File::from_raw_fd(ready_write_fd) };
+    set_close_on_exec(fds[0])?;
+    set_close_on_exec(ready_write_fd)?;
+
+    let inherited: Vec<RawFd> = listener_fds
+        .iter()
+        .cloned()
+        .chain(Some(ready_write_fd))
+        .collect();
+    let fd_list = listener_fds
+        .iter()
+        .map(RawFd::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FDS_VAR, fd_list)
+        .env(READY_FD_VAR, ready_write_fd.to_string());
+    unsafe {
+        // Only the child clears the flags, so no other process started meanwhile inherits them
+        command.pre_exec(move || {
+            for fd in &inherited {
+                clear_close_on_exec(*fd)?;
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    drop(ready_write);
+
+    let mut poll_fd = libc::pollfd {
+        fd: fds[0],
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let polled = unsafe { libc::poll(&mut poll_fd, 1, READY_TIMEOUT_MS) };
+    let mut byte = [0u8];
+    let outcome = match polled {
+        -1 => Err(Error::last_os_error()),
+        0 => Err(Error::new(
+            ErrorKind::TimedOut,
+            "new process did not become ready",
+        )),
+        _ => match ready.read(&mut byte)? {
+            0 => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "new process exited before it was ready",
+            )),
+            _ => Ok(()),
+        },
+    };
+
+    if outcome.is_err() {
+        // Reap the new process if it is already gone
+        let _ = child.try_wait();
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::unix::io::IntoRawFd;
+
+    #[test]
+    fn take_over_listeners_and_report_ready() {
+        // Given
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        env::set_var(LISTEN_FDS_VAR, listener.into_raw_fd().to_string());
+        let mut fds = [0 as RawFd; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut ready = unsafe { File::from_raw_fd(fds[0]) };
+        env::set_var(READY_FD_VAR, fds[1].to_string());
+
+        // When
+        let taking_over = is_taking_over();
+        let listeners = take_listeners();
+        notify_ready();
+
+        // Then
+        assert!(taking_over);
+        let addresses: Vec<String> = listeners.iter().map(Listener::local_address).collect();
+        assert_eq!(addresses, [address]);
+        assert!(!is_taking_over());
+        let mut notification = String::new();
+        ready.read_to_string(&mut notification).unwrap();
+        assert_eq!(notification, "1");
+    }
+}
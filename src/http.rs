@@ -0,0 +1,225 @@
+//! A minimal HTTP/1.1 client for posting to webhooks and talking to the web API of a server, over
+//! TLS for `https` URLs.
+//!
+//! Every request opens a connection of its own and closes it after the response. Server
+//! certificates are verified against the Mozilla root certificates that are built in.
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConnection, StreamOwned};
+
+use crate::tls;
+
+/// How long to wait for connecting, sending and the response
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most of a response that is kept for error messages
+const MAX_ERROR_BODY: usize = 200;
+
+/// An `http` or `https` URL
+#[derive(Clone)]
+pub struct Url {
+    https: bool,
+    host: String,
+    port: u16,
+    /// Path and query, starting with `/`
+    path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, String> {
+        let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err("must start with http:// or https://".to_string());
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/"),
+        };
+        let default_port = if https { 443 } else { 80 };
+        // IPv6 addresses are in brackets, so their colons do not start the port
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse().map_err(|_| format!("invalid port {}", port))?,
+            ),
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err("must contain a host".to_string());
+        }
+        Ok(Url {
+            https,
+            host: host.to_string(),
+            port,
+            path: if path.starts_with('?') {
+                format!("/{}", path)
+            } else {
+                path.to_string()
+            },
+        })
+    }
+
+    /// The URL with a path appended to its own, e.g. where a proxy serves a server
+    pub fn join(&self, path: &str) -> Url {
+        Url {
+            path: format!("{}{}", self.path.trim_end_matches('/'), path),
+            ..self.clone()
+        }
+    }
+}
+
+/// Post a body and fail unless the response has a 2xx status
+pub fn post(url: &Url, content_type: &str, body: &[u8]) -> Result<(), Error> {
+    request(url, "POST", &[("Content-Type", content_type)], body).map(|_| ())
+}
+
+/// Send a request and get the body of the response, failing unless it has a 2xx status.
+/// A 404 response fails with `ErrorKind::NotFound`.
+pub fn request(
+    url: &Url,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no address for {}", url.host)))?;
+    let stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rust-smtp-server/{}\r\n\
+         {}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        url.path,
+        url.host,
+        env!("CARGO_PKG_VERSION"),
+        headers,
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+
+    let mut response = Vec::new();
+    if url.https {
+        let name = ServerName::try_from(url.host.clone())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let connection = ClientConnection::new(tls::client_config(), name).map_err(Error::other)?;
+        let mut stream = StreamOwned::new(connection, stream);
+        stream.write_all(&request)?;
+        read_response(&mut stream, &mut response)?;
+    } else {
+        let mut stream = stream;
+        stream.write_all(&request)?;
+        read_response(&mut stream, &mut response)?;
+    }
+
+    let (head, body) = match response.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(index) => (&response[..index], &response[index + 4..]),
+        None => (&response[..], &[][..]),
+    };
+    let head = String::from_utf8_lossy(head);
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line.split(' ').nth(1).unwrap_or_default();
+    if status.starts_with('2') && status.len() == 3 {
+        return Ok(body.to_vec());
+    }
+    let body = String::from_utf8_lossy(body);
+    let body: String = body.trim().chars().take(MAX_ERROR_BODY).collect();
+    let kind = if status == "404" {
+        ErrorKind::NotFound
+    } else {
+        ErrorKind::Other
+    };
+    Err(Error::new(
+        kind,
+        format!("{} {}", status_line, body).trim().to_string(),
+    ))
+}
+
+/// Read a response until the server closes the connection.
+/// Servers often close TLS connections without notifying, which is fine after a response.
+fn read_response(stream: &mut dyn Read, response: &mut Vec<u8>) -> Result<(), Error> {
+    match stream.read_to_end(response) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof && !response.is_empty() => Ok(()),
+        outcome => outcome.map(|_| ()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn parse_urls() {
+        let url = Url::parse("https://hooks.example.com/services/T0/B0").unwrap();
+        assert!(url.https);
+        assert_eq!((url.host.as_str(), url.port), ("hooks.example.com", 443));
+        assert_eq!(url.path, "/services/T0/B0");
+
+        let url = Url::parse("http://[::1]:8080?wait=true").unwrap();
+        assert!(!url.https);
+        assert_eq!((url.host.as_str(), url.port), ("::1", 8080));
+        assert_eq!(url.path, "/?wait=true");
+
+        assert!(Url::parse("ftp://example.com").is_err());
+    }
+
+    #[test]
+    fn post_and_check_status() {
+        // Given
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/hook", server.local_addr().unwrap())).unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for reply in [
+                "HTTP/1.1 204 No Content\r\n\r\n",
+                "HTTP/1.1 404 Not Found\r\n\r\nno_hook",
+            ] {
+                let (mut stream, _) = server.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                while !request.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut request).unwrap();
+                }
+                let mut body = [0; 2];
+                reader.read_exact(&mut body).unwrap();
+                requests.push(request + &String::from_utf8_lossy(&body));
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        // When
+        let accepted = post(&url, "application/json", b"{}");
+        let rejected = post(&url, "application/json", b"{}");
+
+        // Then
+        accepted.unwrap();
+        let rejected = rejected.unwrap_err();
+        assert_eq!(rejected.kind(), ErrorKind::NotFound);
+        assert_eq!(rejected.to_string(), "HTTP/1.1 404 Not Found no_hook");
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(requests[0].contains("Content-Length: 2\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\n{}"));
+    }
+}
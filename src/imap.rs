@@ -0,0 +1,1548 @@
+//! A read-only IMAP server on the kept messages, so that QA staff can point a mail client such as
+//! Thunderbird at them and automated clients can fetch and search them
+//! ([RFC 3501](https://tools.ietf.org/html/rfc3501)).
+//!
+//! `INBOX` holds all kept messages, and there is a folder for each inbox of the web UI that has
+//! messages: one for every recipient address without its `+tag`, like `ci@example.com`, and one
+//! for every tag, like `+job-42`. Other addresses can be selected as well. Messages cannot be
+//! added, copied or removed, but the `\Seen` flag can be set and cleared. It is kept in the summary
+//! of a message, so all folders and sessions share it. Only the `--auth-user` credentials log in if
+//! they are given, and any otherwise.
+//!
+//! Messages get their UIDs in the order the server first sees them and keep them until the process
+//! ends, so the UIDVALIDITY of every folder is the time the server started. Sessions learn about
+//! new and removed messages with NOOP and CHECK.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::iter;
+use std::net::TcpStream;
+use std::slice;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auth::Credentials;
+use crate::clock;
+use crate::mime;
+use crate::relay::path_address;
+use crate::store::{Entry, MessageStore};
+use crate::web::in_inbox;
+
+/// How long a client may be idle, the minimum of RFC 3501
+const TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Largest literal that a command may carry, e.g. a password or a search term
+const MAX_LITERAL: usize = 64 * 1024;
+
+const CAPABILITIES: &str = "IMAP4rev1 UNSELECT";
+const MSG_NOT_VALID: &str = "BAD Unknown command or not valid in this state";
+const MSG_READ_ONLY: &str = "NO Folders and messages cannot be changed";
+const MSG_NO_SUCH_FOLDER: &str = "NO No such folder";
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Serve the folders on an address as host:port until the process ends
+pub fn run(address: String, store: Arc<dyn MessageStore>, credentials: Option<Arc<Credentials>>) {
+    let listener = crate::bind_retrying(&address, "IMAP");
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let uids = Arc::new(Uids::new(started as u32));
+    for stream in listener.incoming().flatten() {
+        let (store, credentials, uids) = (store.clone(), credentials.clone(), uids.clone());
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, store.as_ref(), credentials.as_deref(), &uids) {
+                tracing::warn!("Error communicating with IMAP client: {}", e);
+            }
+        });
+    }
+}
+
+/// The UIDs of the messages, given in the order they are first seen
+pub struct Uids {
+    uids: Mutex<HashMap<String, u32>>,
+    /// UIDVALIDITY of all folders
+    validity: u32,
+}
+
+impl Uids {
+    fn new(validity: u32) -> Uids {
+        Uids {
+            uids: Mutex::new(HashMap::new()),
+            validity,
+        }
+    }
+
+    fn uid(&self, id: &str) -> u32 {
+        let mut uids = self.uids();
+        let next = uids.len() as u32 + 1;
+        *uids.entry(id.to_string()).or_insert(next)
+    }
+
+    /// The UID that the next new message gets
+    fn next(&self) -> u32 {
+        self.uids().len() as u32 + 1
+    }
+
+    /// A panic while holding the lock cannot leave the map inconsistent
+    fn uids(&self) -> MutexGuard<'_, HashMap<String, u32>> {
+        self.uids.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn handle(
+    stream: TcpStream,
+    store: &dyn MessageStore,
+    credentials: Option<&Credentials>,
+    uids: &Uids,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut session = Session {
+        store,
+        credentials,
+        uids,
+        logged_in: false,
+        selected: None,
+    };
+    session.run(&mut reader, &mut writer)
+}
+
+/// A folder as selected, with its messages as the client was last told about them
+struct Selected {
+    /// Lower case inbox, none for INBOX
+    inbox: Option<String>,
+    read_only: bool,
+    /// The messages with their UIDs, in the order of their UIDs
+    messages: Vec<(u32, Entry)>,
+}
+
+struct Session<'a> {
+    store: &'a dyn MessageStore,
+    credentials: Option<&'a Credentials>,
+    uids: &'a Uids,
+    logged_in: bool,
+    selected: Option<Selected>,
+}
+
+impl Session<'_> {
+    /// Talk to a client until it logs out or goes away
+    fn run(&mut self, reader: &mut dyn BufRead, writer: &mut dyn Write) -> io::Result<()> {
+        write!(
+            writer,
+            "* OK [CAPABILITY {}] IMAP server ready\r\n",
+            CAPABILITIES
+        )?;
+        while let Some(line) = read_command(reader, writer)? {
+            let tag = line.split(|&b| b == b' ').next().unwrap_or_default();
+            let tag = String::from_utf8_lossy(tag).into_owned();
+            let tokens = match parse(&line) {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    write!(writer, "{} BAD {}\r\n", tag, e)?;
+                    continue;
+                }
+            };
+            let command = match tokens.get(1) {
+                Some(Token::Atom(command)) => command.to_ascii_uppercase(),
+                _ => {
+                    write!(writer, "{} BAD Missing command\r\n", tag)?;
+                    continue;
+                }
+            };
+            if command == "LOGOUT" {
+                write!(
+                    writer,
+                    "* BYE Logging out\r\n{} OK LOGOUT completed\r\n",
+                    tag
+                )?;
+                return writer.flush();
+            }
+            let reply = self.execute(&command, &tokens[2..], writer)?;
+            write!(writer, "{} {}\r\n", tag, reply)?;
+        }
+        Ok(())
+    }
+
+    /// Carry out a command, writing its untagged responses, and get the tagged one
+    fn execute(
+        &mut self,
+        command: &str,
+        arguments: &[Token],
+        out: &mut dyn Write,
+    ) -> io::Result<String> {
+        let selected = self.selected.is_some();
+        Ok(match (command, self.logged_in) {
+            ("CAPABILITY", _) => {
+                write!(out, "* CAPABILITY {}\r\n", CAPABILITIES)?;
+                "OK CAPABILITY completed".to_string()
+            }
+            ("NOOP", _) => {
+                self.refresh(out)?;
+                "OK NOOP completed".to_string()
+            }
+            ("LOGIN", false) => self.login(arguments),
+            ("AUTHENTICATE", false) => "NO Only LOGIN is supported".to_string(),
+            ("SELECT", true) => self.select(arguments, false, out)?,
+            ("EXAMINE", true) => self.select(arguments, true, out)?,
+            ("LIST" | "LSUB", true) => self.list(command, arguments, out)?,
+            ("STATUS", true) => self.status(arguments, out)?,
+            ("SUBSCRIBE" | "UNSUBSCRIBE", true) => format!("OK {} completed", command),
+            ("CREATE" | "DELETE" | "RENAME" | "APPEND", true) => MSG_READ_ONLY.to_string(),
+            ("CHECK", true) if selected => {
+                self.refresh(out)?;
+                "OK CHECK completed".to_string()
+            }
+            ("CLOSE" | "UNSELECT", true) if selected => {
+                self.selected = None;
+                format!("OK {} completed", command)
+            }
+            ("EXPUNGE" | "COPY", true) if selected => MSG_READ_ONLY.to_string(),
+            ("FETCH" | "STORE" | "SEARCH", true) if selected => {
+                self.on_messages(command, arguments, false, out)?
+            }
+            ("UID", true) if selected => match arguments.split_first() {
+                Some((Token::Atom(command), arguments)) => {
+                    match command.to_ascii_uppercase().as_str() {
+                        command @ ("FETCH" | "STORE" | "SEARCH") => {
+                            self.on_messages(command, arguments, true, out)?
+                        }
+                        "COPY" => MSG_READ_ONLY.to_string(),
+                        _ => MSG_NOT_VALID.to_string(),
+                    }
+                }
+                _ => MSG_NOT_VALID.to_string(),
+            },
+            _ => MSG_NOT_VALID.to_string(),
+        })
+    }
+
+    fn login(&mut self, arguments: &[Token]) -> String {
+        let (Some(user), Some(password)) = (
+            arguments.first().and_then(Token::text),
+            arguments.get(1).and_then(Token::text),
+        ) else {
+            return "BAD LOGIN needs a user and a password".to_string();
+        };
+        if self
+            .credentials
+            .is_none_or(|credentials| user == credentials.user && password == credentials.password)
+        {
+            self.logged_in = true;
+            "OK LOGIN completed".to_string()
+        } else {
+            "NO [AUTHENTICATIONFAILED] Invalid user or password".to_string()
+        }
+    }
+
+    /// The messages in INBOX or an inbox with their UIDs, in the order of their UIDs
+    fn mailbox(&self, inbox: Option<&str>) -> io::Result<Vec<(u32, Entry)>> {
+        let mut messages: Vec<(u32, Entry)> = self
+            .store
+            .entries()?
+            .into_iter()
+            .filter(|entry| inbox.is_none_or(|inbox| in_inbox(entry, inbox)))
+            .map(|entry| (self.uids.uid(&entry.id), entry))
+            .collect();
+        messages.sort_by_key(|(uid, _)| *uid);
+        Ok(messages)
+    }
+
+    fn select(
+        &mut self,
+        arguments: &[Token],
+        read_only: bool,
+        out: &mut dyn Write,
+    ) -> io::Result<String> {
+        // A failed SELECT leaves no folder selected
+        self.selected = None;
+        let Some(name) = arguments.first().and_then(Token::text) else {
+            return Ok("BAD Missing folder".to_string());
+        };
+        let Some(inbox) = folder(&name) else {
+            return Ok(MSG_NO_SUCH_FOLDER.to_string());
+        };
+        let messages = self.mailbox(inbox.as_deref())?;
+        let changeable = if read_only { "" } else { "\\Seen" };
+        write!(
+            out,
+            "* FLAGS (\\Seen)\r\n\
+             * OK [PERMANENTFLAGS ({})] Flags that can be changed\r\n\
+             * {} EXISTS\r\n\
+             * 0 RECENT\r\n",
+            changeable,
+            messages.len()
+        )?;
+        if let Some(index) = messages.iter().position(|(_, entry)| !seen(entry)) {
+            write!(out, "* OK [UNSEEN {}] First unseen message\r\n", index + 1)?;
+        }
+        write!(
+            out,
+            "* OK [UIDVALIDITY {}] UIDs are valid\r\n* OK [UIDNEXT {}] Predicted next UID\r\n",
+            self.uids.validity,
+            self.uids.next()
+        )?;
+        self.selected = Some(Selected {
+            inbox,
+            read_only,
+            messages,
+        });
+        Ok(if read_only {
+            "OK [READ-ONLY] EXAMINE completed".to_string()
+        } else {
+            "OK [READ-WRITE] SELECT completed".to_string()
+        })
+    }
+
+    /// List the folders whose names match a pattern, in which `*` and `%` match anything as there
+    /// is no hierarchy
+    fn list(&self, command: &str, arguments: &[Token], out: &mut dyn Write) -> io::Result<String> {
+        let (Some(reference), Some(pattern)) = (
+            arguments.first().and_then(Token::text),
+            arguments.get(1).and_then(Token::text),
+        ) else {
+            return Ok(format!("BAD {} needs a reference and a pattern", command));
+        };
+        if pattern.is_empty() {
+            write!(out, "* {} (\\Noselect) NIL \"\"\r\n", command)?;
+            return Ok(format!("OK {} completed", command));
+        }
+        let pattern = reference + &pattern;
+        let names = iter::once("INBOX".to_string()).chain(folders(&self.store.entries()?));
+        for name in names.filter(|name| wildcard_match(pattern.as_bytes(), name.as_bytes())) {
+            out.write_all(format!("* {} (\\HasNoChildren) NIL ", command).as_bytes())?;
+            out.write_all(&string(name.as_bytes()))?;
+            out.write_all(b"\r\n")?;
+        }
+        Ok(format!("OK {} completed", command))
+    }
+
+    fn status(&self, arguments: &[Token], out: &mut dyn Write) -> io::Result<String> {
+        let (Some(name), Some(Token::List(items))) =
+            (arguments.first().and_then(Token::text), arguments.get(1))
+        else {
+            return Ok("BAD STATUS needs a folder and a list of items".to_string());
+        };
+        let Some(inbox) = folder(&name) else {
+            return Ok(MSG_NO_SUCH_FOLDER.to_string());
+        };
+        let messages = self.mailbox(inbox.as_deref())?;
+        let mut values = Vec::new();
+        for item in items {
+            let item = item.text().unwrap_or_default().to_ascii_uppercase();
+            let value = match item.as_str() {
+                "MESSAGES" => messages.len(),
+                "RECENT" => 0,
+                "UIDNEXT" => self.uids.next() as usize,
+                "UIDVALIDITY" => self.uids.validity as usize,
+                "UNSEEN" => messages.iter().filter(|(_, entry)| !seen(entry)).count(),
+                _ => return Ok(format!("BAD Unknown status item {}", item)),
+            };
+            values.push(format!("{} {}", item, value));
+        }
+        out.write_all(b"* STATUS ")?;
+        out.write_all(&string(name.as_bytes()))?;
+        write!(out, " ({})\r\n", values.join(" "))?;
+        Ok("OK STATUS completed".to_string())
+    }
+
+    /// Tell the client about the messages of the selected folder that were removed or arrived
+    /// since it was last told, and about changed flags
+    fn refresh(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        let Some(selected) = &self.selected else {
+            return Ok(());
+        };
+        let current = self.mailbox(selected.inbox.as_deref())?;
+        let Some(selected) = &mut self.selected else {
+            return Ok(());
+        };
+        let find = |uid: u32| current.binary_search_by_key(&uid, |(uid, _)| *uid);
+        // From the last, so that the numbers of the messages before stay as the client knows them
+        for index in (0..selected.messages.len()).rev() {
+            if find(selected.messages[index].0).is_err() {
+                write!(out, "* {} EXPUNGE\r\n", index + 1)?;
+                selected.messages.remove(index);
+            }
+        }
+        for (index, (uid, entry)) in selected.messages.iter_mut().enumerate() {
+            if let Ok(found) = find(*uid) {
+                let now = &current[found].1;
+                if seen(now) != seen(entry) {
+                    write!(out, "* {} FETCH (FLAGS ({}))\r\n", index + 1, flags(now))?;
+                }
+                *entry = now.clone();
+            }
+        }
+        let known = selected.messages.last().map_or(0, |(uid, _)| *uid);
+        let count = selected.messages.len();
+        selected
+            .messages
+            .extend(current.into_iter().filter(|(uid, _)| *uid > known));
+        if selected.messages.len() > count {
+            write!(out, "* {} EXISTS\r\n", selected.messages.len())?;
+        }
+        Ok(())
+    }
+
+    /// FETCH, STORE or SEARCH with sequence numbers or UIDs
+    fn on_messages(
+        &mut self,
+        command: &str,
+        arguments: &[Token],
+        by_uid: bool,
+        out: &mut dyn Write,
+    ) -> io::Result<String> {
+        if command == "SEARCH" {
+            return self.search(arguments, by_uid, out);
+        }
+        let Some(selected) = &self.selected else {
+            return Ok(MSG_NOT_VALID.to_string());
+        };
+        let Some(indexes) = arguments
+            .first()
+            .and_then(Token::text)
+            .and_then(|set| indexes(&selected.messages, &set, by_uid))
+        else {
+            return Ok(format!("BAD {} needs a sequence set", command));
+        };
+        if command == "FETCH" {
+            self.fetch(&indexes, &arguments[1..], by_uid, out)
+        } else {
+            self.store(&indexes, &arguments[1..], by_uid, out)
+        }
+    }
+
+    fn fetch(
+        &mut self,
+        indexes: &[usize],
+        arguments: &[Token],
+        by_uid: bool,
+        out: &mut dyn Write,
+    ) -> io::Result<String> {
+        let Some(items) = fetch_items(arguments, by_uid) else {
+            return Ok("BAD Unknown fetch items".to_string());
+        };
+        let store = self.store;
+        let Some(selected) = &mut self.selected else {
+            return Ok(MSG_NOT_VALID.to_string());
+        };
+        // Reading a section that is not peeked at marks the message as seen
+        let sets_seen = !selected.read_only
+            && items
+                .iter()
+                .any(|item| matches!(item, Item::Section { peek: false, .. }));
+        for &index in indexes {
+            let (uid, entry) = &mut selected.messages[index];
+            // Removed since the client was last told, which it learns with NOOP
+            let Some(content) = store.content(&entry.id)? else {
+                continue;
+            };
+            let content = crlf(&content);
+            let mut items = items.iter().collect::<Vec<_>>();
+            if sets_seen && !seen(entry) {
+                if let Some(updated) = set_seen(store, &entry.id, true)? {
+                    *entry = updated;
+                }
+                if !items.iter().any(|item| matches!(item, Item::Flags)) {
+                    items.push(&Item::Flags);
+                }
+            }
+            let mut response = format!("* {} FETCH (", index + 1).into_bytes();
+            for (number, item) in items.iter().enumerate() {
+                if number > 0 {
+                    response.push(b' ');
+                }
+                let (header, body) = mime::split_header(&content);
+                match item {
+                    Item::Uid => response.extend(format!("UID {}", uid).as_bytes()),
+                    Item::Flags => response.extend(format!("FLAGS ({})", flags(entry)).as_bytes()),
+                    Item::InternalDate => response.extend(
+                        format!("INTERNALDATE \"{}\"", internal_date(entry.received)).as_bytes(),
+                    ),
+                    Item::Size => {
+                        response.extend(format!("RFC822.SIZE {}", content.len()).as_bytes())
+                    }
+                    Item::Envelope => {
+                        response.extend(b"ENVELOPE ");
+                        response.extend(envelope(header));
+                    }
+                    Item::Structure(name) => {
+                        response.extend(format!("{} ", name).as_bytes());
+                        response.extend(structure(header, body));
+                    }
+                    Item::Section {
+                        name,
+                        section,
+                        partial,
+                        ..
+                    } => {
+                        let data = self::section(&content, section).unwrap_or_default();
+                        let data = match partial {
+                            Some((origin, count)) => {
+                                response.extend(format!("{}<{}> ", name, origin).as_bytes());
+                                let start = (*origin).min(data.len());
+                                data[start..(start + count).min(data.len())].to_vec()
+                            }
+                            None => {
+                                response.extend(format!("{} ", name).as_bytes());
+                                data
+                            }
+                        };
+                        response.extend(literal(&data));
+                    }
+                }
+            }
+            response.extend(b")\r\n");
+            out.write_all(&response)?;
+        }
+        Ok("OK FETCH completed".to_string())
+    }
+
+    /// Set or clear the `\Seen` flag, the only one that is kept
+    fn store(
+        &mut self,
+        indexes: &[usize],
+        arguments: &[Token],
+        by_uid: bool,
+        out: &mut dyn Write,
+    ) -> io::Result<String> {
+        let store = self.store;
+        let Some(selected) = &mut self.selected else {
+            return Ok(MSG_NOT_VALID.to_string());
+        };
+        if selected.read_only {
+            return Ok("NO Folder is read-only".to_string());
+        }
+        let (Some(action), Some(flag_list)) =
+            (arguments.first().and_then(Token::text), arguments.get(1))
+        else {
+            return Ok("BAD STORE needs an action and flags".to_string());
+        };
+        let action = action.to_ascii_uppercase();
+        let (action, silent) = match action.strip_suffix(".SILENT") {
+            Some(action) => (action, true),
+            None => (action.as_str(), false),
+        };
+        let given = match flag_list {
+            Token::List(flags) => flags.iter().filter_map(Token::text).collect(),
+            flag => flag.text().into_iter().collect::<Vec<_>>(),
+        };
+        let seen_given = given.iter().any(|flag| flag.eq_ignore_ascii_case("\\Seen"));
+        for &index in indexes {
+            let (uid, entry) = &mut selected.messages[index];
+            let was_seen = seen(entry);
+            let is_seen = match action {
+                "+FLAGS" => was_seen || seen_given,
+                "-FLAGS" => was_seen && !seen_given,
+                "FLAGS" => seen_given,
+                _ => return Ok(format!("BAD Unknown STORE action {}", action)),
+            };
+            if is_seen != was_seen {
+                if let Some(updated) = set_seen(store, &entry.id, is_seen)? {
+                    *entry = updated;
+                }
+            }
+            if !silent {
+                let uid = if by_uid {
+                    format!(" UID {}", uid)
+                } else {
+                    String::new()
+                };
+                write!(
+                    out,
+                    "* {} FETCH (FLAGS ({}){})\r\n",
+                    index + 1,
+                    flags(entry),
+                    uid
+                )?;
+            }
+        }
+        Ok("OK STORE completed".to_string())
+    }
+
+    fn search(
+        &mut self,
+        arguments: &[Token],
+        by_uid: bool,
+        out: &mut dyn Write,
+    ) -> io::Result<String> {
+        let Some(selected) = &self.selected else {
+            return Ok(MSG_NOT_VALID.to_string());
+        };
+        let mut arguments = arguments;
+        // The terms are compared as UTF-8 in any case
+        if let Some(Token::Atom(charset)) = arguments.first() {
+            if charset.eq_ignore_ascii_case("CHARSET") {
+                arguments = arguments.get(2..).unwrap_or_default();
+            }
+        }
+        let last = (
+            selected.messages.len() as u32,
+            selected.messages.last().map_or(0, |(uid, _)| *uid),
+        );
+        let mut tokens = arguments.iter();
+        let mut keys = Vec::new();
+        while tokens.len() > 0 {
+            match search_key(&mut tokens, last) {
+                Some(key) => keys.push(key),
+                None => return Ok("BAD Invalid search criteria".to_string()),
+            }
+        }
+        let key = Key::And(keys);
+        let mut found = Vec::new();
+        for (index, (uid, entry)) in selected.messages.iter().enumerate() {
+            let mut candidate = Candidate {
+                number: index as u32 + 1,
+                uid: *uid,
+                entry,
+                store: self.store,
+                content: None,
+            };
+            if candidate.matches(&key)? {
+                found.push(if by_uid { *uid } else { index as u32 + 1 });
+            }
+        }
+        let found: Vec<String> = found.iter().map(u32::to_string).collect();
+        if found.is_empty() {
+            write!(out, "* SEARCH\r\n")?;
+        } else {
+            write!(out, "* SEARCH {}\r\n", found.join(" "))?;
+        }
+        Ok("OK SEARCH completed".to_string())
+    }
+}
+
+/// Read a command line, asking for the literals at the ends of its lines and taking them in
+fn read_command(reader: &mut dyn BufRead, writer: &mut dyn Write) -> io::Result<Option<Vec<u8>>> {
+    let mut command = Vec::new();
+    loop {
+        // Responses are buffered until the client has to wait for more
+        writer.flush()?;
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        command.extend_from_slice(line);
+        // Too large a literal is not asked for, so the command fails to parse
+        let Some(size) = literal_size(line).filter(|&size| size <= MAX_LITERAL) else {
+            return Ok(Some(command));
+        };
+        write!(writer, "+ Ready for literal\r\n")?;
+        command.extend_from_slice(b"\r\n");
+        let start = command.len();
+        command.resize(start + size, 0);
+        reader.read_exact(&mut command[start..])?;
+    }
+}
+
+/// The size of the literal that a line ends with, like `{6}`
+fn literal_size(line: &[u8]) -> Option<usize> {
+    let line = line.strip_suffix(b"}")?;
+    let start = line.iter().rposition(|&b| b == b'{')?;
+    std::str::from_utf8(&line[start + 1..]).ok()?.parse().ok()
+}
+
+/// A part of a command
+#[derive(Debug, PartialEq)]
+enum Token {
+    /// An atom, which takes in a section in brackets like `BODY[HEADER.FIELDS (SUBJECT)]<0.100>`
+    Atom(String),
+    /// A quoted string or a literal
+    String(Vec<u8>),
+    List(Vec<Token>),
+}
+
+impl Token {
+    /// An atom or a string as text, for arguments that may be either
+    fn text(&self) -> Option<String> {
+        match self {
+            Token::Atom(atom) => Some(atom.clone()),
+            Token::String(string) => Some(String::from_utf8_lossy(string).into_owned()),
+            Token::List(_) => None,
+        }
+    }
+}
+
+/// Split a command into its tokens
+fn parse(line: &[u8]) -> Result<Vec<Token>, String> {
+    parse_list(line, &mut 0, false)
+}
+
+/// Parse tokens up to the end of the line, or up to the parenthesis that closes a list
+fn parse_list(line: &[u8], position: &mut usize, nested: bool) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    loop {
+        while line.get(*position) == Some(&b' ') {
+            *position += 1;
+        }
+        match line.get(*position) {
+            None if nested => return Err("Missing )".to_string()),
+            None => return Ok(tokens),
+            Some(b')') if nested => {
+                *position += 1;
+                return Ok(tokens);
+            }
+            Some(b'(') => {
+                *position += 1;
+                tokens.push(Token::List(parse_list(line, position, true)?));
+            }
+            Some(b'"') => {
+                let mut string = Vec::new();
+                *position += 1;
+                loop {
+                    match line.get(*position) {
+                        None => return Err("Missing closing quote".to_string()),
+                        Some(b'"') => break,
+                        Some(b'\\') => {
+                            *position += 1;
+                            string.extend(line.get(*position));
+                        }
+                        Some(&byte) => string.push(byte),
+                    }
+                    *position += 1;
+                }
+                *position += 1;
+                tokens.push(Token::String(string));
+            }
+            Some(b'{') => {
+                let rest = &line[*position..];
+                let end = rest.iter().position(|&b| b == b'}').ok_or("Missing }")?;
+                let size = literal_size(&rest[..=end]).ok_or("Invalid literal")?;
+                let start = *position + end + 1 + 2;
+                let content = line
+                    .get(start..start + size)
+                    .filter(|_| rest[end + 1..].starts_with(b"\r\n"))
+                    .ok_or("Literal too large")?;
+                tokens.push(Token::String(content.to_vec()));
+                *position = start + size;
+            }
+            Some(_) => {
+                let start = *position;
+                let mut depth = 0;
+                while let Some(&byte) = line.get(*position) {
+                    match byte {
+                        b'[' => depth += 1,
+                        b']' => depth -= 1,
+                        b' ' | b'(' | b')' if depth == 0 => break,
+                        _ => {}
+                    }
+                    *position += 1;
+                }
+                tokens.push(Token::Atom(
+                    String::from_utf8_lossy(&line[start..*position]).into_owned(),
+                ));
+            }
+        }
+    }
+}
+
+/// The inbox of a folder name, none for INBOX, or nothing if the name is not a folder
+fn folder(name: &str) -> Option<Option<String>> {
+    if name.eq_ignore_ascii_case("INBOX") {
+        return Some(None);
+    }
+    let name = name.to_lowercase();
+    (name.starts_with('+') || name.contains('@')).then_some(Some(name))
+}
+
+/// The folders besides INBOX that have messages: the recipient addresses without their tags,
+/// followed by the tags
+fn folders(entries: &[Entry]) -> Vec<String> {
+    let (mut addresses, mut tags) = (BTreeSet::new(), BTreeSet::new());
+    for entry in entries {
+        let recipients = entry.summary["to"].as_array().cloned().unwrap_or_default();
+        for recipient in recipients.iter().filter_map(|recipient| recipient.as_str()) {
+            let recipient = path_address(recipient).to_lowercase();
+            let (local, domain) = recipient.rsplit_once('@').unwrap_or((&recipient, ""));
+            match local.split_once('+') {
+                Some((user, tag)) => {
+                    addresses.insert(format!("{}@{}", user, domain));
+                    tags.insert(format!("+{}", tag));
+                }
+                None => {
+                    addresses.insert(recipient.clone());
+                }
+            }
+        }
+    }
+    addresses.into_iter().chain(tags).collect()
+}
+
+/// Whether a folder name matches a LIST pattern, ignoring case
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*' | b'%', rest)) => {
+            (0..=name.len()).any(|skip| wildcard_match(rest, &name[skip..]))
+        }
+        Some((c, rest)) => name
+            .split_first()
+            .is_some_and(|(n, name)| n.eq_ignore_ascii_case(c) && wildcard_match(rest, name)),
+    }
+}
+
+/// The indexes of the messages in a sequence set of numbers or UIDs, like `1:4,7,9:*`
+fn indexes(messages: &[(u32, Entry)], set: &str, by_uid: bool) -> Option<Vec<usize>> {
+    let last = match by_uid {
+        true => messages.last().map_or(0, |(uid, _)| *uid),
+        false => messages.len() as u32,
+    };
+    let ranges = sequence_set(set, last)?;
+    Some(
+        (0..messages.len())
+            .filter(|&index| {
+                let number = if by_uid {
+                    messages[index].0
+                } else {
+                    index as u32 + 1
+                };
+                in_ranges(&ranges, number)
+            })
+            .collect(),
+    )
+}
+
+/// The ranges of a sequence set, in which `*` is the last number
+fn sequence_set(set: &str, last: u32) -> Option<Vec<(u32, u32)>> {
+    let number = |number: &str| match number {
+        "*" => Some(last),
+        number => number.parse().ok().filter(|&number| number > 0),
+    };
+    set.split(',')
+        .map(|range| {
+            let (first, second) = match range.split_once(':') {
+                Some((first, second)) => (number(first)?, number(second)?),
+                None => (number(range)?, number(range)?),
+            };
+            Some((first.min(second), first.max(second)))
+        })
+        .collect()
+}
+
+fn in_ranges(ranges: &[(u32, u32)], number: u32) -> bool {
+    ranges
+        .iter()
+        .any(|(first, last)| (*first..=*last).contains(&number))
+}
+
+fn seen(entry: &Entry) -> bool {
+    entry.summary["seen"] == true
+}
+
+fn flags(entry: &Entry) -> &'static str {
+    if seen(entry) {
+        "\\Seen"
+    } else {
+        ""
+    }
+}
+
+/// Set or clear the `\Seen` flag of a message in its summary as it is now. Returns the updated
+/// message, or none if it was removed.
+fn set_seen(store: &dyn MessageStore, id: &str, seen: bool) -> io::Result<Option<Entry>> {
+    let Some(mut entry) = store.entries()?.into_iter().find(|entry| entry.id == id) else {
+        return Ok(None);
+    };
+    match entry.summary.as_object_mut() {
+        Some(fields) if seen => {
+            fields.insert("seen".to_string(), true.into());
+        }
+        Some(fields) => {
+            fields.remove("seen");
+        }
+        None => return Ok(None),
+    }
+    Ok(store.update(entry.clone())?.then_some(entry))
+}
+
+/// What FETCH gets of a message
+enum Item {
+    Uid,
+    Flags,
+    InternalDate,
+    Size,
+    Envelope,
+    /// BODY or BODYSTRUCTURE, which are the same without extension data
+    Structure(String),
+    /// A section of the content, with the name to answer with, like `BODY[HEADER]`
+    Section {
+        name: String,
+        section: String,
+        peek: bool,
+        partial: Option<(usize, usize)>,
+    },
+}
+
+/// The items of a FETCH, with the UID that UID FETCH always answers with
+fn fetch_items(arguments: &[Token], by_uid: bool) -> Option<Vec<Item>> {
+    let names: Vec<String> = match arguments {
+        [Token::List(names)] => names.iter().map(Token::text).collect::<Option<_>>()?,
+        [Token::Atom(name)] => match name.to_ascii_uppercase().as_str() {
+            "ALL" => vec!["FLAGS", "INTERNALDATE", "RFC822.SIZE", "ENVELOPE"],
+            "FAST" => vec!["FLAGS", "INTERNALDATE", "RFC822.SIZE"],
+            "FULL" => vec!["FLAGS", "INTERNALDATE", "RFC822.SIZE", "ENVELOPE", "BODY"],
+            name => vec![name],
+        }
+        .into_iter()
+        .map(str::to_string)
+        .collect(),
+        _ => return None,
+    };
+    let mut items = names
+        .iter()
+        .map(|name| fetch_item(name))
+        .collect::<Option<Vec<_>>>()?;
+    if by_uid && !items.iter().any(|item| matches!(item, Item::Uid)) {
+        items.insert(0, Item::Uid);
+    }
+    Some(items)
+}
+
+fn fetch_item(name: &str) -> Option<Item> {
+    let name = name.to_ascii_uppercase();
+    let section = |name: &str, section: &str, peek| Item::Section {
+        name: name.to_string(),
+        section: section.to_string(),
+        peek,
+        partial: None,
+    };
+    Some(match name.as_str() {
+        "UID" => Item::Uid,
+        "FLAGS" => Item::Flags,
+        "INTERNALDATE" => Item::InternalDate,
+        "RFC822.SIZE" => Item::Size,
+        "ENVELOPE" => Item::Envelope,
+        "BODY" | "BODYSTRUCTURE" => Item::Structure(name),
+        "RFC822" => section("RFC822", "", false),
+        "RFC822.HEADER" => section("RFC822.HEADER", "HEADER", true),
+        "RFC822.TEXT" => section("RFC822.TEXT", "TEXT", false),
+        _ => {
+            let (peek, rest) = match name.strip_prefix("BODY.PEEK[") {
+                Some(rest) => (true, rest),
+                None => (false, name.strip_prefix("BODY[")?),
+            };
+            let (section, partial) = rest.split_once(']')?;
+            let partial = match partial {
+                "" => None,
+                partial => {
+                    let partial = partial.strip_prefix('<')?.strip_suffix('>')?;
+                    let (origin, count) = partial.split_once('.')?;
+                    Some((origin.parse().ok()?, count.parse().ok()?))
+                }
+            };
+            Item::Section {
+                name: format!("BODY[{}]", section),
+                section: section.to_string(),
+                peek,
+                partial,
+            }
+        }
+    })
+}
+
+/// The content with CRLF line endings, as IMAP has it
+fn crlf(content: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(content.len());
+    for (index, &byte) in content.iter().enumerate() {
+        if byte == b'\n' && (index == 0 || content[index - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+        converted.push(byte);
+    }
+    converted
+}
+
+/// A section of a message, like `1.2`, `2.MIME`, `TEXT` or `HEADER.FIELDS (SUBJECT)`, or
+/// nothing if the message does not have it
+fn section(content: &[u8], section: &str) -> Option<Vec<u8>> {
+    let (mut header, mut body) = mime::split_header(content);
+    // Whether the header and body are those of a message rather than of a part
+    let mut message = true;
+    let mut rest = section;
+    let mut numbered = false;
+    loop {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            break;
+        }
+        let number: usize = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        // The parts of an attached message are those of the message
+        if !message && content_type(header).0 == "message/rfc822" {
+            (header, body) = mime::split_header(body);
+            message = true;
+        }
+        let (media, parameters) = content_type(header);
+        if media.starts_with("multipart/") {
+            let boundary = mime::parameter(&parameters, "boundary")?;
+            let part = *mime::split_multipart(body, boundary).get(number.checked_sub(1)?)?;
+            (header, body) = mime::split_header(part);
+        } else if number != 1 || !message {
+            return None;
+        }
+        message = false;
+        numbered = true;
+        match rest.strip_prefix('.') {
+            Some(after) => rest = after,
+            None if rest.is_empty() => break,
+            None => return None,
+        }
+    }
+    if numbered && !rest.is_empty() && rest != "MIME" {
+        // HEADER and TEXT of a part are those of the message it is
+        if content_type(header).0 != "message/rfc822" {
+            return None;
+        }
+        (header, body) = mime::split_header(body);
+    }
+    let with_end = |header: &[u8]| [header, b"\r\n"].concat();
+    match rest {
+        "" if numbered => Some(body.to_vec()),
+        "" => Some(content.to_vec()),
+        "MIME" if numbered => Some(with_end(header)),
+        "HEADER" => Some(with_end(header)),
+        "TEXT" => Some(body.to_vec()),
+        rest => {
+            let (not, names) = match rest.strip_prefix("HEADER.FIELDS.NOT") {
+                Some(names) => (true, names),
+                None => (false, rest.strip_prefix("HEADER.FIELDS")?),
+            };
+            let names: Vec<&str> = names
+                .trim()
+                .strip_prefix('(')?
+                .strip_suffix(')')?
+                .split_whitespace()
+                .collect();
+            let lines: Vec<u8> = header_lines(header)
+                .into_iter()
+                .filter(|(name, _)| {
+                    names.iter().any(|wanted| wanted.eq_ignore_ascii_case(name)) != not
+                })
+                .flat_map(|(_, lines)| lines.to_vec())
+                .collect();
+            Some(with_end(&lines))
+        }
+    }
+}
+
+/// The media type of a part with its parameters, `text/plain` without a Content-Type
+fn content_type(header: &[u8]) -> (String, Vec<(String, String)>) {
+    let fields = mime::header_fields(header);
+    mime::parameters(mime::field(&fields, "content-type").unwrap_or("text/plain"))
+}
+
+/// The fields of a header as they are, each with its name and its lines
+fn header_lines(header: &[u8]) -> Vec<(String, &[u8])> {
+    let mut fields: Vec<(String, &[u8])> = Vec::new();
+    let mut start = 0;
+    for line in header.split_inclusive(|&b| b == b'\n') {
+        let end = start + line.len();
+        match fields.last_mut() {
+            Some((_, lines)) if line.starts_with(b" ") || line.starts_with(b"\t") => {
+                *lines = &header[end - line.len() - lines.len()..end];
+            }
+            _ => {
+                let name = line.split(|&b| b == b':').next().unwrap_or_default();
+                let name = String::from_utf8_lossy(name).trim().to_string();
+                fields.push((name, &header[start..end]));
+            }
+        }
+        start = end;
+    }
+    fields
+}
+
+/// The value of the first header field with a name, unfolded but not decoded
+fn raw_field(header: &[u8], name: &str) -> Option<String> {
+    let (_, lines) = header_lines(header)
+        .into_iter()
+        .find(|(field, _)| field.eq_ignore_ascii_case(name))?;
+    let lines = String::from_utf8_lossy(lines).replace(['\r', '\n'], "");
+    Some(lines.split_once(':')?.1.trim().to_string())
+}
+
+/// The ENVELOPE of a message, with the fields as they are
+fn envelope(header: &[u8]) -> Vec<u8> {
+    let text = |name: &str| nstring(raw_field(header, name).as_deref().map(str::as_bytes));
+    let from = raw_field(header, "from");
+    // Sender and Reply-To are the From field if the message does not have them
+    let addresses = |name: &str| match raw_field(header, name).or_else(|| {
+        ["sender", "reply-to"]
+            .contains(&name)
+            .then(|| from.clone())
+            .flatten()
+    }) {
+        Some(list) => addresses(&list),
+        None => b"NIL".to_vec(),
+    };
+    let mut envelope = b"(".to_vec();
+    envelope.extend(text("date"));
+    envelope.push(b' ');
+    envelope.extend(text("subject"));
+    for name in ["from", "sender", "reply-to", "to", "cc", "bcc"] {
+        envelope.push(b' ');
+        envelope.extend(addresses(name));
+    }
+    envelope.push(b' ');
+    envelope.extend(text("in-reply-to"));
+    envelope.push(b' ');
+    envelope.extend(text("message-id"));
+    envelope.push(b')');
+    envelope
+}
+
+/// An address list of the ENVELOPE, like `(("Name" NIL "user" "example.com"))`
+fn addresses(list: &str) -> Vec<u8> {
+    let mut addresses = Vec::new();
+    for address in split_addresses(list) {
+        let (name, address) = match address.rsplit_once('<') {
+            Some((name, address)) => (name.trim().trim_matches('"'), address.trim_end_matches('>')),
+            None => ("", address),
+        };
+        let (mailbox, host) = address
+            .trim()
+            .rsplit_once('@')
+            .unwrap_or((address.trim(), ""));
+        if mailbox.is_empty() {
+            continue;
+        }
+        addresses.push(b'(');
+        addresses.extend(nstring(
+            Some(name.as_bytes()).filter(|name| !name.is_empty()),
+        ));
+        addresses.extend(b" NIL ");
+        addresses.extend(string(mailbox.as_bytes()));
+        addresses.push(b' ');
+        addresses.extend(nstring(
+            Some(host.as_bytes()).filter(|host| !host.is_empty()),
+        ));
+        addresses.push(b')');
+    }
+    if addresses.is_empty() {
+        return b"NIL".to_vec();
+    }
+    [&b"("[..], &addresses, b")"].concat()
+}
+
+/// Split an address list at the commas that are not quoted or in angle brackets
+fn split_addresses(list: &str) -> Vec<&str> {
+    let (mut addresses, mut start) = (Vec::new(), 0);
+    let (mut quoted, mut bracketed) = (false, false);
+    for (index, c) in list.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '<' if !quoted => bracketed = true,
+            '>' if !quoted => bracketed = false,
+            ',' if !quoted && !bracketed => {
+                addresses.push(&list[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    addresses.push(&list[start..]);
+    addresses
+        .into_iter()
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .collect()
+}
+
+/// The BODYSTRUCTURE of a message or part, without extension data
+fn structure(header: &[u8], body: &[u8]) -> Vec<u8> {
+    let fields = mime::header_fields(header);
+    let (media, parameters) = content_type(header);
+    let (kind, subtype) = media.split_once('/').unwrap_or(("text", "plain"));
+    let upper = |value: &str| string(value.to_ascii_uppercase().as_bytes());
+    let mut structure = b"(".to_vec();
+    if kind == "multipart" {
+        if let Some(boundary) = mime::parameter(&parameters, "boundary") {
+            for part in mime::split_multipart(body, boundary) {
+                let (header, body) = mime::split_header(part);
+                structure.extend(self::structure(header, body));
+            }
+        }
+        structure.push(b' ');
+        structure.extend(upper(subtype));
+        structure.push(b')');
+        return structure;
+    }
+    structure.extend(upper(kind));
+    structure.push(b' ');
+    structure.extend(upper(subtype));
+    structure.push(b' ');
+    if parameters.is_empty() {
+        structure.extend(b"NIL");
+    } else {
+        structure.push(b'(');
+        for (index, (name, value)) in parameters.iter().enumerate() {
+            if index > 0 {
+                structure.push(b' ');
+            }
+            structure.extend(upper(name));
+            structure.push(b' ');
+            structure.extend(string(value.as_bytes()));
+        }
+        structure.push(b')');
+    }
+    let field = |name| nstring(mime::field(&fields, name).map(str::as_bytes));
+    structure.push(b' ');
+    structure.extend(field("content-id"));
+    structure.push(b' ');
+    structure.extend(field("content-description"));
+    structure.push(b' ');
+    structure.extend(upper(
+        mime::field(&fields, "content-transfer-encoding").unwrap_or("7bit"),
+    ));
+    structure.extend(format!(" {}", body.len()).as_bytes());
+    let lines = body.iter().filter(|&&b| b == b'\n').count();
+    if media == "message/rfc822" {
+        let (header, message_body) = mime::split_header(body);
+        structure.push(b' ');
+        structure.extend(envelope(header));
+        structure.push(b' ');
+        structure.extend(self::structure(header, message_body));
+        structure.extend(format!(" {}", lines).as_bytes());
+    } else if kind == "text" {
+        structure.extend(format!(" {}", lines).as_bytes());
+    }
+    structure.push(b')');
+    structure
+}
+
+/// A string as a quoted string if it can be one, and as a literal otherwise
+fn string(value: &[u8]) -> Vec<u8> {
+    if value.len() > 1000 || !value.iter().all(|&b| (0x20..0x7f).contains(&b)) {
+        return literal(value);
+    }
+    let mut quoted = b"\"".to_vec();
+    for &byte in value {
+        if byte == b'"' || byte == b'\\' {
+            quoted.push(b'\\');
+        }
+        quoted.push(byte);
+    }
+    quoted.push(b'"');
+    quoted
+}
+
+fn nstring(value: Option<&[u8]>) -> Vec<u8> {
+    value.map_or_else(|| b"NIL".to_vec(), string)
+}
+
+fn literal(value: &[u8]) -> Vec<u8> {
+    [format!("{{{}}}\r\n", value.len()).as_bytes(), value].concat()
+}
+
+/// The time a message was received like `14-Nov-2023 22:13:20 +0000`
+fn internal_date(received: u64) -> String {
+    let (year, month, day) = clock::civil_date(received / 86400);
+    let seconds = received % 86400;
+    format!(
+        "{:2}-{}-{} {:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+fn month_number(name: &str) -> Option<u64> {
+    let index = MONTHS
+        .iter()
+        .position(|month| month.eq_ignore_ascii_case(name))?;
+    Some(index as u64 + 1)
+}
+
+/// The day of a search date like `1-Feb-1994`
+fn search_date(date: &str) -> Option<u64> {
+    let mut parts = date.trim_matches('"').split('-');
+    let day = parts.next()?.parse().ok()?;
+    let month = month_number(parts.next()?)?;
+    let year = parts.next()?.parse().ok()?;
+    Some(clock::days_from_civil(year, month, day))
+}
+
+/// The day of the Date field like `Tue, 14 Nov 2023 22:13:20 +0000`, ignoring the time zone
+fn sent_day(header: &[u8]) -> Option<u64> {
+    let date = raw_field(header, "date")?;
+    let date = date.split_once(',').map_or(date.as_str(), |(_, date)| date);
+    let mut parts = date.split_whitespace();
+    let day = parts.next()?.parse().ok()?;
+    let month = month_number(parts.next()?)?;
+    let year = parts.next()?.parse().ok()?;
+    Some(clock::days_from_civil(year, month, day))
+}
+
+/// A search criterion
+enum Key {
+    All,
+    Not(Box<Key>),
+    Or(Box<Key>, Box<Key>),
+    And(Vec<Key>),
+    Seen(bool),
+    Numbers(Vec<(u32, u32)>),
+    Uids(Vec<(u32, u32)>),
+    /// A lower case term in a header field with a lower case name, decoded
+    Header(String, String),
+    Body(String),
+    /// A lower case term anywhere in the message
+    Text(String),
+    Larger(usize),
+    Smaller(usize),
+    /// A day, and whether it is the day of sending rather than of receiving
+    Before(u64, bool),
+    On(u64, bool),
+    Since(u64, bool),
+}
+
+/// Parse the next search criterion, with the last sequence number and UID for `*`
+fn search_key(tokens: &mut slice::Iter<Token>, last: (u32, u32)) -> Option<Key> {
+    let token = tokens.next()?;
+    if let Token::List(list) = token {
+        let mut inner = list.iter();
+        let mut keys = Vec::new();
+        while inner.len() > 0 {
+            keys.push(search_key(&mut inner, last)?);
+        }
+        return Some(Key::And(keys));
+    }
+    let name = token.text()?.to_ascii_uppercase();
+    let mut term = || Some(tokens.next()?.text()?.to_lowercase());
+    let never = || Key::Not(Box::new(Key::All));
+    Some(match name.as_str() {
+        "ALL" | "OLD" | "UNANSWERED" | "UNDELETED" | "UNDRAFT" | "UNFLAGGED" => Key::All,
+        "ANSWERED" | "DELETED" | "DRAFT" | "FLAGGED" | "NEW" | "RECENT" => never(),
+        "KEYWORD" => {
+            term()?;
+            never()
+        }
+        "UNKEYWORD" => {
+            term()?;
+            Key::All
+        }
+        "SEEN" => Key::Seen(true),
+        "UNSEEN" => Key::Seen(false),
+        "FROM" | "TO" | "CC" | "BCC" | "SUBJECT" => Key::Header(name.to_lowercase(), term()?),
+        "HEADER" => Key::Header(term()?, term()?),
+        "BODY" => Key::Body(term()?),
+        "TEXT" => Key::Text(term()?),
+        "LARGER" => Key::Larger(term()?.parse().ok()?),
+        "SMALLER" => Key::Smaller(term()?.parse().ok()?),
+        "UID" => Key::Uids(sequence_set(&term()?, last.1)?),
+        "BEFORE" => Key::Before(search_date(&term()?)?, false),
+        "ON" => Key::On(search_date(&term()?)?, false),
+        "SINCE" => Key::Since(search_date(&term()?)?, false),
+        "SENTBEFORE" => Key::Before(search_date(&term()?)?, true),
+        "SENTON" => Key::On(search_date(&term()?)?, true),
+        "SENTSINCE" => Key::Since(search_date(&term()?)?, true),
+        "NOT" => Key::Not(Box::new(search_key(tokens, last)?)),
+        "OR" => {
+            let first = search_key(tokens, last)?;
+            Key::Or(Box::new(first), Box::new(search_key(tokens, last)?))
+        }
+        set => Key::Numbers(sequence_set(set, last.0)?),
+    })
+}
+
+/// A message that a search looks at, with its content once a criterion needs it
+struct Candidate<'a> {
+    number: u32,
+    uid: u32,
+    entry: &'a Entry,
+    store: &'a dyn MessageStore,
+    content: Option<Vec<u8>>,
+}
+
+impl Candidate<'_> {
+    fn content(&mut self) -> io::Result<&[u8]> {
+        if self.content.is_none() {
+            // Removed since, so there is nothing to find in it
+            let content = self.store.content(&self.entry.id)?.unwrap_or_default();
+            self.content = Some(crlf(&content));
+        }
+        Ok(self.content.as_deref().unwrap_or_default())
+    }
+
+    fn matches(&mut self, key: &Key) -> io::Result<bool> {
+        let contains =
+            |text: &[u8], term: &str| String::from_utf8_lossy(text).to_lowercase().contains(term);
+        let received = self.entry.received / 86400;
+        let mut day = |sent: bool| -> io::Result<Option<u64>> {
+            if !sent {
+                return Ok(Some(received));
+            }
+            Ok(sent_day(mime::split_header(self.content()?).0))
+        };
+        Ok(match key {
+            Key::All => true,
+            Key::Not(key) => !self.matches(key)?,
+            Key::Or(first, second) => self.matches(first)? || self.matches(second)?,
+            Key::And(keys) => {
+                for key in keys {
+                    if !self.matches(key)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            Key::Seen(wanted) => seen(self.entry) == *wanted,
+            Key::Numbers(ranges) => in_ranges(ranges, self.number),
+            Key::Uids(ranges) => in_ranges(ranges, self.uid),
+            Key::Header(name, term) => {
+                let fields = mime::header_fields(mime::split_header(self.content()?).0);
+                fields.iter().any(|(field, value)| {
+                    field.eq_ignore_ascii_case(name) && value.to_lowercase().contains(term)
+                })
+            }
+            Key::Body(term) => contains(mime::split_header(self.content()?).1, term),
+            Key::Text(term) => contains(self.content()?, term),
+            Key::Larger(size) => self.content()?.len() > *size,
+            Key::Smaller(size) => self.content()?.len() < *size,
+            Key::Before(wanted, sent) => day(*sent)?.is_some_and(|day| day < *wanted),
+            Key::On(wanted, sent) => day(*sent)?.is_some_and(|day| day == *wanted),
+            Key::Since(wanted, sent) => day(*sent)?.is_some_and(|day| day >= *wanted),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Memory;
+
+    const MULTIPART: &str = "From: \"App\" <app@example.com>\r\n\
+                             To: ci+job-42@example.com, Other <other@example.com>\r\n\
+                             Subject: Welcome\r\n\
+                             Date: Tue, 14 Nov 2023 22:13:20 +0000\r\n\
+                             Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+                             \r\n\
+                             --b\r\n\
+                             Content-Type: text/plain; charset=utf-8\r\n\
+                             \r\n\
+                             Hello\r\n\
+                             --b\r\n\
+                             Content-Type: application/pdf\r\n\
+                             Content-Transfer-Encoding: base64\r\n\
+                             \r\n\
+                             JVBERg==\r\n\
+                             --b--\r\n";
+
+    #[test]
+    fn fetch_search_and_flag_messages() {
+        // Given
+        let store = Memory::default();
+        for (id, to, content) in [
+            ("1", "<ci+job-42@example.com>", MULTIPART),
+            ("2", "<other@example.com>", "Subject: Other\n\nBye\n"),
+        ] {
+            let entry = Entry {
+                id: id.to_string(),
+                received: 1_700_000_000,
+                size: content.len(),
+                summary: serde_json::json!({ "id": id, "to": [to] }),
+            };
+            store.add(entry, content.as_bytes()).unwrap();
+        }
+        let credentials = Credentials {
+            user: "tester".to_string(),
+            password: "secret".to_string(),
+        };
+        let uids = Uids::new(1_700_000_000);
+        let request = "a1 LOGIN tester wrong\r\n\
+                       a2 LOGIN tester {6}\r\nsecret\r\n\
+                       a3 LIST \"\" *\r\n\
+                       a4 SELECT +JOB-42\r\n\
+                       a5 FETCH 1 (UID FLAGS BODY.PEEK[HEADER.FIELDS (SUBJECT)] BODY[2]<0.4>)\r\n\
+                       a6 SELECT INBOX\r\n\
+                       a7 UID SEARCH UNSEEN SUBJECT \"other\"\r\n\
+                       a8 SEARCH SEEN SENTON 14-Nov-2023\r\n\
+                       a9 STORE 1:* -FLAGS (\\Seen)\r\n\
+                       a10 FETCH 2 RFC822.SIZE\r\n\
+                       a11 LOGOUT\r\n";
+        let mut session = Session {
+            store: &store,
+            credentials: Some(&credentials),
+            uids: &uids,
+            logged_in: false,
+            selected: None,
+        };
+        let mut output = Vec::new();
+
+        // When
+        session.run(&mut request.as_bytes(), &mut output).unwrap();
+
+        // Then
+        let output = String::from_utf8(output).unwrap();
+        let expected = [
+            "a1 NO [AUTHENTICATIONFAILED] Invalid user or password\r\n",
+            "+ Ready for literal\r\na2 OK LOGIN completed\r\n",
+            "* LIST (\\HasNoChildren) NIL \"INBOX\"\r\n\
+             * LIST (\\HasNoChildren) NIL \"ci@example.com\"\r\n\
+             * LIST (\\HasNoChildren) NIL \"other@example.com\"\r\n\
+             * LIST (\\HasNoChildren) NIL \"+job-42\"\r\na3 OK",
+            "* 1 EXISTS\r\n",
+            "* OK [UIDVALIDITY 1700000000] UIDs are valid\r\n",
+            "* 1 FETCH (UID 1 FLAGS (\\Seen) BODY[HEADER.FIELDS (SUBJECT)] {20}\r\n\
+             Subject: Welcome\r\n\r\n BODY[2]<0> {4}\r\nJVBE)\r\n",
+            "* SEARCH 2\r\na7 OK",
+            "* SEARCH 1\r\na8 OK",
+            "* 1 FETCH (FLAGS ())\r\n* 2 FETCH (FLAGS ())\r\na9 OK",
+            "* 2 FETCH (RFC822.SIZE 23)\r\n",
+            "* BYE Logging out\r\na11 OK LOGOUT completed\r\n",
+        ];
+        for expected in expected {
+            assert!(
+                output.contains(expected),
+                "{:?} not in {}",
+                expected,
+                output
+            );
+        }
+        assert!(store.entries().unwrap().iter().all(|entry| !seen(entry)));
+    }
+
+    #[test]
+    fn describe_message_structure() {
+        // Given
+        let (header, body) = mime::split_header(MULTIPART.as_bytes());
+
+        // When
+        let structure = String::from_utf8(structure(header, body)).unwrap();
+        let envelope = String::from_utf8(envelope(header)).unwrap();
+
+        // Then
+        assert_eq!(
+            structure,
+            "((\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" 5 0)\
+             (\"APPLICATION\" \"PDF\" NIL NIL NIL \"BASE64\" 8) \"MIXED\")"
+        );
+        assert_eq!(
+            envelope,
+            "(\"Tue, 14 Nov 2023 22:13:20 +0000\" \"Welcome\" \
+             ((\"App\" NIL \"app\" \"example.com\")) ((\"App\" NIL \"app\" \"example.com\")) \
+             ((\"App\" NIL \"app\" \"example.com\")) \
+             ((NIL NIL \"ci+job-42\" \"example.com\")(\"Other\" NIL \"other\" \"example.com\")) \
+             NIL NIL NIL NIL)"
+        );
+        let section = |name| section(MULTIPART.as_bytes(), name).map(String::from_utf8);
+        assert_eq!(section("1"), Some(Ok("Hello".to_string())));
+        assert_eq!(
+            section("2.MIME"),
+            Some(Ok(
+                "Content-Type: application/pdf\r\nContent-Transfer-Encoding: base64\r\n\r\n"
+                    .to_string()
+            ))
+        );
+        assert_eq!(section("3"), None);
+        assert_eq!(internal_date(1_700_000_000), "14-Nov-2023 22:13:20 +0000");
+    }
+}
@@ -0,0 +1,558 @@
+//! Publishing received messages to a Kafka topic, e.g. to test a pipeline that consumes mail
+//! events.
+//!
+//! This is a minimal producer for the Kafka protocol: it looks up the partition leaders with a
+//! metadata request and sends every message as a record batch of one record with a produce
+//! request, acknowledged by the leader. The key of a record is the UUID of the message, and
+//! partitions are chosen by key like the default partitioner of the Java client. There is no
+//! TLS, SASL or compression.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::clock::Clock;
+use crate::Session;
+
+const CLIENT_ID: &str = "rust-smtp-server";
+
+const PRODUCE_API_KEY: i16 = 0;
+const PRODUCE_API_VERSION: i16 = 3;
+const METADATA_API_KEY: i16 = 1;
+const METADATA_API_VERSION: i16 = 4;
+
+/// Error code of a topic or partition whose leader is being elected, e.g. right after the
+/// topic was created
+const LEADER_NOT_AVAILABLE: i16 = 5;
+
+/// How often and how long to wait for a partition leader to be elected
+const METADATA_ATTEMPTS: u32 = 5;
+const METADATA_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// How long the leader waits to write a record before failing the request
+const PRODUCE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a broker to answer, which includes writing a record
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// What is published for a message
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// A JSON object with the envelope, like the lines of `--print-format jsonl`
+    Summary,
+    /// The content as received
+    Raw,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 2] = ["summary", "raw"];
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "summary" => Some(Format::Summary),
+            "raw" => Some(Format::Raw),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Summary => "summary",
+            Format::Raw => "raw",
+        }
+    }
+}
+
+/// Where and what to publish
+#[derive(Clone)]
+pub struct Sink {
+    /// Addresses of brokers to look up the partition leaders with, as host:port
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub format: Format,
+    /// Source of the record timestamps
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Publish the messages of every session received until the channel closes.
+/// Messages that fail to publish are logged and dropped.
+pub fn run(sink: Sink, server: Option<String>, sessions: Receiver<Arc<Session>>) {
+    let mut producer = Producer::new(&sink);
+    for session in sessions {
+        let connection = &session.connection;
+        let (Some(sender_domain), Some(messages)) =
+            (connection.get_sender_domain(), connection.get_messages())
+        else {
+            continue;
+        };
+        for message in messages {
+            let value = match sink.format {
+                Format::Summary => {
+                    crate::message_json(&session, sender_domain, message, server.as_deref())
+                        .to_string()
+                        .into_bytes()
+                }
+                Format::Raw => message.get_content().to_vec(),
+            };
+            if let Err(e) = producer.send(message.get_id().as_bytes(), &value) {
+                tracing::error!("Publishing to Kafka topic {} failed: {}", sink.topic, e);
+                // The cluster may have changed, so everything is looked up again next time
+                producer = Producer::new(&sink);
+            }
+        }
+    }
+}
+
+/// A connection to a broker
+struct Connection {
+    stream: TcpStream,
+    correlation_id: i32,
+}
+
+impl Connection {
+    fn open(address: &str) -> Result<Connection, Error> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(Connection {
+            stream,
+            correlation_id: 0,
+        })
+    }
+
+    /// Send a request and read the body of its response
+    fn request(&mut self, api_key: i16, api_version: i16, body: &[u8]) -> Result<Vec<u8>, Error> {
+        self.correlation_id += 1;
+        let mut request = Encoder::default();
+        request.i16(api_key);
+        request.i16(api_version);
+        request.i32(self.correlation_id);
+        request.string(CLIENT_ID);
+        request.0.extend_from_slice(body);
+
+        let mut framed = Encoder::default();
+        framed.bytes(&request.0);
+        self.stream.write_all(&framed.0)?;
+
+        let mut size = [0; 4];
+        self.stream.read_exact(&mut size)?;
+        let mut response = vec![0; i32::from_be_bytes(size).max(0) as usize];
+        self.stream.read_exact(&mut response)?;
+        let mut decoder = Decoder(&response);
+        if decoder.i32()? != self.correlation_id {
+            return Err(invalid("response to another request"));
+        }
+        Ok(decoder.0.to_vec())
+    }
+}
+
+/// Produces records to the partitions of one topic
+struct Producer<'a> {
+    sink: &'a Sink,
+    /// Address of the leader of every partition, once looked up
+    leaders: Vec<String>,
+    /// Open connections by address
+    connections: Vec<(String, Connection)>,
+}
+
+impl<'a> Producer<'a> {
+    fn new(sink: &'a Sink) -> Producer<'a> {
+        Producer {
+            sink,
+            leaders: Vec::new(),
+            connections: Vec::new(),
+        }
+    }
+
+    /// Produce a record to the partition of its key and wait for the leader's acknowledgement
+    fn send(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        if self.leaders.is_empty() {
+            self.leaders = self.lookup_leaders()?;
+        }
+        let partition = partition(key, self.leaders.len());
+        let leader = self.leaders[partition].clone();
+
+        let mut body = Encoder::default();
+        // No transactional ID
+        body.i16(-1);
+        // Acknowledged by the leader
+        body.i16(1);
+        body.i32(PRODUCE_TIMEOUT.as_millis() as i32);
+        body.i32(1);
+        body.string(&self.sink.topic);
+        body.i32(1);
+        body.i32(partition as i32);
+        let timestamp = self
+            .sink
+            .clock
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as i64);
+        body.bytes(&record_batch(key, value, timestamp));
+
+        let response =
+            self.connection(&leader)?
+                .request(PRODUCE_API_KEY, PRODUCE_API_VERSION, &body.0)?;
+        let mut response = Decoder(&response);
+        for _ in 0..response.i32()? {
+            response.string()?;
+            for _ in 0..response.i32()? {
+                let index = response.i32()?;
+                let error_code = response.i16()?;
+                // Base offset and log append time
+                response.i64()?;
+                response.i64()?;
+                if error_code != 0 {
+                    return Err(Error::other(format!(
+                        "partition {} failed with error code {}",
+                        index, error_code
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the connection to a broker, opening it if needed
+    fn connection(&mut self, address: &str) -> Result<&mut Connection, Error> {
+        let index = match self
+            .connections
+            .iter()
+            .position(|(open, _)| open == address)
+        {
+            Some(index) => index,
+            None => {
+                self.connections
+                    .push((address.to_string(), Connection::open(address)?));
+                self.connections.len() - 1
+            }
+        };
+        Ok(&mut self.connections[index].1)
+    }
+
+    /// Look up the addresses of the partition leaders of the topic with the first broker that
+    /// answers, waiting for leaders that are being elected
+    fn lookup_leaders(&mut self) -> Result<Vec<String>, Error> {
+        let mut body = Encoder::default();
+        body.i32(1);
+        body.string(&self.sink.topic);
+        // Allow the broker to create the topic
+        body.i8(1);
+
+        let sink = self.sink;
+        let mut last_error = invalid("no brokers");
+        for attempt in 1..=METADATA_ATTEMPTS {
+            for broker in &sink.brokers {
+                let response = self.connection(broker).and_then(|connection| {
+                    connection.request(METADATA_API_KEY, METADATA_API_VERSION, &body.0)
+                });
+                match response.and_then(|response| leaders(&response)) {
+                    Ok(Some(leaders)) => return Ok(leaders),
+                    Ok(None) => {
+                        last_error = Error::other("partition leaders are not available yet");
+                        break;
+                    }
+                    Err(e) => {
+                        self.connections.retain(|(open, _)| open != broker);
+                        last_error = e;
+                    }
+                }
+            }
+            if attempt < METADATA_ATTEMPTS {
+                thread::sleep(METADATA_RETRY_DELAY);
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Get the leader addresses of the partitions of the topic in a metadata response, by partition
+/// index, or none if any leader is not available yet
+fn leaders(response: &[u8]) -> Result<Option<Vec<String>>, Error> {
+    let mut response = Decoder(response);
+    // Throttle time
+    response.i32()?;
+    let mut brokers = Vec::new();
+    for _ in 0..response.i32()? {
+        let node_id = response.i32()?;
+        let host = response.string()?;
+        let port = response.i32()?;
+        // Rack
+        response.nullable_string()?;
+        brokers.push((node_id, format!("{}:{}", host, port)));
+    }
+    // Cluster and controller
+    response.nullable_string()?;
+    response.i32()?;
+
+    let topics = response.i32()?;
+    if topics != 1 {
+        return Err(invalid("metadata for another number of topics"));
+    }
+    let error_code = response.i16()?;
+    let name = response.string()?;
+    match error_code {
+        0 => {}
+        LEADER_NOT_AVAILABLE => return Ok(None),
+        _ => {
+            return Err(Error::other(format!(
+                "topic {} failed with error code {}",
+                name, error_code
+            )))
+        }
+    }
+    // Internal
+    response.i8()?;
+    let mut partitions = Vec::new();
+    for _ in 0..response.i32()? {
+        let error_code = response.i16()?;
+        let index = response.i32()?;
+        let leader = response.i32()?;
+        // Replicas and in-sync replicas
+        for _ in 0..2 {
+            for _ in 0..response.i32()? {
+                response.i32()?;
+            }
+        }
+        if error_code == LEADER_NOT_AVAILABLE || leader < 0 {
+            return Ok(None);
+        }
+        let Some((_, address)) = brokers.iter().find(|(node_id, _)| *node_id == leader) else {
+            return Err(invalid("partition leader is not a known broker"));
+        };
+        partitions.push((index, address.clone()));
+    }
+    if partitions.is_empty() {
+        return Ok(None);
+    }
+    partitions.sort();
+    Ok(Some(
+        partitions.into_iter().map(|(_, address)| address).collect(),
+    ))
+}
+
+/// Choose the partition for a key like the default partitioner of the Java client
+fn partition(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fff_ffff) as usize % partitions
+}
+
+/// The 32 bit MurmurHash2 with the seed of the Java client
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let mut h = 0x9747_b28c ^ data.len() as u32;
+    let chunks = data.chunks_exact(4);
+    let rest = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    if !rest.is_empty() {
+        for (i, &b) in rest.iter().enumerate().rev() {
+            h ^= (b as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+/// Make a record batch in the format of Kafka 0.11 and later with one record without headers,
+/// with a timestamp in milliseconds since the epoch
+fn record_batch(key: &[u8], value: &[u8], timestamp: i64) -> Vec<u8> {
+    let mut record = Encoder::default();
+    // Attributes, timestamp delta and offset delta
+    record.i8(0);
+    record.varint(0);
+    record.varint(0);
+    record.varint(key.len() as i64);
+    record.0.extend_from_slice(key);
+    record.varint(value.len() as i64);
+    record.0.extend_from_slice(value);
+    // Headers
+    record.varint(0);
+
+    // The part of the batch covered by the checksum
+    let mut checked = Encoder::default();
+    // Attributes and last offset delta
+    checked.i16(0);
+    checked.i32(0);
+    // First and maximum timestamp
+    checked.i64(timestamp);
+    checked.i64(timestamp);
+    // No producer ID, epoch or sequence
+    checked.i64(-1);
+    checked.i16(-1);
+    checked.i32(-1);
+    checked.i32(1);
+    checked.varint(record.0.len() as i64);
+    checked.0.extend_from_slice(&record.0);
+
+    let mut batch = Encoder::default();
+    // Base offset, assigned by the broker
+    batch.i64(0);
+    // Length of the rest of the batch: leader epoch, magic, checksum and the checked part
+    batch.i32(4 + 1 + 4 + checked.0.len() as i32);
+    batch.i32(-1);
+    batch.i8(2);
+    batch.0.extend_from_slice(&crc32c(&checked.0).to_be_bytes());
+    batch.0.extend_from_slice(&checked.0);
+    batch.0
+}
+
+/// CRC-32C (Castagnoli) checksum as used in record batches
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid Kafka response: {}", message),
+    )
+}
+
+/// Writes values in the big endian encoding of the Kafka protocol
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn i8(&mut self, value: i8) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn i16(&mut self, value: i16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.i16(value.len() as i16);
+        self.0.extend_from_slice(value.as_bytes());
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.i32(value.len() as i32);
+        self.0.extend_from_slice(value);
+    }
+
+    /// Write a zigzag encoded variable length integer, as used within record batches
+    fn varint(&mut self, value: i64) {
+        let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+        while zigzag >= 0x80 {
+            self.0.push((zigzag as u8) | 0x80);
+            zigzag >>= 7;
+        }
+        self.0.push(zigzag as u8);
+    }
+}
+
+/// Reads values in the big endian encoding of the Kafka protocol
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        if self.0.len() < N {
+            return Err(invalid("too short"));
+        }
+        let (value, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(value.try_into().unwrap())
+    }
+
+    fn i8(&mut self) -> Result<i8, Error> {
+        self.take().map(i8::from_be_bytes)
+    }
+
+    fn i16(&mut self) -> Result<i16, Error> {
+        self.take().map(i16::from_be_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        self.take().map(i32::from_be_bytes)
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        self.take().map(i64::from_be_bytes)
+    }
+
+    fn nullable_string(&mut self) -> Result<Option<String>, Error> {
+        let length = self.i16()?;
+        if length < 0 {
+            return Ok(None);
+        }
+        let length = length as usize;
+        if self.0.len() < length {
+            return Err(invalid("too short"));
+        }
+        let (value, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(Some(String::from_utf8_lossy(value).into_owned()))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.nullable_string()?
+            .ok_or_else(|| invalid("missing string"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_like_the_java_client() {
+        // Values from the tests of the Java client
+        assert_eq!(murmur2(b"21") as i32, -973932308);
+        assert_eq!(murmur2(b"foobar") as i32, -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string") as i32, -985981536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string") as i32, -1486304829);
+        assert_eq!(murmur2(b"abc") as i32, 479470107);
+    }
+
+    #[test]
+    fn encode_record_batch() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+
+        let batch = record_batch(b"key", b"value", 1_700_000_000_000);
+        let mut decoder = Decoder(&batch);
+        assert_eq!(decoder.i64().unwrap(), 0);
+        assert_eq!(decoder.i32().unwrap() as usize, batch.len() - 12);
+        assert_eq!(decoder.i32().unwrap(), -1);
+        assert_eq!(decoder.i8().unwrap(), 2);
+        assert_eq!(
+            decoder.take::<4>().unwrap(),
+            crc32c(&batch[21..]).to_be_bytes()
+        );
+        // The record: length, attributes, deltas, key, value and headers
+        assert!(batch.ends_with(b"\x1c\x00\x00\x00\x06key\x0avalue\x00"));
+    }
+}
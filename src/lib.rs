@@ -0,0 +1,3293 @@
+//! A simple SMTP server that prints, forwards or keeps the messages it receives.
+//!
+//! The binary is a command line over [`run_cli`]. [`SmtpServer`] runs a server inside another
+//! program instead, e.g. in the integration tests of an application that sends mail.
+
+extern crate clap;
+#[cfg(unix)]
+extern crate libc;
+extern crate num_cpus;
+extern crate threadpool;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::io::{self, BufRead, BufReader, ErrorKind, LineWriter, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use threadpool::ThreadPool;
+
+use broadcast::Broadcaster;
+pub use embedded::{Builder, Handle, SmtpServer};
+pub use error::Error;
+pub use store::{Entry, MessageStore};
+
+mod amqp;
+mod auth;
+mod broadcast;
+mod chaos;
+mod check;
+mod client;
+mod clock;
+mod completions;
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod data;
+mod dkim;
+mod dns;
+mod embedded;
+mod error;
+mod exec;
+#[cfg(unix)]
+mod handoff;
+mod http;
+mod imap;
+mod kafka;
+mod limits;
+mod loadgen;
+mod logging;
+mod mail;
+mod mime;
+mod mqtt;
+mod nats;
+mod notify;
+mod pop3;
+mod proxy;
+mod queue;
+mod relay;
+mod replay;
+mod rewrite;
+mod rules;
+mod script;
+mod send;
+#[cfg(unix)]
+mod signals;
+mod smtp;
+mod spf;
+mod store;
+#[cfg(unix)]
+mod systemd;
+mod tempfail;
+mod tls;
+mod verify;
+mod web;
+mod webhook;
+#[cfg(windows)]
+mod winservice;
+mod zip;
+
+/// What the program was asked to do
+enum Command {
+    /// Run the main server followed by the virtual servers
+    Serve(Vec<Config>),
+    Check(Vec<Config>),
+    Queue(Vec<Config>),
+    Messages(Vec<Config>, store::Action),
+    Loadgen(loadgen::Options),
+    Send(send::Options),
+    Replay(replay::Options),
+    Mail(mail::Options),
+    Completions(completions::Target),
+}
+
+/// Server settings
+struct Config {
+    /// Name of a virtual server, none for the main server
+    name: Option<String>,
+    bind_addresses: Vec<String>,
+    socket_paths: Vec<String>,
+    concurrency: usize,
+    buffer_size: usize,
+    max_message_size: Option<usize>,
+    print: Print,
+    print_format: PrintFormat,
+    /// Whether sessions keep a transcript of their commands and replies
+    transcript: bool,
+    /// Whether clients speak LMTP rather than SMTP
+    lmtp: bool,
+    /// Whether connections start with a PROXY protocol header
+    proxy_protocol: bool,
+    /// How long a client may keep a session waiting before it is closed
+    idle_timeout: Duration,
+    /// Upstream server to relay received messages to
+    relay: Option<relay::Relay>,
+    /// Kafka topic to publish received messages to
+    kafka: Option<kafka::Sink>,
+    /// NATS subject to publish received messages to
+    nats: Option<nats::Sink>,
+    /// AMQP exchange to publish received messages to
+    amqp: Option<amqp::Sink>,
+    /// MQTT topic to publish notifications of received messages under
+    mqtt: Option<mqtt::Sink>,
+    /// Command to run for every received message
+    exec: Option<exec::Hook>,
+    /// HTTP endpoint to post received messages to
+    webhook: Option<webhook::Webhook>,
+    /// Lua script that decides about recipients and messages
+    script: Option<Arc<script::Script>>,
+    /// Canned replies to commands
+    rules: Option<Arc<rules::Rules>>,
+    /// Certificate and key to offer STARTTLS with
+    tls: Option<Arc<tls::Acceptor>>,
+    /// The only credentials clients may log in with, instead of any
+    auth: Option<Arc<auth::Credentials>>,
+    /// Whether clients have to log in before they can send mail
+    auth_required: bool,
+    /// Delivery attempts of messages, the first of which fail temporarily
+    tempfail: Option<Arc<tempfail::Attempts>>,
+    /// Failures to inject into sessions at random
+    chaos: Option<Arc<chaos::Chaos>>,
+    /// Limits on connections, commands and recipients of a client
+    limits: Option<Arc<limits::Limits>>,
+    /// SPF and DKIM checks of received messages
+    verify: Option<Arc<verify::Verifier>>,
+    /// Chat webhooks to post summaries of received messages to
+    notifications: Option<notify::Notifications>,
+    /// Directory to write a recording of every session to
+    record: Option<PathBuf>,
+    /// Where received messages are kept
+    store: Option<Arc<dyn store::MessageStore>>,
+    /// Where and how to serve the web UI for browsing the kept messages
+    web: Option<Arc<web::Web>>,
+    /// Address to serve the kept messages over POP3 on
+    pop3: Option<String>,
+    /// Address to serve the kept messages over IMAP on
+    imap: Option<String>,
+    /// The server's notion of the current time
+    clock: Arc<dyn clock::Clock>,
+    /// File to write the bound addresses to
+    port_file: Option<String>,
+    /// Inherited file descriptor to write the bound addresses to
+    ready_fd: Option<i32>,
+    daemon: bool,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pid_file: Option<String>,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    log_file: Option<String>,
+    logging: logging::Logging,
+    /// How long active sessions may take to finish when the server is told to stop
+    #[cfg_attr(not(unix), allow(dead_code))]
+    shutdown_timeout: Duration,
+    #[cfg(windows)]
+    service_command: Option<winservice::Command>,
+}
+
+/// How much of each received message is printed on stdout
+#[derive(Clone, Copy, PartialEq)]
+enum Print {
+    None,
+    /// One line per message
+    Summary,
+    /// Envelope and content
+    Full,
+    /// Envelope and the decoded headers, bodies and attachments of the content
+    Parsed,
+}
+
+impl Print {
+    const NAMES: [&'static str; 4] = ["none", "summary", "full", "parsed"];
+
+    fn from_name(name: &str) -> Option<Print> {
+        match name {
+            "none" => Some(Print::None),
+            "summary" => Some(Print::Summary),
+            "full" => Some(Print::Full),
+            "parsed" => Some(Print::Parsed),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Print::None => "none",
+            Print::Summary => "summary",
+            Print::Full => "full",
+            Print::Parsed => "parsed",
+        }
+    }
+}
+
+/// How received messages are printed on stdout
+#[derive(Clone, Copy, PartialEq)]
+enum PrintFormat {
+    /// For people
+    Text,
+    /// One JSON object per line and message
+    Jsonl,
+}
+
+impl PrintFormat {
+    const NAMES: [&'static str; 2] = ["text", "jsonl"];
+
+    fn from_name(name: &str) -> Option<PrintFormat> {
+        match name {
+            "text" => Some(PrintFormat::Text),
+            "jsonl" => Some(PrintFormat::Jsonl),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PrintFormat::Text => "text",
+            PrintFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// A client connection
+trait Stream: Read + Write + Send + Sized + 'static {
+    /// Describe the address of the client
+    fn peer_address(&self) -> String;
+
+    /// The IP address of the client, if it has one
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+
+    /// Make reads and writes fail when the client keeps them waiting for longer than the timeout
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn peer_address(&self) -> String {
+        match self.peer_addr() {
+            // IPv4 clients of a dual stack listener show up as IPv4-mapped IPv6 addresses
+            Ok(SocketAddr::V6(address)) => match address.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(IpAddr::V4(ip), address.port()).to_string(),
+                None => address.to_string(),
+            },
+            Ok(address) => address.to_string(),
+            Err(e) => format!("unknown ({})", e),
+        }
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        match self.peer_addr().ok()?.ip() {
+            IpAddr::V6(ip) => Some(ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4)),
+            ip => Some(ip),
+        }
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn peer_address(&self) -> String {
+        // Clients of unix domain sockets are usually unnamed, so describe the socket instead
+        match self.local_addr() {
+            Ok(address) => match address.as_pathname() {
+                Some(path) => format!("unix:{}", path.display()),
+                None => "unix".to_string(),
+            },
+            Err(e) => format!("unknown ({})", e),
+        }
+    }
+
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+}
+
+/// Validate that a command line argument is a number of the given type
+fn validate_number<T>(s: String) -> Result<(), String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    s.parse::<T>().and(Ok(())).map_err(|e| e.to_string())
+}
+
+/// Validate that a command line argument is a positive number
+fn validate_positive(s: String) -> Result<(), String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Validate that a command line argument is a probability from 0 to 1
+fn validate_probability(s: String) -> Result<(), String> {
+    match s.parse::<f64>() {
+        Ok(p) if (0.0..=1.0).contains(&p) => Ok(()),
+        Ok(_) => Err("must be from 0 to 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Validate that a command line argument is a domain mapping such as `customer.com=test.example`
+fn validate_domain_mapping(s: String) -> Result<(), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(()),
+        _ => Err("must be a domain and its replacement as domain=replacement".to_string()),
+    }
+}
+
+/// Validate that a command line argument is a relay route such as `example.com=mx.example.com:25`
+fn validate_route(s: String) -> Result<(), String> {
+    match s.split_once('=') {
+        Some((domain, address)) if !domain.is_empty() && address.contains(':') => Ok(()),
+        _ => Err("must be a domain and its upstream server as domain=host:port".to_string()),
+    }
+}
+
+/// Validate that a command line argument is a token for an inbox such as `s3cret=+team-a`
+fn validate_inbox_token(s: String) -> Result<(), String> {
+    match s.split_once('=') {
+        Some((token, inbox)) if !token.is_empty() && !inbox.is_empty() => Ok(()),
+        _ => Err("must be a token and the inbox it is for as token=inbox".to_string()),
+    }
+}
+
+/// Validate that a command line argument is an MQTT topic name without wildcards
+fn validate_topic(s: String) -> Result<(), String> {
+    if s.is_empty() || s.contains(['+', '#']) {
+        Err("must be a topic name without wildcards".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Validate that a command line argument is an http or https URL
+fn validate_url(s: String) -> Result<(), String> {
+    http::Url::parse(&s).map(|_| ())
+}
+
+/// Combine a host and a port into a bind address, putting IPv6 addresses in brackets
+fn join_host_port(host: &str, port: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Name of the subcommand that runs the server, which is also run without a subcommand
+const SERVE_SUBCOMMAND_NAME: &str = "serve";
+
+/// Names of all subcommands
+const SUBCOMMAND_NAMES: [&str; 9] = [
+    SERVE_SUBCOMMAND_NAME,
+    check::SUBCOMMAND_NAME,
+    queue::SUBCOMMAND_NAME,
+    store::SUBCOMMAND_NAME,
+    loadgen::SUBCOMMAND_NAME,
+    send::SUBCOMMAND_NAME,
+    replay::SUBCOMMAND_NAME,
+    mail::SUBCOMMAND_NAME,
+    completions::SUBCOMMAND_NAME,
+];
+
+const CONFIG_ARG_NAME: &str = "config";
+const PROFILE_ARG_NAME: &str = "profile";
+const BIND_HOST_ARG_NAME: &str = "host";
+const BIND_PORT_ARG_NAME: &str = "smtp-port";
+const LISTEN_ARG_NAME: &str = "listen";
+const SOCKET_ARG_NAME: &str = "socket";
+const CONCURRENCY_ARG_NAME: &str = "concurrency";
+const BUFFER_SIZE_ARG_NAME: &str = "buffer-size";
+const MAX_MESSAGE_SIZE_ARG_NAME: &str = "max-message-size";
+const MAX_CONNECTIONS_PER_IP_ARG_NAME: &str = "max-connections-per-ip";
+const MAX_COMMANDS_PER_SECOND_ARG_NAME: &str = "max-commands-per-second";
+const MAX_RECIPIENTS_ARG_NAME: &str = "max-recipients";
+const PRINT_ARG_NAME: &str = "print";
+const QUIET_ARG_NAME: &str = "quiet";
+const PRINT_FORMAT_ARG_NAME: &str = "print-format";
+const DAEMON_ARG_NAME: &str = "daemon";
+const PID_FILE_ARG_NAME: &str = "pid-file";
+const SHUTDOWN_TIMEOUT_ARG_NAME: &str = "shutdown-timeout";
+const LOG_FILE_ARG_NAME: &str = "log-file";
+const LOG_LEVEL_ARG_NAME: &str = "log-level";
+const LOG_FORMAT_ARG_NAME: &str = "log-format";
+const TRANSCRIPT_ARG_NAME: &str = "transcript";
+const LMTP_ARG_NAME: &str = "lmtp";
+const PROXY_PROTOCOL_ARG_NAME: &str = "proxy-protocol";
+const IDLE_TIMEOUT_ARG_NAME: &str = "idle-timeout";
+const PORT_FILE_ARG_NAME: &str = "port-file";
+const READY_FD_ARG_NAME: &str = "ready-fd";
+const PRINT_CONFIG_ARG_NAME: &str = "print-config";
+const RELAY_ARG_NAME: &str = "relay";
+const RELAY_ROUTE_ARG_NAME: &str = "relay-route";
+const RELAY_USER_ARG_NAME: &str = "relay-user";
+const RELAY_PASSWORD_ARG_NAME: &str = "relay-password";
+const RELAY_TLS_ARG_NAME: &str = "relay-tls";
+const RELAY_ON_RELEASE_ARG_NAME: &str = "relay-on-release";
+const REWRITE_DOMAIN_ARG_NAME: &str = "rewrite-domain";
+const MASQUERADE_ARG_NAME: &str = "masquerade";
+const REDIRECT_TO_ARG_NAME: &str = "redirect-to";
+const DKIM_DOMAIN_ARG_NAME: &str = "dkim-domain";
+const DKIM_SELECTOR_ARG_NAME: &str = "dkim-selector";
+const DKIM_KEY_ARG_NAME: &str = "dkim-key";
+const KAFKA_BROKERS_ARG_NAME: &str = "kafka-brokers";
+const KAFKA_TOPIC_ARG_NAME: &str = "kafka-topic";
+const KAFKA_FORMAT_ARG_NAME: &str = "kafka-format";
+const NATS_SERVER_ARG_NAME: &str = "nats-server";
+const NATS_SUBJECT_ARG_NAME: &str = "nats-subject";
+const NATS_JETSTREAM_ARG_NAME: &str = "nats-jetstream";
+const AMQP_SERVER_ARG_NAME: &str = "amqp-server";
+const AMQP_VHOST_ARG_NAME: &str = "amqp-vhost";
+const AMQP_USER_ARG_NAME: &str = "amqp-user";
+const AMQP_PASSWORD_ARG_NAME: &str = "amqp-password";
+const AMQP_EXCHANGE_ARG_NAME: &str = "amqp-exchange";
+const AMQP_ROUTING_KEY_ARG_NAME: &str = "amqp-routing-key";
+const MQTT_BROKER_ARG_NAME: &str = "mqtt-broker";
+const MQTT_TOPIC_ARG_NAME: &str = "mqtt-topic";
+const MQTT_QOS_ARG_NAME: &str = "mqtt-qos";
+const EXEC_ARG_NAME: &str = "exec";
+const EXEC_CONCURRENCY_ARG_NAME: &str = "exec-concurrency";
+const EXEC_TIMEOUT_ARG_NAME: &str = "exec-timeout";
+const WEBHOOK_URL_ARG_NAME: &str = "webhook-url";
+const WEBHOOK_CONCURRENCY_ARG_NAME: &str = "webhook-concurrency";
+const WEBHOOK_ATTEMPTS_ARG_NAME: &str = "webhook-attempts";
+const SCRIPT_ARG_NAME: &str = "script";
+const RULES_ARG_NAME: &str = "rules";
+const TLS_CERT_ARG_NAME: &str = "tls-cert";
+const TLS_KEY_ARG_NAME: &str = "tls-key";
+const AUTH_USER_ARG_NAME: &str = "auth-user";
+const AUTH_PASS_ARG_NAME: &str = "auth-pass";
+const AUTH_REQUIRED_ARG_NAME: &str = "auth-required";
+const TEMPFAIL_ATTEMPTS_ARG_NAME: &str = "tempfail-attempts";
+const VERIFY_ARG_NAME: &str = "verify";
+const VERIFY_DNS_ARG_NAME: &str = "verify-dns";
+const VERIFY_OFFLINE_ARG_NAME: &str = "verify-offline";
+const CHAOS_RCPT_TEMPFAIL_ARG_NAME: &str = "chaos-rcpt-tempfail";
+const CHAOS_RCPT_REJECT_ARG_NAME: &str = "chaos-rcpt-reject";
+const CHAOS_DROP_DATA_ARG_NAME: &str = "chaos-drop-data";
+const CHAOS_SLOW_REPLY_ARG_NAME: &str = "chaos-slow-reply";
+const CHAOS_SLOW_GREETING_ARG_NAME: &str = "chaos-slow-greeting";
+const CHAOS_DELAY_ARG_NAME: &str = "chaos-delay";
+/// The chaos settings that are probabilities, any of which turns chaos mode on
+const CHAOS_PROBABILITY_ARG_NAMES: [&str; 5] = [
+    CHAOS_RCPT_TEMPFAIL_ARG_NAME,
+    CHAOS_RCPT_REJECT_ARG_NAME,
+    CHAOS_DROP_DATA_ARG_NAME,
+    CHAOS_SLOW_REPLY_ARG_NAME,
+    CHAOS_SLOW_GREETING_ARG_NAME,
+];
+const SLACK_WEBHOOK_ARG_NAME: &str = "slack-webhook";
+const DISCORD_WEBHOOK_ARG_NAME: &str = "discord-webhook";
+const NOTIFY_MATCH_ARG_NAME: &str = "notify-match";
+const RECORD_ARG_NAME: &str = "record";
+const STORAGE_ARG_NAME: &str = "storage";
+const STORAGE_DIR_ARG_NAME: &str = "storage-dir";
+const RETAIN_MESSAGES_ARG_NAME: &str = "retain-messages";
+const RETAIN_BYTES_ARG_NAME: &str = "retain-bytes";
+const RETAIN_AGE_ARG_NAME: &str = "retain-age";
+const WEB_ARG_NAME: &str = "web";
+const API_TOKEN_ARG_NAME: &str = "api-token";
+const INBOX_TOKEN_ARG_NAME: &str = "inbox-token";
+const WEB_TLS_ARG_NAME: &str = "web-tls";
+const POP3_PORT_ARG_NAME: &str = "pop3-port";
+const IMAP_PORT_ARG_NAME: &str = "imap-port";
+const CLOCK_OFFSET_ARG_NAME: &str = "clock-offset";
+const CLOCK_FREEZE_ARG_NAME: &str = "clock-freeze";
+const RELAY_QUEUE_ARG_NAME: &str = "relay-queue";
+const RELAY_QUEUE_LIFETIME_ARG_NAME: &str = "relay-queue-lifetime";
+
+/// Settings that apply to the whole process and cannot be given for a virtual server
+const PROCESS_ARG_NAMES: [&str; 10] = [
+    CONFIG_ARG_NAME,
+    PROFILE_ARG_NAME,
+    PORT_FILE_ARG_NAME,
+    READY_FD_ARG_NAME,
+    DAEMON_ARG_NAME,
+    PID_FILE_ARG_NAME,
+    LOG_FILE_ARG_NAME,
+    LOG_LEVEL_ARG_NAME,
+    LOG_FORMAT_ARG_NAME,
+    SHUTDOWN_TIMEOUT_ARG_NAME,
+];
+
+/// The command line definition
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("Rust SMTP server")
+        .version("1.0")
+        .author("Andreas Zitzelsberger <az@az82.de>")
+        .about("Simple SMTP server that will print out messages received on stdout")
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(serve_subcommand())
+        .subcommand(check::subcommand())
+        .subcommand(queue::subcommand())
+        .subcommand(store::subcommand())
+        .subcommand(loadgen::subcommand())
+        .subcommand(send::subcommand())
+        .subcommand(replay::subcommand())
+        .subcommand(mail::subcommand())
+        .subcommand(completions::subcommand())
+}
+
+/// The command line arguments with the server settings
+fn server_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name(CONFIG_ARG_NAME)
+            .long(CONFIG_ARG_NAME)
+            .help("TOML file with settings, keyed by long flag name")
+            .takes_value(true),
+        Arg::with_name(PROFILE_ARG_NAME)
+            .long(PROFILE_ARG_NAME)
+            .help("Named set of settings, ci, load or one from the configuration file's [profiles]")
+            .takes_value(true),
+        Arg::with_name(BIND_HOST_ARG_NAME)
+            .long(BIND_HOST_ARG_NAME)
+            .help("Bind host, an IPv6 address such as :: binds dual stack")
+            .default_value("localhost"),
+        Arg::with_name(BIND_PORT_ARG_NAME)
+            .short("p")
+            .long(BIND_PORT_ARG_NAME)
+            .help("Bind port")
+            .default_value("2525")
+            .validator(validate_number::<u16>),
+        Arg::with_name(LISTEN_ARG_NAME)
+            .short("l")
+            .long(LISTEN_ARG_NAME)
+            .help("Additional bind address as host:port, may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name(SOCKET_ARG_NAME)
+            .short("u")
+            .long(SOCKET_ARG_NAME)
+            .help("Unix domain socket path to bind, may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name(CONCURRENCY_ARG_NAME)
+            .short("c")
+            .long(CONCURRENCY_ARG_NAME)
+            .help("Maximum number of concurrent SMTP sessions [default: number of cores]")
+            .takes_value(true)
+            .validator(validate_positive),
+        Arg::with_name(BUFFER_SIZE_ARG_NAME)
+            .long(BUFFER_SIZE_ARG_NAME)
+            .help("Size in bytes of the read buffer of each SMTP session")
+            .default_value("8192")
+            .validator(validate_positive),
+        Arg::with_name(MAX_MESSAGE_SIZE_ARG_NAME)
+            .long(MAX_MESSAGE_SIZE_ARG_NAME)
+            .help("Size in bytes of the largest message accepted, announced with SIZE [default: unlimited]")
+            .takes_value(true)
+            .validator(validate_positive),
+        Arg::with_name(MAX_CONNECTIONS_PER_IP_ARG_NAME)
+            .long(MAX_CONNECTIONS_PER_IP_ARG_NAME)
+            .help("Maximum number of concurrent SMTP sessions from one IP address [default: unlimited]")
+            .takes_value(true)
+            .validator(validate_positive),
+        Arg::with_name(MAX_COMMANDS_PER_SECOND_ARG_NAME)
+            .long(MAX_COMMANDS_PER_SECOND_ARG_NAME)
+            .help("Maximum number of commands per second of a session, beyond which the client is disconnected [default: unlimited]")
+            .takes_value(true)
+            .validator(validate_positive),
+        Arg::with_name(MAX_RECIPIENTS_ARG_NAME)
+            .long(MAX_RECIPIENTS_ARG_NAME)
+            .help("Maximum number of recipients of a message [default: unlimited]")
+            .takes_value(true)
+            .validator(validate_positive),
+        Arg::with_name(PRINT_ARG_NAME)
+            .long(PRINT_ARG_NAME)
+            .help("What to print on stdout for each received message")
+            .possible_values(&Print::NAMES)
+            .default_value("full"),
+        Arg::with_name(QUIET_ARG_NAME)
+            .short("q")
+            .long(QUIET_ARG_NAME)
+            .help("Print nothing for received messages, same as --print none"),
+        Arg::with_name(PRINT_FORMAT_ARG_NAME)
+            .long(PRINT_FORMAT_ARG_NAME)
+            .help("Format of printed messages, jsonl prints a JSON object per line")
+            .possible_values(&PrintFormat::NAMES)
+            .default_value("text"),
+        Arg::with_name(TRANSCRIPT_ARG_NAME)
+            .long(TRANSCRIPT_ARG_NAME)
+            .help("Keep the commands and replies of every session with its messages, except for content and credentials"),
+        Arg::with_name(LMTP_ARG_NAME)
+            .long(LMTP_ARG_NAME)
+            .help("Speak LMTP instead of SMTP, greeted with LHLO and replying for every recipient after the content"),
+        Arg::with_name(PROXY_PROTOCOL_ARG_NAME)
+            .long(PROXY_PROTOCOL_ARG_NAME)
+            .help("Expect a PROXY protocol header of version 1 or 2 from a load balancer before every session, with the address of the client"),
+        Arg::with_name(IDLE_TIMEOUT_ARG_NAME)
+            .long(IDLE_TIMEOUT_ARG_NAME)
+            .help("Seconds to wait for a client to send or receive anything before replying 421 and closing the connection")
+            .default_value("300")
+            .validator(validate_positive),
+        Arg::with_name(RELAY_ARG_NAME)
+            .long(RELAY_ARG_NAME)
+            .help("Upstream SMTP server as host:port to relay received messages to")
+            .takes_value(true),
+        Arg::with_name(RELAY_ROUTE_ARG_NAME)
+            .long(RELAY_ROUTE_ARG_NAME)
+            .help("Upstream SMTP server for a recipient domain as domain=host:port instead of --relay, may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(validate_route),
+        Arg::with_name(RELAY_USER_ARG_NAME)
+            .long(RELAY_USER_ARG_NAME)
+            .help("User to log in to the relay server with, using AUTH PLAIN")
+            .takes_value(true),
+        Arg::with_name(RELAY_PASSWORD_ARG_NAME)
+            .long(RELAY_PASSWORD_ARG_NAME)
+            .help("Password to log in to the relay server with, which needs --relay-tls")
+            .takes_value(true),
+        Arg::with_name(RELAY_TLS_ARG_NAME)
+            .long(RELAY_TLS_ARG_NAME)
+            .help("How to encrypt connections to upstream servers, with STARTTLS or TLS from the start as on port 465")
+            .possible_values(&client::Encryption::NAMES)
+            .default_value("none"),
+        Arg::with_name(RELAY_ON_RELEASE_ARG_NAME)
+            .long(RELAY_ON_RELEASE_ARG_NAME)
+            .help("Relay kept messages only when they are released, with the web UI API or messages --release"),
+        Arg::with_name(REWRITE_DOMAIN_ARG_NAME)
+            .long(REWRITE_DOMAIN_ARG_NAME)
+            .help("Domain of relayed addresses to replace as domain=replacement, may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(validate_domain_mapping),
+        Arg::with_name(MASQUERADE_ARG_NAME)
+            .long(MASQUERADE_ARG_NAME)
+            .help("Domain to replace the domain of relayed sender addresses with")
+            .takes_value(true),
+        Arg::with_name(REDIRECT_TO_ARG_NAME)
+            .long(REDIRECT_TO_ARG_NAME)
+            .help("Address to relay all messages to instead of their recipients")
+            .takes_value(true),
+        Arg::with_name(DKIM_DOMAIN_ARG_NAME)
+            .long(DKIM_DOMAIN_ARG_NAME)
+            .help("Domain to sign relayed messages for with DKIM")
+            .takes_value(true),
+        Arg::with_name(DKIM_SELECTOR_ARG_NAME)
+            .long(DKIM_SELECTOR_ARG_NAME)
+            .help("DKIM selector of the signing key, published under <selector>._domainkey.<domain>")
+            .takes_value(true),
+        Arg::with_name(DKIM_KEY_ARG_NAME)
+            .long(DKIM_KEY_ARG_NAME)
+            .help("PEM file with the RSA private key to sign relayed messages with")
+            .takes_value(true),
+        Arg::with_name(RELAY_QUEUE_ARG_NAME)
+            .long(RELAY_QUEUE_ARG_NAME)
+            .help("Directory to queue messages that failed to relay in for retries [default: drop them]")
+            .takes_value(true),
+        Arg::with_name(RELAY_QUEUE_LIFETIME_ARG_NAME)
+            .long(RELAY_QUEUE_LIFETIME_ARG_NAME)
+            .help("Seconds after which a queued message is given up on and bounced")
+            .default_value("86400")
+            .validator(validate_positive),
+        Arg::with_name(KAFKA_BROKERS_ARG_NAME)
+            .long(KAFKA_BROKERS_ARG_NAME)
+            .help("Kafka broker as host:port to publish received messages with, may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name(KAFKA_TOPIC_ARG_NAME)
+            .long(KAFKA_TOPIC_ARG_NAME)
+            .help("Kafka topic to publish received messages to, keyed by message UUID")
+            .takes_value(true),
+        Arg::with_name(KAFKA_FORMAT_ARG_NAME)
+            .long(KAFKA_FORMAT_ARG_NAME)
+            .help("What to publish to Kafka for each message, a JSON summary or the raw content")
+            .possible_values(&kafka::Format::NAMES)
+            .default_value("summary"),
+        Arg::with_name(NATS_SERVER_ARG_NAME)
+            .long(NATS_SERVER_ARG_NAME)
+            .help("NATS server as host:port to publish received messages with, may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name(NATS_SUBJECT_ARG_NAME)
+            .long(NATS_SUBJECT_ARG_NAME)
+            .help("NATS subject to publish a JSON summary of received messages to")
+            .takes_value(true),
+        Arg::with_name(NATS_JETSTREAM_ARG_NAME)
+            .long(NATS_JETSTREAM_ARG_NAME)
+            .help("Wait for a JetStream stream to acknowledge every published message"),
+        Arg::with_name(AMQP_SERVER_ARG_NAME)
+            .long(AMQP_SERVER_ARG_NAME)
+            .help("AMQP 0-9-1 broker as host:port to publish a JSON summary of received messages to")
+            .takes_value(true),
+        Arg::with_name(AMQP_VHOST_ARG_NAME)
+            .long(AMQP_VHOST_ARG_NAME)
+            .help("Virtual host of the AMQP broker")
+            .default_value("/"),
+        Arg::with_name(AMQP_USER_ARG_NAME)
+            .long(AMQP_USER_ARG_NAME)
+            .help("User to log in to the AMQP broker with")
+            .default_value("guest"),
+        Arg::with_name(AMQP_PASSWORD_ARG_NAME)
+            .long(AMQP_PASSWORD_ARG_NAME)
+            .help("Password to log in to the AMQP broker with, sent unencrypted")
+            .default_value("guest"),
+        Arg::with_name(AMQP_EXCHANGE_ARG_NAME)
+            .long(AMQP_EXCHANGE_ARG_NAME)
+            .help("AMQP exchange to publish to [default: the default exchange]")
+            .takes_value(true),
+        Arg::with_name(AMQP_ROUTING_KEY_ARG_NAME)
+            .long(AMQP_ROUTING_KEY_ARG_NAME)
+            .help("Routing key of published messages, the queue name with the default exchange")
+            .takes_value(true),
+        Arg::with_name(MQTT_BROKER_ARG_NAME)
+            .long(MQTT_BROKER_ARG_NAME)
+            .help("MQTT broker as host:port to publish notifications of received messages to")
+            .takes_value(true),
+        Arg::with_name(MQTT_TOPIC_ARG_NAME)
+            .long(MQTT_TOPIC_ARG_NAME)
+            .help("MQTT topic to publish notifications under, in a subtopic per recipient address")
+            .takes_value(true)
+            .validator(validate_topic),
+        Arg::with_name(MQTT_QOS_ARG_NAME)
+            .long(MQTT_QOS_ARG_NAME)
+            .help("Quality of service of MQTT notifications, 0 for at most once or 1 for at least once")
+            .possible_values(&["0", "1"])
+            .default_value("1"),
+        Arg::with_name(EXEC_ARG_NAME)
+            .long(EXEC_ARG_NAME)
+            .help("Shell command to run for every received message, with the content on stdin and the envelope in SMTP_* variables")
+            .takes_value(true),
+        Arg::with_name(EXEC_CONCURRENCY_ARG_NAME)
+            .long(EXEC_CONCURRENCY_ARG_NAME)
+            .help("Number of --exec commands that may run at the same time")
+            .default_value("4")
+            .validator(validate_positive),
+        Arg::with_name(EXEC_TIMEOUT_ARG_NAME)
+            .long(EXEC_TIMEOUT_ARG_NAME)
+            .help("Seconds after which an --exec command is killed")
+            .default_value("30")
+            .validator(validate_positive),
+        Arg::with_name(WEBHOOK_URL_ARG_NAME)
+            .long(WEBHOOK_URL_ARG_NAME)
+            .help("HTTP endpoint to POST every received message to as JSON")
+            .takes_value(true)
+            .validator(validate_url),
+        Arg::with_name(WEBHOOK_CONCURRENCY_ARG_NAME)
+            .long(WEBHOOK_CONCURRENCY_ARG_NAME)
+            .help("Number of --webhook-url posts that may be in progress at the same time")
+            .default_value("4")
+            .validator(validate_positive),
+        Arg::with_name(WEBHOOK_ATTEMPTS_ARG_NAME)
+            .long(WEBHOOK_ATTEMPTS_ARG_NAME)
+            .help("Number of attempts to post a message to --webhook-url, with a doubling delay in between")
+            .default_value("5")
+            .validator(validate_positive),
+        Arg::with_name(SCRIPT_ARG_NAME)
+            .long(SCRIPT_ARG_NAME)
+            .help("Lua script with on_rcpt, on_data and on_received functions to decide about recipients and messages")
+            .takes_value(true),
+        Arg::with_name(RULES_ARG_NAME)
+            .long(RULES_ARG_NAME)
+            .help("JSON file with rules for canned replies to commands, which PUT /rules on the --web server replaces")
+            .takes_value(true),
+        Arg::with_name(TLS_CERT_ARG_NAME)
+            .long(TLS_CERT_ARG_NAME)
+            .help("PEM file with the certificate chain to offer STARTTLS with")
+            .takes_value(true),
+        Arg::with_name(TLS_KEY_ARG_NAME)
+            .long(TLS_KEY_ARG_NAME)
+            .help("PEM file with the private key of the --tls-cert certificate")
+            .takes_value(true),
+        Arg::with_name(AUTH_USER_ARG_NAME)
+            .long(AUTH_USER_ARG_NAME)
+            .help("User clients have to log in as with AUTH, instead of any user")
+            .takes_value(true),
+        Arg::with_name(AUTH_PASS_ARG_NAME)
+            .long(AUTH_PASS_ARG_NAME)
+            .help("Password clients have to log in with as the --auth-user")
+            .takes_value(true),
+        Arg::with_name(AUTH_REQUIRED_ARG_NAME)
+            .long(AUTH_REQUIRED_ARG_NAME)
+            .help("Reject MAIL FROM with 530 until the client logged in with AUTH"),
+        Arg::with_name(TEMPFAIL_ATTEMPTS_ARG_NAME)
+            .long(TEMPFAIL_ATTEMPTS_ARG_NAME)
+            .help("Number of attempts to deliver a message, by sender, recipients and Message-ID, to fail temporarily before accepting it")
+            .takes_value(true)
+            .validator(validate_positive),
+        Arg::with_name(VERIFY_ARG_NAME)
+            .long(VERIFY_ARG_NAME)
+            .help("Check SPF and DKIM of received messages and attach the results"),
+        Arg::with_name(VERIFY_DNS_ARG_NAME)
+            .long(VERIFY_DNS_ARG_NAME)
+            .help("Resolver to look up SPF and DKIM records with as ip:port [default: the first nameserver of /etc/resolv.conf]")
+            .takes_value(true)
+            .validator(validate_number::<SocketAddr>),
+        Arg::with_name(VERIFY_OFFLINE_ARG_NAME)
+            .long(VERIFY_OFFLINE_ARG_NAME)
+            .help("Skip the lookups of --verify, which marks every result as skipped"),
+        Arg::with_name(CHAOS_RCPT_TEMPFAIL_ARG_NAME)
+            .long(CHAOS_RCPT_TEMPFAIL_ARG_NAME)
+            .help("Probability from 0 to 1 of rejecting a recipient with a temporary error")
+            .takes_value(true)
+            .validator(validate_probability),
+        Arg::with_name(CHAOS_RCPT_REJECT_ARG_NAME)
+            .long(CHAOS_RCPT_REJECT_ARG_NAME)
+            .help("Probability from 0 to 1 of rejecting a recipient with a permanent error")
+            .takes_value(true)
+            .validator(validate_probability),
+        Arg::with_name(CHAOS_DROP_DATA_ARG_NAME)
+            .long(CHAOS_DROP_DATA_ARG_NAME)
+            .help("Probability from 0 to 1 of dropping the connection during DATA")
+            .takes_value(true)
+            .validator(validate_probability),
+        Arg::with_name(CHAOS_SLOW_REPLY_ARG_NAME)
+            .long(CHAOS_SLOW_REPLY_ARG_NAME)
+            .help("Probability from 0 to 1 of delaying the reply to a command by --chaos-delay")
+            .takes_value(true)
+            .validator(validate_probability),
+        Arg::with_name(CHAOS_SLOW_GREETING_ARG_NAME)
+            .long(CHAOS_SLOW_GREETING_ARG_NAME)
+            .help("Probability from 0 to 1 of delaying the greeting by --chaos-delay")
+            .takes_value(true)
+            .validator(validate_probability),
+        Arg::with_name(CHAOS_DELAY_ARG_NAME)
+            .long(CHAOS_DELAY_ARG_NAME)
+            .help("Seconds by which slow replies and greetings are delayed, may be a fraction")
+            .default_value("5")
+            .validator(validate_number::<f64>),
+        Arg::with_name(SLACK_WEBHOOK_ARG_NAME)
+            .long(SLACK_WEBHOOK_ARG_NAME)
+            .help("Slack incoming webhook URL to post a summary of received messages to")
+            .takes_value(true)
+            .validator(validate_url),
+        Arg::with_name(DISCORD_WEBHOOK_ARG_NAME)
+            .long(DISCORD_WEBHOOK_ARG_NAME)
+            .help("Discord webhook URL to post a summary of received messages to")
+            .takes_value(true)
+            .validator(validate_url),
+        Arg::with_name(NOTIFY_MATCH_ARG_NAME)
+            .long(NOTIFY_MATCH_ARG_NAME)
+            .help("Only post messages with a sender or recipient address like this pattern, e.g. *@example.com, may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name(RECORD_ARG_NAME)
+            .long(RECORD_ARG_NAME)
+            .help("Directory to write a recording of every session to, for the replay subcommand")
+            .takes_value(true),
+        Arg::with_name(STORAGE_ARG_NAME)
+            .long(STORAGE_ARG_NAME)
+            .help("Where to keep received messages, in memory until the server stops or in --storage-dir")
+            .takes_value(true)
+            .possible_values(&["memory", "directory"]),
+        Arg::with_name(STORAGE_DIR_ARG_NAME)
+            .long(STORAGE_DIR_ARG_NAME)
+            .help("Directory to keep received messages in with --storage directory")
+            .takes_value(true),
+        Arg::with_name(RETAIN_MESSAGES_ARG_NAME)
+            .long(RETAIN_MESSAGES_ARG_NAME)
+            .help("Number of kept messages beyond which the oldest are removed")
+            .takes_value(true)
+            .validator(validate_positive),
+        Arg::with_name(RETAIN_BYTES_ARG_NAME)
+            .long(RETAIN_BYTES_ARG_NAME)
+            .help("Total size in bytes of the kept messages beyond which the oldest are removed")
+            .takes_value(true)
+            .validator(validate_positive),
+        Arg::with_name(RETAIN_AGE_ARG_NAME)
+            .long(RETAIN_AGE_ARG_NAME)
+            .help("Seconds after which a kept message is removed")
+            .takes_value(true)
+            .validator(validate_positive),
+        Arg::with_name(WEB_ARG_NAME)
+            .long(WEB_ARG_NAME)
+            .help("Address as host:port to serve a web UI for browsing kept messages on, keeping them in memory without --storage")
+            .takes_value(true),
+        Arg::with_name(API_TOKEN_ARG_NAME)
+            .long(API_TOKEN_ARG_NAME)
+            .help("Token that requests to the --web server need, as bearer token or as basic auth password with any user")
+            .takes_value(true),
+        Arg::with_name(INBOX_TOKEN_ARG_NAME)
+            .long(INBOX_TOKEN_ARG_NAME)
+            .help("Token that only gives access to the messages of an inbox as token=inbox, e.g. s3cret=+team-a, instead of the --api-token, may be given multiple times")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(validate_inbox_token),
+        Arg::with_name(WEB_TLS_ARG_NAME)
+            .long(WEB_TLS_ARG_NAME)
+            .help("Serve the --web server over HTTPS with the --tls-cert and --tls-key"),
+        Arg::with_name(POP3_PORT_ARG_NAME)
+            .long(POP3_PORT_ARG_NAME)
+            .help("Port on the bind host to serve kept messages over POP3 on, keeping them in memory without --storage")
+            .takes_value(true)
+            .validator(validate_number::<u16>),
+        Arg::with_name(IMAP_PORT_ARG_NAME)
+            .long(IMAP_PORT_ARG_NAME)
+            .help("Port on the bind host to serve kept messages over read-only IMAP on, keeping them in memory without --storage")
+            .takes_value(true)
+            .validator(validate_number::<u16>),
+        Arg::with_name(CLOCK_OFFSET_ARG_NAME)
+            .long(CLOCK_OFFSET_ARG_NAME)
+            .help("Seconds to shift the server's time by, negative to go back, e.g. to test relay retries")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .validator(validate_number::<i64>),
+        Arg::with_name(CLOCK_FREEZE_ARG_NAME)
+            .long(CLOCK_FREEZE_ARG_NAME)
+            .help("Seconds since the epoch to stop the server's time at, for deterministic timestamps")
+            .takes_value(true)
+            .validator(validate_number::<u64>)
+            .conflicts_with(CLOCK_OFFSET_ARG_NAME),
+        Arg::with_name(DAEMON_ARG_NAME)
+            .short("d")
+            .long(DAEMON_ARG_NAME)
+            .help("Run in the background (unix platforms only)"),
+        Arg::with_name(PID_FILE_ARG_NAME)
+            .long(PID_FILE_ARG_NAME)
+            .help("File to write the daemon's PID to")
+            .takes_value(true),
+        Arg::with_name(LOG_FILE_ARG_NAME)
+            .long(LOG_FILE_ARG_NAME)
+            .help("File the daemon appends its output to [default: discard output]")
+            .takes_value(true),
+        Arg::with_name(LOG_LEVEL_ARG_NAME)
+            .long(LOG_LEVEL_ARG_NAME)
+            .help("Most verbose level of messages to log on stderr, debug logs every command and reply")
+            .possible_values(&logging::LEVEL_NAMES)
+            .default_value("info"),
+        Arg::with_name(LOG_FORMAT_ARG_NAME)
+            .long(LOG_FORMAT_ARG_NAME)
+            .help("Format of logged messages, json logs a JSON object per line")
+            .possible_values(&logging::Format::NAMES)
+            .default_value("text"),
+        Arg::with_name(SHUTDOWN_TIMEOUT_ARG_NAME)
+            .long(SHUTDOWN_TIMEOUT_ARG_NAME)
+            .help("Seconds to let active sessions finish on SIGTERM or SIGINT before exiting anyway")
+            .default_value("30")
+            .validator(validate_positive),
+        Arg::with_name(PORT_FILE_ARG_NAME)
+            .long(PORT_FILE_ARG_NAME)
+            .help("File to write the bound addresses to, one per line, e.g. when binding port 0")
+            .takes_value(true),
+        Arg::with_name(READY_FD_ARG_NAME)
+            .long(READY_FD_ARG_NAME)
+            .help("Inherited file descriptor to write the bound addresses to and close (unix platforms only)")
+            .takes_value(true)
+            .validator(validate_number::<i32>),
+    ]
+}
+
+/// The command line definition of the subcommand running the server
+fn serve_subcommand<'a, 'b>() -> App<'a, 'b> {
+    let app = SubCommand::with_name(SERVE_SUBCOMMAND_NAME)
+        .about("Run the SMTP server, the default without a subcommand")
+        .args(&server_args())
+        .arg(
+            Arg::with_name(PRINT_CONFIG_ARG_NAME)
+                .long(PRINT_CONFIG_ARG_NAME)
+                .help("Print the effective settings as a configuration file and exit"),
+        );
+    #[cfg(windows)]
+    let app = app.args(&winservice::args());
+    app
+}
+
+/// Parse the command and its settings from the command line arguments and the configuration file
+fn parse_args() -> Result<Command, Error> {
+    let matches = app().get_matches();
+
+    match matches.subcommand() {
+        (loadgen::SUBCOMMAND_NAME, Some(matches)) => {
+            Ok(Command::Loadgen(loadgen::options(matches)))
+        }
+        (send::SUBCOMMAND_NAME, Some(matches)) => Ok(Command::Send(send::options(matches))),
+        (replay::SUBCOMMAND_NAME, Some(matches)) => Ok(Command::Replay(replay::options(matches))),
+        (mail::SUBCOMMAND_NAME, Some(matches)) => Ok(Command::Mail(mail::options(matches))),
+        (completions::SUBCOMMAND_NAME, Some(matches)) => {
+            Ok(Command::Completions(completions::target(matches)))
+        }
+        (check::SUBCOMMAND_NAME, Some(matches)) => Ok(Command::Check(load_config(
+            check::subcommand,
+            matches.clone(),
+        )?)),
+        (queue::SUBCOMMAND_NAME, Some(matches)) => Ok(Command::Queue(load_config(
+            queue::subcommand,
+            matches.clone(),
+        )?)),
+        (store::SUBCOMMAND_NAME, Some(matches)) => {
+            let configs = load_config(store::subcommand, matches.clone())?;
+            let action = store::action(matches);
+            store::check_action(&configs, &action)?;
+            Ok(Command::Messages(configs, action))
+        }
+        (SERVE_SUBCOMMAND_NAME, Some(matches)) => Ok(Command::Serve(load_config(
+            serve_subcommand,
+            matches.clone(),
+        )?)),
+        // Without a subcommand the server runs with the default settings
+        _ => Ok(Command::Serve(load_config(
+            serve_subcommand,
+            serve_subcommand().get_matches_from([SERVE_SUBCOMMAND_NAME]),
+        )?)),
+    }
+}
+
+/// Get the configurations of the main server and the virtual servers from the matches of a
+/// subcommand with the server arguments, the environment and the configuration file
+fn load_config<'a, 'b>(
+    app: fn() -> App<'a, 'b>,
+    matches: ArgMatches<'a>,
+) -> Result<Vec<Config>, Error> {
+    #[cfg(windows)]
+    let service_command = winservice::command(&matches);
+    // Only taken from the command line, like --help
+    let print = matches.is_present(PRINT_CONFIG_ARG_NAME);
+    let settings = config::Settings::load(app, matches)?;
+
+    #[cfg_attr(not(windows), allow(unused_mut))]
+    let mut main_config = config(&settings, None)?;
+    #[cfg(windows)]
+    {
+        main_config.service_command = service_command;
+    }
+    let mut configs = vec![main_config];
+    for (name, server_settings) in settings.servers() {
+        if let Some(arg) = PROCESS_ARG_NAMES
+            .iter()
+            .find(|arg| server_settings.origin(arg) != "default")
+        {
+            return Err(Error::Config {
+                path: settings.value_of(CONFIG_ARG_NAME).unwrap().to_string(),
+                message: format!("{} cannot be set for the virtual server {}", arg, name),
+            });
+        }
+        configs.push(config(server_settings, Some(name))?);
+    }
+
+    if print {
+        print_config(&settings, &configs[0]);
+        for ((name, server_settings), config) in settings.servers().iter().zip(&configs[1..]) {
+            println!();
+            println!("[servers.{}]", name);
+            print_config(server_settings, config);
+        }
+        process::exit(0);
+    }
+    Ok(configs)
+}
+
+/// An error of settings that contradict each other, which ends the process on the command line
+pub(crate) fn settings_error(description: &str, kind: clap::ErrorKind) -> Error {
+    // Without colors, as embedded servers hand it to the program
+    Error::Settings(clap::Error {
+        message: format!("error: {}", description),
+        kind,
+        info: None,
+    })
+}
+
+/// Get the settings of the main server or a virtual server
+fn config(settings: &config::Settings, name: Option<&str>) -> Result<Config, Error> {
+    let daemon = settings.is_present(DAEMON_ARG_NAME);
+    // Checked here rather than by clap, so that the flags may come from different sources
+    if !daemon && (settings.is_present(PID_FILE_ARG_NAME) || settings.is_present(LOG_FILE_ARG_NAME))
+    {
+        return Err(settings_error(
+            "--pid-file and --log-file can only be used with --daemon",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    let relay_user = settings.value_of(RELAY_USER_ARG_NAME);
+    let relay_password = settings.value_of(RELAY_PASSWORD_ARG_NAME);
+    let relay_encryption = settings
+        .value_of(RELAY_TLS_ARG_NAME)
+        .and_then(client::Encryption::from_name)
+        .unwrap_or(client::Encryption::None);
+    if relay_user.is_some() != relay_password.is_some()
+        || (relay_user.is_some() && !settings.is_present(RELAY_ARG_NAME))
+    {
+        return Err(settings_error(
+            "--relay-user and --relay-password must be given together and with --relay",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    if relay_user.is_some() && relay_encryption == client::Encryption::None {
+        return Err(settings_error(
+            "--relay-user and --relay-password need --relay-tls, so that the password is encrypted",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    if settings.is_present(RELAY_QUEUE_ARG_NAME) && !settings.is_present(RELAY_ARG_NAME) {
+        return Err(settings_error(
+            "--relay-queue can only be used with --relay",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    if [
+        RELAY_ROUTE_ARG_NAME,
+        RELAY_ON_RELEASE_ARG_NAME,
+        REWRITE_DOMAIN_ARG_NAME,
+        MASQUERADE_ARG_NAME,
+        REDIRECT_TO_ARG_NAME,
+    ]
+    .iter()
+    .any(|name| settings.is_present(name))
+        && !settings.is_present(RELAY_ARG_NAME)
+    {
+        return Err(settings_error(
+            "--relay-route, --relay-on-release, --rewrite-domain, --masquerade and --redirect-to can only be used with --relay",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    if settings.is_present(KAFKA_BROKERS_ARG_NAME) != settings.is_present(KAFKA_TOPIC_ARG_NAME) {
+        return Err(settings_error(
+            "--kafka-brokers and --kafka-topic must be given together",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    if settings.is_present(NATS_SERVER_ARG_NAME) != settings.is_present(NATS_SUBJECT_ARG_NAME)
+        || settings.is_present(NATS_JETSTREAM_ARG_NAME)
+            && !settings.is_present(NATS_SUBJECT_ARG_NAME)
+    {
+        return Err(settings_error(
+            "--nats-server and --nats-subject must be given together, and --nats-jetstream needs them",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    if settings.is_present(AMQP_SERVER_ARG_NAME) != settings.is_present(AMQP_ROUTING_KEY_ARG_NAME)
+        || settings.is_present(AMQP_EXCHANGE_ARG_NAME) && !settings.is_present(AMQP_SERVER_ARG_NAME)
+    {
+        return Err(settings_error(
+            "--amqp-server and --amqp-routing-key must be given together, and --amqp-exchange needs them",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    if settings.is_present(MQTT_BROKER_ARG_NAME) != settings.is_present(MQTT_TOPIC_ARG_NAME) {
+        return Err(settings_error(
+            "--mqtt-broker and --mqtt-topic must be given together",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    let dkim = [
+        DKIM_DOMAIN_ARG_NAME,
+        DKIM_SELECTOR_ARG_NAME,
+        DKIM_KEY_ARG_NAME,
+    ]
+    .map(|name| settings.value_of(name));
+    let webhooks: Vec<(notify::Service, String)> = [
+        (notify::Service::Slack, SLACK_WEBHOOK_ARG_NAME),
+        (notify::Service::Discord, DISCORD_WEBHOOK_ARG_NAME),
+    ]
+    .iter()
+    .filter_map(|&(service, name)| Some((service, settings.value_of(name)?.to_string())))
+    .collect();
+    if webhooks.is_empty() && settings.is_present(NOTIFY_MATCH_ARG_NAME) {
+        return Err(settings_error(
+            "--notify-match can only be used with --slack-webhook or --discord-webhook",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    let tls = match (
+        settings.value_of(TLS_CERT_ARG_NAME),
+        settings.value_of(TLS_KEY_ARG_NAME),
+    ) {
+        (None, None) => None,
+        (Some(cert), Some(key)) => Some(Arc::new(
+            tls::Acceptor::load(cert, key)
+                .map_err(Error::io("Reading the TLS certificate and key"))?,
+        )),
+        _ => {
+            return Err(settings_error(
+                "--tls-cert and --tls-key must be given together",
+                clap::ErrorKind::MissingRequiredArgument,
+            ))
+        }
+    };
+    let auth = match (
+        settings.value_of(AUTH_USER_ARG_NAME),
+        settings.value_of(AUTH_PASS_ARG_NAME),
+    ) {
+        (None, None) => None,
+        (Some(user), Some(password)) => Some(Arc::new(auth::Credentials {
+            user: user.to_string(),
+            password: password.to_string(),
+        })),
+        _ => {
+            return Err(settings_error(
+                "--auth-user and --auth-pass must be given together",
+                clap::ErrorKind::MissingRequiredArgument,
+            ))
+        }
+    };
+    let verify = match (
+        settings.is_present(VERIFY_ARG_NAME),
+        settings.value_of(VERIFY_DNS_ARG_NAME),
+        settings.is_present(VERIFY_OFFLINE_ARG_NAME),
+    ) {
+        (false, None, false) => None,
+        (true, None, true) => Some(verify::Verifier { resolver: None }),
+        (true, Some(server), false) => Some(verify::Verifier {
+            resolver: Some(dns::Resolver {
+                server: server.parse().unwrap(),
+            }),
+        }),
+        (true, None, false) => match dns::Resolver::system() {
+            Some(resolver) => Some(verify::Verifier {
+                resolver: Some(resolver),
+            }),
+            None => return Err(settings_error(
+                "--verify needs a nameserver in /etc/resolv.conf, --verify-dns or --verify-offline",
+                clap::ErrorKind::MissingRequiredArgument,
+            )),
+        },
+        _ => return Err(settings_error(
+            "--verify-dns and --verify-offline can only be used with --verify, and not together",
+            clap::ErrorKind::ArgumentConflict,
+        )),
+    };
+    let web = match (
+        settings.value_of(WEB_ARG_NAME),
+        settings.is_present(WEB_TLS_ARG_NAME),
+        &tls,
+    ) {
+        (Some(_), true, None) => {
+            return Err(settings_error(
+                "--web-tls needs --tls-cert and --tls-key",
+                clap::ErrorKind::MissingRequiredArgument,
+            ))
+        }
+        // Without the token for everything, anyone could see every inbox
+        (Some(_), _, _)
+            if settings.is_present(INBOX_TOKEN_ARG_NAME)
+                && !settings.is_present(API_TOKEN_ARG_NAME) =>
+        {
+            return Err(settings_error(
+                "--inbox-token needs --api-token",
+                clap::ErrorKind::MissingRequiredArgument,
+            ))
+        }
+        (Some(address), web_tls, _) => Some(Arc::new(web::Web {
+            address: address.to_string(),
+            token: settings.value_of(API_TOKEN_ARG_NAME).map(str::to_string),
+            inbox_tokens: settings.values_of(INBOX_TOKEN_ARG_NAME).map_or_else(
+                Vec::new,
+                |tokens| {
+                    tokens
+                        .filter_map(|token| token.split_once('='))
+                        .map(|(token, inbox)| (token.to_string(), inbox.to_lowercase()))
+                        .collect()
+                },
+            ),
+            tls: tls.clone().filter(|_| web_tls),
+        })),
+        (None, web_tls, _)
+            if web_tls
+                || settings.is_present(API_TOKEN_ARG_NAME)
+                || settings.is_present(INBOX_TOKEN_ARG_NAME) =>
+        {
+            return Err(settings_error(
+                "--api-token, --inbox-token and --web-tls can only be used with --web",
+                clap::ErrorKind::MissingRequiredArgument,
+            ))
+        }
+        (None, _, _) => None,
+    };
+    let pop3 = settings
+        .value_of(POP3_PORT_ARG_NAME)
+        .map(|port| join_host_port(settings.value_of(BIND_HOST_ARG_NAME).unwrap(), port));
+    let imap = settings
+        .value_of(IMAP_PORT_ARG_NAME)
+        .map(|port| join_host_port(settings.value_of(BIND_HOST_ARG_NAME).unwrap(), port));
+    let store: Option<Box<dyn store::MessageStore>> = match (
+        settings.value_of(STORAGE_ARG_NAME),
+        settings.value_of(STORAGE_DIR_ARG_NAME),
+    ) {
+        // The web UI, POP3 and IMAP need messages to show
+        (None, None) if web.is_some() || pop3.is_some() || imap.is_some() => {
+            Some(Box::new(store::Memory::default()))
+        }
+        (None, None) => None,
+        (Some("memory"), None) => Some(Box::new(store::Memory::default())),
+        (Some("directory"), Some(path)) => Some(Box::new(store::Directory {
+            path: PathBuf::from(path),
+        })),
+        _ => {
+            return Err(settings_error(
+                "--storage-dir must be given with --storage directory, and only then",
+                clap::ErrorKind::MissingRequiredArgument,
+            ))
+        }
+    };
+    let script = settings
+        .value_of(SCRIPT_ARG_NAME)
+        .map(script::Script::load)
+        .transpose()
+        .map_err(Error::io("Reading the script"))?
+        .map(Arc::new);
+    let rules = match settings.value_of(RULES_ARG_NAME) {
+        Some(path) => Some(rules::Rules::load(path).map_err(Error::io("Reading the rules"))?),
+        // Rules can be put to the web UI server later
+        None if web.is_some() => Some(rules::Rules::default()),
+        None => None,
+    };
+    let clock: Arc<dyn clock::Clock> = match settings.value_of(CLOCK_FREEZE_ARG_NAME) {
+        Some(time) => Arc::new(clock::FrozenClock::at(time.parse().unwrap())),
+        None => Arc::new(clock::SystemClock {
+            offset: settings
+                .value_of(CLOCK_OFFSET_ARG_NAME)
+                .map_or(0, |offset| offset.parse().unwrap()),
+        }),
+    };
+    let retention = store::Retention {
+        max_messages: settings
+            .value_of(RETAIN_MESSAGES_ARG_NAME)
+            .map(|count| count.parse().unwrap()),
+        max_bytes: settings
+            .value_of(RETAIN_BYTES_ARG_NAME)
+            .map(|size| size.parse().unwrap()),
+        max_age: settings
+            .value_of(RETAIN_AGE_ARG_NAME)
+            .map(|age| age.parse().unwrap()),
+    };
+    let store: Option<Arc<dyn store::MessageStore>> = match store {
+        Some(store)
+            if retention.max_messages.is_some()
+                || retention.max_bytes.is_some()
+                || retention.max_age.is_some() =>
+        {
+            Some(Arc::new(store::Retained {
+                store,
+                retention,
+                clock: clock.clone(),
+            }))
+        }
+        Some(store) => Some(Arc::from(store)),
+        None if settings.is_present(RETAIN_MESSAGES_ARG_NAME)
+            || settings.is_present(RETAIN_BYTES_ARG_NAME)
+            || settings.is_present(RETAIN_AGE_ARG_NAME) =>
+        {
+            return Err(settings_error(
+                "--retain-messages, --retain-bytes and --retain-age need kept messages, with --storage, --web, --pop3-port or --imap-port",
+                clap::ErrorKind::MissingRequiredArgument,
+            ))
+        }
+        None => None,
+    };
+    let signer = match dkim {
+        [None, None, None] => None,
+        [Some(domain), Some(selector), Some(key)] if settings.is_present(RELAY_ARG_NAME) => Some(
+            dkim::Signer::load(domain, selector, key, clock.clone())
+                .map_err(Error::io("Reading the DKIM key"))?,
+        ),
+        _ => return Err(settings_error(
+            "--dkim-domain, --dkim-selector and --dkim-key must be given together and with --relay",
+            clap::ErrorKind::MissingRequiredArgument,
+        )),
+    };
+
+    let mut bind_addresses = vec![join_host_port(
+        settings.value_of(BIND_HOST_ARG_NAME).unwrap(),
+        settings.value_of(BIND_PORT_ARG_NAME).unwrap(),
+    )];
+    if let Some(addresses) = settings.values_of(LISTEN_ARG_NAME) {
+        bind_addresses.extend(addresses.map(str::to_string));
+    }
+
+    Ok(Config {
+        name: name.map(str::to_string),
+        bind_addresses,
+        socket_paths: settings
+            .values_of(SOCKET_ARG_NAME)
+            .map_or_else(Vec::new, |paths| paths.map(str::to_string).collect()),
+        concurrency: settings
+            .value_of(CONCURRENCY_ARG_NAME)
+            .map_or_else(num_cpus::get, |s| s.parse().unwrap()),
+        buffer_size: settings
+            .value_of(BUFFER_SIZE_ARG_NAME)
+            .unwrap()
+            .parse()
+            .unwrap(),
+        max_message_size: settings
+            .value_of(MAX_MESSAGE_SIZE_ARG_NAME)
+            .map(|size| size.parse().unwrap()),
+        print: if settings.is_present(QUIET_ARG_NAME) {
+            Print::None
+        } else {
+            settings
+                .value_of(PRINT_ARG_NAME)
+                .and_then(Print::from_name)
+                .unwrap_or(Print::Full)
+        },
+        print_format: settings
+            .value_of(PRINT_FORMAT_ARG_NAME)
+            .and_then(PrintFormat::from_name)
+            .unwrap_or(PrintFormat::Text),
+        transcript: settings.is_present(TRANSCRIPT_ARG_NAME),
+        lmtp: settings.is_present(LMTP_ARG_NAME),
+        proxy_protocol: settings.is_present(PROXY_PROTOCOL_ARG_NAME),
+        idle_timeout: Duration::from_secs(
+            settings
+                .value_of(IDLE_TIMEOUT_ARG_NAME)
+                .unwrap()
+                .parse()
+                .unwrap(),
+        ),
+        relay: settings
+            .value_of(RELAY_ARG_NAME)
+            .map(|address| relay::Relay {
+                address: address.to_string(),
+                routes: settings
+                    .values_of(RELAY_ROUTE_ARG_NAME)
+                    .map_or_else(Vec::new, |routes| {
+                        routes
+                            .filter_map(|route| route.split_once('='))
+                            .map(|(domain, address)| (domain.to_string(), address.to_string()))
+                            .collect()
+                    }),
+                credentials: relay_user
+                    .zip(relay_password)
+                    .map(|(user, password)| (user.to_string(), password.to_string())),
+                encryption: relay_encryption,
+                on_release: settings.is_present(RELAY_ON_RELEASE_ARG_NAME),
+                rewrite: rewrite::Rules {
+                    domains: settings.values_of(REWRITE_DOMAIN_ARG_NAME).map_or_else(
+                        Vec::new,
+                        |mappings| {
+                            mappings
+                                .filter_map(|mapping| mapping.split_once('='))
+                                .map(|(from, to)| (from.to_string(), to.to_string()))
+                                .collect()
+                        },
+                    ),
+                    masquerade: settings.value_of(MASQUERADE_ARG_NAME).map(str::to_string),
+                    redirect: settings.value_of(REDIRECT_TO_ARG_NAME).map(str::to_string),
+                },
+                signer,
+                queue: settings
+                    .value_of(RELAY_QUEUE_ARG_NAME)
+                    .map(|path| queue::Queue {
+                        path: path.into(),
+                        lifetime: Duration::from_secs(
+                            settings
+                                .value_of(RELAY_QUEUE_LIFETIME_ARG_NAME)
+                                .unwrap()
+                                .parse()
+                                .unwrap(),
+                        ),
+                        clock: clock.clone(),
+                    }),
+                pool: Arc::default(),
+            }),
+        kafka: settings
+            .value_of(KAFKA_TOPIC_ARG_NAME)
+            .map(|topic| kafka::Sink {
+                brokers: settings
+                    .values_of(KAFKA_BROKERS_ARG_NAME)
+                    .map_or_else(Vec::new, |brokers| brokers.map(str::to_string).collect()),
+                topic: topic.to_string(),
+                format: settings
+                    .value_of(KAFKA_FORMAT_ARG_NAME)
+                    .and_then(kafka::Format::from_name)
+                    .unwrap_or(kafka::Format::Summary),
+                clock: clock.clone(),
+            }),
+        nats: settings
+            .value_of(NATS_SUBJECT_ARG_NAME)
+            .map(|subject| nats::Sink {
+                servers: settings
+                    .values_of(NATS_SERVER_ARG_NAME)
+                    .map_or_else(Vec::new, |servers| servers.map(str::to_string).collect()),
+                subject: subject.to_string(),
+                jetstream: settings.is_present(NATS_JETSTREAM_ARG_NAME),
+            }),
+        amqp: settings
+            .value_of(AMQP_SERVER_ARG_NAME)
+            .map(|server| amqp::Sink {
+                server: server.to_string(),
+                vhost: settings.value_of(AMQP_VHOST_ARG_NAME).unwrap().to_string(),
+                user: settings.value_of(AMQP_USER_ARG_NAME).unwrap().to_string(),
+                password: settings
+                    .value_of(AMQP_PASSWORD_ARG_NAME)
+                    .unwrap()
+                    .to_string(),
+                exchange: settings
+                    .value_of(AMQP_EXCHANGE_ARG_NAME)
+                    .unwrap_or_default()
+                    .to_string(),
+                routing_key: settings
+                    .value_of(AMQP_ROUTING_KEY_ARG_NAME)
+                    .unwrap()
+                    .to_string(),
+            }),
+        mqtt: settings
+            .value_of(MQTT_BROKER_ARG_NAME)
+            .map(|broker| mqtt::Sink {
+                broker: broker.to_string(),
+                topic: settings
+                    .value_of(MQTT_TOPIC_ARG_NAME)
+                    .unwrap()
+                    .trim_end_matches('/')
+                    .to_string(),
+                qos: settings
+                    .value_of(MQTT_QOS_ARG_NAME)
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+            }),
+        script,
+        rules: rules.map(Arc::new),
+        tls,
+        auth,
+        auth_required: settings.is_present(AUTH_REQUIRED_ARG_NAME),
+        tempfail: settings
+            .value_of(TEMPFAIL_ATTEMPTS_ARG_NAME)
+            .map(|failures| Arc::new(tempfail::Attempts::new(failures.parse().unwrap()))),
+        chaos: CHAOS_PROBABILITY_ARG_NAMES
+            .iter()
+            .any(|name| settings.is_present(name))
+            .then(|| {
+                let probability =
+                    |name| settings.value_of(name).map_or(0.0, |p| p.parse().unwrap());
+                Arc::new(chaos::Chaos {
+                    rcpt_tempfail: probability(CHAOS_RCPT_TEMPFAIL_ARG_NAME),
+                    rcpt_reject: probability(CHAOS_RCPT_REJECT_ARG_NAME),
+                    drop_data: probability(CHAOS_DROP_DATA_ARG_NAME),
+                    slow_reply: probability(CHAOS_SLOW_REPLY_ARG_NAME),
+                    slow_greeting: probability(CHAOS_SLOW_GREETING_ARG_NAME),
+                    delay: Duration::from_secs_f64(
+                        settings
+                            .value_of(CHAOS_DELAY_ARG_NAME)
+                            .unwrap()
+                            .parse()
+                            .unwrap(),
+                    ),
+                })
+            }),
+        limits: [
+            MAX_CONNECTIONS_PER_IP_ARG_NAME,
+            MAX_COMMANDS_PER_SECOND_ARG_NAME,
+            MAX_RECIPIENTS_ARG_NAME,
+        ]
+        .iter()
+        .any(|name| settings.is_present(name))
+        .then(|| {
+            Arc::new(limits::Limits::new(
+                settings
+                    .value_of(MAX_CONNECTIONS_PER_IP_ARG_NAME)
+                    .map(|n| n.parse().unwrap()),
+                settings
+                    .value_of(MAX_COMMANDS_PER_SECOND_ARG_NAME)
+                    .map(|n| n.parse().unwrap()),
+                settings
+                    .value_of(MAX_RECIPIENTS_ARG_NAME)
+                    .map(|n| n.parse().unwrap()),
+            ))
+        }),
+        verify: verify.map(Arc::new),
+        notifications: (!webhooks.is_empty()).then(|| notify::Notifications {
+            webhooks,
+            patterns: settings
+                .values_of(NOTIFY_MATCH_ARG_NAME)
+                .map_or_else(Vec::new, |patterns| patterns.map(str::to_string).collect()),
+        }),
+        record: settings.value_of(RECORD_ARG_NAME).map(PathBuf::from),
+        store,
+        web,
+        pop3,
+        imap,
+        clock,
+        exec: settings.value_of(EXEC_ARG_NAME).map(|command| exec::Hook {
+            command: command.to_string(),
+            concurrency: settings
+                .value_of(EXEC_CONCURRENCY_ARG_NAME)
+                .unwrap()
+                .parse()
+                .unwrap(),
+            timeout: Duration::from_secs(
+                settings
+                    .value_of(EXEC_TIMEOUT_ARG_NAME)
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+            ),
+        }),
+        webhook: settings
+            .value_of(WEBHOOK_URL_ARG_NAME)
+            .map(|url| webhook::Webhook {
+                url: url.to_string(),
+                concurrency: settings
+                    .value_of(WEBHOOK_CONCURRENCY_ARG_NAME)
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+                attempts: settings
+                    .value_of(WEBHOOK_ATTEMPTS_ARG_NAME)
+                    .unwrap()
+                    .parse()
+                    .unwrap(),
+            }),
+        port_file: settings.value_of(PORT_FILE_ARG_NAME).map(str::to_string),
+        ready_fd: settings
+            .value_of(READY_FD_ARG_NAME)
+            .map(|fd| fd.parse().unwrap()),
+        daemon,
+        pid_file: settings.value_of(PID_FILE_ARG_NAME).map(str::to_string),
+        log_file: settings.value_of(LOG_FILE_ARG_NAME).map(str::to_string),
+        logging: logging::Logging {
+            level: settings
+                .value_of(LOG_LEVEL_ARG_NAME)
+                .unwrap()
+                .parse()
+                .unwrap(),
+            format: settings
+                .value_of(LOG_FORMAT_ARG_NAME)
+                .and_then(logging::Format::from_name)
+                .unwrap_or(logging::Format::Text),
+        },
+        shutdown_timeout: Duration::from_secs(
+            settings
+                .value_of(SHUTDOWN_TIMEOUT_ARG_NAME)
+                .unwrap()
+                .parse()
+                .unwrap(),
+        ),
+        #[cfg(windows)]
+        service_command: None,
+    })
+}
+
+/// Print the effective settings on stdout in the format of the configuration file, each with
+/// where it comes from. The settings of the whole process are left out for a virtual server.
+fn print_config(settings: &config::Settings, config: &Config) {
+    let strings = |values: &[String]| {
+        toml::Value::Array(values.iter().cloned().map(toml::Value::String).collect())
+    };
+    let print = |name: &str, value: toml::Value| {
+        println!("{} = {}  # {}", name, value, settings.origin(name));
+    };
+
+    if config.name.is_none() {
+        if let Some(path) = settings.value_of(CONFIG_ARG_NAME) {
+            println!("# Configuration file: {}", path);
+        }
+        if let Some(profile) = settings.value_of(PROFILE_ARG_NAME) {
+            print(PROFILE_ARG_NAME, toml::Value::String(profile.to_string()));
+        }
+    }
+    let host = settings.value_of(BIND_HOST_ARG_NAME).unwrap_or_default();
+    print(BIND_HOST_ARG_NAME, toml::Value::String(host.to_string()));
+    let port = settings.value_of(BIND_PORT_ARG_NAME).unwrap_or_default();
+    print(
+        BIND_PORT_ARG_NAME,
+        toml::Value::Integer(port.parse().unwrap_or_default()),
+    );
+    // The first bind address is the one made of host and port
+    print(LISTEN_ARG_NAME, strings(&config.bind_addresses[1..]));
+    print(SOCKET_ARG_NAME, strings(&config.socket_paths));
+    print(
+        CONCURRENCY_ARG_NAME,
+        toml::Value::Integer(config.concurrency as i64),
+    );
+    print(
+        BUFFER_SIZE_ARG_NAME,
+        toml::Value::Integer(config.buffer_size as i64),
+    );
+    if let Some(size) = config.max_message_size {
+        print(MAX_MESSAGE_SIZE_ARG_NAME, toml::Value::Integer(size as i64));
+    }
+    println!(
+        "{} = {}  # {}",
+        PRINT_ARG_NAME,
+        toml::Value::String(config.print.name().to_string()),
+        match settings.origin(QUIET_ARG_NAME) {
+            "default" => settings.origin(PRINT_ARG_NAME),
+            origin => origin,
+        }
+    );
+    print(
+        PRINT_FORMAT_ARG_NAME,
+        toml::Value::String(config.print_format.name().to_string()),
+    );
+    print(TRANSCRIPT_ARG_NAME, toml::Value::Boolean(config.transcript));
+    print(LMTP_ARG_NAME, toml::Value::Boolean(config.lmtp));
+    print(
+        PROXY_PROTOCOL_ARG_NAME,
+        toml::Value::Boolean(config.proxy_protocol),
+    );
+    print(
+        IDLE_TIMEOUT_ARG_NAME,
+        toml::Value::Integer(config.idle_timeout.as_secs() as i64),
+    );
+    if let Some(relay) = &config.relay {
+        print(RELAY_ARG_NAME, toml::Value::String(relay.address.clone()));
+        if !relay.routes.is_empty() {
+            let routes: Vec<String> = relay
+                .routes
+                .iter()
+                .map(|(domain, address)| format!("{}={}", domain, address))
+                .collect();
+            print(RELAY_ROUTE_ARG_NAME, strings(&routes));
+        }
+        if let Some((user, _)) = &relay.credentials {
+            print(RELAY_USER_ARG_NAME, toml::Value::String(user.clone()));
+            // Secrets stay out of the output, which may end up in logs
+            print(
+                RELAY_PASSWORD_ARG_NAME,
+                toml::Value::String("********".to_string()),
+            );
+        }
+        print(
+            RELAY_TLS_ARG_NAME,
+            toml::Value::String(relay.encryption.name().to_string()),
+        );
+        print(
+            RELAY_ON_RELEASE_ARG_NAME,
+            toml::Value::Boolean(relay.on_release),
+        );
+        let rewrite = &relay.rewrite;
+        if !rewrite.domains.is_empty() {
+            let mappings: Vec<String> = rewrite
+                .domains
+                .iter()
+                .map(|(from, to)| format!("{}={}", from, to))
+                .collect();
+            print(REWRITE_DOMAIN_ARG_NAME, strings(&mappings));
+        }
+        if let Some(domain) = &rewrite.masquerade {
+            print(MASQUERADE_ARG_NAME, toml::Value::String(domain.clone()));
+        }
+        if let Some(address) = &rewrite.redirect {
+            print(REDIRECT_TO_ARG_NAME, toml::Value::String(address.clone()));
+        }
+        if let Some(signer) = &relay.signer {
+            print(
+                DKIM_DOMAIN_ARG_NAME,
+                toml::Value::String(signer.domain.clone()),
+            );
+            print(
+                DKIM_SELECTOR_ARG_NAME,
+                toml::Value::String(signer.selector.clone()),
+            );
+            let key = settings.value_of(DKIM_KEY_ARG_NAME).unwrap_or_default();
+            print(DKIM_KEY_ARG_NAME, toml::Value::String(key.to_string()));
+        }
+        if let Some(queue) = &relay.queue {
+            print(
+                RELAY_QUEUE_ARG_NAME,
+                toml::Value::String(queue.path.display().to_string()),
+            );
+            print(
+                RELAY_QUEUE_LIFETIME_ARG_NAME,
+                toml::Value::Integer(queue.lifetime.as_secs() as i64),
+            );
+        }
+    }
+    if let Some(kafka) = &config.kafka {
+        print(KAFKA_BROKERS_ARG_NAME, strings(&kafka.brokers));
+        print(
+            KAFKA_TOPIC_ARG_NAME,
+            toml::Value::String(kafka.topic.clone()),
+        );
+        print(
+            KAFKA_FORMAT_ARG_NAME,
+            toml::Value::String(kafka.format.name().to_string()),
+        );
+    }
+    if let Some(nats) = &config.nats {
+        print(NATS_SERVER_ARG_NAME, strings(&nats.servers));
+        print(
+            NATS_SUBJECT_ARG_NAME,
+            toml::Value::String(nats.subject.clone()),
+        );
+        print(
+            NATS_JETSTREAM_ARG_NAME,
+            toml::Value::Boolean(nats.jetstream),
+        );
+    }
+    if let Some(amqp) = &config.amqp {
+        print(
+            AMQP_SERVER_ARG_NAME,
+            toml::Value::String(amqp.server.clone()),
+        );
+        print(AMQP_VHOST_ARG_NAME, toml::Value::String(amqp.vhost.clone()));
+        print(AMQP_USER_ARG_NAME, toml::Value::String(amqp.user.clone()));
+        print(
+            AMQP_PASSWORD_ARG_NAME,
+            toml::Value::String("********".to_string()),
+        );
+        print(
+            AMQP_EXCHANGE_ARG_NAME,
+            toml::Value::String(amqp.exchange.clone()),
+        );
+        print(
+            AMQP_ROUTING_KEY_ARG_NAME,
+            toml::Value::String(amqp.routing_key.clone()),
+        );
+    }
+    if let Some(mqtt) = &config.mqtt {
+        print(
+            MQTT_BROKER_ARG_NAME,
+            toml::Value::String(mqtt.broker.clone()),
+        );
+        print(MQTT_TOPIC_ARG_NAME, toml::Value::String(mqtt.topic.clone()));
+        print(MQTT_QOS_ARG_NAME, toml::Value::Integer(mqtt.qos as i64));
+    }
+    if let Some(hook) = &config.exec {
+        print(EXEC_ARG_NAME, toml::Value::String(hook.command.clone()));
+        print(
+            EXEC_CONCURRENCY_ARG_NAME,
+            toml::Value::Integer(hook.concurrency as i64),
+        );
+        print(
+            EXEC_TIMEOUT_ARG_NAME,
+            toml::Value::Integer(hook.timeout.as_secs() as i64),
+        );
+    }
+    if let Some(webhook) = &config.webhook {
+        // The URL may contain a secret, like those of chat webhooks
+        print(
+            WEBHOOK_URL_ARG_NAME,
+            toml::Value::String("********".to_string()),
+        );
+        print(
+            WEBHOOK_CONCURRENCY_ARG_NAME,
+            toml::Value::Integer(webhook.concurrency as i64),
+        );
+        print(
+            WEBHOOK_ATTEMPTS_ARG_NAME,
+            toml::Value::Integer(webhook.attempts.into()),
+        );
+    }
+    if let Some(script) = &config.script {
+        print(SCRIPT_ARG_NAME, toml::Value::String(script.path.clone()));
+    }
+    if let Some(path) = settings.value_of(RULES_ARG_NAME) {
+        print(RULES_ARG_NAME, toml::Value::String(path.to_string()));
+    }
+    if let Some(acceptor) = &config.tls {
+        print(
+            TLS_CERT_ARG_NAME,
+            toml::Value::String(acceptor.cert_path.clone()),
+        );
+        print(
+            TLS_KEY_ARG_NAME,
+            toml::Value::String(acceptor.key_path.clone()),
+        );
+    }
+    if let Some(credentials) = &config.auth {
+        print(
+            AUTH_USER_ARG_NAME,
+            toml::Value::String(credentials.user.clone()),
+        );
+        print(
+            AUTH_PASS_ARG_NAME,
+            toml::Value::String("********".to_string()),
+        );
+    }
+    print(
+        AUTH_REQUIRED_ARG_NAME,
+        toml::Value::Boolean(config.auth_required),
+    );
+    if let Some(attempts) = &config.tempfail {
+        print(
+            TEMPFAIL_ATTEMPTS_ARG_NAME,
+            toml::Value::Integer(attempts.failures.into()),
+        );
+    }
+    if let Some(chaos) = &config.chaos {
+        for (name, probability) in [
+            (CHAOS_RCPT_TEMPFAIL_ARG_NAME, chaos.rcpt_tempfail),
+            (CHAOS_RCPT_REJECT_ARG_NAME, chaos.rcpt_reject),
+            (CHAOS_DROP_DATA_ARG_NAME, chaos.drop_data),
+            (CHAOS_SLOW_REPLY_ARG_NAME, chaos.slow_reply),
+            (CHAOS_SLOW_GREETING_ARG_NAME, chaos.slow_greeting),
+        ] {
+            print(name, toml::Value::Float(probability));
+        }
+        print(
+            CHAOS_DELAY_ARG_NAME,
+            toml::Value::Float(chaos.delay.as_secs_f64()),
+        );
+    }
+    if let Some(limits) = &config.limits {
+        for (name, limit) in [
+            (MAX_CONNECTIONS_PER_IP_ARG_NAME, limits.connections_per_ip),
+            (
+                MAX_COMMANDS_PER_SECOND_ARG_NAME,
+                limits.commands_per_second.map(|n| n as usize),
+            ),
+            (MAX_RECIPIENTS_ARG_NAME, limits.recipients),
+        ] {
+            if let Some(limit) = limit {
+                print(name, toml::Value::Integer(limit as i64));
+            }
+        }
+    }
+    if let Some(verifier) = &config.verify {
+        print(VERIFY_ARG_NAME, toml::Value::Boolean(true));
+        match &verifier.resolver {
+            Some(resolver) => print(
+                VERIFY_DNS_ARG_NAME,
+                toml::Value::String(resolver.server.to_string()),
+            ),
+            None => print(VERIFY_OFFLINE_ARG_NAME, toml::Value::Boolean(true)),
+        }
+    }
+    if let Some(notifications) = &config.notifications {
+        for (service, _) in &notifications.webhooks {
+            let name = match service {
+                notify::Service::Slack => SLACK_WEBHOOK_ARG_NAME,
+                notify::Service::Discord => DISCORD_WEBHOOK_ARG_NAME,
+            };
+            // Webhook URLs contain their secret
+            print(name, toml::Value::String("********".to_string()));
+        }
+        print(NOTIFY_MATCH_ARG_NAME, strings(&notifications.patterns));
+    }
+    if let Some(path) = &config.record {
+        print(
+            RECORD_ARG_NAME,
+            toml::Value::String(path.display().to_string()),
+        );
+    }
+    if let Some(store) = &config.store {
+        match store.path() {
+            Some(path) => {
+                print(
+                    STORAGE_ARG_NAME,
+                    toml::Value::String("directory".to_string()),
+                );
+                print(
+                    STORAGE_DIR_ARG_NAME,
+                    toml::Value::String(path.display().to_string()),
+                );
+            }
+            None => print(STORAGE_ARG_NAME, toml::Value::String("memory".to_string())),
+        }
+        if let Some(retention) = store.retention() {
+            for (name, limit) in [
+                (
+                    RETAIN_MESSAGES_ARG_NAME,
+                    retention.max_messages.map(|max| max as u64),
+                ),
+                (
+                    RETAIN_BYTES_ARG_NAME,
+                    retention.max_bytes.map(|max| max as u64),
+                ),
+                (RETAIN_AGE_ARG_NAME, retention.max_age),
+            ] {
+                if let Some(limit) = limit {
+                    print(name, toml::Value::Integer(limit as i64));
+                }
+            }
+        }
+    }
+    if let Some(web) = &config.web {
+        print(WEB_ARG_NAME, toml::Value::String(web.address.clone()));
+        if web.token.is_some() {
+            print(
+                API_TOKEN_ARG_NAME,
+                toml::Value::String("********".to_string()),
+            );
+        }
+        if !web.inbox_tokens.is_empty() {
+            let inboxes: Vec<String> = web
+                .inbox_tokens
+                .iter()
+                .map(|(_, inbox)| format!("********={}", inbox))
+                .collect();
+            print(INBOX_TOKEN_ARG_NAME, strings(&inboxes));
+        }
+        print(WEB_TLS_ARG_NAME, toml::Value::Boolean(web.tls.is_some()));
+    }
+    if let Some(port) = settings.value_of(POP3_PORT_ARG_NAME) {
+        print(
+            POP3_PORT_ARG_NAME,
+            toml::Value::Integer(port.parse().unwrap()),
+        );
+    }
+    if let Some(port) = settings.value_of(IMAP_PORT_ARG_NAME) {
+        print(
+            IMAP_PORT_ARG_NAME,
+            toml::Value::Integer(port.parse().unwrap()),
+        );
+    }
+    for name in [CLOCK_OFFSET_ARG_NAME, CLOCK_FREEZE_ARG_NAME] {
+        if let Some(seconds) = settings.value_of(name) {
+            print(name, toml::Value::Integer(seconds.parse().unwrap()));
+        }
+    }
+    if config.name.is_some() {
+        return;
+    }
+    if let Some(path) = &config.port_file {
+        print(PORT_FILE_ARG_NAME, toml::Value::String(path.clone()));
+    }
+    if let Some(fd) = config.ready_fd {
+        print(READY_FD_ARG_NAME, toml::Value::Integer(fd.into()));
+    }
+    print(DAEMON_ARG_NAME, toml::Value::Boolean(config.daemon));
+    if let Some(path) = &config.pid_file {
+        print(PID_FILE_ARG_NAME, toml::Value::String(path.clone()));
+    }
+    if let Some(path) = &config.log_file {
+        print(LOG_FILE_ARG_NAME, toml::Value::String(path.clone()));
+    }
+    print(
+        LOG_LEVEL_ARG_NAME,
+        toml::Value::String(config.logging.level.to_string().to_lowercase()),
+    );
+    print(
+        LOG_FORMAT_ARG_NAME,
+        toml::Value::String(config.logging.format.name().to_string()),
+    );
+    print(
+        SHUTDOWN_TIMEOUT_ARG_NAME,
+        toml::Value::Integer(config.shutdown_timeout.as_secs() as i64),
+    );
+}
+
+/// A completed SMTP session
+struct Session {
+    /// Random UUID, which the log messages of the session carry too
+    id: String,
+    client_address: String,
+    /// Address of the proxy the client connected through, with the PROXY protocol
+    proxy_address: Option<String>,
+    connected: SystemTime,
+    disconnected: SystemTime,
+    /// Bytes of the session from the client, without TLS
+    bytes_received: u64,
+    /// Bytes of the session to the client, without TLS
+    bytes_sent: u64,
+    connection: smtp::Connection,
+}
+
+/// What workers need to run SMTP sessions
+#[derive(Clone)]
+struct Sessions {
+    buffer_size: usize,
+    max_message_size: Option<usize>,
+    transcript: bool,
+    lmtp: bool,
+    proxy_protocol: bool,
+    idle_timeout: Duration,
+    /// Receives every successfully completed session
+    broadcaster: Arc<Broadcaster<Arc<Session>>>,
+    drain: Arc<Drain>,
+    /// Script that decides about recipients and messages
+    script: Option<Arc<script::Script>>,
+    /// Canned replies to commands
+    rules: Option<Arc<rules::Rules>>,
+    tls: Option<Arc<tls::Acceptor>>,
+    auth: Option<Arc<auth::Credentials>>,
+    auth_required: bool,
+    tempfail: Option<Arc<tempfail::Attempts>>,
+    chaos: Option<Arc<chaos::Chaos>>,
+    limits: Option<Arc<limits::Limits>>,
+    verify: Option<Arc<verify::Verifier>>,
+    /// Directory to write a recording of every session to
+    record: Option<PathBuf>,
+    clock: Arc<dyn clock::Clock>,
+}
+
+/// State of a server that stops accepting connections
+#[derive(Default)]
+struct Drain {
+    /// Set when the server stops accepting connections
+    started: AtomicBool,
+    /// Set when the server shuts down, after which connections are not handled at all
+    stopped: AtomicBool,
+    /// Number of connections handled outside the pool while draining
+    sessions: AtomicUsize,
+}
+
+/// Handle a client connection.
+/// If the SMTP communication was successful, publish the session to all subscribers.
+fn handle_connection<S: Stream>(
+    stream: S,
+    sessions: &Sessions,
+    id: String,
+    client: Option<SocketAddr>,
+) {
+    // The stream comes from the proxy if a PROXY protocol header passed on the client
+    let (client_address, client_ip, proxy_address) = match client {
+        Some(client) => (
+            client.to_string(),
+            Some(client.ip()),
+            Some(stream.peer_address()),
+        ),
+        None => (stream.peer_address(), stream.peer_ip(), None),
+    };
+    let recording = sessions
+        .record
+        .as_ref()
+        .map(|_| replay::Recording::default());
+    let connected = sessions.clock.now();
+    let mut transport = ClientTransport::new(stream, sessions, recording.as_ref());
+
+    // Failing attempts comes first, so scripts only see the messages that get through. Canned
+    // replies come before anything else, they are what the tester asked for.
+    let mut policies: Vec<Box<dyn smtp::Policy>> = Vec::new();
+    if let Some(rules) = &sessions.rules {
+        policies.push(Box::new(rules::Policy::new(rules.clone())));
+    }
+    if let Some(chaos) = &sessions.chaos {
+        policies.push(Box::new(chaos::Policy(chaos.clone())));
+    }
+    if let Some(credentials) = &sessions.auth {
+        policies.push(Box::new(auth::Policy(credentials.clone())));
+    }
+    if let Some(attempts) = &sessions.tempfail {
+        policies.push(Box::new(tempfail::Policy(attempts.clone())));
+    }
+    if let Some(verifier) = &sessions.verify {
+        policies.push(Box::new(verify::Policy::new(verifier.clone(), client_ip)));
+    }
+    if let Some(script) = &sessions.script {
+        match script.policy() {
+            Ok(policy) => policies.push(Box::new(policy)),
+            Err(e) => {
+                tracing::error!("Script {} failed: {}", script.path, e);
+                if let Err(e) = smtp::Connection::reject(&mut transport.writer) {
+                    tracing::warn!("Unable to reject client connection: {}", e);
+                }
+                return;
+            }
+        }
+    }
+    // Limits come last, so they only count the recipients that all other policies accept
+    if let Some(limits) = &sessions.limits {
+        policies.push(Box::new(limits::Policy::new(limits.clone())));
+    }
+    let outcome = smtp::Connection::handle_transport(&mut transport, &mut policies);
+    // Timed out reads fail with WouldBlock on unix platforms and TimedOut on Windows
+    if let Err(e) = &outcome {
+        if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) {
+            if let Err(e) = smtp::Connection::time_out(&mut transport.writer) {
+                tracing::warn!("Unable to tell the client about the timeout: {}", e);
+            }
+        }
+    }
+    let disconnected = sessions.clock.now();
+    let (bytes_received, bytes_sent) = (
+        transport.reader.get_ref().bytes,
+        transport.writer.get_ref().bytes,
+    );
+    // Sessions that failed are recorded too, they are often the interesting ones
+    if let (Some(recording), Some(directory)) = (recording, &sessions.record) {
+        drop(transport);
+        if let Err(e) = recording.save(directory, &client_address, sessions.clock.unix_time()) {
+            tracing::error!("Recording the session failed: {}", e);
+        }
+    }
+    match outcome {
+        Ok(connection) => sessions.broadcaster.publish(Arc::new(Session {
+            id,
+            client_address,
+            proxy_address,
+            connected,
+            disconnected,
+            bytes_received,
+            bytes_sent,
+            connection,
+        })),
+        Err(e) => tracing::warn!("Error communicating with client: {}", e),
+    }
+}
+
+/// A reader or writer that counts the bytes that go through it
+struct Counted<T> {
+    inner: T,
+    bytes: u64,
+}
+
+impl<T> Counted<T> {
+    fn new(inner: T) -> Counted<T> {
+        Counted { inner, bytes: 0 }
+    }
+}
+
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.bytes += count as u64;
+        Ok(count)
+    }
+}
+
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.bytes += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The client end of a session, buffered, counted and possibly recorded, that can switch to TLS
+struct ClientTransport<S: Stream> {
+    stream: tls::Stream<S>,
+    reader: BufReader<Counted<Box<dyn Read>>>,
+    writer: LineWriter<Counted<Box<dyn Write>>>,
+    tls: Option<Arc<tls::Acceptor>>,
+    max_message_size: Option<usize>,
+    transcript: bool,
+    lmtp: bool,
+    auth_required: bool,
+}
+
+impl<S: Stream> ClientTransport<S> {
+    fn new(
+        stream: S,
+        sessions: &Sessions,
+        recording: Option<&replay::Recording>,
+    ) -> ClientTransport<S> {
+        let stream = tls::Stream::new(stream);
+        // Recording happens above TLS, so recordings are in plain text
+        let (read_half, write_half): (Box<dyn Read>, Box<dyn Write>) = match recording {
+            Some(recording) => (
+                Box::new(recording.client(stream.clone())),
+                Box::new(recording.server(stream.clone())),
+            ),
+            None => (Box::new(stream.clone()), Box::new(stream.clone())),
+        };
+        ClientTransport {
+            stream,
+            reader: BufReader::with_capacity(sessions.buffer_size, Counted::new(read_half)),
+            // Send each reply with a single write instead of one for the text and one for the
+            // newline
+            writer: LineWriter::new(Counted::new(write_half)),
+            tls: sessions.tls.clone(),
+            max_message_size: sessions.max_message_size,
+            transcript: sessions.transcript,
+            lmtp: sessions.lmtp,
+            auth_required: sessions.auth_required,
+        }
+    }
+}
+
+impl<S: Stream> smtp::Transport for ClientTransport<S> {
+    fn reader(&mut self) -> &mut dyn BufRead {
+        &mut self.reader
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        &mut self.writer
+    }
+
+    fn can_start_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    fn keeps_transcript(&self) -> bool {
+        self.transcript
+    }
+
+    fn speaks_lmtp(&self) -> bool {
+        self.lmtp
+    }
+
+    fn requires_auth(&self) -> bool {
+        self.auth_required
+    }
+
+    fn start_tls(&mut self) -> io::Result<()> {
+        // Commands that came along with STARTTLS must not pass as encrypted ones
+        if !self.reader.buffer().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "client sent more after STARTTLS",
+            ));
+        }
+        self.writer.flush()?;
+        match &self.tls {
+            Some(acceptor) => self.stream.start_tls(acceptor),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS is not available",
+            )),
+        }
+    }
+}
+
+/// Handle a client connection, containing a panic to this one session.
+/// The calling thread, a worker or an acceptor, keeps running either way.
+fn handle_connection_isolated<S: Stream>(mut stream: S, sessions: &Sessions) {
+    let id = smtp::new_uuid();
+    // Everything logged during the session tells which one it is
+    let span = tracing::info_span!(
+        "session",
+        id = %id,
+        client = tracing::field::Empty,
+        proxy = tracing::field::Empty
+    );
+    let _entered = span.enter();
+    // Neither a silent proxy nor a silent client may hold on to the worker
+    if let Err(e) = stream.set_timeout(sessions.idle_timeout) {
+        tracing::warn!("Unable to set the idle timeout: {}", e);
+    }
+    let client = if sessions.proxy_protocol {
+        match proxy::read_header(&mut stream) {
+            Ok(client) => client,
+            Err(e) => {
+                span.record("client", tracing::field::display(stream.peer_address()));
+                tracing::warn!("Reading the PROXY protocol header failed: {}", e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    match client {
+        Some(client) => {
+            span.record("client", tracing::field::display(client));
+            span.record("proxy", tracing::field::display(stream.peer_address()));
+        }
+        None => {
+            span.record("client", tracing::field::display(stream.peer_address()));
+        }
+    }
+    // The connection counts against the limit of its client until its session ends, which is
+    // the client behind the proxy if there is one
+    let _slot = match (
+        &sessions.limits,
+        client.map(|client| client.ip()).or(stream.peer_ip()),
+    ) {
+        (Some(limits), Some(ip)) => match limits.connect(ip) {
+            Some(slot) => Some(slot),
+            None => {
+                reject_client(stream, ip);
+                return;
+            }
+        },
+        _ => None,
+    };
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        handle_connection(stream, sessions, id, client)
+    }));
+    if outcome.is_err() {
+        tracing::error!("Client session aborted by an internal error");
+    }
+}
+
+/// Describe a received message as JSON, without its content
+fn message_json(
+    session: &Session,
+    sender_domain: &str,
+    message: &smtp::Message,
+    server: Option<&str>,
+) -> serde_json::Value {
+    let mut object = serde_json::json!({
+        "id": message.get_id(),
+        "session": session.id,
+        "client": session.client_address,
+        "sender_domain": sender_domain,
+        "from": message.get_sender(),
+        "to": message.get_recipients(),
+        "size": message.get_size(),
+    });
+    if !message.get_tags().is_empty() {
+        object["tags"] = message.get_tags().into();
+    }
+    if !message.get_auth_results().is_empty() {
+        let results: serde_json::Map<String, serde_json::Value> = message
+            .get_auth_results()
+            .iter()
+            .map(|(method, result)| (method.clone(), result.as_str().into()))
+            .collect();
+        object["auth"] = results.into();
+    }
+    if let Some(server) = server {
+        object["server"] = server.into();
+    }
+    if session.connection.is_extended() {
+        object["ehlo"] = true.into();
+    }
+    if session.connection.is_lmtp() {
+        object["lmtp"] = true.into();
+    }
+    if let Some(proxy) = &session.proxy_address {
+        object["proxy"] = proxy.as_str().into();
+    }
+    let millis = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64)
+    };
+    object["connection"] = serde_json::json!({
+        "connected": millis(session.connected),
+        "disconnected": millis(session.disconnected),
+        "bytes_received": session.bytes_received,
+        "bytes_sent": session.bytes_sent,
+        "rejected": session.connection.get_rejected(),
+    });
+    if session.connection.is_encrypted() {
+        object["tls"] = true.into();
+    }
+    if let Some(user) = session.connection.get_user() {
+        object["user"] = user.into();
+    }
+    if let Some(transcript) = session.connection.get_transcript() {
+        object["transcript"] = transcript.into();
+    }
+    object
+}
+
+/// Print the results of the authenticity checks of a message on a line, if it was checked
+fn print_auth_results(out: &mut dyn Write, message: &smtp::Message) -> io::Result<()> {
+    let results = message.get_auth_results();
+    if results.is_empty() {
+        return Ok(());
+    }
+    let results: Vec<String> = results
+        .iter()
+        .map(|(method, result)| format!("{}={}", method, result))
+        .collect();
+    writeln!(out, "Authentication: {}", results.join(" "))
+}
+
+/// Print the decoded headers, the text body, or else the HTML body, and a line per attachment
+fn print_parsed(out: &mut dyn Write, message: &mime::ParsedMessage) -> io::Result<()> {
+    for (name, value) in &message.headers {
+        writeln!(out, "{}: {}", name, value)?;
+    }
+    writeln!(out)?;
+    if let Some(body) = message.text_body.as_ref().or(message.html_body.as_ref()) {
+        writeln!(out, "{}", body.strip_suffix('\n').unwrap_or(body))?;
+    }
+    for attachment in &message.attachments {
+        writeln!(
+            out,
+            "Attachment: {} ({}, {} bytes)",
+            attachment.filename.as_deref().unwrap_or("(no name)"),
+            attachment.content_type,
+            attachment.content.len()
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the messages received in a session on stdout, with the name of the virtual server
+/// that received them
+fn print_session(
+    session: &Session,
+    print: Print,
+    format: PrintFormat,
+    server: Option<&str>,
+) -> io::Result<()> {
+    let connection = &session.connection;
+    let (Some(sender_domain), Some(messages)) =
+        (connection.get_sender_domain(), connection.get_messages())
+    else {
+        return Ok(());
+    };
+
+    // Holding the lock keeps the output of a session together and saves locking for every line
+    let mut out = io::stdout().lock();
+    if format == PrintFormat::Jsonl {
+        for message in messages {
+            let mut object = message_json(session, sender_domain, message, server);
+            match print {
+                Print::Full => object["data"] = message.get_data().into(),
+                Print::Parsed => object["parsed"] = mime::parse(message.get_content()).to_json(),
+                _ => {}
+            }
+            writeln!(out, "{}", object)?;
+        }
+        return out.flush();
+    }
+
+    if print != Print::Summary {
+        if let Some(server) = server {
+            writeln!(out, "Server: {}", server)?;
+        }
+        if session.connection.is_encrypted() {
+            writeln!(out, "Client address: {} (TLS)", session.client_address)?;
+        } else {
+            writeln!(out, "Client address: {}", session.client_address)?;
+        }
+        writeln!(out, "Sender domain: {}", sender_domain)?;
+        if let Some(user) = connection.get_user() {
+            writeln!(out, "Logged in as: {}", user)?;
+        }
+    }
+    for message in messages {
+        if print == Print::Full {
+            writeln!(out, "Message from: {}", message.get_sender())?;
+            writeln!(out, "To: {}", message.get_recipients().join(", "))?;
+            print_auth_results(&mut out, message)?;
+            writeln!(out, "{}", message.get_data())?;
+        } else if print == Print::Parsed {
+            writeln!(out, "Message from: {}", message.get_sender())?;
+            writeln!(out, "To: {}", message.get_recipients().join(", "))?;
+            print_auth_results(&mut out, message)?;
+            print_parsed(&mut out, &mime::parse(message.get_content()))?;
+        } else {
+            if let Some(server) = server {
+                write!(out, "[{}] ", server)?;
+            }
+            writeln!(
+                out,
+                "{} {} -> {} ({} bytes)",
+                session.client_address,
+                message.get_sender(),
+                message.get_recipients().join(", "),
+                message.get_size()
+            )?;
+        }
+    }
+    out.flush()
+}
+
+/// Reject a client connection because all workers are busy.
+/// The client is told to come back later instead of being queued.
+fn reject_connection<S: Stream>(mut stream: S, active: usize, queued: usize) {
+    tracing::warn!(
+        client = %stream.peer_address(),
+        "Rejecting client connection: {} active, {} queued",
+        active,
+        queued
+    );
+    if let Err(e) = smtp::Connection::reject(&mut stream) {
+        tracing::warn!("Error communicating with client: {}", e);
+    }
+}
+
+/// Reject a client connection because its client has as many connections as allowed
+fn reject_client<S: Stream>(mut stream: S, ip: IpAddr) {
+    tracing::warn!(
+        "Rejecting client connection: too many connections from {}",
+        ip
+    );
+    if let Err(e) = writeln!(stream, "{}", limits::MSG_TOO_MANY_CONNECTIONS) {
+        tracing::warn!("Error communicating with client: {}", e);
+    }
+}
+
+/// Accept client connections on a listener and hand them to the worker pool.
+/// Connections beyond the pool size are rejected rather than queued without bound.
+/// Accept errors, e.g. running out of file descriptors, pause accepting with an increasing
+/// delay instead of ending the loop.
+fn serve<S: Stream>(
+    incoming: impl Iterator<Item = io::Result<S>>,
+    pool: ThreadPool,
+    sessions: Sessions,
+) {
+    let mut backoff = Duration::from_millis(0);
+    for stream_result in incoming {
+        match stream_result {
+            Ok(stream) => {
+                backoff = Duration::from_millis(0);
+                let drain = &sessions.drain;
+                // This is the connection that woke up the acceptor to shut down
+                if drain.stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                if drain.started.load(Ordering::SeqCst) {
+                    // A connection that still arrives while draining is the last one on this
+                    // listener, so it is handled right away instead of waiting for a worker
+                    drain.sessions.fetch_add(1, Ordering::SeqCst);
+                    handle_connection_isolated(stream, &sessions);
+                    drain.sessions.fetch_sub(1, Ordering::SeqCst);
+                    return;
+                }
+
+                let (active, queued) = (pool.active_count(), pool.queued_count());
+                if active + queued >= pool.max_count() {
+                    reject_connection(stream, active, queued);
+                    continue;
+                }
+                let sessions = sessions.clone();
+                pool.execute(move || handle_connection_isolated(stream, &sessions))
+            }
+            Err(_) if sessions.drain.started.load(Ordering::SeqCst) => return,
+            Err(e) => {
+                tracing::error!("Unable to handle client connection: {}", e);
+                backoff = (backoff * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF);
+                thread::sleep(backoff);
+            }
+        }
+    }
+}
+
+/// Delay after the first of consecutive accept errors
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+/// Longest delay between accept attempts that keep failing
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A bound listening socket
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Accept client connections until the listener fails
+    fn serve(self, pool: ThreadPool, sessions: Sessions) {
+        match self {
+            Listener::Tcp(listener) => serve(listener.incoming(), pool, sessions),
+            #[cfg(unix)]
+            Listener::Unix(listener) => serve(listener.incoming(), pool, sessions),
+        }
+    }
+
+    /// Take ownership of a listening TCP or unix domain socket
+    #[cfg(unix)]
+    unsafe fn from_raw_fd(fd: RawFd) -> Listener {
+        // A TCP listener can only report its local address for an internet socket
+        let tcp_listener = TcpListener::from_raw_fd(fd);
+        if tcp_listener.local_addr().is_ok() {
+            Listener::Tcp(tcp_listener)
+        } else {
+            Listener::Unix(UnixListener::from_raw_fd(tcp_listener.into_raw_fd()))
+        }
+    }
+
+    /// Describe the address the listener is bound to, with the actual port if port 0 was bound
+    fn local_address(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => address.to_string(),
+                Err(e) => format!("unknown ({})", e),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener.local_addr() {
+                Ok(address) => match address.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix".to_string(),
+                },
+                Err(e) => format!("unknown ({})", e),
+            },
+        }
+    }
+
+    #[cfg(unix)]
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// A running server
+struct Server {
+    pool: ThreadPool,
+    drain: Arc<Drain>,
+    /// The acceptor threads, which only finish if their listener fails or on shutdown
+    acceptors: Vec<JoinHandle<()>>,
+    broadcaster: Arc<Broadcaster<Arc<Session>>>,
+    /// The threads passing completed sessions on, which finish once the broadcaster is closed
+    sinks: Vec<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Stop accepting client connections on the given listener descriptors.
+    /// Acceptor threads that are blocked waiting for a connection still take one more.
+    #[cfg(unix)]
+    fn stop_accepting(&self, listener_fds: &[RawFd]) -> io::Result<()> {
+        self.drain.started.store(true, Ordering::SeqCst);
+
+        // Replacing the descriptors makes further accept calls fail without touching the
+        // sockets themselves, which may be shared with another process
+        let placeholder = std::fs::File::open("/dev/null")?;
+        for fd in listener_fds {
+            if unsafe { libc::dup2(placeholder.as_raw_fd(), *fd) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait until all accepted client connections have been handled, or until the deadline.
+    /// Returns whether they all were.
+    fn drain(&self, deadline: Option<Instant>) -> bool {
+        while self.pool.active_count()
+            + self.pool.queued_count()
+            + self.drain.sessions.load(Ordering::SeqCst)
+            > 0
+        {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
+
+    /// Let the sinks finish with the sessions completed so far, or wait until the deadline.
+    /// Sessions completed afterwards are not passed on. Returns whether all sinks finished.
+    fn flush(&self, deadline: Option<Instant>) -> bool {
+        self.broadcaster.close();
+        while !self.sinks.iter().all(JoinHandle::is_finished) {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
+}
+
+/// Bind a unix domain socket, replacing a stale socket file left behind by an earlier run
+#[cfg(unix)]
+fn bind_unix_socket(path: &str) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path)
+}
+
+/// Bind a TCP address of a configuration. The IPv6 wildcard address binds IPv6 only if the
+/// configuration binds an IPv4 address on the same port, which a dual stack socket would take.
+fn bind_tcp(address: &str, addresses: &[String]) -> io::Result<TcpListener> {
+    #[cfg(unix)]
+    if let Ok(SocketAddr::V6(ipv6)) = address.parse::<SocketAddr>() {
+        let port = ipv6.port();
+        let ipv4_on_port = |other: &String| {
+            std::net::ToSocketAddrs::to_socket_addrs(other).is_ok_and(|mut resolved| {
+                resolved.any(|resolved| resolved.is_ipv4() && resolved.port() == port)
+            })
+        };
+        if ipv6.ip().is_unspecified() && port != 0 && addresses.iter().any(ipv4_on_port) {
+            return bind_ipv6_only(ipv6);
+        }
+    }
+    TcpListener::bind(address)
+}
+
+/// Bind an IPv6 address without accepting IPv4 clients, which std cannot do
+#[cfg(unix)]
+fn bind_ipv6_only(address: std::net::SocketAddrV6) -> io::Result<TcpListener> {
+    use std::mem;
+
+    let check = |result: libc::c_int| match result {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    };
+    let enable: libc::c_int = 1;
+    let set_option = |fd, level, name| {
+        check(unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })
+    };
+
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_STREAM, 0) };
+    check(fd)?;
+    // Owned right away, so that the socket is closed on errors
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    signals::set_close_on_exec(fd)?;
+    // Like std, so that a restarted server can bind while old connections linger
+    set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+    set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)?;
+
+    let mut sockaddr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sockaddr.sin6_port = address.port().to_be();
+    sockaddr.sin6_flowinfo = address.flowinfo();
+    sockaddr.sin6_addr.s6_addr = address.ip().octets();
+    sockaddr.sin6_scope_id = address.scope_id();
+    check(unsafe {
+        libc::bind(
+            fd,
+            &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    })?;
+    check(unsafe { libc::listen(fd, 128) })?;
+    Ok(listener)
+}
+
+/// Bind a listener that is not handed over to a process taking over, like the web UI's. Binding
+/// is retried until it succeeds, so the new process gets the address once the old one is gone.
+fn bind_retrying(address: &str, purpose: &str) -> TcpListener {
+    let mut logged = false;
+    loop {
+        match TcpListener::bind(address) {
+            Ok(listener) => return listener,
+            Err(e) if !logged => {
+                tracing::warn!("Binding {} to {} failed, retrying: {}", purpose, address, e);
+                logged = true;
+            }
+            Err(_) => {}
+        }
+        thread::sleep(BIND_RETRY_DELAY);
+    }
+}
+
+/// Delay between attempts to bind a listener that is not handed over
+const BIND_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Bind the listeners of the main server and the virtual servers, in the order of the
+/// configurations
+fn bind_servers(configs: &[Config]) -> Result<Vec<Vec<Listener>>, Error> {
+    // A previous server process hands over the sockets of all servers in this order
+    #[cfg(unix)]
+    if handoff::is_taking_over() && configs.len() > 1 {
+        let mut inherited = handoff::take_listeners().into_iter();
+        let counts: Vec<usize> = configs
+            .iter()
+            .map(|config| config.bind_addresses.len() + config.socket_paths.len())
+            .collect();
+        let expected = counts.iter().sum();
+        if inherited.len() != expected {
+            return Err(Error::Handoff {
+                expected,
+                received: inherited.len(),
+            });
+        }
+        return Ok(counts
+            .into_iter()
+            .map(|count| inherited.by_ref().take(count).collect())
+            .collect());
+    }
+
+    let mut servers = vec![bind_listeners(&configs[0])?];
+    for config in &configs[1..] {
+        servers.push(bind_configured(config)?);
+    }
+    Ok(servers)
+}
+
+/// Bind the configured listeners, or take over the ones passed in by socket activation
+fn bind_listeners(config: &Config) -> Result<Vec<Listener>, Error> {
+    // Sockets handed over by a previous server process or passed in by socket activation
+    // replace the configured ones
+    #[cfg(unix)]
+    {
+        let mut listeners = handoff::take_listeners();
+        if listeners.is_empty() {
+            listeners = systemd::take_listeners();
+        }
+        if !listeners.is_empty() {
+            return Ok(listeners);
+        }
+    }
+    bind_configured(config)
+}
+
+/// Bind the configured listeners
+fn bind_configured(config: &Config) -> Result<Vec<Listener>, Error> {
+    let mut listeners = Vec::new();
+    for address in &config.bind_addresses {
+        let listener = bind_tcp(address, &config.bind_addresses).map_err(|source| Error::Bind {
+            address: address.clone(),
+            source,
+        })?;
+        listeners.push(Listener::Tcp(listener));
+    }
+
+    #[cfg(unix)]
+    for path in &config.socket_paths {
+        let listener = bind_unix_socket(path).map_err(|source| Error::Bind {
+            address: path.clone(),
+            source,
+        })?;
+        listeners.push(Listener::Unix(listener));
+    }
+    #[cfg(not(unix))]
+    if !config.socket_paths.is_empty() {
+        return Err(Error::Unsupported(
+            "Unix domain sockets are not supported on this platform",
+        ));
+    }
+
+    Ok(listeners)
+}
+
+/// Report the bound addresses on stdout, in the port file and on the ready descriptor, so that
+/// whoever started the server can find out the ports chosen for port 0.
+/// The addresses of virtual servers are reported with the name of the server.
+fn announce_listeners(
+    configs: &[Config],
+    listeners: &[Vec<Listener>],
+    announce_ready: bool,
+) -> Result<(), Error> {
+    let mut content = String::new();
+    for (config, listeners) in configs.iter().zip(listeners) {
+        for address in listeners.iter().map(Listener::local_address) {
+            match (config.print_format, &config.name) {
+                (PrintFormat::Text, None) => println!("Listening on {}", address),
+                (PrintFormat::Text, Some(name)) => {
+                    println!("Listening on {} for server {}", address, name)
+                }
+                (PrintFormat::Jsonl, None) => {
+                    println!("{}", serde_json::json!({ "listening": address }))
+                }
+                (PrintFormat::Jsonl, Some(name)) => println!(
+                    "{}",
+                    serde_json::json!({ "listening": address, "server": name })
+                ),
+            }
+
+            match &config.name {
+                None => content += &format!("{}\n", address),
+                Some(name) => content += &format!("{} {}\n", name, address),
+            }
+        }
+    }
+
+    let config = &configs[0];
+    if let Some(path) = &config.port_file {
+        // Readers polling for the file never see it half written
+        let partial_path = format!("{}.partial", path);
+        std::fs::write(&partial_path, &content)
+            .and_then(|()| std::fs::rename(&partial_path, path))
+            .map_err(Error::io("Writing the port file"))?;
+    }
+    if let Some(fd) = config.ready_fd.filter(|_| announce_ready) {
+        #[cfg(unix)]
+        {
+            // The descriptor was passed in to be written once and closed
+            let mut ready = unsafe { std::fs::File::from_raw_fd(fd) };
+            ready
+                .write_all(content.as_bytes())
+                .map_err(Error::io("Writing to the ready descriptor"))?;
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = fd;
+            return Err(Error::Unsupported(
+                "Ready descriptors are not supported on this platform",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Start accepting client connections on all listeners of a server
+fn start(config: &Config, listeners: Vec<Listener>) -> Server {
+    // All listeners of a server share one pool, so the concurrency limit applies to the whole
+    // server. Virtual servers have their own pools and do not take workers from each other.
+    let pool = ThreadPool::new(config.concurrency);
+    let sessions = Sessions {
+        buffer_size: config.buffer_size,
+        max_message_size: config.max_message_size,
+        transcript: config.transcript,
+        lmtp: config.lmtp,
+        proxy_protocol: config.proxy_protocol,
+        idle_timeout: config.idle_timeout,
+        broadcaster: Arc::new(Broadcaster::new()),
+        drain: Arc::new(Drain::default()),
+        script: config.script.clone(),
+        rules: config.rules.clone(),
+        tls: config.tls.clone(),
+        auth: config.auth.clone(),
+        auth_required: config.auth_required,
+        tempfail: config.tempfail.clone(),
+        chaos: config.chaos.clone(),
+        limits: config.limits.clone(),
+        verify: config.verify.clone(),
+        record: config.record.clone(),
+        clock: config.clock.clone(),
+    };
+
+    let mut sinks = Vec::new();
+
+    // Printing happens on its own thread so that a slow stdout does not hold up the workers
+    if config.print != Print::None {
+        let (print, format) = (config.print, config.print_format);
+        let name = config.name.clone();
+        let printed = sessions.broadcaster.subscribe("Printing");
+        sinks.push(thread::spawn(move || {
+            for session in printed {
+                if let Err(e) = print_session(&session, print, format, name.as_deref()) {
+                    tracing::error!("Printing a session failed: {}", e);
+                }
+            }
+        }));
+    }
+
+    if let Some(relay) = &config.relay {
+        let pool = relay.pool.clone();
+        thread::spawn(move || pool.expire_idle());
+    }
+    // Relaying happens on its own thread too, so a slow upstream server does not hold up anyone
+    if let Some(relay) = config.relay.clone().filter(|relay| !relay.on_release) {
+        if relay.queue.is_some() {
+            let relay = relay.clone();
+            thread::spawn(move || relay::retry_queued(relay));
+        }
+        let relayed = sessions.broadcaster.subscribe("Relaying");
+        sinks.push(thread::spawn(move || relay::run(relay, relayed)));
+    }
+    if let Some(sink) = config.kafka.clone() {
+        let name = config.name.clone();
+        let published = sessions.broadcaster.subscribe("Publishing to Kafka");
+        sinks.push(thread::spawn(move || kafka::run(sink, name, published)));
+    }
+    if let Some(sink) = config.nats.clone() {
+        let name = config.name.clone();
+        let published = sessions.broadcaster.subscribe("Publishing to NATS");
+        sinks.push(thread::spawn(move || nats::run(sink, name, published)));
+    }
+    if let Some(sink) = config.amqp.clone() {
+        let name = config.name.clone();
+        let published = sessions.broadcaster.subscribe("Publishing to AMQP");
+        sinks.push(thread::spawn(move || amqp::run(sink, name, published)));
+    }
+    if let Some(sink) = config.mqtt.clone() {
+        let name = config.name.clone();
+        let published = sessions.broadcaster.subscribe("Publishing to MQTT");
+        sinks.push(thread::spawn(move || mqtt::run(sink, name, published)));
+    }
+    if let Some(script) = config
+        .script
+        .clone()
+        .filter(|script| script.handles_received)
+    {
+        let received = sessions.broadcaster.subscribe("The script");
+        sinks.push(thread::spawn(move || script::run(script, received)));
+    }
+    if let Some(store) = config.store.clone() {
+        let (clock, name) = (config.clock.clone(), config.name.clone());
+        let kept = sessions.broadcaster.subscribe("Keeping messages");
+        sinks.push(thread::spawn(move || store::keep(store, clock, name, kept)));
+    }
+    if let (Some(web), Some(store), Some(rules)) = (
+        config.web.clone(),
+        config.store.clone(),
+        config.rules.clone(),
+    ) {
+        let state = Arc::new(web::State {
+            store,
+            rules,
+            relay: config.relay.clone(),
+            clock: config.clock.clone(),
+            events: sessions.broadcaster.clone(),
+            name: config.name.clone(),
+        });
+        thread::spawn(move || web::run(web, state));
+    }
+    if let (Some(address), Some(store)) = (config.pop3.clone(), config.store.clone()) {
+        let credentials = config.auth.clone();
+        thread::spawn(move || pop3::run(address, store, credentials));
+    }
+    if let (Some(address), Some(store)) = (config.imap.clone(), config.store.clone()) {
+        let credentials = config.auth.clone();
+        thread::spawn(move || imap::run(address, store, credentials));
+    }
+    if let Some(notifications) = config.notifications.clone() {
+        let name = config.name.clone();
+        let notified = sessions.broadcaster.subscribe("Notifying");
+        sinks.push(thread::spawn(move || {
+            notify::run(notifications, name, notified)
+        }));
+    }
+    if let Some(hook) = config.exec.clone() {
+        let name = config.name.clone();
+        let executed = sessions.broadcaster.subscribe("The exec hook");
+        sinks.push(thread::spawn(move || exec::run(hook, name, executed)));
+    }
+    if let Some(webhook) = config.webhook.clone() {
+        let name = config.name.clone();
+        let posted = sessions.broadcaster.subscribe("The webhook");
+        sinks.push(thread::spawn(move || webhook::run(webhook, name, posted)));
+    }
+
+    let acceptors = listeners
+        .into_iter()
+        .map(|listener| {
+            let pool = pool.clone();
+            let sessions = sessions.clone();
+            thread::spawn(move || listener.serve(pool, sessions))
+        })
+        .collect();
+
+    Server {
+        pool,
+        drain: sessions.drain,
+        acceptors,
+        broadcaster: sessions.broadcaster,
+        sinks,
+    }
+}
+
+/// Wait for signals and hand the listening sockets of all servers over to a new server process
+/// on SIGUSR2, or shut down on SIGTERM and SIGINT. A second SIGTERM or SIGINT while shutting
+/// down exits right away.
+#[cfg(unix)]
+fn supervise(servers: Vec<(Server, Vec<RawFd>)>, shutdown_timeout: Duration) -> Result<(), Error> {
+    let listener_fds: Vec<RawFd> = servers
+        .iter()
+        .flat_map(|(_, fds)| fds.iter().cloned())
+        .collect();
+    let servers = Arc::new(servers);
+    let mut signals = signals::Signals::install(&[libc::SIGUSR2, libc::SIGTERM, libc::SIGINT])
+        .map_err(Error::io("Installing signal handlers"))?;
+    let mut shutting_down = false;
+
+    loop {
+        match signals.wait() {
+            Ok(libc::SIGUSR2) if !shutting_down => match handoff::hand_over(&listener_fds) {
+                Ok(()) => {
+                    tracing::info!("Listeners handed over, finishing active sessions");
+                    stop(&servers, None);
+                    process::exit(0);
+                }
+                Err(e) => tracing::error!("Handing over listeners failed: {}", e),
+            },
+            Ok(libc::SIGTERM | libc::SIGINT) if shutting_down => {
+                tracing::warn!("Exiting without waiting for active sessions");
+                process::exit(1);
+            }
+            Ok(libc::SIGTERM | libc::SIGINT) => {
+                tracing::info!("Shutting down, finishing active sessions");
+                shutting_down = true;
+                // Unlike after a handover, nobody else serves a connection that still arrives
+                for (server, _) in servers.iter() {
+                    server.drain.stopped.store(true, Ordering::SeqCst);
+                }
+                // Signals are still waited for meanwhile, so that a second one can cut it short
+                let servers = servers.clone();
+                let deadline = Instant::now() + shutdown_timeout;
+                thread::spawn(move || {
+                    process::exit(if stop(&servers, Some(deadline)) { 0 } else { 1 })
+                });
+            }
+            Ok(_) => {}
+            Err(e) => return Err(Error::io("Waiting for signals")(e)),
+        }
+    }
+}
+
+/// Stop accepting connections, then wait for the active sessions and the sinks to finish or
+/// until the deadline. Returns whether everything finished.
+#[cfg(unix)]
+fn stop(servers: &[(Server, Vec<RawFd>)], deadline: Option<Instant>) -> bool {
+    for (server, fds) in servers {
+        if let Err(e) = server.stop_accepting(fds) {
+            tracing::error!("Closing listeners failed: {}", e);
+        }
+    }
+    if !servers.iter().all(|(server, _)| server.drain(deadline)) {
+        tracing::warn!("Active sessions did not finish in time, exiting anyway");
+        return false;
+    }
+    if !servers.iter().all(|(server, _)| server.flush(deadline)) {
+        tracing::warn!("Passing on the last messages did not finish in time, exiting anyway");
+        return false;
+    }
+    true
+}
+
+/// Run the given command until the server stops or the command completes
+fn run(command: Command) -> Result<(), Error> {
+    let configs = match command {
+        Command::Serve(configs) => configs,
+        Command::Check(configs) => return check::run(&configs),
+        Command::Queue(configs) => {
+            return queue::run(&configs).map_err(Error::io("Reading the relay queue"));
+        }
+        Command::Messages(configs, action) => {
+            return store::run(&configs, &action).map_err(Error::io("Reading the kept messages"));
+        }
+        Command::Loadgen(options) => {
+            return loadgen::run(options).map_err(Error::io("Load generation"));
+        }
+        Command::Send(options) => {
+            return send::run(options).map_err(Error::io("Sending the message"));
+        }
+        Command::Replay(options) => {
+            return replay::run(options).map_err(Error::io("Replaying the sessions"));
+        }
+        Command::Mail(options) => {
+            return mail::run(options).map_err(Error::io("Talking to the web API"));
+        }
+        Command::Completions(target) => {
+            return completions::run(target).map_err(Error::io("Writing completions"));
+        }
+    };
+
+    #[cfg(windows)]
+    if let Some(command) = configs[0].service_command {
+        return winservice::execute(command, configs);
+    }
+
+    // Only the first process gets the ready descriptor, so a process taking over its sockets
+    // must not use it. Binding removes the sign of a takeover, so this is checked first.
+    #[cfg(unix)]
+    let announce_ready = !handoff::is_taking_over();
+    #[cfg(not(unix))]
+    let announce_ready = true;
+
+    let listeners = bind_servers(&configs)?;
+    // Before daemonizing, so the addresses still reach the starting process's stdout
+    announce_listeners(&configs, &listeners, announce_ready)?;
+
+    // The settings of the whole process are those of the main server
+    let config = &configs[0];
+    // Forking has to happen after binding, so bind errors are still visible, but before any
+    // threads are started
+    if config.daemon {
+        #[cfg(unix)]
+        daemon::daemonize(config.pid_file.as_deref(), config.log_file.as_deref())
+            .map_err(Error::io("Starting the daemon"))?;
+        #[cfg(not(unix))]
+        return Err(Error::Unsupported(
+            "Daemon mode is not supported on this platform",
+        ));
+    }
+
+    logging::init(&config.logging);
+
+    #[cfg(unix)]
+    {
+        let servers = configs
+            .iter()
+            .zip(listeners)
+            .map(|(config, listeners)| {
+                let fds = listeners.iter().map(Listener::as_raw_fd).collect();
+                (start(config, listeners), fds)
+            })
+            .collect();
+        handoff::notify_ready();
+        supervise(servers, config.shutdown_timeout)
+    }
+    #[cfg(not(unix))]
+    {
+        let servers: Vec<Server> = configs
+            .iter()
+            .zip(listeners)
+            .map(|(config, listeners)| start(config, listeners))
+            .collect();
+        for acceptor in servers.into_iter().flat_map(|server| server.acceptors) {
+            // Acceptors contain session panics, so a failed join leaves nothing to clean up
+            let _ = acceptor.join();
+        }
+        Ok(())
+    }
+}
+
+/// Run the command given on the command line, exiting the process if it fails
+pub fn run_cli() {
+    match parse_args().and_then(run) {
+        Ok(()) => {}
+        // Like the errors clap finds itself
+        Err(Error::Settings(e)) => e.exit(),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Limits on what a single client can take up, so that one misbehaving client cannot starve the
+//! others.
+//!
+//! Connections are counted per client IP address when they are accepted, and rejected with 421
+//! beyond the limit. Within a session, a client that sends commands faster than allowed is
+//! disconnected with 421, and recipients beyond the limit of a message are rejected with 452.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::smtp::{self, Interference, Message, Stage, Verdict, MAIL_START};
+
+/// Reply to connections beyond the limit of their client
+pub const MSG_TOO_MANY_CONNECTIONS: &str = "421 Too many connections from your address";
+const MSG_TOO_MANY_COMMANDS: &str = "421 Too many commands per second, closing connection";
+const MSG_TOO_MANY_RECIPIENTS: &str = "452 Too many recipients";
+
+/// The limits of a server and the connections counted against them
+pub struct Limits {
+    pub connections_per_ip: Option<usize>,
+    pub commands_per_second: Option<u32>,
+    /// Recipients per message
+    pub recipients: Option<usize>,
+    /// Open connections by client address
+    connections: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl Limits {
+    pub fn new(
+        connections_per_ip: Option<usize>,
+        commands_per_second: Option<u32>,
+        recipients: Option<usize>,
+    ) -> Limits {
+        Limits {
+            connections_per_ip,
+            commands_per_second,
+            recipients,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a connection from a client address, unless there are as many as allowed already.
+    /// The connection counts until the returned slot is dropped.
+    pub fn connect(self: &Arc<Self>, ip: IpAddr) -> Option<Slot> {
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(ip).or_insert(0);
+        if self.connections_per_ip.is_some_and(|limit| *count >= limit) {
+            return None;
+        }
+        *count += 1;
+        Some(Slot {
+            limits: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// A counted connection
+pub struct Slot {
+    limits: Arc<Limits>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut connections = self.limits.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Enforces the limits within a session
+pub struct Policy {
+    limits: Arc<Limits>,
+    /// Start of the current second of commands and the commands in it so far
+    second: (Instant, u32),
+    /// Recipients of the current message so far
+    recipients: usize,
+}
+
+impl Policy {
+    pub fn new(limits: Arc<Limits>) -> Policy {
+        Policy {
+            limits,
+            second: (Instant::now(), 0),
+            recipients: 0,
+        }
+    }
+}
+
+impl smtp::Policy for Policy {
+    fn check_recipient(&mut self, _sender: &str, _recipient: &str) -> Option<String> {
+        // This policy comes last, so a recipient it lets through is accepted
+        if self
+            .limits
+            .recipients
+            .is_some_and(|limit| self.recipients >= limit)
+        {
+            return Some(MSG_TOO_MANY_RECIPIENTS.to_string());
+        }
+        self.recipients += 1;
+        None
+    }
+
+    fn check_message(&mut self, _message: &mut Message) -> Verdict {
+        Verdict::Accept
+    }
+
+    fn interfere(&mut self, stage: &Stage) -> Interference {
+        let Stage::Command(line) = stage else {
+            return Interference::Proceed;
+        };
+        if line.starts_with(MAIL_START) {
+            self.recipients = 0;
+        }
+        if let Some(limit) = self.limits.commands_per_second {
+            let now = Instant::now();
+            if now.duration_since(self.second.0) >= Duration::from_secs(1) {
+                self.second = (now, 0);
+            }
+            self.second.1 += 1;
+            if self.second.1 > limit {
+                return Interference::Close(MSG_TOO_MANY_COMMANDS.to_string());
+            }
+        }
+        Interference::Proceed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::io::BufReader;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn limit_connections_per_ip() {
+        // Given
+        let limits = Arc::new(Limits::new(Some(1), None, None));
+        let (first, second) = (
+            IpAddr::from(Ipv4Addr::LOCALHOST),
+            IpAddr::from([10, 0, 0, 1]),
+        );
+
+        // When
+        let slot = limits.connect(first);
+
+        // Then
+        assert!(slot.is_some());
+        assert!(limits.connect(first).is_none());
+        assert!(limits.connect(second).is_some());
+        drop(slot);
+        assert!(limits.connect(first).is_some());
+    }
+
+    #[test]
+    fn limit_recipients_and_commands() {
+        // Given
+        let request = "HELO localhost\n\
+                       MAIL FROM: tester@localhost\n\
+                       RCPT TO: first@localhost\n\
+                       RCPT TO: second@localhost\n\
+                       DATA\n\
+                       Hello\n\
+                       .\n\
+                       MAIL FROM: tester@localhost\n\
+                       RCPT TO: third@localhost\n\
+                       QUIT\n";
+        let mut policy = Policy::new(Arc::new(Limits::new(None, Some(6), Some(1))));
+        let mut response = Vec::new();
+
+        // When
+        let result = Connection::handle_with_policy(
+            &mut BufReader::new(request.as_bytes()),
+            &mut response,
+            &mut policy,
+        );
+
+        // Then
+        assert!(result.is_err());
+        let response = String::from_utf8(response).unwrap();
+        let replies: Vec<&str> = response.lines().collect();
+        assert_eq!(replies[4], MSG_TOO_MANY_RECIPIENTS);
+        assert_eq!(replies[7], "250 OK");
+        assert_eq!(replies[8], MSG_TOO_MANY_COMMANDS);
+    }
+}
@@ -0,0 +1,317 @@
+//! Load generation against an SMTP server.
+//!
+//! Every message is sent in its own session, so the measured latency covers connecting,
+//! the complete mail transaction and QUIT.
+
+use std::fs;
+use std::io::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::client::Client;
+
+/// Name of the subcommand
+pub const SUBCOMMAND_NAME: &str = "loadgen";
+
+const TARGET_ARG_NAME: &str = "target";
+const RATE_ARG_NAME: &str = "rate";
+const CONCURRENCY_ARG_NAME: &str = "concurrency";
+const COUNT_ARG_NAME: &str = "count";
+const SIZE_ARG_NAME: &str = "size";
+const TEMPLATE_ARG_NAME: &str = "template";
+const FROM_ARG_NAME: &str = "from";
+const TO_ARG_NAME: &str = "to";
+
+/// Placeholder in message templates that is replaced with the number of the message
+const MESSAGE_NUMBER_PLACEHOLDER: &str = "{n}";
+
+/// Maximum length of generated body lines
+const LINE_LENGTH: usize = 76;
+
+pub struct Options {
+    pub target: String,
+    /// Messages per second over all workers, unlimited if zero
+    pub rate: f64,
+    pub concurrency: usize,
+    pub count: usize,
+    /// Size of generated message bodies in bytes
+    pub size: usize,
+    /// File with the message content to send instead of a generated message
+    pub template: Option<String>,
+    pub sender: String,
+    pub recipient: String,
+}
+
+/// The command line definition of the subcommand
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Send messages to an SMTP server and report latencies")
+        .arg(
+            Arg::with_name(TARGET_ARG_NAME)
+                .long(TARGET_ARG_NAME)
+                .help("Address of the server as host:port")
+                .default_value("localhost:2525"),
+        )
+        .arg(
+            Arg::with_name(RATE_ARG_NAME)
+                .long(RATE_ARG_NAME)
+                .help("Messages per second, 0 for as fast as possible")
+                .default_value("0")
+                .validator(crate::validate_number::<f64>),
+        )
+        .arg(
+            Arg::with_name(CONCURRENCY_ARG_NAME)
+                .long(CONCURRENCY_ARG_NAME)
+                .help("Number of concurrent sessions")
+                .default_value("10")
+                .validator(crate::validate_positive),
+        )
+        .arg(
+            Arg::with_name(COUNT_ARG_NAME)
+                .long(COUNT_ARG_NAME)
+                .help("Number of messages to send")
+                .default_value("1000")
+                .validator(crate::validate_number::<usize>),
+        )
+        .arg(
+            Arg::with_name(SIZE_ARG_NAME)
+                .long(SIZE_ARG_NAME)
+                .help("Body size in bytes of generated messages")
+                .default_value("1024")
+                .validator(crate::validate_number::<usize>),
+        )
+        .arg(
+            Arg::with_name(TEMPLATE_ARG_NAME)
+                .long(TEMPLATE_ARG_NAME)
+                .help("File with the message to send, {n} is replaced with the message number")
+                .takes_value(true)
+                .conflicts_with(SIZE_ARG_NAME),
+        )
+        .arg(
+            Arg::with_name(FROM_ARG_NAME)
+                .long(FROM_ARG_NAME)
+                .help("Sender address")
+                .default_value("loadgen@localhost"),
+        )
+        .arg(
+            Arg::with_name(TO_ARG_NAME)
+                .long(TO_ARG_NAME)
+                .help("Recipient address")
+                .default_value("sink@localhost"),
+        )
+}
+
+/// Get the options from the parsed subcommand arguments
+pub fn options(matches: &ArgMatches) -> Options {
+    Options {
+        target: matches.value_of(TARGET_ARG_NAME).unwrap().to_string(),
+        rate: matches.value_of(RATE_ARG_NAME).unwrap().parse().unwrap(),
+        concurrency: matches
+            .value_of(CONCURRENCY_ARG_NAME)
+            .unwrap()
+            .parse()
+            .unwrap(),
+        count: matches.value_of(COUNT_ARG_NAME).unwrap().parse().unwrap(),
+        size: matches.value_of(SIZE_ARG_NAME).unwrap().parse().unwrap(),
+        template: matches.value_of(TEMPLATE_ARG_NAME).map(str::to_string),
+        sender: matches.value_of(FROM_ARG_NAME).unwrap().to_string(),
+        recipient: matches.value_of(TO_ARG_NAME).unwrap().to_string(),
+    }
+}
+
+/// The outcome of one worker
+#[derive(Default)]
+struct WorkerResult {
+    latencies: Vec<Duration>,
+    failures: usize,
+    first_error: Option<String>,
+}
+
+/// Send the configured number of messages and print a report on stdout
+pub fn run(options: Options) -> Result<(), Error> {
+    let template = match &options.template {
+        Some(path) => String::from_utf8_lossy(&fs::read(path)?).into_owned(),
+        None => generate_template(&options),
+    };
+
+    let options = Arc::new(options);
+    let template = Arc::new(template);
+    let next_message = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|_| {
+            let options = Arc::clone(&options);
+            let template = Arc::clone(&template);
+            let next_message = Arc::clone(&next_message);
+            thread::spawn(move || work(&options, &template, &next_message, start))
+        })
+        .collect();
+
+    let mut result = WorkerResult::default();
+    for worker in workers {
+        let worker_result = worker.join().unwrap();
+        result.latencies.extend(worker_result.latencies);
+        result.failures += worker_result.failures;
+        result.first_error = result.first_error.or(worker_result.first_error);
+    }
+    let elapsed = start.elapsed();
+
+    print_report(&mut result, elapsed);
+    Ok(())
+}
+
+fn work(
+    options: &Options,
+    template: &str,
+    next_message: &AtomicUsize,
+    start: Instant,
+) -> WorkerResult {
+    let mut result = WorkerResult::default();
+    let recipients = [options.recipient.clone()];
+
+    loop {
+        let number = next_message.fetch_add(1, Ordering::SeqCst);
+        if number >= options.count {
+            return result;
+        }
+
+        // Messages are spread evenly over time when the rate is limited
+        if options.rate > 0.0 {
+            let due = start + Duration::from_secs_f64(number as f64 / options.rate);
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+
+        let data = template.replace(MESSAGE_NUMBER_PLACEHOLDER, &number.to_string());
+        let sent = Instant::now();
+        let outcome = Client::connect(&options.target, "loadgen").and_then(|mut client| {
+            client.send(&options.sender, &recipients, data.as_bytes())?;
+            client.quit()
+        });
+
+        match outcome {
+            Ok(()) => result.latencies.push(sent.elapsed()),
+            Err(e) => {
+                result.failures += 1;
+                if result.first_error.is_none() {
+                    result.first_error = Some(e.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Generate a message with a body of the configured size
+fn generate_template(options: &Options) -> String {
+    let mut message = format!(
+        "From: <{}>\r\nTo: <{}>\r\nSubject: Load test message {}\r\n\r\n",
+        options.sender, options.recipient, MESSAGE_NUMBER_PLACEHOLDER
+    );
+
+    let mut remaining = options.size;
+    while remaining > 0 {
+        let line_length = remaining.min(LINE_LENGTH);
+        message.push_str(&"x".repeat(line_length));
+        message.push_str("\r\n");
+        remaining -= line_length;
+    }
+    message
+}
+
+fn print_report(result: &mut WorkerResult, elapsed: Duration) {
+    let sent = result.latencies.len();
+    println!(
+        "Sent {} messages, {} failed in {:.2} s ({:.1} messages/s)",
+        sent,
+        result.failures,
+        elapsed.as_secs_f64(),
+        sent as f64 / elapsed.as_secs_f64()
+    );
+
+    if sent > 0 {
+        result.latencies.sort();
+        println!(
+            "Latency: p50 {}, p90 {}, p99 {}, max {}",
+            format_ms(percentile(&result.latencies, 50)),
+            format_ms(percentile(&result.latencies, 90)),
+            format_ms(percentile(&result.latencies, 99)),
+            format_ms(result.latencies[sent - 1])
+        );
+    }
+    if let Some(error) = &result.first_error {
+        println!("First error: {}", error);
+    }
+}
+
+/// Get a percentile from sorted, non-empty latencies
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100);
+    sorted[rank.max(1) - 1]
+}
+
+fn format_ms(duration: Duration) -> String {
+    format!("{:.2} ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+
+    #[test]
+    fn send_numbered_messages() {
+        // Given
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let options = Options {
+            target: listener.local_addr().unwrap().to_string(),
+            rate: 0.0,
+            concurrency: 1,
+            count: 3,
+            size: 100,
+            template: None,
+            sender: "loadgen@localhost".to_string(),
+            recipient: "sink@localhost".to_string(),
+        };
+        let server = thread::spawn(move || {
+            let mut subjects = Vec::new();
+            for _ in 0..3 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let result = Connection::handle(&mut reader, &mut stream).unwrap();
+                let data = result.get_messages().unwrap()[0].get_data();
+                subjects.push(data.lines().nth(2).unwrap().to_string());
+            }
+            subjects
+        });
+
+        // When
+        let template = generate_template(&options);
+        let result = work(&options, &template, &AtomicUsize::new(0), Instant::now());
+
+        // Then
+        assert_eq!((result.latencies.len(), result.failures), (3, 0));
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                "Subject: Load test message 0",
+                "Subject: Load test message 1",
+                "Subject: Load test message 2",
+            ]
+        );
+        // A full line and the rest
+        let body = format!("\r\n\r\n{}\r\n{}\r\n", "x".repeat(76), "x".repeat(24));
+        assert!(template.ends_with(&body));
+        let latencies: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50), Duration::from_millis(5));
+        assert_eq!(percentile(&latencies, 99), Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,65 @@
+//! Logging of the server with [tracing](https://docs.rs/tracing), on stderr as text or as JSON
+//! lines.
+//!
+//! Every SMTP session has a span with its ID and the client address, so the lines of concurrent
+//! sessions can be told apart. At the debug level, the commands and replies of every session are
+//! logged as well. Applications that embed the server install their own subscriber instead.
+
+use std::io::{self, IsTerminal};
+
+use tracing::level_filters::LevelFilter;
+
+/// How log lines are written
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// For people
+    Text,
+    /// One JSON object per line, with the fields of the session span
+    Json,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 2] = ["text", "json"];
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::Json => "json",
+        }
+    }
+}
+
+/// The logging settings of the process
+pub struct Logging {
+    /// The most verbose level that is logged
+    pub level: LevelFilter,
+    pub format: Format,
+}
+
+/// The level names, from the least to the most verbose
+pub const LEVEL_NAMES: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+/// Log on stderr with the settings from now on
+pub fn init(logging: &Logging) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(logging.level)
+        .with_writer(io::stderr)
+        // Log files of the daemon get no colors
+        .with_ansi(io::stderr().is_terminal());
+    let result = match logging.format {
+        Format::Text => builder.try_init(),
+        Format::Json => builder.json().try_init(),
+    };
+    // Only fails if a subscriber was installed before, which then gets the events
+    if let Err(e) = result {
+        eprintln!("Setting up logging failed: {}", e);
+    }
+}
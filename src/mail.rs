@@ -0,0 +1,370 @@
+//! A client for the web API of a running server, so that shell scripts of tests can list, print
+//! and remove the kept messages without curl and jq.
+//!
+//! Lists are printed as a table, or as the JSON of the API with `--json`. `get` prints a message
+//! as received, or its details as JSON.
+
+use std::io::{self, Error, ErrorKind, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_json::Value;
+
+use crate::http::{self, Url};
+
+/// Name of the subcommand
+pub const SUBCOMMAND_NAME: &str = "mail";
+
+const LIST_SUBCOMMAND_NAME: &str = "list";
+const GET_SUBCOMMAND_NAME: &str = "get";
+const DELETE_SUBCOMMAND_NAME: &str = "delete";
+const WIPE_SUBCOMMAND_NAME: &str = "wipe";
+const SEARCH_SUBCOMMAND_NAME: &str = "search";
+
+const URL_ARG_NAME: &str = "url";
+const TOKEN_ARG_NAME: &str = "api-token";
+const JSON_ARG_NAME: &str = "json";
+const INBOX_ARG_NAME: &str = "inbox";
+const LIMIT_ARG_NAME: &str = "limit";
+const ID_ARG_NAME: &str = "id";
+const TERMS_ARG_NAME: &str = "terms";
+
+pub struct Options {
+    /// Where the web API is served
+    pub url: Url,
+    /// Token of the web API, if it needs one
+    pub token: Option<String>,
+    /// Print the JSON of the API instead of a table or a message
+    pub json: bool,
+    pub action: Action,
+}
+
+/// What to do with the kept messages
+pub enum Action {
+    /// List the messages, in an inbox if there is one, that match the query of `/api/messages`
+    List {
+        inbox: Option<String>,
+        query: String,
+    },
+    Get(String),
+    Delete(String),
+    /// Remove all messages, or those in an inbox
+    Wipe(Option<String>),
+}
+
+/// The command line definition of the subcommand
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    let inbox = || {
+        Arg::with_name(INBOX_ARG_NAME)
+            .long(INBOX_ARG_NAME)
+            .help("Recipient address or +tag of the inbox to take, instead of all messages")
+            .takes_value(true)
+    };
+    let limit = || {
+        Arg::with_name(LIMIT_ARG_NAME)
+            .long(LIMIT_ARG_NAME)
+            .help("Most messages to list, newest first")
+            .default_value("100")
+            .validator(crate::validate_positive)
+    };
+    let id = || {
+        Arg::with_name(ID_ARG_NAME)
+            .help("ID of the message")
+            .required(true)
+    };
+    SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("List, print or remove the messages kept by a server with --web")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name(URL_ARG_NAME)
+                .long(URL_ARG_NAME)
+                .help("URL of the web UI of the server")
+                .default_value("http://localhost:8025")
+                .validator(crate::validate_url)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name(TOKEN_ARG_NAME)
+                .long(TOKEN_ARG_NAME)
+                .help("Token that the server was given with --api-token")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name(JSON_ARG_NAME)
+                .long(JSON_ARG_NAME)
+                .help("Print the JSON of the web API instead of a table or a message")
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name(LIST_SUBCOMMAND_NAME)
+                .about("List the kept messages as a table")
+                .arg(inbox())
+                .arg(limit()),
+        )
+        .subcommand(
+            SubCommand::with_name(SEARCH_SUBCOMMAND_NAME)
+                .about("List the kept messages whose envelope or content contains the terms")
+                .arg(
+                    Arg::with_name(TERMS_ARG_NAME)
+                        .help("Terms to search for, ignoring case")
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(inbox())
+                .arg(limit()),
+        )
+        .subcommand(
+            SubCommand::with_name(GET_SUBCOMMAND_NAME)
+                .about("Print a message as received")
+                .arg(id()),
+        )
+        .subcommand(
+            SubCommand::with_name(DELETE_SUBCOMMAND_NAME)
+                .about("Remove a message")
+                .arg(id()),
+        )
+        .subcommand(
+            SubCommand::with_name(WIPE_SUBCOMMAND_NAME)
+                .about("Remove all kept messages")
+                .arg(inbox()),
+        )
+}
+
+/// Get the options from the parsed subcommand arguments
+pub fn options(matches: &ArgMatches) -> Options {
+    let (name, action) = matches.subcommand();
+    let action = action.expect("a subcommand is required");
+    let inbox = || action.value_of(INBOX_ARG_NAME).map(str::to_string);
+    let limit = || format!("limit={}", action.value_of(LIMIT_ARG_NAME).unwrap());
+    let id = || action.value_of(ID_ARG_NAME).unwrap().to_string();
+    // Global arguments before the subcommand are only in the matches of this one
+    let global = |name| match action.occurrences_of(name) {
+        0 => matches,
+        _ => action,
+    };
+    Options {
+        url: Url::parse(global(URL_ARG_NAME).value_of(URL_ARG_NAME).unwrap()).unwrap(),
+        token: global(TOKEN_ARG_NAME)
+            .value_of(TOKEN_ARG_NAME)
+            .map(str::to_string),
+        json: global(JSON_ARG_NAME).is_present(JSON_ARG_NAME),
+        action: match name {
+            LIST_SUBCOMMAND_NAME => Action::List {
+                inbox: inbox(),
+                query: limit(),
+            },
+            SEARCH_SUBCOMMAND_NAME => {
+                let terms: Vec<&str> = action.values_of(TERMS_ARG_NAME).unwrap().collect();
+                Action::List {
+                    inbox: inbox(),
+                    query: format!("search={}&{}", encode(&terms.join(" ")), limit()),
+                }
+            }
+            GET_SUBCOMMAND_NAME => Action::Get(id()),
+            DELETE_SUBCOMMAND_NAME => Action::Delete(id()),
+            WIPE_SUBCOMMAND_NAME => Action::Wipe(inbox()),
+            name => unreachable!("unknown subcommand {}", name),
+        },
+    }
+}
+
+pub fn run(options: Options) -> Result<(), Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let mut out = io::stdout().lock();
+    execute(&options, now, &mut out)?;
+    out.flush()
+}
+
+/// Carry out the action and write what comes of it
+fn execute(options: &Options, now: u64, out: &mut dyn Write) -> Result<(), Error> {
+    match &options.action {
+        Action::List { inbox, query } => {
+            let path = match inbox {
+                Some(inbox) => format!("/inboxes/{}/messages?{}", encode(inbox), query),
+                None => format!("/api/messages?{}", query),
+            };
+            let body = request(options, "GET", &path)?;
+            if options.json {
+                out.write_all(&body)?;
+                return writeln!(out);
+            }
+            let messages: Value =
+                serde_json::from_slice(&body).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            write_table(out, messages.as_array().map_or(&[], Vec::as_slice), now)
+        }
+        Action::Get(id) => {
+            let path = if options.json {
+                format!("/api/messages/{}", encode(id))
+            } else {
+                format!("/api/messages/{}/raw", encode(id))
+            };
+            out.write_all(&message_request(options, "GET", &path, id)?)
+        }
+        Action::Delete(id) => {
+            message_request(
+                options,
+                "DELETE",
+                &format!("/api/messages/{}", encode(id)),
+                id,
+            )?;
+            writeln!(out, "Removed {}", id)
+        }
+        Action::Wipe(inbox) => {
+            let path = match inbox {
+                Some(inbox) => format!("/inboxes/{}", encode(inbox)),
+                None => "/api/messages".to_string(),
+            };
+            let body = request(options, "DELETE", &path)?;
+            if options.json {
+                out.write_all(&body)?;
+                return writeln!(out);
+            }
+            let wiped: Value =
+                serde_json::from_slice(&body).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            writeln!(out, "Removed {} messages", wiped["removed"])
+        }
+    }
+}
+
+/// Send a request to the web API and get the body of the response
+fn request(options: &Options, method: &str, path: &str) -> Result<Vec<u8>, Error> {
+    let authorization = options
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token));
+    let headers: Vec<(&str, &str)> = authorization
+        .iter()
+        .map(|value| ("Authorization", value.as_str()))
+        .collect();
+    http::request(&options.url.join(path), method, &headers, b"")
+}
+
+/// Send a request about a message, failing like `messages` if it is not kept
+fn message_request(
+    options: &Options,
+    method: &str,
+    path: &str,
+    id: &str,
+) -> Result<Vec<u8>, Error> {
+    request(options, method, path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => Error::new(ErrorKind::NotFound, format!("no message {}", id)),
+        _ => e,
+    })
+}
+
+/// Write messages as listed by the API as a table with a line for each
+fn write_table(out: &mut dyn Write, messages: &[Value], now: u64) -> Result<(), Error> {
+    let mut rows = vec![["ID", "RECEIVED", "FROM", "TO", "SUBJECT"].map(str::to_string)];
+    for message in messages {
+        let text = |value: &Value| value.as_str().unwrap_or_default().to_string();
+        let recipients: Vec<String> = message["to"]
+            .as_array()
+            .map_or_else(Vec::new, |to| to.iter().map(text).collect());
+        let received = message["received"].as_u64().unwrap_or_default();
+        rows.push([
+            text(&message["id"]),
+            format!("{}s ago", now.saturating_sub(received)),
+            text(&message["from"]),
+            recipients.join(", "),
+            text(&message["subject"]),
+        ]);
+    }
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    for row in &rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        writeln!(out, "{}", line.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+/// Percent-encode a value for a path segment or the query string
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn list_and_get_messages() {
+        // Given
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", server.local_addr().unwrap())).unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in [
+                "HTTP/1.1 200 OK\r\n\r\n[{\"id\":\"2\",\"received\":1699999990,\
+                 \"from\":\"<app@example.com>\",\"to\":[\"<ci+job-42@example.com>\"],\
+                 \"subject\":\"Welcome\"},{\"id\":\"1\",\"received\":1699999000,\"from\":\"<>\",\
+                 \"to\":[\"<a@example.com>\",\"<b@example.com>\"],\"subject\":null}]",
+                "HTTP/1.1 404 Not Found\r\n\r\n",
+            ] {
+                let (mut stream, _) = server.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                while !request.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut request).unwrap();
+                }
+                requests.push(request);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        let options = |action| Options {
+            url: url.clone(),
+            token: Some("secret".to_string()),
+            json: false,
+            action,
+        };
+        let mut out = Vec::new();
+
+        // When
+        let search = Action::List {
+            inbox: Some("ci+job-42@example.com".to_string()),
+            query: format!("search={}", encode("hello world")),
+        };
+        execute(&options(search), 1_700_000_000, &mut out).unwrap();
+        let missing = execute(&options(Action::Get("1".to_string())), 0, &mut Vec::new());
+
+        // Then
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "ID  RECEIVED   FROM               TO                                SUBJECT\n\
+             2   10s ago    <app@example.com>  <ci+job-42@example.com>           Welcome\n\
+             1   1000s ago  <>                 <a@example.com>, <b@example.com>\n"
+        );
+        assert_eq!(missing.unwrap_err().to_string(), "no message 1");
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with(
+            "GET /inboxes/ci%2Bjob-42@example.com/messages?search=hello%20world HTTP/1.1\r\n"
+        ));
+        assert!(requests[0].contains("Authorization: Bearer secret\r\n"));
+        assert!(requests[1].starts_with("GET /api/messages/1/raw HTTP/1.1\r\n"));
+    }
+}
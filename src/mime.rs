@@ -0,0 +1,410 @@
+//! Parsing the MIME structure of messages into decoded headers, bodies and attachments, so that
+//! tests can check what a message says without parsing MIME themselves.
+//!
+//! Multipart messages are walked depth first. The first `text/plain` and `text/html` parts that
+//! are not attachments are the bodies, and all other parts are attachments. Base64 and
+//! quoted-printable content is decoded, and so are encoded words in header fields
+//! ([RFC 2047](https://tools.ietf.org/html/rfc2047)). Text is decoded from UTF-8 or ISO-8859-1 and
+//! has LF line endings.
+
+use crate::client::base64;
+
+/// A message with its parts decoded
+#[derive(Default)]
+pub struct ParsedMessage {
+    /// Header fields of the message in order, unfolded and decoded
+    pub headers: Vec<(String, String)>,
+    pub text_body: Option<String>,
+    pub html_body: Option<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+/// A part of a message that is not one of its bodies
+pub struct Attachment {
+    pub filename: Option<String>,
+    /// Media type without parameters, like `application/pdf`
+    pub content_type: String,
+    /// Decoded content
+    pub content: Vec<u8>,
+}
+
+impl ParsedMessage {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "headers": self
+                .headers
+                .iter()
+                .map(|(name, value)| serde_json::json!({ "name": name, "value": value }))
+                .collect::<Vec<_>>(),
+            "text_body": self.text_body,
+            "html_body": self.html_body,
+            "attachments": self
+                .attachments
+                .iter()
+                .map(|attachment| serde_json::json!({
+                    "filename": attachment.filename,
+                    "content_type": attachment.content_type,
+                    "size": attachment.content.len(),
+                    "content": base64(&attachment.content),
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Parse a message as received
+pub fn parse(content: &[u8]) -> ParsedMessage {
+    let (header, body) = split_header(content);
+    let mut message = ParsedMessage {
+        headers: header_fields(header),
+        ..ParsedMessage::default()
+    };
+    let headers = message.headers.clone();
+    add_part(&mut message, &headers, body);
+    message
+}
+
+/// The header fields of a message, unfolded and decoded, without looking at its parts
+pub fn headers(content: &[u8]) -> Vec<(String, String)> {
+    header_fields(split_header(content).0)
+}
+
+/// Add a part with its header fields to the bodies or attachments, or its parts if it is a
+/// multipart
+fn add_part(message: &mut ParsedMessage, headers: &[(String, String)], body: &[u8]) {
+    let (content_type, type_parameters) = match field(headers, "content-type") {
+        Some(value) => parameters(value),
+        None => ("text/plain".to_string(), Vec::new()),
+    };
+    if content_type.starts_with("multipart/") {
+        if let Some(boundary) = parameter(&type_parameters, "boundary") {
+            for part in split_multipart(body, boundary) {
+                let (header, body) = split_header(part);
+                add_part(message, &header_fields(header), body);
+            }
+            return;
+        }
+    }
+
+    let (disposition, disposition_parameters) = field(headers, "content-disposition")
+        .map(parameters)
+        .unwrap_or_default();
+    let filename = parameter(&disposition_parameters, "filename")
+        .or_else(|| parameter(&type_parameters, "name"))
+        .map(decode_words);
+    let content = match field(headers, "content-transfer-encoding")
+        .map(|encoding| encoding.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("base64") => decode_base64(body),
+        Some("quoted-printable") => decode_quoted_printable(body, false),
+        _ => body.to_vec(),
+    };
+
+    let body = match content_type.as_str() {
+        "text/plain" => Some(&mut message.text_body),
+        "text/html" => Some(&mut message.html_body),
+        _ => None,
+    };
+    if let Some(body) = body.filter(|body| body.is_none()) {
+        if disposition != "attachment" && filename.is_none() {
+            let charset = parameter(&type_parameters, "charset").unwrap_or("us-ascii");
+            *body = Some(decode_text(&content, charset).replace("\r\n", "\n"));
+            return;
+        }
+    }
+    message.attachments.push(Attachment {
+        filename,
+        content_type,
+        content,
+    });
+}
+
+/// Split a message or part into its header and body at the first empty line
+pub fn split_header(content: &[u8]) -> (&[u8], &[u8]) {
+    let mut position = 0;
+    for line in content.split_inclusive(|&b| b == b'\n') {
+        if line == b"\n" || line == b"\r\n" {
+            return (&content[..position], &content[position + line.len()..]);
+        }
+        position += line.len();
+    }
+    // Without an empty line, all of it is header
+    (content, &[])
+}
+
+/// Unfold and decode the fields of a header
+pub fn header_fields(header: &[u8]) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in String::from_utf8_lossy(header).lines() {
+        match fields.last_mut() {
+            Some((_, value)) if line.starts_with([' ', '\t']) => *value += line,
+            _ => {
+                if let Some((name, value)) = line.split_once(':') {
+                    fields.push((name.trim().to_string(), value.to_string()));
+                }
+            }
+        }
+    }
+    fields
+        .into_iter()
+        .map(|(name, value)| (name, decode_words(value.trim())))
+        .collect()
+}
+
+/// Get the value of the first field with a name, ignoring case
+pub fn field<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(field_name, _)| field_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Split a field value like `text/plain; charset="utf-8"` into its lowercase value and its
+/// parameters with lowercase names
+pub fn parameters(value: &str) -> (String, Vec<(String, String)>) {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut quoted = false;
+    for c in value.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => items.push(std::mem::take(&mut item)),
+            c => item.push(c),
+        }
+    }
+    items.push(item);
+    let mut items = items.into_iter();
+    let value = items.next().unwrap_or_default().trim().to_ascii_lowercase();
+    let parameters = items
+        .filter_map(|item| {
+            let (name, value) = item.split_once('=')?;
+            Some((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        })
+        .collect();
+    (value, parameters)
+}
+
+pub fn parameter<'a>(parameters: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    parameters
+        .iter()
+        .find(|(parameter_name, _)| parameter_name == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Split the body of a multipart into its parts, without the line breaks before the delimiters
+pub fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut position = 0;
+    for line in body.split_inclusive(|&b| b == b'\n') {
+        if let Some(rest) = line.strip_prefix(delimiter.as_bytes()) {
+            let rest = rest.trim_ascii_end();
+            if rest.is_empty() || rest == b"--" {
+                if let Some(start) = start {
+                    let part = &body[start..position];
+                    let part = part.strip_suffix(b"\n").unwrap_or(part);
+                    parts.push(part.strip_suffix(b"\r").unwrap_or(part));
+                }
+                if rest == b"--" {
+                    return parts;
+                }
+                start = Some(position + line.len());
+            }
+        }
+        position += line.len();
+    }
+    // A multipart without its closing delimiter ends with the message
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+/// Decode the encoded words in a field value, like `=?UTF-8?Q?Gr=C3=BC=C3=9Fe?=`.
+/// Whitespace between encoded words is dropped.
+fn decode_words(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, word) = rest.split_at(start);
+        match decode_word(word) {
+            Some((text, length)) => {
+                if !(after_word && before.trim().is_empty()) {
+                    decoded += before;
+                }
+                decoded += &text;
+                rest = &word[length..];
+                after_word = true;
+            }
+            None => {
+                decoded += before;
+                decoded += "=?";
+                rest = &word[2..];
+                after_word = false;
+            }
+        }
+    }
+    decoded + rest
+}
+
+/// Decode an encoded word at the start of a text and get its length
+fn decode_word(text: &str) -> Option<(String, usize)> {
+    let inner = text.strip_prefix("=?")?;
+    let (charset, rest) = inner.split_once('?')?;
+    let (encoding, rest) = rest.split_once('?')?;
+    let end = rest.find("?=")?;
+    let encoded = &rest.as_bytes()[..end];
+    let bytes = match encoding {
+        "B" | "b" => decode_base64(encoded),
+        "Q" | "q" => decode_quoted_printable(encoded, true),
+        _ => return None,
+    };
+    // A language may follow the charset, like `UTF-8*en`
+    let charset = charset.split('*').next().unwrap_or_default();
+    Some((
+        decode_text(&bytes, charset),
+        text.len() - rest.len() + end + 2,
+    ))
+}
+
+fn decode_text(bytes: &[u8], charset: &str) -> String {
+    if charset.eq_ignore_ascii_case("iso-8859-1") || charset.eq_ignore_ascii_case("latin1") {
+        bytes.iter().map(|&b| b as char).collect()
+    } else {
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+/// Decode base64, skipping line breaks and anything else that is not part of the alphabet
+pub fn decode_base64(encoded: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &b in encoded {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => continue,
+        };
+        bits = bits << 6 | value as u32;
+        count += 1;
+        if count == 4 {
+            decoded.extend_from_slice(&bits.to_be_bytes()[1..]);
+            bits = 0;
+            count = 0;
+        }
+    }
+    match count {
+        2 => decoded.push((bits >> 4) as u8),
+        3 => decoded.extend_from_slice(&((bits >> 2) as u16).to_be_bytes()),
+        _ => {}
+    }
+    decoded
+}
+
+/// Decode quoted-printable content, or the Q encoding of encoded words with underscores for
+/// spaces
+fn decode_quoted_printable(encoded: &[u8], underscores: bool) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        match encoded[i] {
+            b'=' => {
+                let rest = &encoded[i + 1..];
+                if rest.starts_with(b"\r\n") {
+                    // A soft line break
+                    i += 3;
+                    continue;
+                } else if rest.starts_with(b"\n") {
+                    i += 2;
+                    continue;
+                }
+                let hex = rest.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'='),
+                }
+            }
+            b'_' if underscores => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_multipart_message() {
+        // Given
+        let content = b"From: tester@localhost\r\n\
+                        Subject: =?UTF-8?Q?Gr=C3=BC=C3=9Fe?= =?ISO-8859-1?B?4A==?= and\r\n \
+                        more\r\n\
+                        Content-Type: multipart/mixed; boundary=\"outer; b\"\r\n\
+                        \r\n\
+                        Preamble\r\n\
+                        --outer; b\r\n\
+                        Content-Type: multipart/alternative; boundary=inner\r\n\
+                        \r\n\
+                        --inner\r\n\
+                        Content-Type: text/plain; charset=utf-8\r\n\
+                        Content-Transfer-Encoding: quoted-printable\r\n\
+                        \r\n\
+                        Hello W=C3=B6rld, a long line that is=\r\n \
+                        broken\r\n\
+                        --inner\r\n\
+                        Content-Type: text/html\r\n\
+                        \r\n\
+                        <p>Hello</p>\r\n\
+                        --inner--\r\n\
+                        --outer; b\r\n\
+                        Content-Type: application/octet-stream; name=data.bin\r\n\
+                        Content-Transfer-Encoding: base64\r\n\
+                        \r\n\
+                        AAEC\r\n\
+                        /w==\r\n\
+                        --outer; b--\r\n\
+                        Epilogue\r\n";
+
+        // When
+        let message = parse(content);
+
+        // Then
+        assert_eq!(message.headers.len(), 3);
+        assert_eq!(message.headers[1].1, "Grüßeà and more");
+        assert_eq!(
+            message.text_body.as_deref(),
+            Some("Hello Wörld, a long line that is broken")
+        );
+        assert_eq!(message.html_body.as_deref(), Some("<p>Hello</p>"));
+        assert_eq!(message.attachments.len(), 1);
+        let attachment = &message.attachments[0];
+        assert_eq!(attachment.filename.as_deref(), Some("data.bin"));
+        assert_eq!(attachment.content_type, "application/octet-stream");
+        assert_eq!(attachment.content, [0, 1, 2, 255]);
+    }
+
+    #[test]
+    fn parse_plain_message() {
+        let message = parse(b"Subject: Hi\nFrom: a@localhost\n\nLine 1\nLine 2\n");
+
+        assert_eq!(field(&message.headers, "SUBJECT"), Some("Hi"));
+        assert_eq!(message.text_body.as_deref(), Some("Line 1\nLine 2\n"));
+        assert!(message.html_body.is_none());
+        assert!(message.attachments.is_empty());
+    }
+}
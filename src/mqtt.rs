@@ -0,0 +1,259 @@
+//! Publishing notifications of received messages to an MQTT broker, e.g. for dashboards and test
+//! rigs that already speak MQTT.
+//!
+//! This is a minimal MQTT 3.1.1 client. Every message is announced once per recipient, on a
+//! subtopic of the topic named after the recipient address, so a subscriber can wait for the
+//! mail of one address. The notification is a small JSON object with the message UUID, sender,
+//! recipient and size. With QoS 1, every notification waits for the broker's acknowledgement.
+//! There is no TLS or authentication.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::relay::path_address;
+use crate::Session;
+
+/// How long to wait for the broker to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+
+/// Protocol level of MQTT 3.1.1
+const PROTOCOL_LEVEL: u8 = 4;
+
+/// Connect flags for a clean session without will or login
+const CLEAN_SESSION: u8 = 0x02;
+
+/// Where to publish
+#[derive(Clone)]
+pub struct Sink {
+    /// Address of the broker as host:port
+    pub broker: String,
+    /// Topic that the recipient subtopics are published under, e.g. `smtp/received`
+    pub topic: String,
+    /// Quality of service, 0 for at most once or 1 for at least once
+    pub qos: u8,
+}
+
+/// Publish notifications for the messages of every session received until the channel closes.
+/// Notifications that fail to publish are logged and dropped.
+pub fn run(sink: Sink, server: Option<String>, sessions: Receiver<Arc<Session>>) {
+    let mut connection: Option<Connection> = None;
+    for session in sessions {
+        let Some(messages) = session.connection.get_messages() else {
+            continue;
+        };
+        for message in messages {
+            let sender = path_address(message.get_sender());
+            for recipient in message.get_recipients() {
+                let recipient = path_address(recipient);
+                let mut notification = serde_json::json!({
+                    "id": message.get_id(),
+                    "from": sender,
+                    "to": recipient,
+                    "size": message.get_size(),
+                });
+                if let Some(server) = &server {
+                    notification["server"] = server.as_str().into();
+                }
+                let topic = format!("{}/{}", sink.topic, topic_level(recipient));
+                let published = match connection.as_mut() {
+                    Some(connection) => Ok(connection),
+                    None => Connection::open(&sink.broker).map(|open| connection.insert(open)),
+                }
+                .and_then(|connection| {
+                    connection.publish(&topic, sink.qos, notification.to_string().as_bytes())
+                });
+                if let Err(e) = published {
+                    tracing::error!("Publishing to MQTT topic {} failed: {}", topic, e);
+                    // The state of the connection is unknown, so the next message connects again
+                    connection = None;
+                }
+            }
+        }
+    }
+}
+
+/// Make a topic level of an address. Wildcards and level separators are not allowed in the
+/// topic of a published message, so they are percent encoded, like the percent sign itself.
+fn topic_level(address: &str) -> String {
+    let mut level = String::with_capacity(address.len());
+    for c in address.chars() {
+        match c {
+            '+' | '#' | '/' | '%' | '\0' => level += &format!("%{:02X}", c as u32),
+            c => level.push(c),
+        }
+    }
+    level
+}
+
+/// A connection to a broker
+struct Connection {
+    stream: TcpStream,
+    /// Identifier of the last packet that needed an acknowledgement
+    packet_id: u16,
+}
+
+impl Connection {
+    fn open(address: &str) -> Result<Connection, Error> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut connection = Connection {
+            stream,
+            packet_id: 0,
+        };
+
+        // Brokers disconnect an older client with the same ID, so every connection has its own
+        let client_id = format!(
+            "rust-smtp-server-{}",
+            &crate::smtp::new_uuid().replace('-', "")[..12]
+        );
+        let mut connect = Vec::new();
+        string(&mut connect, "MQTT");
+        connect.push(PROTOCOL_LEVEL);
+        connect.push(CLEAN_SESSION);
+        // No keep alive, the broker does not expect pings
+        connect.extend_from_slice(&0u16.to_be_bytes());
+        string(&mut connect, &client_id);
+        connection.send(CONNECT, &connect)?;
+
+        let (packet_type, connack) = connection.receive()?;
+        match (packet_type, connack.as_slice()) {
+            (CONNACK, [_, 0]) => Ok(connection),
+            (CONNACK, [_, code]) => Err(Error::other(format!(
+                "broker refused the connection with return code {}",
+                code
+            ))),
+            _ => Err(invalid(packet_type)),
+        }
+    }
+
+    /// Publish a message, waiting for the acknowledgement with QoS 1
+    fn publish(&mut self, topic: &str, qos: u8, payload: &[u8]) -> Result<(), Error> {
+        let mut publish = Vec::with_capacity(topic.len() + payload.len() + 4);
+        string(&mut publish, topic);
+        if qos > 0 {
+            self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+            publish.extend_from_slice(&self.packet_id.to_be_bytes());
+        }
+        publish.extend_from_slice(payload);
+        self.send(PUBLISH | qos << 1, &publish)?;
+        if qos == 0 {
+            return Ok(());
+        }
+
+        let (packet_type, puback) = self.receive()?;
+        if packet_type != PUBACK || puback != self.packet_id.to_be_bytes() {
+            return Err(invalid(packet_type));
+        }
+        Ok(())
+    }
+
+    /// Send a packet with a fixed header of its type and flags
+    fn send(&mut self, header: u8, body: &[u8]) -> Result<(), Error> {
+        let mut packet = vec![header];
+        remaining_length(&mut packet, body.len());
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet)
+    }
+
+    /// Receive a packet as type and body
+    fn receive(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let mut header = [0; 1];
+        self.stream.read_exact(&mut header)?;
+        let mut length = 0;
+        for shift in (0..28).step_by(7) {
+            let mut byte = [0; 1];
+            self.stream.read_exact(&mut byte)?;
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        self.stream.read_exact(&mut body)?;
+        Ok((header[0] & 0xf0, body))
+    }
+}
+
+/// Write a string with its length
+fn string(packet: &mut Vec<u8>, value: &str) {
+    packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    packet.extend_from_slice(value.as_bytes());
+}
+
+/// Write the length of the rest of a packet, seven bits per byte
+fn remaining_length(packet: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let byte = (length % 0x80) as u8;
+        length /= 0x80;
+        if length == 0 {
+            packet.push(byte);
+            return;
+        }
+        packet.push(byte | 0x80);
+    }
+}
+
+fn invalid(packet_type: u8) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected packet from MQTT broker: {:#04x}", packet_type),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn encode_topic_and_length() {
+        assert_eq!(topic_level("a+test@example.com"), "a%2Btest@example.com");
+        assert_eq!(topic_level("#/%"), "%23%2F%25");
+
+        let mut packet = Vec::new();
+        remaining_length(&mut packet, 321);
+        assert_eq!(packet, [0xc1, 0x02]);
+    }
+
+    #[test]
+    fn publish_with_acknowledgement() {
+        // Given
+        let broker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = broker.local_addr().unwrap().to_string();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = broker.accept().unwrap();
+            let mut connect = [0; 2];
+            stream.read_exact(&mut connect).unwrap();
+            let mut body = vec![0; connect[1] as usize];
+            stream.read_exact(&mut body).unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+
+            let mut publish = [0; 2];
+            stream.read_exact(&mut publish).unwrap();
+            let mut body = vec![0; publish[1] as usize];
+            stream.read_exact(&mut body).unwrap();
+            stream.write_all(&[PUBACK, 2, 0, 1]).unwrap();
+            (connect[0], publish[0], body)
+        });
+
+        // When
+        let mut connection = Connection::open(&address).unwrap();
+        let published = connection.publish("smtp/a@example.com", 1, b"{}");
+
+        // Then
+        published.unwrap();
+        let (connect, publish, body) = broker.join().unwrap();
+        assert_eq!(connect, CONNECT);
+        assert_eq!(publish, 0x32);
+        assert_eq!(body, b"\x00\x12smtp/a@example.com\x00\x01{}");
+    }
+}
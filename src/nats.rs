@@ -0,0 +1,275 @@
+//! Publishing received messages to a NATS subject, so that test infrastructure can react to
+//! captured mail.
+//!
+//! This is a minimal client for the text protocol of NATS: every message is published as its
+//! JSON summary. Core NATS publishing is fire and forget. With JetStream, the stream that stores
+//! the subject acknowledges every message, and the message UUID is sent as `Nats-Msg-Id` so that
+//! the stream drops duplicates. There is no TLS or authentication.
+
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::Session;
+
+/// How long to wait for the server to answer, e.g. with the acknowledgement of a stream
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where to publish
+#[derive(Clone)]
+pub struct Sink {
+    /// Addresses of servers to connect to as host:port, the first one that answers is used
+    pub servers: Vec<String>,
+    pub subject: String,
+    /// Whether to wait for the acknowledgement of a JetStream stream
+    pub jetstream: bool,
+}
+
+/// Publish the messages of every session received until the channel closes.
+/// Messages that fail to publish are logged and dropped.
+pub fn run(sink: Sink, server: Option<String>, sessions: Receiver<Arc<Session>>) {
+    let mut connection: Option<Connection> = None;
+    for session in sessions {
+        let smtp = &session.connection;
+        let (Some(sender_domain), Some(messages)) = (smtp.get_sender_domain(), smtp.get_messages())
+        else {
+            continue;
+        };
+        for message in messages {
+            let summary = crate::message_json(&session, sender_domain, message, server.as_deref())
+                .to_string();
+            let published = match connection.as_mut() {
+                Some(connection) => Ok(connection),
+                None => Connection::open(&sink.servers).map(|open| connection.insert(open)),
+            }
+            .and_then(|connection| connection.publish(&sink, message.get_id(), summary.as_bytes()));
+            if let Err(e) = published {
+                tracing::error!("Publishing to NATS subject {} failed: {}", sink.subject, e);
+                // The state of the connection is unknown, so the next message connects again
+                connection = None;
+            }
+        }
+    }
+}
+
+/// A connection to a NATS server
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    /// Subject that JetStream acknowledgements are sent to
+    inbox: String,
+}
+
+impl Connection {
+    /// Connect to the first server that answers and subscribe to the inbox
+    fn open(servers: &[String]) -> Result<Connection, Error> {
+        let mut last_error = Error::new(ErrorKind::InvalidInput, "no servers");
+        for server in servers {
+            match Connection::open_server(server) {
+                Ok(connection) => return Ok(connection),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn open_server(address: &str) -> Result<Connection, Error> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            inbox: format!("_INBOX.{}", crate::smtp::new_uuid().replace('-', "")),
+        };
+
+        let info = connection.read_line()?;
+        if !info.starts_with("INFO ") {
+            return Err(invalid(&info));
+        }
+        // Headers are needed for message IDs, and without responders the server tells right
+        // away that no stream stores the subject
+        let options = serde_json::json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "rust-smtp-server",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 1,
+            "headers": true,
+            "no_responders": true,
+        });
+        let handshake = format!(
+            "CONNECT {}\r\nSUB {} 1\r\nPING\r\n",
+            options, connection.inbox
+        );
+        connection.writer.write_all(handshake.as_bytes())?;
+        loop {
+            match connection.read_line()?.as_str() {
+                "PONG" => return Ok(connection),
+                line => connection.handle(line)?,
+            }
+        }
+    }
+
+    /// Publish a message, waiting for the stream to acknowledge it with JetStream
+    fn publish(&mut self, sink: &Sink, id: &str, payload: &[u8]) -> Result<(), Error> {
+        let mut request = if sink.jetstream {
+            let headers = format!("NATS/1.0\r\nNats-Msg-Id: {}\r\n\r\n", id);
+            let mut request = format!(
+                "HPUB {} {} {} {}\r\n{}",
+                sink.subject,
+                self.inbox,
+                headers.len(),
+                headers.len() + payload.len(),
+                headers
+            )
+            .into_bytes();
+            request.extend_from_slice(payload);
+            request
+        } else {
+            let mut request = format!("PUB {} {}\r\n", sink.subject, payload.len()).into_bytes();
+            request.extend_from_slice(payload);
+            request
+        };
+        request.extend_from_slice(b"\r\n");
+        self.writer.write_all(&request)?;
+        if !sink.jetstream {
+            return Ok(());
+        }
+
+        loop {
+            let line = self.read_line()?;
+            let mut fields = line.split(' ');
+            match fields.next() {
+                Some("MSG") => {
+                    let size = fields.nth(2).and_then(|size| size.parse().ok());
+                    let ack = self.read_payload(size.ok_or_else(|| invalid(&line))?)?;
+                    return acknowledged(&ack);
+                }
+                Some("HMSG") => {
+                    // Without a stream for the subject, the server answers with status 503
+                    let size = fields.nth(3).and_then(|size| size.parse().ok());
+                    let reply = self.read_payload(size.ok_or_else(|| invalid(&line))?)?;
+                    let status = String::from_utf8_lossy(&reply);
+                    let status = status.lines().next().unwrap_or_default();
+                    return Err(Error::other(format!(
+                        "no acknowledgement from a stream: {}",
+                        status
+                    )));
+                }
+                _ => self.handle(&line)?,
+            }
+        }
+    }
+
+    /// Handle a line from the server that is not an answer to the current request
+    fn handle(&mut self, line: &str) -> Result<(), Error> {
+        match line.split(' ').next() {
+            Some("PING") => self.writer.write_all(b"PONG\r\n"),
+            Some("INFO") | Some("+OK") => Ok(()),
+            Some("-ERR") => Err(Error::other(line.to_string())),
+            _ => Err(invalid(line)),
+        }
+    }
+
+    fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "NATS server closed the connection",
+            ));
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    /// Read the payload of a message and the line ending after it
+    fn read_payload(&mut self, size: usize) -> Result<Vec<u8>, Error> {
+        let mut payload = vec![0; size + 2];
+        self.reader.read_exact(&mut payload)?;
+        payload.truncate(size);
+        Ok(payload)
+    }
+}
+
+/// Check the acknowledgement of a stream, e.g. `{"stream":"MAIL","seq":1}`
+fn acknowledged(ack: &[u8]) -> Result<(), Error> {
+    let ack: serde_json::Value =
+        serde_json::from_slice(ack).map_err(|_| invalid(&String::from_utf8_lossy(ack)))?;
+    match ack.get("error") {
+        Some(error) => Err(Error::other(format!("stream failed: {}", error))),
+        None if ack.get("stream").is_some() => Ok(()),
+        None => Err(invalid(&ack.to_string())),
+    }
+}
+
+fn invalid(line: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected answer from NATS server: {}", line),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn publish_to_jetstream() {
+        // Given
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sink = Sink {
+            servers: vec![server.local_addr().unwrap().to_string()],
+            subject: "mail.received".to_string(),
+            jetstream: true,
+        };
+        let stream = thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"INFO {\"headers\":true}\r\n").unwrap();
+            let mut lines = Vec::new();
+            for _ in 0..5 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.starts_with("PING") {
+                    stream.write_all(b"PONG\r\n").unwrap();
+                }
+                lines.push(line.trim_end().to_string());
+            }
+            let inbox = lines[1].split(' ').nth(1).unwrap().to_string();
+            let ack = b"{\"stream\":\"MAIL\",\"seq\":1}";
+            write!(stream, "PING\r\nMSG {} 1 {}\r\n", inbox, ack.len()).unwrap();
+            stream.write_all(ack).unwrap();
+            stream.write_all(b"\r\n").unwrap();
+            // The rest of the message and the answer to the ping
+            for _ in 0..4 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                lines.push(line.trim_end().to_string());
+            }
+            lines
+        });
+
+        // When
+        let mut connection = Connection::open(&sink.servers).unwrap();
+        let published = connection.publish(&sink, "some-id", b"{}");
+
+        // Then
+        published.unwrap();
+        let lines = stream.join().unwrap();
+        assert!(lines[0].starts_with("CONNECT {"));
+        assert_eq!(lines[1], format!("SUB {} 1", connection.inbox));
+        assert_eq!(
+            lines[3],
+            format!("HPUB mail.received {} 34 36", connection.inbox)
+        );
+        assert_eq!(
+            lines[4..],
+            ["NATS/1.0", "Nats-Msg-Id: some-id", "", "{}", "PONG"]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Posting a summary of received messages to Slack or Discord, so that a team notices mail in a
+//! staging environment without watching the output.
+//!
+//! Summaries go to incoming webhooks and name the subject, sender, recipients and UUID of a
+//! message. With patterns, only messages whose sender or one of whose recipients match one of
+//! them are posted.
+
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+use crate::http::{self, Url};
+use crate::relay::path_address;
+use crate::smtp::{header_field, Message};
+use crate::Session;
+
+/// Longest message that Discord accepts
+const DISCORD_MAX_LENGTH: usize = 2000;
+
+/// Chat services with incoming webhooks
+#[derive(Clone, Copy)]
+pub enum Service {
+    Slack,
+    Discord,
+}
+
+impl Service {
+    fn name(self) -> &'static str {
+        match self {
+            Service::Slack => "Slack",
+            Service::Discord => "Discord",
+        }
+    }
+}
+
+/// Where to post and which messages
+#[derive(Clone)]
+pub struct Notifications {
+    /// Services and their webhook URLs as given
+    pub webhooks: Vec<(Service, String)>,
+    /// Address patterns with `*` as wildcard, of which one must match if there are any
+    pub patterns: Vec<String>,
+}
+
+impl Notifications {
+    /// Whether a message matches the patterns
+    fn matches(&self, message: &Message) -> bool {
+        let sender = path_address(message.get_sender());
+        let mut addresses = std::iter::once(sender).chain(
+            message
+                .get_recipients()
+                .iter()
+                .map(|recipient| path_address(recipient)),
+        );
+        self.patterns.is_empty()
+            || addresses.any(|address| {
+                self.patterns
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, address))
+            })
+    }
+}
+
+/// Post the matching messages of every session received until the channel closes.
+/// Posts that fail are logged and dropped.
+pub fn run(notifications: Notifications, server: Option<String>, sessions: Receiver<Arc<Session>>) {
+    // URLs are validated with the settings
+    let webhooks: Vec<(Service, Url)> = notifications
+        .webhooks
+        .iter()
+        .map(|(service, url)| (*service, Url::parse(url).unwrap()))
+        .collect();
+    for session in sessions {
+        let Some(messages) = session.connection.get_messages() else {
+            continue;
+        };
+        for message in messages
+            .iter()
+            .filter(|message| notifications.matches(message))
+        {
+            let text = summary(message, server.as_deref());
+            for (service, url) in &webhooks {
+                let body = match service {
+                    Service::Slack => serde_json::json!({ "text": text }),
+                    Service::Discord => serde_json::json!({
+                        "content": text.chars().take(DISCORD_MAX_LENGTH).collect::<String>(),
+                    }),
+                };
+                if let Err(e) = http::post(url, "application/json", body.to_string().as_bytes()) {
+                    tracing::error!("Posting to {} failed: {}", service.name(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Summarize a message in Markdown, which both services understand in this simple form
+fn summary(message: &Message, server: Option<&str>) -> String {
+    let recipients: Vec<&str> = message
+        .get_recipients()
+        .iter()
+        .map(|recipient| path_address(recipient))
+        .collect();
+    let mut text = format!(
+        "*{}*\nFrom: {}\nTo: {}\nID: `{}`",
+        subject(message.get_content()).unwrap_or_else(|| "(no subject)".to_string()),
+        path_address(message.get_sender()),
+        recipients.join(", "),
+        message.get_id()
+    );
+    if let Some(server) = server {
+        text += &format!("\nServer: {}", server);
+    }
+    text
+}
+
+/// Get the unfolded subject from the header of a message
+fn subject(content: &[u8]) -> Option<String> {
+    header_field(content, "subject")
+}
+
+/// Match an address against a pattern with `*` as wildcard, ignoring case
+pub fn matches_pattern(pattern: &str, address: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let address = address.to_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = address.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard, so the whole address must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_patterns() {
+        assert!(matches_pattern("*@Example.com", "someone@example.com"));
+        assert!(matches_pattern("qa-*@*.example", "qa-1@staging.example"));
+        assert!(matches_pattern("admin@localhost", "admin@localhost"));
+        assert!(!matches_pattern(
+            "admin@localhost",
+            "admin@localhost.example"
+        ));
+        assert!(!matches_pattern("*@example.com", "someone@example.org"));
+        assert!(!matches_pattern("a*a", "a"));
+    }
+
+    #[test]
+    fn find_subject() {
+        assert_eq!(
+            subject(
+                b"From: a@example.com\r\nSubject: Hello\r\n\tworld\r\nTo: b\r\n\r\nSubject: no"
+            ),
+            Some("Hello\tworld".to_string())
+        );
+        assert_eq!(subject(b"From: a@example.com\n\nSubject: no"), None);
+    }
+}
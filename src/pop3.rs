@@ -0,0 +1,275 @@
+//! A POP3 server on the kept messages, for test suites that check delivery by fetching the mail
+//! from a mailbox ([RFC 1939](https://tools.ietf.org/html/rfc1939)).
+//!
+//! All kept messages are in one mailbox that every user sees. Only the `--auth-user` credentials
+//! log in if they are given, and any otherwise. Messages deleted with DELE are removed from the
+//! store when the session ends with QUIT.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::auth::Credentials;
+use crate::store::{Entry, MessageStore};
+
+/// How long a client may be idle, the minimum of RFC 1939
+const TIMEOUT: Duration = Duration::from_secs(600);
+
+const MSG_READY: &str = "+OK POP3 server ready";
+const MSG_OK: &str = "+OK";
+const MSG_BYE: &str = "+OK Bye";
+const MSG_CAPABILITIES: &str = "+OK Capabilities follow\r\nUSER\r\nUIDL\r\n.";
+const MSG_NO_USER: &str = "-ERR USER first";
+const MSG_INVALID_CREDENTIALS: &str = "-ERR Invalid user or password";
+const MSG_NO_SUCH_MESSAGE: &str = "-ERR No such message";
+const MSG_UNKNOWN_COMMAND: &str = "-ERR Unknown command";
+
+/// Serve the mailbox on an address as host:port until the process ends
+pub fn run(address: String, store: Arc<dyn MessageStore>, credentials: Option<Arc<Credentials>>) {
+    let listener = crate::bind_retrying(&address, "POP3");
+    for stream in listener.incoming().flatten() {
+        let (store, credentials) = (store.clone(), credentials.clone());
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, store.as_ref(), credentials.as_deref()) {
+                tracing::warn!("Error communicating with POP3 client: {}", e);
+            }
+        });
+    }
+}
+
+fn handle(
+    stream: TcpStream,
+    store: &dyn MessageStore,
+    credentials: Option<&Credentials>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    session(&mut reader, &mut writer, store, credentials)
+}
+
+/// Talk to a client until it quits or goes away
+fn session(
+    reader: &mut dyn BufRead,
+    writer: &mut dyn Write,
+    store: &dyn MessageStore,
+    credentials: Option<&Credentials>,
+) -> io::Result<()> {
+    write!(writer, "{}\r\n", MSG_READY)?;
+    let mut user = None;
+    // The messages as of the login, each with whether it is deleted
+    let mut mailbox: Option<Vec<(Entry, bool)>> = None;
+    loop {
+        let mut line = String::new();
+        // Without QUIT, deleted messages are kept
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = line.trim_end_matches(['\n', '\r']);
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let reply = match (command.to_ascii_uppercase().as_str(), &mut mailbox) {
+            ("QUIT", mailbox) => {
+                for (entry, _) in mailbox.iter().flatten().filter(|(_, deleted)| *deleted) {
+                    store.remove(&entry.id)?;
+                }
+                write!(writer, "{}\r\n", MSG_BYE)?;
+                return Ok(());
+            }
+            ("CAPA", _) => MSG_CAPABILITIES.to_string(),
+            ("USER", None) => {
+                user = Some(argument.to_string());
+                MSG_OK.to_string()
+            }
+            ("PASS", None) => match user.take() {
+                None => MSG_NO_USER.to_string(),
+                Some(user)
+                    if credentials.is_none_or(|credentials| {
+                        user == credentials.user && argument == credentials.password
+                    }) =>
+                {
+                    let entries = store.entries()?;
+                    let reply = format!("+OK {} messages", entries.len());
+                    mailbox = Some(entries.into_iter().map(|entry| (entry, false)).collect());
+                    reply
+                }
+                Some(_) => MSG_INVALID_CREDENTIALS.to_string(),
+            },
+            ("STAT", Some(messages)) => {
+                let kept = messages.iter().filter(|(_, deleted)| !deleted);
+                let (count, size) = kept.fold((0, 0), |(count, size), (entry, _)| {
+                    (count + 1, size + entry.size)
+                });
+                format!("+OK {} {}", count, size)
+            }
+            ("LIST", Some(messages)) => listing(messages, argument, |entry| entry.size.to_string()),
+            ("UIDL", Some(messages)) => listing(messages, argument, |entry| entry.id.clone()),
+            ("RETR", Some(messages)) => match message(messages, argument) {
+                Some((entry, _)) => match store.content(&entry.id)? {
+                    Some(content) => {
+                        write!(writer, "+OK {} octets\r\n", content.len())?;
+                        writer.write_all(&stuffed(&content))?;
+                        ".".to_string()
+                    }
+                    // Removed from the store since the login
+                    None => MSG_NO_SUCH_MESSAGE.to_string(),
+                },
+                None => MSG_NO_SUCH_MESSAGE.to_string(),
+            },
+            ("DELE", Some(messages)) => match message(messages, argument) {
+                Some((_, deleted)) => {
+                    *deleted = true;
+                    MSG_OK.to_string()
+                }
+                None => MSG_NO_SUCH_MESSAGE.to_string(),
+            },
+            ("RSET", Some(messages)) => {
+                for (_, deleted) in messages.iter_mut() {
+                    *deleted = false;
+                }
+                MSG_OK.to_string()
+            }
+            ("NOOP", Some(_)) => MSG_OK.to_string(),
+            _ => MSG_UNKNOWN_COMMAND.to_string(),
+        };
+        write!(writer, "{}\r\n", reply)?;
+    }
+}
+
+/// A message that is not deleted by its number, which starts at 1
+fn message<'a>(messages: &'a mut [(Entry, bool)], number: &str) -> Option<&'a mut (Entry, bool)> {
+    let index = number.parse::<usize>().ok()?.checked_sub(1)?;
+    messages.get_mut(index).filter(|(_, deleted)| !deleted)
+}
+
+/// The lines of a message as they are, whatever their encoding, ending with CRLF. Lines starting
+/// with the terminator are dot-stuffed, like in DATA.
+fn stuffed(content: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(content.len() + 2);
+    if content.is_empty() {
+        return stuffed;
+    }
+    // A line ending at the end of the content does not start another line
+    let content = content.strip_suffix(b"\n").unwrap_or(content);
+    for line in content.split(|&byte| byte == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b".") {
+            stuffed.push(b'.');
+        }
+        stuffed.extend_from_slice(line);
+        stuffed.extend_from_slice(b"\r\n");
+    }
+    stuffed
+}
+
+/// The reply to LIST or UIDL, for one message if there is an argument and for all otherwise
+fn listing(
+    messages: &mut [(Entry, bool)],
+    argument: &str,
+    describe: impl Fn(&Entry) -> String,
+) -> String {
+    if !argument.is_empty() {
+        return match message(messages, argument) {
+            Some((entry, _)) => format!("+OK {} {}", argument, describe(entry)),
+            None => MSG_NO_SUCH_MESSAGE.to_string(),
+        };
+    }
+    let mut reply = MSG_OK.to_string();
+    for (number, (entry, deleted)) in messages.iter().enumerate() {
+        if !deleted {
+            reply += &format!("\r\n{} {}", number + 1, describe(entry));
+        }
+    }
+    reply + "\r\n."
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Memory;
+
+    #[test]
+    fn retrieve_and_delete_messages() {
+        // Given
+        let store = Memory::default();
+        for (id, content) in [
+            ("1", "Subject: First\r\n\r\n.hidden\r\n"),
+            ("2", "Second\r\n"),
+        ] {
+            let entry = Entry {
+                id: id.to_string(),
+                received: 0,
+                size: content.len(),
+                summary: serde_json::json!({ "id": id }),
+            };
+            store.add(entry, content.as_bytes()).unwrap();
+        }
+        let credentials = Credentials {
+            user: "tester".to_string(),
+            password: "secret".to_string(),
+        };
+        let request = "USER tester\r\n\
+                       PASS wrong\r\n\
+                       USER tester\r\n\
+                       PASS secret\r\n\
+                       STAT\r\n\
+                       RETR 1\r\n\
+                       DELE 1\r\n\
+                       LIST\r\n\
+                       QUIT\r\n";
+        let mut response = Vec::new();
+
+        // When
+        session(
+            &mut request.as_bytes(),
+            &mut response,
+            &store,
+            Some(&credentials),
+        )
+        .unwrap();
+
+        // Then
+        let response = String::from_utf8(response).unwrap();
+        let replies: Vec<&str> = response.split("\r\n").collect();
+        assert_eq!(replies[2], MSG_INVALID_CREDENTIALS);
+        assert_eq!(replies[4], "+OK 2 messages");
+        assert_eq!(replies[5], "+OK 2 35");
+        assert_eq!(
+            replies[6..11],
+            ["+OK 27 octets", "Subject: First", "", "..hidden", "."]
+        );
+        assert_eq!(replies[12..15], ["+OK", "2 8", "."]);
+        assert_eq!(replies[15], MSG_BYE);
+        let entries = store.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, "2");
+    }
+    #[test]
+    fn retrieve_binary_content() {
+        // Given
+        let store = Memory::default();
+        let content = b"Subject: Latin-1\r\n\r\nGr\xfc\xdfe\n.\xff\r\n";
+        let entry = Entry {
+            id: "1".to_string(),
+            received: 0,
+            size: content.len(),
+            summary: serde_json::json!({ "id": "1" }),
+        };
+        store.add(entry, content).unwrap();
+        let request = "USER tester\r\nPASS secret\r\nRETR 1\r\nQUIT\r\n";
+        let mut response = Vec::new();
+
+        // When
+        session(&mut request.as_bytes(), &mut response, &store, None).unwrap();
+
+        // Then
+        let expected =
+            b"+OK 30 octets\r\nSubject: Latin-1\r\n\r\nGr\xfc\xdfe\r\n..\xff\r\n.\r\n+OK Bye\r\n";
+        assert!(
+            response.ends_with(expected),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+    }
+}
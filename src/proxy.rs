@@ -0,0 +1,135 @@
+//! The [PROXY protocol](https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt) of HAProxy
+//! and load balancers, which pass on the address of the client in a header before the session.
+//!
+//! Both the text header of version 1 and the binary header of version 2 are understood. Headers
+//! without an address, e.g. of the health checks of the proxy, leave the session with the address
+//! of the proxy.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_START: &[u8] = b"PROXY ";
+/// Size of the longest version 1 header, with its CRLF
+const V1_MAX_SIZE: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read the header from a stream, without reading any further, and get the address of the
+/// client if it has one
+pub fn read_header(stream: &mut dyn Read) -> Result<Option<SocketAddr>, Error> {
+    let mut start = [0; 6];
+    stream.read_exact(&mut start)?;
+    if start == V1_START {
+        read_v1(stream)
+    } else if start == V2_SIGNATURE[..6] {
+        read_v2(stream)
+    } else {
+        Err(invalid("no PROXY protocol header"))
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Read the rest of a header like `PROXY TCP4 192.0.2.1 198.51.100.1 56324 25`
+fn read_v1(stream: &mut dyn Read) -> Result<Option<SocketAddr>, Error> {
+    // Byte by byte, because the session starts right after the line
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if V1_START.len() + line.len() == V1_MAX_SIZE {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        let mut byte = [0];
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8_lossy(&line[..line.len() - 2]);
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["UNKNOWN", ..] => Ok(None),
+        [protocol @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid address in PROXY protocol header"))?;
+            let port: u16 = port
+                .parse()
+                .map_err(|_| invalid("invalid port in PROXY protocol header"))?;
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return Err(invalid(
+                    "address of the wrong family in PROXY protocol header",
+                ));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY protocol header")),
+    }
+}
+
+/// Read the rest of a binary header, after the start of its signature
+fn read_v2(stream: &mut dyn Read) -> Result<Option<SocketAddr>, Error> {
+    let mut header = [0; 10];
+    stream.read_exact(&mut header)?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("invalid PROXY protocol header"));
+    }
+    let (version_command, family) = (header[6], header[7]);
+    let mut addresses = vec![0; u16::from_be_bytes([header[8], header[9]]) as usize];
+    stream.read_exact(&mut addresses)?;
+    match version_command {
+        // LOCAL, the proxy's own connection
+        0x20 => return Ok(None),
+        // PROXY
+        0x21 => {}
+        _ => return Err(invalid("unsupported PROXY protocol version or command")),
+    }
+    // Source address, destination address, source port, destination port, then extensions
+    Ok(match (family >> 4, &addresses[..]) {
+        (1, [a, b, c, d, _, _, _, _, high, low, ..]) => Some(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(*a, *b, *c, *d)),
+            u16::from_be_bytes([*high, *low]),
+        )),
+        (2, addresses) if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(ip)),
+                u16::from_be_bytes([addresses[32], addresses[33]]),
+            ))
+        }
+        (1 | 2, _) => return Err(invalid("PROXY protocol header too short")),
+        // Unix sockets and unspecified families have no address to pass on
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_client_addresses() {
+        // Given
+        let v1 = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 25\r\nEHLO localhost\r\n";
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([
+            0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0, 25,
+        ]);
+        v2.extend(b"EHLO localhost\r\n");
+        let local = [&V2_SIGNATURE[..], &[0x20, 0x00, 0, 0]].concat();
+
+        // When
+        let mut v1_stream = &v1[..];
+        let v1_client = read_header(&mut v1_stream).unwrap();
+        let mut v2_stream = &v2[..];
+        let v2_client = read_header(&mut v2_stream).unwrap();
+
+        // Then
+        assert_eq!(v1_client, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(v1_stream, b"EHLO localhost\r\n");
+        assert_eq!(v2_client, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(v2_stream, b"EHLO localhost\r\n");
+        assert_eq!(read_header(&mut &local[..]).unwrap(), None);
+        assert!(read_header(&mut &b"EHLO localhost\r\n"[..]).is_err());
+        assert!(read_header(&mut &b"PROXY TCP4 2001:db8::1 ::1 1 2\r\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! A queue on disk for messages that could not be relayed, so they survive a restart.
+//!
+//! Each queued message is a pair of files named after its ID: the content as received in
+//! `<id>.eml` and the envelope and delivery state as JSON in `<id>.json`. Messages that are given
+//! up on move to the `bounced` subdirectory, where their state with the last error is the bounce
+//! record. Messages that the upstream server rejects with a 5xx reply are given up on right away,
+//! only 4xx replies and connection failures are retried.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{App, SubCommand};
+use serde_json::Value;
+
+use crate::client;
+use crate::clock::Clock;
+use crate::{Config, PrintFormat};
+
+/// Name of the subcommand
+pub const SUBCOMMAND_NAME: &str = "queue";
+
+/// Subdirectory with the messages that were given up on
+const BOUNCED_DIR: &str = "bounced";
+
+/// Delay before the first retry, doubled for every further one
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Longest delay between retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// A queue directory
+#[derive(Clone)]
+pub struct Queue {
+    pub path: PathBuf,
+    /// How long after queueing a message is given up on
+    pub lifetime: Duration,
+    pub clock: Arc<dyn Clock>,
+}
+
+/// A queued message without its content
+pub struct Entry {
+    pub id: String,
+    /// Domain the client introduced itself with
+    pub domain: String,
+    pub sender: String,
+    pub recipients: Vec<String>,
+    /// Seconds since the epoch when the message was queued
+    pub queued: u64,
+    /// Number of failed relay attempts
+    pub attempts: u32,
+    /// Seconds since the epoch when the message is retried next
+    pub next_attempt: u64,
+    /// Why the last attempt failed
+    pub error: String,
+}
+
+impl Entry {
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "id": self.id,
+            "domain": self.domain,
+            "from": self.sender,
+            "to": self.recipients,
+            "queued": self.queued,
+            "attempts": self.attempts,
+            "next_attempt": self.next_attempt,
+            "error": self.error,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Entry> {
+        let string = |key: &str| value[key].as_str().map(str::to_string);
+        Some(Entry {
+            id: string("id")?,
+            domain: string("domain")?,
+            sender: string("from")?,
+            recipients: value["to"]
+                .as_array()?
+                .iter()
+                .map(|recipient| recipient.as_str().map(str::to_string))
+                .collect::<Option<_>>()?,
+            queued: value["queued"].as_u64()?,
+            attempts: u32::try_from(value["attempts"].as_u64()?).ok()?,
+            next_attempt: value["next_attempt"].as_u64()?,
+            error: string("error")?,
+        })
+    }
+}
+
+/// The delay before the next attempt after the given number of failed ones
+fn retry_delay(attempts: u32) -> Duration {
+    MIN_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Make a name for a new message that no other message of this or another process has
+fn new_id() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos());
+    format!(
+        "{}-{}-{}",
+        nanos,
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+/// Write a file so that readers never see it half written
+pub fn write_atomically(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let mut partial_path = path.as_os_str().to_owned();
+    partial_path.push(".partial");
+    fs::write(&partial_path, content)?;
+    fs::rename(&partial_path, path)
+}
+
+impl Queue {
+    /// Queue a message after the first relay attempt failed, or bounce it right away if the
+    /// failure was permanent
+    pub fn add(
+        &self,
+        domain: &str,
+        sender: &str,
+        recipients: &[String],
+        content: &[u8],
+        error: &Error,
+    ) -> Result<Entry, Error> {
+        fs::create_dir_all(&self.path)?;
+        let now = self.clock.unix_time();
+        let entry = Entry {
+            id: new_id(),
+            domain: domain.to_string(),
+            sender: sender.to_string(),
+            recipients: recipients.to_vec(),
+            queued: now,
+            attempts: 1,
+            next_attempt: now + retry_delay(1).as_secs(),
+            error: error.to_string(),
+        };
+        // The state is written last, so a message is only seen once its content is complete
+        write_atomically(&self.path.join(format!("{}.eml", entry.id)), content)?;
+        self.save(&entry)?;
+        if client::is_permanent(error) {
+            self.bounce(&entry)?;
+        }
+        Ok(entry)
+    }
+
+    /// The queued messages, oldest first
+    pub fn entries(&self) -> Result<Vec<Entry>, Error> {
+        read_entries(&self.path)
+    }
+
+    /// The messages that were given up on, oldest first
+    pub fn bounced(&self) -> Result<Vec<Entry>, Error> {
+        read_entries(&self.path.join(BOUNCED_DIR))
+    }
+
+    /// Read the content of a queued message
+    pub fn content(&self, entry: &Entry) -> Result<Vec<u8>, Error> {
+        fs::read(self.path.join(format!("{}.eml", entry.id)))
+    }
+
+    /// Remove a message that was relayed
+    pub fn remove(&self, entry: &Entry) -> Result<(), Error> {
+        fs::remove_file(self.path.join(format!("{}.json", entry.id)))?;
+        fs::remove_file(self.path.join(format!("{}.eml", entry.id)))
+    }
+
+    /// Record another failed attempt. Returns whether the message has been given up on, because
+    /// the failure was permanent or the message is too old, and moved to the bounced messages.
+    pub fn fail(&self, entry: &mut Entry, error: &Error) -> Result<bool, Error> {
+        let now = self.clock.unix_time();
+        entry.attempts += 1;
+        entry.error = error.to_string();
+        if client::is_permanent(error)
+            || now >= entry.queued.saturating_add(self.lifetime.as_secs())
+        {
+            self.bounce(entry)?;
+            return Ok(true);
+        }
+        entry.next_attempt = now + retry_delay(entry.attempts).as_secs();
+        self.save(entry)?;
+        Ok(false)
+    }
+
+    /// Move a queued message to the bounced messages
+    fn bounce(&self, entry: &Entry) -> Result<(), Error> {
+        let bounced = self.path.join(BOUNCED_DIR);
+        fs::create_dir_all(&bounced)?;
+        write_atomically(
+            &bounced.join(format!("{}.json", entry.id)),
+            entry.to_json().to_string().as_bytes(),
+        )?;
+        fs::rename(
+            self.path.join(format!("{}.eml", entry.id)),
+            bounced.join(format!("{}.eml", entry.id)),
+        )?;
+        fs::remove_file(self.path.join(format!("{}.json", entry.id)))
+    }
+
+    fn save(&self, entry: &Entry) -> Result<(), Error> {
+        write_atomically(
+            &self.path.join(format!("{}.json", entry.id)),
+            entry.to_json().to_string().as_bytes(),
+        )
+    }
+}
+
+/// Read the states of the messages in a directory, oldest first.
+/// A directory that does not exist yet has no messages.
+fn read_entries(path: &Path) -> Result<Vec<Entry>, Error> {
+    let dir = match fs::read_dir(path) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    for file in dir {
+        let path = file?.path();
+        if path.extension().is_none_or(|extension| extension != "json") {
+            continue;
+        }
+        let entry = serde_json::from_slice(&fs::read(&path)?)
+            .ok()
+            .and_then(|value: Value| Entry::from_json(&value))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid queue file {}", path.display()),
+                )
+            })?;
+        entries.push(entry);
+    }
+    entries.sort_by(|a, b| (a.queued, &a.id).cmp(&(b.queued, &b.id)));
+    Ok(entries)
+}
+
+/// The command line definition of the subcommand, taking the same settings as the server
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("List the messages in the relay queues of the servers, then exit")
+        .args(&crate::server_args())
+}
+
+/// Print the queued and bounced messages of the main server and the virtual servers on stdout
+pub fn run(configs: &[Config]) -> Result<(), Error> {
+    for config in configs {
+        let Some(queue) = config.relay.as_ref().and_then(|relay| relay.queue.as_ref()) else {
+            continue;
+        };
+        let now = queue.clock.unix_time();
+        for (state, entries) in [("queued", queue.entries()?), ("bounced", queue.bounced()?)] {
+            for entry in entries {
+                print_entry(config, state, &entry, now);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_entry(config: &Config, state: &str, entry: &Entry, now: u64) {
+    if config.print_format == PrintFormat::Jsonl {
+        let mut object = entry.to_json();
+        object["state"] = state.into();
+        if let Some(name) = &config.name {
+            object["server"] = name.as_str().into();
+        }
+        println!("{}", object);
+        return;
+    }
+
+    if let Some(name) = &config.name {
+        print!("[{}] ", name);
+    }
+    print!(
+        "{} {} {} -> {}, queued {}s ago, {} attempts",
+        entry.id,
+        state,
+        entry.sender,
+        entry.recipients.join(", "),
+        now.saturating_sub(entry.queued),
+        entry.attempts
+    );
+    if state == "queued" {
+        print!(", next in {}s", entry.next_attempt.saturating_sub(now));
+    }
+    println!(": {}", entry.error);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FrozenClock;
+
+    #[test]
+    fn increase_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::from_secs(60));
+        assert_eq!(retry_delay(2), Duration::from_secs(120));
+        assert_eq!(retry_delay(6), Duration::from_secs(1920));
+        assert_eq!(retry_delay(7), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn queue_retry_and_bounce() {
+        // Given
+        let path = std::env::temp_dir().join(format!("smtp-queue-test-{}", new_id()));
+        let mut queue = Queue {
+            path: path.clone(),
+            lifetime: Duration::from_secs(3600),
+            clock: Arc::new(FrozenClock::at(1_700_000_000)),
+        };
+        let error = Error::other("connection refused");
+        let recipients = vec!["<admin@localhost>".to_string()];
+
+        // When
+        let entry = queue
+            .add(
+                "localhost",
+                "<tester@localhost>",
+                &recipients,
+                b"Hello\r\n",
+                &error,
+            )
+            .unwrap();
+        let mut entries = queue.entries().unwrap();
+        let bounced_early = queue.fail(&mut entries[0], &error).unwrap();
+        let retried = queue.entries().unwrap();
+        queue.clock = Arc::new(FrozenClock::at(1_700_003_600));
+        let mut entries = queue.entries().unwrap();
+        let bounced_late = queue.fail(&mut entries[0], &error).unwrap();
+
+        // Then
+        assert!(!bounced_early);
+        assert_eq!(entry.next_attempt, 1_700_000_060);
+        assert_eq!(retried[0].next_attempt, 1_700_000_120);
+        assert!(bounced_late);
+        assert!(queue.entries().unwrap().is_empty());
+        let bounced = queue.bounced().unwrap();
+        assert_eq!(bounced.len(), 1);
+        assert_eq!(bounced[0].id, entry.id);
+        assert_eq!(bounced[0].recipients, recipients);
+        assert_eq!(bounced[0].attempts, 3);
+        assert_eq!(bounced[0].error, "connection refused");
+        assert_eq!(
+            fs::read(path.join(BOUNCED_DIR).join(format!("{}.eml", entry.id))).unwrap(),
+            b"Hello\r\n"
+        );
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn bounce_permanent_failures_right_away() {
+        // Given
+        let path = std::env::temp_dir().join(format!("smtp-queue-test-{}", new_id()));
+        let queue = Queue {
+            path: path.clone(),
+            lifetime: Duration::from_secs(3600),
+            clock: Arc::new(FrozenClock::at(1_700_000_000)),
+        };
+        let temporary = Error::other(client::Reply {
+            code: 451,
+            text: "Try again later".to_string(),
+        });
+        let permanent = Error::other(client::Reply {
+            code: 550,
+            text: "No such user".to_string(),
+        });
+        let recipients = vec!["<admin@localhost>".to_string()];
+
+        // When
+        let rejected = queue
+            .add("localhost", "<a@localhost>", &recipients, b"A", &permanent)
+            .unwrap();
+        let deferred = queue
+            .add("localhost", "<b@localhost>", &recipients, b"B", &temporary)
+            .unwrap();
+        let mut entries = queue.entries().unwrap();
+        let bounced_on_retry = queue.fail(&mut entries[0], &permanent).unwrap();
+
+        // Then
+        assert!(bounced_on_retry);
+        assert!(queue.entries().unwrap().is_empty());
+        let bounced = queue.bounced().unwrap();
+        let ids: Vec<&str> = bounced.iter().map(|entry| entry.id.as_str()).collect();
+        assert_eq!(ids, [rejected.id.as_str(), deferred.id.as_str()]);
+        assert_eq!(bounced[0].attempts, 1);
+        assert_eq!(bounced[0].error, "unexpected reply: 550 No such user");
+        assert_eq!(bounced[1].attempts, 2);
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
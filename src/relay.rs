@@ -0,0 +1,671 @@
+//! Relaying received messages to an upstream SMTP server, e.g. the real provider of an
+//! application, so that the server captures messages without keeping them from their recipients.
+//!
+//! Connections to upstream servers are plain, or encrypted with STARTTLS or TLS from the start,
+//! verifying the certificates of the servers. Credentials are only ever sent encrypted.
+//!
+//! Recipients can be routed to different upstream servers by domain, like a transport map.
+//! Addresses can be rewritten before relaying, and messages can be signed with DKIM on the way,
+//! as if they were sent by the domain of the key.
+//!
+//! Connections to upstream servers are kept open for a while after use, so that bursts of
+//! messages, e.g. released ones, do not pay for connecting, TLS and logging in every time. A
+//! pooled connection is checked with RSET before it is used again.
+//!
+//! With a queue, messages that fail to relay are kept on disk and retried with an increasing
+//! delay until they are relayed or given up on. Recipients that the upstream server rejects with
+//! a 5xx reply are given up on right away.
+
+use std::io::Error;
+use std::mem;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::{self, Client, Encryption};
+use crate::dkim::Signer;
+use crate::queue::{Entry, Queue};
+use crate::rewrite::Rules;
+use crate::Session;
+
+/// How often the queue is checked for messages that are due for a retry
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a connection to an upstream server is kept open without being used
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Most connections kept open for reuse, over all upstream servers
+const POOL_SIZE: usize = 8;
+
+/// Where and how to relay messages
+#[derive(Clone)]
+pub struct Relay {
+    /// Address of the default upstream server as host:port
+    pub address: String,
+    /// Recipient domains and the addresses of their upstream servers, instead of the default one
+    pub routes: Vec<(String, String)>,
+    /// User and password to log in to the default upstream server with
+    pub credentials: Option<(String, String)>,
+    /// How connections to all upstream servers are encrypted
+    pub encryption: Encryption,
+    /// Only relay kept messages that are released, not every received one
+    pub on_release: bool,
+    /// How addresses are rewritten
+    pub rewrite: Rules,
+    /// Key to sign messages with
+    pub signer: Option<Signer>,
+    /// Queue for messages that failed to relay, which are dropped without one
+    pub queue: Option<Queue>,
+    /// Open connections to upstream servers, shared by all clones
+    pub pool: Arc<Pool>,
+}
+
+/// Connections to upstream servers that are kept open for reuse
+pub struct Pool {
+    /// Address of the server, domain we introduced ourselves with, the connection and since
+    /// when it is idle
+    idle: Mutex<Vec<(String, String, Client, Instant)>>,
+    idle_timeout: Duration,
+}
+
+impl Default for Pool {
+    fn default() -> Pool {
+        Pool::new(POOL_IDLE_TIMEOUT)
+    }
+}
+
+impl Pool {
+    pub fn new(idle_timeout: Duration) -> Pool {
+        Pool {
+            idle: Mutex::new(Vec::new()),
+            idle_timeout,
+        }
+    }
+
+    /// Take an open connection to an upstream server that still answers, if there is one
+    fn take(&self, address: &str, domain: &str) -> Option<Client> {
+        loop {
+            let mut client = {
+                let mut idle = self.idle();
+                let index = idle
+                    .iter()
+                    .position(|(open, introduced, _, _)| open == address && introduced == domain)?;
+                idle.remove(index).2
+            };
+            match client.reset() {
+                Ok(()) => return Some(client),
+                Err(e) => tracing::debug!("Dropping the connection to {}: {}", address, e),
+            }
+        }
+    }
+
+    /// Keep a connection for reuse, or end it if enough are kept already
+    fn put(&self, address: &str, domain: &str, client: Client) {
+        let mut idle = self.idle();
+        if idle.len() < POOL_SIZE {
+            idle.push((
+                address.to_string(),
+                domain.to_string(),
+                client,
+                Instant::now(),
+            ));
+            return;
+        }
+        drop(idle);
+        quit(address, client);
+    }
+
+    /// End the connections that were not used for a while, forever
+    pub fn expire_idle(&self) {
+        loop {
+            thread::sleep(self.idle_timeout / 4);
+            self.expire();
+        }
+    }
+
+    /// End the connections that were not used for a while
+    fn expire(&self) {
+        let expired: Vec<_> = {
+            let mut idle = self.idle();
+            let (expired, kept) = mem::take(&mut *idle)
+                .into_iter()
+                .partition(|(_, _, _, since)| since.elapsed() >= self.idle_timeout);
+            *idle = kept;
+            expired
+        };
+        for (address, _, client, _) in expired {
+            quit(&address, client);
+        }
+    }
+
+    /// End all kept connections, e.g. before the process exits
+    pub fn close(&self) {
+        let idle = mem::take(&mut *self.idle());
+        for (address, _, client, _) in idle {
+            quit(&address, client);
+        }
+    }
+
+    /// A panic while holding the lock cannot leave the list inconsistent
+    fn idle(&self) -> MutexGuard<'_, Vec<(String, String, Client, Instant)>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// End the session with an upstream server. All messages were accepted or have failed already,
+/// so a failing goodbye does not matter.
+fn quit(address: &str, client: Client) {
+    if let Err(e) = client.quit() {
+        tracing::error!("Ending the session with {} failed: {}", address, e);
+    }
+}
+
+/// Relay the messages of every session received until the channel closes.
+/// Messages that fail to relay are queued, or logged and dropped without a queue.
+pub fn run(relay: Relay, sessions: Receiver<Arc<Session>>) {
+    for session in sessions {
+        let connection = &session.connection;
+        let (Some(domain), Some(messages)) =
+            (connection.get_sender_domain(), connection.get_messages())
+        else {
+            continue;
+        };
+
+        let mut connections = Connections::new(&relay, domain);
+        for message in messages {
+            let failures = connections.deliver(
+                message.get_sender(),
+                message.get_recipients(),
+                message.get_content(),
+            );
+            let Some(queue) = &relay.queue else {
+                continue;
+            };
+            for (recipients, e) in failures {
+                match queue.add(
+                    domain,
+                    message.get_sender(),
+                    &recipients,
+                    message.get_content(),
+                    &e,
+                ) {
+                    Ok(entry) if client::is_permanent(&e) => {
+                        tracing::error!("Giving up on relaying message {}: {}", entry.id, e)
+                    }
+                    Ok(entry) => tracing::info!("Queued message {} for another attempt", entry.id),
+                    Err(e) => tracing::error!("Queueing a message failed, dropping it: {}", e),
+                }
+            }
+        }
+        connections.finish();
+    }
+    relay.pool.close();
+}
+
+/// Relay a message that was kept instead of relayed, e.g. to let a captured message through.
+/// Returns the reason of the last route that failed.
+pub fn release(
+    relay: &Relay,
+    domain: &str,
+    sender: &str,
+    recipients: &[String],
+    content: &[u8],
+) -> Result<(), Error> {
+    let mut connections = Connections::new(relay, domain);
+    let mut failures = connections.deliver(sender, recipients, content);
+    connections.finish();
+    failures.pop().map_or(Ok(()), |(_, e)| Err(e))
+}
+
+/// Retry the queued messages that are due, forever
+pub fn retry_queued(relay: Relay) {
+    let Some(queue) = &relay.queue else {
+        return;
+    };
+    loop {
+        match queue.entries() {
+            Ok(entries) => {
+                let now = queue.clock.unix_time();
+                for mut entry in entries
+                    .into_iter()
+                    .filter(|entry| entry.next_attempt <= now)
+                {
+                    retry(&relay, queue, &mut entry);
+                }
+            }
+            Err(e) => tracing::error!("Reading the relay queue failed: {}", e),
+        }
+        thread::sleep(QUEUE_POLL_INTERVAL);
+    }
+}
+
+/// Retry a queued message and update the queue with the outcome.
+/// Only the recipients whose route failed again for a temporary reason stay queued, those
+/// rejected for good are bounced on their own.
+fn retry(relay: &Relay, queue: &Queue, entry: &mut Entry) {
+    let failures = match queue.content(entry) {
+        Ok(content) => {
+            let mut connections = Connections::new(relay, &entry.domain);
+            let failures = connections.deliver(&entry.sender, &entry.recipients, &content);
+            connections.finish();
+            let (permanent, temporary): (Vec<_>, Vec<_>) = failures
+                .into_iter()
+                .partition(|(_, e)| client::is_permanent(e));
+            if temporary.is_empty() {
+                permanent
+            } else {
+                for (recipients, e) in permanent {
+                    match queue.add(&entry.domain, &entry.sender, &recipients, &content, &e) {
+                        Ok(bounced) => tracing::error!(
+                            "Giving up on relaying message {} to {}, bounced as {}: {}",
+                            entry.id,
+                            recipients.join(", "),
+                            bounced.id,
+                            e
+                        ),
+                        Err(e) => tracing::error!("Updating the relay queue failed: {}", e),
+                    }
+                }
+                temporary
+            }
+        }
+        Err(e) => vec![(entry.recipients.clone(), e)],
+    };
+    let Some((_, e)) = failures.last() else {
+        if let Err(e) = queue.remove(entry) {
+            tracing::error!("Updating the relay queue failed: {}", e);
+        }
+        return;
+    };
+
+    entry.recipients = failures
+        .iter()
+        .flat_map(|(recipients, _)| recipients.iter().cloned())
+        .collect();
+    match queue.fail(entry, e) {
+        Ok(true) => tracing::error!(
+            "Giving up on relaying message {} after {} attempts: {}",
+            entry.id,
+            entry.attempts,
+            e
+        ),
+        Ok(false) => {}
+        Err(e) => tracing::error!("Updating the relay queue failed: {}", e),
+    }
+}
+
+impl Relay {
+    /// The upstream server for a recipient address after rewriting
+    fn route(&self, recipient: &str) -> &str {
+        let domain = recipient.rsplit_once('@').map_or("", |(_, domain)| domain);
+        self.routes
+            .iter()
+            .find(|(route_domain, _)| route_domain.eq_ignore_ascii_case(domain))
+            .map_or(&self.address, |(_, address)| address)
+    }
+
+    /// Group recipients as received by the upstream server their rewritten address is routed to
+    fn routes(&self, recipients: &[String]) -> Vec<(&str, Vec<String>)> {
+        let mut routes: Vec<(&str, Vec<String>)> = Vec::new();
+        for recipient in recipients {
+            let rewritten = self
+                .rewrite
+                .recipients(&[path_address(recipient).to_string()]);
+            let address = self.route(&rewritten[0]);
+            match routes.iter_mut().find(|(route, _)| *route == address) {
+                Some((_, recipients)) => recipients.push(recipient.clone()),
+                None => routes.push((address, vec![recipient.clone()])),
+            }
+        }
+        routes
+    }
+}
+
+/// The connections to upstream servers while relaying the messages of a session
+struct Connections<'a> {
+    relay: &'a Relay,
+    /// Domain the client introduced itself with, which we introduce ourselves with upstream
+    domain: &'a str,
+    /// Open connections by address
+    clients: Vec<(&'a str, Client)>,
+}
+
+impl<'a> Connections<'a> {
+    fn new(relay: &'a Relay, domain: &'a str) -> Connections<'a> {
+        Connections {
+            relay,
+            domain,
+            clients: Vec::new(),
+        }
+    }
+
+    /// Relay a message to the upstream servers of its recipients.
+    /// Returns the recipients as received that failed, grouped by route, with the reason.
+    fn deliver(
+        &mut self,
+        sender: &str,
+        recipients: &[String],
+        content: &[u8],
+    ) -> Vec<(Vec<String>, Error)> {
+        let mut failures = Vec::new();
+        for (address, recipients) in self.relay.routes(recipients) {
+            if let Err(e) = self.send(address, sender, &recipients, content) {
+                tracing::warn!("Relaying to {} failed: {}", address, e);
+                failures.push((recipients, e));
+            }
+        }
+        failures
+    }
+
+    /// Send a message over the connection to an upstream server, opening it if needed
+    fn send(
+        &mut self,
+        address: &'a str,
+        sender: &str,
+        recipients: &[String],
+        content: &[u8],
+    ) -> Result<(), Error> {
+        let index = match self.clients.iter().position(|(open, _)| *open == address) {
+            Some(index) => index,
+            None => {
+                let client = match self.relay.pool.take(address, self.domain) {
+                    Some(client) => client,
+                    None => connect(self.relay, address, self.domain)?,
+                };
+                self.clients.push((address, client));
+                self.clients.len() - 1
+            }
+        };
+        let outcome = send(
+            self.relay,
+            &mut self.clients[index].1,
+            sender,
+            recipients,
+            content,
+        );
+        // After a failure the state of the session is unknown, so the connection is not reused
+        if outcome.is_err() {
+            self.clients.remove(index);
+        }
+        outcome
+    }
+
+    /// Keep the connections for the next messages
+    fn finish(self) {
+        for (address, client) in self.clients {
+            self.relay.pool.put(address, self.domain, client);
+        }
+    }
+}
+
+/// Connect to an upstream server, logging in if it is the default one and there are credentials
+fn connect(relay: &Relay, address: &str, domain: &str) -> Result<Client, Error> {
+    let credentials = relay
+        .credentials
+        .as_ref()
+        .filter(|_| address == relay.address)
+        .map(|(user, password)| (user.as_str(), password.as_str()));
+    Client::connect_with(address, domain, relay.encryption, credentials)
+}
+
+/// Send a message with the sender and recipients as received after rewriting, signed if there is
+/// a key
+fn send(
+    relay: &Relay,
+    client: &mut Client,
+    sender: &str,
+    recipients: &[String],
+    content: &[u8],
+) -> Result<(), Error> {
+    let recipients: Vec<String> = recipients
+        .iter()
+        .map(|recipient| path_address(recipient).to_string())
+        .collect();
+    let sender = relay.rewrite.sender(path_address(sender));
+    let recipients = relay.rewrite.recipients(&recipients);
+    let content = relay.rewrite.content(content);
+    match &relay.signer {
+        Some(signer) => client.send(&sender, &recipients, &signer.sign(&content)?),
+        None => client.send(&sender, &recipients, &content),
+    }
+}
+
+/// Get the address of a reverse or forward path as received, e.g. `<someone@example.com>`
+pub fn path_address(path: &str) -> &str {
+    let path = path.trim();
+    path.strip_prefix('<')
+        .and_then(|path| path.strip_suffix('>'))
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FrozenClock;
+    use crate::smtp::Connection;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn relay_to_upstream_server() {
+        // Given
+        let request = "HELO client.example\n\
+                       MAIL FROM: <tester@localhost>\n\
+                       RCPT TO: <admin@localhost>\n\
+                       DATA\n\
+                       Subject: test\r\n\r\n..leading dot\r\n\
+                       .\n\
+                       QUIT\n";
+        let received =
+            Connection::handle(&mut BufReader::new(request.as_bytes()), &mut Vec::new()).unwrap();
+
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = Relay {
+            address: upstream.local_addr().unwrap().to_string(),
+            routes: Vec::new(),
+            credentials: None,
+            encryption: Encryption::None,
+            on_release: false,
+            rewrite: Rules::default(),
+            signer: None,
+            queue: None,
+            pool: Arc::default(),
+        };
+        let server = thread::spawn(move || {
+            let (stream, _) = upstream.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            Connection::handle(&mut reader, &mut &stream).unwrap()
+        });
+
+        // When
+        let message = &received.get_messages().unwrap()[0];
+        let mut connections = Connections::new(&relay, received.get_sender_domain().unwrap());
+        let failures = connections.deliver(
+            message.get_sender(),
+            message.get_recipients(),
+            message.get_content(),
+        );
+        connections.finish();
+        relay.pool.close();
+
+        // Then
+        assert!(failures.is_empty());
+        let relayed = server.join().unwrap();
+        assert_eq!(relayed.get_sender_domain(), Some("client.example"));
+        let message = &relayed.get_messages().unwrap()[0];
+        assert_eq!(message.get_sender(), "<tester@localhost>");
+        assert_eq!(message.get_recipients().join(", "), "<admin@localhost>");
+        assert_eq!(
+            message.get_content(),
+            b"Subject: test\r\n\r\n.leading dot\r\n"
+        );
+    }
+
+    #[test]
+    fn reuse_pooled_connections_until_idle() {
+        // Given
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = Relay {
+            address: upstream.local_addr().unwrap().to_string(),
+            routes: Vec::new(),
+            credentials: None,
+            encryption: Encryption::None,
+            on_release: false,
+            rewrite: Rules::default(),
+            signer: None,
+            queue: None,
+            pool: Arc::new(Pool::new(Duration::ZERO)),
+        };
+        // Only one connection is accepted, so a second one would fail to deliver
+        let server = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 upstream\r\n").unwrap();
+            let mut commands = Vec::new();
+            let mut content = false;
+            for line in reader.lines() {
+                let line = line.unwrap();
+                let reply = match line.as_str() {
+                    "." if content => {
+                        content = false;
+                        "250 OK"
+                    }
+                    _ if content => continue,
+                    "DATA" => {
+                        content = true;
+                        "354 Go ahead"
+                    }
+                    "QUIT" => "221 Bye",
+                    _ => "250 OK",
+                };
+                commands.push(line);
+                stream
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .unwrap();
+            }
+            commands
+        });
+        let recipients = ["<admin@localhost>".to_string()];
+
+        // When
+        for _ in 0..2 {
+            let mut connections = Connections::new(&relay, "client.example");
+            let failures = connections.deliver("<tester@localhost>", &recipients, b"Hello\r\n");
+            assert!(failures.is_empty());
+            connections.finish();
+        }
+        relay.pool.expire();
+
+        // Then
+        let commands = server.join().unwrap();
+        let count = |command: &str| commands.iter().filter(|line| *line == command).count();
+        assert_eq!(count("DATA"), 2);
+        assert_eq!(count("RSET"), 1);
+        assert_eq!(commands.last().map(String::as_str), Some("QUIT"));
+        assert!(relay.pool.idle().is_empty());
+    }
+
+    #[test]
+    fn bounce_rejected_messages_without_retrying() {
+        // Given
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = std::env::temp_dir().join(format!("smtp-relay-test-{}", std::process::id()));
+        let queue = Queue {
+            path: path.clone(),
+            lifetime: Duration::from_secs(3600),
+            clock: Arc::new(FrozenClock::at(1_700_000_000)),
+        };
+        let relay = Relay {
+            address: upstream.local_addr().unwrap().to_string(),
+            routes: Vec::new(),
+            credentials: None,
+            encryption: Encryption::None,
+            on_release: false,
+            rewrite: Rules::default(),
+            signer: None,
+            queue: Some(queue.clone()),
+            pool: Arc::default(),
+        };
+        let server = thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 upstream\r\n").unwrap();
+            for line in reader.lines() {
+                let reply = match line.unwrap() {
+                    line if line.starts_with("RCPT") => "550 No such user",
+                    _ => "250 OK",
+                };
+                if stream
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        let mut entry = queue
+            .add(
+                "localhost",
+                "<tester@localhost>",
+                &["<nobody@localhost>".to_string()],
+                b"Hello\r\n",
+                &Error::other("connection refused"),
+            )
+            .unwrap();
+
+        // When
+        retry(&relay, &queue, &mut entry);
+
+        // Then
+        server.join().unwrap();
+        assert!(queue.entries().unwrap().is_empty());
+        let bounced = queue.bounced().unwrap();
+        assert_eq!(bounced.len(), 1);
+        assert_eq!(bounced[0].error, "unexpected reply: 550 No such user");
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn route_by_recipient_domain() {
+        // Given
+        let relay = Relay {
+            address: "smarthost:25".to_string(),
+            routes: vec![("Partner.example".to_string(), "partner-mx:25".to_string())],
+            credentials: None,
+            encryption: Encryption::None,
+            on_release: false,
+            rewrite: Rules {
+                domains: vec![("customer.com".to_string(), "partner.example".to_string())],
+                ..Rules::default()
+            },
+            signer: None,
+            queue: None,
+            pool: Arc::default(),
+        };
+        let recipients = [
+            "<a@partner.example>",
+            "<b@other.example>",
+            "<c@customer.com>",
+        ]
+        .map(str::to_string);
+
+        // When
+        let routes = relay.routes(&recipients);
+
+        // Then
+        assert_eq!(
+            routes,
+            vec![
+                (
+                    "partner-mx:25",
+                    vec![
+                        "<a@partner.example>".to_string(),
+                        "<c@customer.com>".to_string()
+                    ]
+                ),
+                ("smarthost:25", vec!["<b@other.example>".to_string()]),
+            ]
+        );
+    }
+}
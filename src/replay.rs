@@ -0,0 +1,298 @@
+//! Recording SMTP sessions as they go over the wire, and replaying the client side of a
+//! recording against a server, to check protocol changes against what real clients send.
+//!
+//! A recording has a line for every line that went over the wire, with `C: ` in front of those
+//! of the client and `S: ` in front of those of the server, and the original line endings. Lines
+//! starting with `#` are comments. Replaying sends the client lines in order and compares the
+//! code of every reply with the recorded one.
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+/// Name of the subcommand
+pub const SUBCOMMAND_NAME: &str = "replay";
+
+const TARGET_ARG_NAME: &str = "target";
+const FILE_ARG_NAME: &str = "file";
+
+/// How long to wait for a reply
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const CLIENT_PREFIX: &[u8] = b"C: ";
+const SERVER_PREFIX: &[u8] = b"S: ";
+
+pub struct Options {
+    pub target: String,
+    /// Recordings to replay
+    pub files: Vec<String>,
+}
+
+/// The command line definition of the subcommand
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Replay recorded SMTP sessions against a server and compare the replies")
+        .arg(
+            Arg::with_name(TARGET_ARG_NAME)
+                .long(TARGET_ARG_NAME)
+                .help("Address of the server as host:port")
+                .default_value("localhost:2525"),
+        )
+        .arg(
+            Arg::with_name(FILE_ARG_NAME)
+                .help("Recording made with --record")
+                .multiple(true)
+                .required(true),
+        )
+}
+
+/// Get the options from the parsed subcommand arguments
+pub fn options(matches: &ArgMatches) -> Options {
+    Options {
+        target: matches.value_of(TARGET_ARG_NAME).unwrap().to_string(),
+        files: matches
+            .values_of(FILE_ARG_NAME)
+            .unwrap()
+            .map(str::to_string)
+            .collect(),
+    }
+}
+
+/// Replay the recordings one after the other, reporting on stdout the replies that differ
+pub fn run(options: Options) -> Result<(), Error> {
+    let mut differences = 0;
+    for path in &options.files {
+        let replies = replay(&options.target, &fs::read(path)?)?;
+        let differing: Vec<&(String, String)> = replies
+            .iter()
+            .filter(|(expected, actual)| reply_code(expected) != reply_code(actual))
+            .collect();
+        for (expected, actual) in &differing {
+            println!("{}: expected {:?}, got {:?}", path, expected, actual);
+        }
+        println!(
+            "{}: {} of {} replies as recorded",
+            path,
+            replies.len() - differing.len(),
+            replies.len()
+        );
+        differences += differing.len();
+    }
+    if differences > 0 {
+        return Err(Error::other(format!("{} replies differ", differences)));
+    }
+    Ok(())
+}
+
+/// Send the client lines of a recording and get the last line of every recorded reply together
+/// with that of the actual one. Replies after the server closed the connection are empty.
+fn replay(target: &str, recording: &[u8]) -> Result<Vec<(String, String)>, Error> {
+    let mut stream = TcpStream::connect(target)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut replies = Vec::new();
+    let mut closed = false;
+    for line in recording.split_inclusive(|&b| b == b'\n') {
+        if let Some(line) = line.strip_prefix(CLIENT_PREFIX) {
+            // A server that closed the connection may not take the rest of the session
+            if !closed {
+                stream.write_all(line)?;
+            }
+        } else if let Some(line) = line.strip_prefix(SERVER_PREFIX) {
+            let expected = String::from_utf8_lossy(line).trim_end().to_string();
+            if is_continued(&expected) {
+                continue;
+            }
+            let actual = if closed {
+                String::new()
+            } else {
+                read_reply(&mut reader)?
+            };
+            closed |= actual.is_empty();
+            replies.push((expected, actual));
+        } else if !line.starts_with(b"#") && !line.trim_ascii().is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "not a recorded line: {:?}",
+                    String::from_utf8_lossy(line).trim_end()
+                ),
+            ));
+        }
+    }
+    Ok(replies)
+}
+
+/// Read a reply of one or more lines and get its last line, empty if the connection is closed
+fn read_reply(reader: &mut dyn BufRead) -> Result<String, Error> {
+    loop {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(String::new());
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+        if !is_continued(&line) {
+            return Ok(line);
+        }
+    }
+}
+
+/// Whether a line of a reply is followed by more, like `250-PIPELINING`
+fn is_continued(line: &str) -> bool {
+    line.as_bytes().get(3) == Some(&b'-')
+}
+
+fn reply_code(line: &str) -> &str {
+    line.get(..3).unwrap_or(line)
+}
+
+/// The wire transcript of a session, made of what both directions of a connection pass on
+#[derive(Clone, Default)]
+pub struct Recording(Rc<RefCell<Transcript>>);
+
+#[derive(Default)]
+struct Transcript {
+    text: Vec<u8>,
+    /// Lines of the client and the server that are not complete yet
+    partial: [Vec<u8>; 2],
+}
+
+impl Transcript {
+    fn add(&mut self, prefix: &'static [u8], bytes: &[u8]) {
+        let Transcript { text, partial } = self;
+        let partial = &mut partial[(prefix == SERVER_PREFIX) as usize];
+        for line in bytes.split_inclusive(|&b| b == b'\n') {
+            partial.extend_from_slice(line);
+            if partial.ends_with(b"\n") {
+                text.extend_from_slice(prefix);
+                text.append(partial);
+            }
+        }
+    }
+}
+
+/// A stream that adds what is read from or written to it to a recording
+pub struct Recorded<S> {
+    stream: S,
+    recording: Recording,
+    prefix: &'static [u8],
+}
+
+impl Recording {
+    /// Record what the client sends, as read from its stream
+    pub fn client<S: Read>(&self, stream: S) -> Recorded<S> {
+        Recorded {
+            stream,
+            recording: self.clone(),
+            prefix: CLIENT_PREFIX,
+        }
+    }
+
+    /// Record what the server replies, as written to the stream of the client
+    pub fn server<S: Write>(&self, stream: S) -> Recorded<S> {
+        Recorded {
+            stream,
+            recording: self.clone(),
+            prefix: SERVER_PREFIX,
+        }
+    }
+
+    /// Write the recording to a new file in a directory, completing lines that are cut off.
+    /// The time in seconds since the epoch comes first in the name, so recordings sort by time.
+    pub fn save(&self, directory: &Path, client_address: &str, now: u64) -> Result<PathBuf, Error> {
+        let mut transcript = self.0.borrow_mut();
+        for (prefix, index) in [(CLIENT_PREFIX, 0), (SERVER_PREFIX, 1)] {
+            if !transcript.partial[index].is_empty() {
+                transcript.add(prefix, b"\n");
+            }
+        }
+        let mut content = format!("# Session of {} at {}\n", client_address, now).into_bytes();
+        content.extend_from_slice(&transcript.text);
+
+        fs::create_dir_all(directory)?;
+        let path = directory.join(format!("{}-{}.smtp", now, crate::smtp::new_uuid()));
+        fs::write(&path, content)?;
+        Ok(path)
+    }
+}
+
+impl<S: Read> Read for Recorded<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let size = self.stream.read(buf)?;
+        self.recording.0.borrow_mut().add(self.prefix, &buf[..size]);
+        Ok(size)
+    }
+}
+
+impl<S: Write> Write for Recorded<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let size = self.stream.write(buf)?;
+        self.recording.0.borrow_mut().add(self.prefix, &buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn record_and_replay_session() {
+        // Given
+        let request = "HELO localhost\r\n\
+                       MAIL FROM: <tester@localhost>\r\n\
+                       RCPT TO: <admin@localhost>\r\n\
+                       DATA\r\n\
+                       It works!\r\n\
+                       .\r\n\
+                       QUIT\r\n";
+        let recording = Recording::default();
+        Connection::handle(
+            &mut BufReader::new(recording.client(request.as_bytes())),
+            &mut recording.server(Vec::new()),
+        )
+        .unwrap();
+        let directory = std::env::temp_dir().join(format!("recordings-{}", std::process::id()));
+        let path = recording
+            .save(&directory, "127.0.0.1:1", 1_700_000_000)
+            .unwrap();
+        let recorded = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = server.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in server.incoming().take(2) {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+                let _ = Connection::handle(&mut reader, &mut writer);
+            }
+        });
+
+        // When
+        let replayed = replay(&target, recorded.as_bytes()).unwrap();
+        let changed = replay(&target, recorded.replace("C: DATA", "C: DATE").as_bytes()).unwrap();
+
+        // Then
+        assert!(recorded.starts_with("# Session of 127.0.0.1:1 at 1700000000\n"));
+        assert!(recorded.contains("\nC: RCPT TO: <admin@localhost>\r\n"));
+        assert!(recorded.ends_with("\nS: 221 Bye\n"));
+        assert_eq!(replayed.len(), 7);
+        assert!(replayed.iter().all(|(expected, actual)| expected == actual));
+        assert_eq!(reply_code(&changed[4].1), "500");
+    }
+}
@@ -0,0 +1,196 @@
+//! Rewriting of addresses in relayed messages, so that test traffic never reaches real people.
+//!
+//! Domains are mapped first, in the envelope and in the address fields of the header. Then the
+//! sender is masqueraded and finally the recipients of the envelope are redirected. Redirection
+//! leaves the header alone, so the receiving inbox still shows who a message was meant for.
+
+/// Header fields with addresses that domains are mapped in
+const ADDRESS_FIELDS: [&str; 8] = [
+    "from",
+    "sender",
+    "reply-to",
+    "return-path",
+    "to",
+    "cc",
+    "bcc",
+    "delivered-to",
+];
+
+/// Header fields with sender addresses that are masqueraded
+const SENDER_FIELDS: [&str; 4] = ["from", "sender", "reply-to", "return-path"];
+
+/// How addresses are rewritten
+#[derive(Clone, Default)]
+pub struct Rules {
+    /// Domains to replace and their replacements
+    pub domains: Vec<(String, String)>,
+    /// Domain to replace the domain of sender addresses with
+    pub masquerade: Option<String>,
+    /// Address to deliver to instead of all recipients
+    pub redirect: Option<String>,
+}
+
+impl Rules {
+    /// Rewrite the sender address of the envelope
+    pub fn sender(&self, address: &str) -> String {
+        self.masquerade(&self.map_domain(address))
+    }
+
+    /// Rewrite the recipient addresses of the envelope
+    pub fn recipients(&self, addresses: &[String]) -> Vec<String> {
+        match &self.redirect {
+            Some(redirect) => vec![redirect.clone()],
+            None => addresses
+                .iter()
+                .map(|address| self.map_domain(address))
+                .collect(),
+        }
+    }
+
+    /// Rewrite the addresses in the header of a message, leaving the body untouched
+    pub fn content(&self, content: &[u8]) -> Vec<u8> {
+        if self.domains.is_empty() && self.masquerade.is_none() {
+            return content.to_vec();
+        }
+
+        let mut result = Vec::with_capacity(content.len());
+        let mut field = String::new();
+        let mut lines = content.split_inclusive(|&b| b == b'\n');
+        for line in lines.by_ref() {
+            // The header ends with the first empty line
+            if line == b"\n" || line == b"\r\n" {
+                result.extend_from_slice(line);
+                break;
+            }
+            // Lines that are not text are passed on as they are
+            let Ok(text) = std::str::from_utf8(line) else {
+                result.extend_from_slice(line);
+                continue;
+            };
+            if !text.starts_with([' ', '\t']) {
+                field = text
+                    .split(':')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_lowercase();
+            }
+            let mut text = text.to_string();
+            if ADDRESS_FIELDS.contains(&field.as_str()) {
+                text = rewrite_addresses(&text, |address| self.map_domain(address));
+            }
+            if SENDER_FIELDS.contains(&field.as_str()) {
+                text = rewrite_addresses(&text, |address| self.masquerade(address));
+            }
+            result.extend_from_slice(text.as_bytes());
+        }
+        for line in lines {
+            result.extend_from_slice(line);
+        }
+        result
+    }
+
+    fn map_domain(&self, address: &str) -> String {
+        let Some((local, domain)) = address.rsplit_once('@') else {
+            return address.to_string();
+        };
+        match self
+            .domains
+            .iter()
+            .find(|(from, _)| from.eq_ignore_ascii_case(domain))
+        {
+            Some((_, to)) => format!("{}@{}", local, to),
+            None => address.to_string(),
+        }
+    }
+
+    fn masquerade(&self, address: &str) -> String {
+        match (&self.masquerade, address.rsplit_once('@')) {
+            (Some(domain), Some((local, _))) => format!("{}@{}", local, domain),
+            _ => address.to_string(),
+        }
+    }
+}
+
+/// Rewrite every word of a header line that looks like an address
+fn rewrite_addresses(line: &str, rewrite: impl Fn(&str) -> String) -> String {
+    let is_delimiter =
+        |c: char| c.is_whitespace() || matches!(c, '<' | '>' | ',' | ';' | ':' | '"' | '(' | ')');
+
+    let mut result = String::with_capacity(line.len());
+    let mut word = String::new();
+    for c in line.chars() {
+        if is_delimiter(c) {
+            if word.contains('@') {
+                result += &rewrite(&word);
+            } else {
+                result += &word;
+            }
+            word.clear();
+            result.push(c);
+        } else {
+            word.push(c);
+        }
+    }
+    if word.contains('@') {
+        result += &rewrite(&word);
+    } else {
+        result += &word;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_envelope_and_header() {
+        // Given
+        let rules = Rules {
+            domains: vec![("customer.com".to_string(), "test.example".to_string())],
+            masquerade: Some("staging.example".to_string()),
+            redirect: None,
+        };
+        let content = b"From: App <app@prod.example>\r\n\
+                        To: a@customer.com,\r\n\tb@Customer.com\r\n\
+                        Subject: mail for a@customer.com\r\n\
+                        \r\n\
+                        Hello a@customer.com\r\n";
+
+        // When
+        let sender = rules.sender("app@prod.example");
+        let recipients =
+            rules.recipients(&["a@customer.com".to_string(), "c@other.com".to_string()]);
+        let content = rules.content(content);
+
+        // Then
+        assert_eq!(sender, "app@staging.example");
+        assert_eq!(recipients, vec!["a@test.example", "c@other.com"]);
+        assert_eq!(
+            String::from_utf8(content).unwrap(),
+            "From: App <app@staging.example>\r\n\
+             To: a@test.example,\r\n\tb@test.example\r\n\
+             Subject: mail for a@customer.com\r\n\
+             \r\n\
+             Hello a@customer.com\r\n"
+        );
+    }
+
+    #[test]
+    fn redirect_all_recipients() {
+        let rules = Rules {
+            redirect: Some("inbox@test.example".to_string()),
+            ..Rules::default()
+        };
+
+        assert_eq!(
+            rules.recipients(&["a@customer.com".to_string(), "b@customer.com".to_string()]),
+            vec!["inbox@test.example"]
+        );
+        assert_eq!(
+            rules.content(b"To: a@customer.com\n\n"),
+            b"To: a@customer.com\n\n"
+        );
+    }
+}
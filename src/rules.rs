@@ -0,0 +1,281 @@
+//! Canned replies, to simulate specific server behavior such as rejecting the recipients of a
+//! domain or failing the third message.
+//!
+//! Rules are a JSON array of objects, read from a file or replaced through the web UI server
+//! with `PUT /rules`. Each rule has the `reply` to answer with and conditions, all of which must
+//! hold for it to match:
+//!
+//! - `command`: the command it answers, e.g. `RCPT`, or `CONNECT` for the greeting and `CONTENT`
+//!   for the reply to the message content.
+//! - `sender` and `recipient`: address patterns with `*` as wildcard, of the current transaction.
+//! - `count`: the number of the match it answers, counted across all sessions, e.g. 3 for only
+//!   the third time the other conditions hold.
+//!
+//! The first rule that matches answers instead of the server, which then does not handle the
+//! command. Replies with code 421 close the connection.
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+
+use crate::notify::matches_pattern;
+use crate::relay::path_address;
+use crate::smtp::{self, Interference, Message, Stage, Verdict};
+
+/// A canned reply and when to give it
+struct Rule {
+    /// Upper case
+    command: Option<String>,
+    sender: Option<String>,
+    recipient: Option<String>,
+    count: Option<u64>,
+    reply: String,
+    /// How often the conditions besides the count held so far
+    matches: u64,
+}
+
+impl Rule {
+    fn from_json(value: &Value) -> Result<Rule, String> {
+        let object = value.as_object().ok_or("a rule must be an object")?;
+        let text = |name: &str| match object.get(name) {
+            None => Ok(None),
+            Some(Value::String(text)) => Ok(Some(text.clone())),
+            Some(_) => Err(format!("{} must be a string", name)),
+        };
+        if let Some(name) = object.keys().find(|name| {
+            !["command", "sender", "recipient", "count", "reply"].contains(&name.as_str())
+        }) {
+            return Err(format!("unknown condition {}", name));
+        }
+        let count = match object.get("count") {
+            None => None,
+            Some(count) => Some(
+                count
+                    .as_u64()
+                    .filter(|&count| count > 0)
+                    .ok_or("count must be a number of at least 1")?,
+            ),
+        };
+        let reply = text("reply")?.ok_or("a rule needs a reply")?;
+        let code = reply.get(..3).and_then(|code| code.parse::<u16>().ok());
+        if !code.is_some_and(|code| (200..600).contains(&code)) {
+            return Err(format!("{} does not start with a reply code", reply));
+        }
+        Ok(Rule {
+            command: text("command")?.map(|command| command.to_ascii_uppercase()),
+            sender: text("sender")?,
+            recipient: text("recipient")?,
+            count,
+            reply,
+            matches: 0,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut object = serde_json::json!({ "reply": self.reply });
+        for (name, value) in [
+            ("command", &self.command),
+            ("sender", &self.sender),
+            ("recipient", &self.recipient),
+        ] {
+            if let Some(value) = value {
+                object[name] = value.as_str().into();
+            }
+        }
+        if let Some(count) = self.count {
+            object["count"] = count.into();
+        }
+        object
+    }
+}
+
+/// The rules of a server, which can be replaced while it runs
+#[derive(Default)]
+pub struct Rules(Mutex<Vec<Rule>>);
+
+impl Rules {
+    /// Read rules from a JSON file
+    pub fn load(path: &str) -> Result<Rules, Error> {
+        let json = fs::read_to_string(path)?;
+        Rules::parse(&json).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Parse rules from a JSON array
+    pub fn parse(json: &str) -> Result<Rules, String> {
+        let rules = Rules::default();
+        rules.replace(json)?;
+        Ok(rules)
+    }
+
+    /// Replace the rules with those of a JSON array, which start counting anew
+    pub fn replace(&self, json: &str) -> Result<(), String> {
+        let value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let rules = value
+            .as_array()
+            .ok_or("rules must be an array")?
+            .iter()
+            .map(Rule::from_json)
+            .collect::<Result<Vec<Rule>, String>>()?;
+        *self.0.lock().unwrap() = rules;
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Value {
+        self.0.lock().unwrap().iter().map(Rule::to_json).collect()
+    }
+
+    /// The reply of the first rule that matches a command of a transaction
+    fn answer(&self, command: &str, sender: &str, recipients: &[String]) -> Option<String> {
+        let mut rules = self.0.lock().unwrap();
+        for rule in rules.iter_mut() {
+            let holds = rule.command.as_ref().is_none_or(|name| name == command)
+                && rule
+                    .sender
+                    .as_ref()
+                    .is_none_or(|pattern| matches_pattern(pattern, sender))
+                && rule.recipient.as_ref().is_none_or(|pattern| {
+                    recipients
+                        .iter()
+                        .any(|recipient| matches_pattern(pattern, recipient))
+                });
+            if !holds {
+                continue;
+            }
+            rule.matches += 1;
+            if rule.count.is_none_or(|count| count == rule.matches) {
+                return Some(rule.reply.clone());
+            }
+        }
+        None
+    }
+}
+
+/// Answers the commands of a session that rules match
+pub struct Policy {
+    rules: Arc<Rules>,
+    /// Sender of the current transaction
+    sender: String,
+    /// Recipients of the current transaction
+    recipients: Vec<String>,
+}
+
+impl Policy {
+    pub fn new(rules: Arc<Rules>) -> Policy {
+        Policy {
+            rules,
+            sender: String::new(),
+            recipients: Vec::new(),
+        }
+    }
+}
+
+impl smtp::Policy for Policy {
+    fn check_recipient(&mut self, _sender: &str, _recipient: &str) -> Option<String> {
+        None
+    }
+
+    fn check_message(&mut self, _message: &mut Message) -> Verdict {
+        Verdict::Accept
+    }
+
+    fn interfere(&mut self, stage: &Stage) -> Interference {
+        let answer = match stage {
+            Stage::Greeting => self.rules.answer("CONNECT", "", &[]),
+            Stage::Data => self.rules.answer("CONTENT", &self.sender, &self.recipients),
+            Stage::Command(line) => {
+                let (command, argument) = line.split_once([' ', ':']).unwrap_or((line, ""));
+                let command = command.to_ascii_uppercase();
+                // Addresses of the command count for its own rules
+                let address = || {
+                    let path = argument.split_once(':').map_or(argument, |(_, path)| path);
+                    let path = path.split_whitespace().next().unwrap_or_default();
+                    path_address(path).to_string()
+                };
+                match command.as_str() {
+                    "MAIL" => {
+                        self.sender = address();
+                        self.recipients.clear();
+                    }
+                    "RCPT" => self.recipients.push(address()),
+                    "RSET" | "HELO" | "EHLO" | "LHLO" => {
+                        self.sender.clear();
+                        self.recipients.clear();
+                    }
+                    _ => {}
+                }
+                let recipients = match command.as_str() {
+                    "RCPT" => &self.recipients[self.recipients.len() - 1..],
+                    _ => &self.recipients[..],
+                };
+                let answer = self.rules.answer(&command, &self.sender, recipients);
+                // A recipient that is answered is not one of the transaction
+                if command == "RCPT" && answer.is_some() {
+                    self.recipients.pop();
+                }
+                answer
+            }
+        };
+        match answer {
+            Some(reply) if reply.starts_with("421") => Interference::Close(reply),
+            Some(reply) => Interference::Reply(reply),
+            None => Interference::Proceed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::io::BufReader;
+
+    #[test]
+    fn answer_matching_commands() {
+        // Given
+        let rules = Rules::parse(
+            r#"[
+                {"command": "rcpt", "recipient": "*@blocked.example", "reply": "550 Blocked"},
+                {"command": "DATA", "count": 2, "reply": "451 Try again"}
+            ]"#,
+        )
+        .unwrap();
+        let request = "HELO localhost\n\
+                       MAIL FROM:<tester@localhost>\n\
+                       RCPT TO:<someone@blocked.example>\n\
+                       RCPT TO:<admin@localhost>\n\
+                       DATA\n\
+                       First\n\
+                       .\n\
+                       MAIL FROM:<tester@localhost>\n\
+                       RCPT TO:<admin@localhost>\n\
+                       DATA\n\
+                       DATA\n\
+                       Second\n\
+                       .\n\
+                       QUIT\n";
+        let mut response = Vec::new();
+
+        // When
+        let result = Connection::handle_with_policy(
+            &mut BufReader::new(request.as_bytes()),
+            &mut response,
+            &mut Policy::new(Arc::new(rules)),
+        )
+        .unwrap();
+
+        // Then
+        let response = String::from_utf8(response).unwrap();
+        let replies: Vec<&str> = response.lines().collect();
+        assert_eq!(replies[3], "550 Blocked");
+        assert_eq!(replies[4], "250 OK");
+        assert_eq!(replies[9], "451 Try again");
+        assert_eq!(replies[10], "354 Send message content");
+        let messages = result.get_messages().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].get_recipients(), &["<admin@localhost>"]);
+        assert!(Rules::parse(r#"[{"reply": "OK"}]"#).is_err());
+        assert!(Rules::parse(r#"[{"reply": "250 OK", "size": 1}]"#).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Lua scripts that decide about recipients and messages, for test policies that the server
+//! has no setting for.
+//!
+//! A script defines any of these global functions:
+//!
+//! - `on_rcpt(sender, recipient)` before a recipient is accepted. Returning a reply such as
+//!   `"550 No such user"` rejects the recipient.
+//! - `on_data(message)` before a message is accepted. Returning a reply rejects the message, and
+//!   returning a table can set `reply`, `drop = true` to accept the message without keeping it,
+//!   and `tags`, a list of labels that come with the message in the `jsonl` output and sinks.
+//! - `on_received(message)` after a message was accepted, on a thread of its own, e.g. to log.
+//!
+//! Messages are tables with `id`, `from`, `to` (a list), `size`, `content` and `tags`. Every
+//! session runs in a fresh Lua state, so scripts cannot keep state between sessions. A script
+//! that fails makes the server reply with a temporary error, so a failure does not go unnoticed.
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+use mlua::{Function, Lua, Table, Value};
+
+use crate::relay::path_address;
+use crate::smtp::{self, Message, Verdict};
+use crate::Session;
+
+/// Reply when the script failed
+const MSG_SCRIPT_FAILED: &str = "451 Script failed, try again later";
+
+/// The source of a script
+pub struct Script {
+    pub path: String,
+    source: String,
+    /// Whether the script defines `on_received`
+    pub handles_received: bool,
+}
+
+impl Script {
+    /// Read a script and run it once, so that errors show at startup
+    pub fn load(path: &str) -> Result<Script, Error> {
+        let mut script = Script {
+            path: path.to_string(),
+            source: fs::read_to_string(path)?,
+            handles_received: false,
+        };
+        let lua = script.lua().map_err(invalid)?;
+        script.handles_received = lua
+            .globals()
+            .get::<Option<Function>>("on_received")
+            .map_err(invalid)?
+            .is_some();
+        Ok(script)
+    }
+
+    /// Make a Lua state with the functions of the script
+    fn lua(&self) -> mlua::Result<Lua> {
+        let lua = Lua::new();
+        lua.load(&self.source).set_name(&self.path).exec()?;
+        Ok(lua)
+    }
+
+    /// Make the policy for one session
+    pub fn policy(&self) -> Result<Policy, Error> {
+        Ok(Policy {
+            lua: self.lua().map_err(invalid)?,
+        })
+    }
+}
+
+/// Decides about recipients and messages of a session with the functions of a script
+pub struct Policy {
+    lua: Lua,
+}
+
+impl smtp::Policy for Policy {
+    fn check_recipient(&mut self, sender: &str, recipient: &str) -> Option<String> {
+        let outcome = call(
+            &self.lua,
+            "on_rcpt",
+            (path_address(sender), path_address(recipient)),
+        )
+        .and_then(|value| match value {
+            Value::Nil => Ok(None),
+            value => reply(value).map(Some),
+        });
+        outcome.unwrap_or_else(|e| {
+            tracing::error!("Script on_rcpt failed: {}", e);
+            Some(MSG_SCRIPT_FAILED.to_string())
+        })
+    }
+
+    fn check_message(&mut self, message: &mut Message) -> Verdict {
+        let outcome = message_table(&self.lua, message)
+            .and_then(|table| call(&self.lua, "on_data", table))
+            .and_then(|value| match value {
+                Value::Table(table) => {
+                    if let Some(tags) = table.get::<Option<Vec<String>>>("tags")? {
+                        for tag in tags {
+                            message.add_tag(&tag);
+                        }
+                    }
+                    match table.get::<Value>("reply")? {
+                        Value::Nil if table.get::<Option<bool>>("drop")? == Some(true) => {
+                            Ok(Verdict::Drop)
+                        }
+                        Value::Nil => Ok(Verdict::Accept),
+                        value => reply(value).map(Verdict::Reject),
+                    }
+                }
+                Value::Nil => Ok(Verdict::Accept),
+                value => reply(value).map(Verdict::Reject),
+            });
+        outcome.unwrap_or_else(|e| {
+            tracing::error!("Script on_data failed: {}", e);
+            Verdict::Reject(MSG_SCRIPT_FAILED.to_string())
+        })
+    }
+}
+
+/// Call `on_received` for the messages of every session received until the channel closes
+pub fn run(script: Arc<Script>, sessions: Receiver<Arc<Session>>) {
+    let lua = match script.lua() {
+        Ok(lua) => lua,
+        Err(e) => {
+            tracing::error!("Script {} failed: {}", script.path, e);
+            return;
+        }
+    };
+    for session in sessions {
+        let Some(messages) = session.connection.get_messages() else {
+            continue;
+        };
+        for message in messages {
+            let outcome =
+                message_table(&lua, message).and_then(|table| call(&lua, "on_received", table));
+            if let Err(e) = outcome {
+                tracing::error!("Script on_received failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Call a function of the script if it is defined
+fn call(lua: &Lua, name: &str, args: impl mlua::IntoLuaMulti) -> mlua::Result<Value> {
+    match lua.globals().get::<Option<Function>>(name)? {
+        Some(function) => function.call(args),
+        None => Ok(Value::Nil),
+    }
+}
+
+/// Get a reply that rejects something, which must start with a 4xx or 5xx code
+fn reply(value: Value) -> mlua::Result<String> {
+    let reply = value
+        .as_string()
+        .map(|reply| reply.to_string_lossy())
+        .unwrap_or_default();
+    let code = reply.get(..3).unwrap_or_default();
+    if code.starts_with(['4', '5']) && code.bytes().all(|b| b.is_ascii_digit()) {
+        Ok(reply)
+    } else {
+        Err(mlua::Error::runtime(format!(
+            "expected a reply with a 4xx or 5xx code, got {:?}",
+            value
+        )))
+    }
+}
+
+fn message_table(lua: &Lua, message: &Message) -> mlua::Result<Table> {
+    let table = lua.create_table()?;
+    table.set("id", message.get_id())?;
+    table.set("from", path_address(message.get_sender()))?;
+    let recipients: Vec<&str> = message
+        .get_recipients()
+        .iter()
+        .map(|recipient| path_address(recipient))
+        .collect();
+    table.set("to", recipients)?;
+    table.set("size", message.get_size())?;
+    table.set("content", lua.create_string(message.get_content())?)?;
+    table.set("tags", message.get_tags())?;
+    Ok(table)
+}
+
+fn invalid(e: mlua::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::io::BufReader;
+
+    #[test]
+    fn decide_with_script() {
+        // Given
+        let script = Script {
+            path: "test.lua".to_string(),
+            source: r#"
+                function on_rcpt(sender, recipient)
+                    if recipient:find("^nobody@") then
+                        return "550 No such user"
+                    end
+                end
+
+                function on_data(message)
+                    if message.content:find("spam") then
+                        return { drop = true }
+                    elseif #message.to > 1 then
+                        return "554 One recipient only"
+                    end
+                    return { tags = { "from:" .. message.from } }
+                end
+            "#
+            .to_string(),
+            handles_received: false,
+        };
+        let request = "HELO localhost\n\
+                       MAIL FROM: <tester@localhost>\n\
+                       RCPT TO: <nobody@localhost>\n\
+                       RCPT TO: <admin@localhost>\n\
+                       DATA\n\
+                       It works!\n\
+                       .\n\
+                       MAIL FROM: <tester@localhost>\n\
+                       RCPT TO: <admin@localhost>\n\
+                       DATA\n\
+                       spam\n\
+                       .\n\
+                       MAIL FROM: <tester@localhost>\n\
+                       RCPT TO: <admin@localhost>\n\
+                       RCPT TO: <root@localhost>\n\
+                       DATA\n\
+                       Hi both\n\
+                       .\n\
+                       QUIT\n";
+        let mut response = Vec::new();
+
+        // When
+        let connection = Connection::handle_with_policy(
+            &mut BufReader::new(request.as_bytes()),
+            &mut response,
+            &mut script.policy().unwrap(),
+        )
+        .unwrap();
+
+        // Then
+        let messages = connection.get_messages().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_recipients().join(", "), "<admin@localhost>");
+        assert_eq!(messages[0].get_tags(), ["from:tester@localhost"]);
+        let response = String::from_utf8(response).unwrap();
+        let replies: Vec<&str> = response.lines().collect();
+        assert_eq!(replies[3], "550 No such user");
+        assert_eq!(replies[6], "250 OK");
+        assert_eq!(replies[10], "250 OK");
+        assert_eq!(replies[15], "554 One recipient only");
+    }
+}
@@ -0,0 +1,169 @@
+//! Sending a single message to an SMTP server, e.g. to check that it accepts mail.
+
+use std::fs;
+use std::io::Error;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::client::Client;
+
+/// Name of the subcommand
+pub const SUBCOMMAND_NAME: &str = "send";
+
+const TARGET_ARG_NAME: &str = "target";
+const FROM_ARG_NAME: &str = "from";
+const TO_ARG_NAME: &str = "to";
+const SUBJECT_ARG_NAME: &str = "subject";
+const BODY_ARG_NAME: &str = "body";
+const FILE_ARG_NAME: &str = "file";
+
+pub struct Options {
+    pub target: String,
+    pub sender: String,
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    /// File with a complete message to send instead of one made of subject and body
+    pub file: Option<String>,
+}
+
+/// The command line definition of the subcommand
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("Send a message to an SMTP server")
+        .arg(
+            Arg::with_name(TARGET_ARG_NAME)
+                .long(TARGET_ARG_NAME)
+                .help("Address of the server as host:port")
+                .default_value("localhost:2525"),
+        )
+        .arg(
+            Arg::with_name(FROM_ARG_NAME)
+                .long(FROM_ARG_NAME)
+                .help("Sender address")
+                .default_value("send@localhost"),
+        )
+        .arg(
+            Arg::with_name(TO_ARG_NAME)
+                .long(TO_ARG_NAME)
+                .help("Recipient address, may be given multiple times")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name(SUBJECT_ARG_NAME)
+                .long(SUBJECT_ARG_NAME)
+                .help("Subject of the message")
+                .default_value("Test message"),
+        )
+        .arg(
+            Arg::with_name(BODY_ARG_NAME)
+                .long(BODY_ARG_NAME)
+                .help("Text of the message")
+                .default_value("This is a test message."),
+        )
+        .arg(
+            Arg::with_name(FILE_ARG_NAME)
+                .long(FILE_ARG_NAME)
+                .help("File with a complete message including headers, sent as is")
+                .takes_value(true)
+                .conflicts_with_all(&[SUBJECT_ARG_NAME, BODY_ARG_NAME]),
+        )
+}
+
+/// Get the options from the parsed subcommand arguments
+pub fn options(matches: &ArgMatches) -> Options {
+    Options {
+        target: matches.value_of(TARGET_ARG_NAME).unwrap().to_string(),
+        sender: matches.value_of(FROM_ARG_NAME).unwrap().to_string(),
+        recipients: matches
+            .values_of(TO_ARG_NAME)
+            .unwrap()
+            .map(str::to_string)
+            .collect(),
+        subject: matches.value_of(SUBJECT_ARG_NAME).unwrap().to_string(),
+        body: matches.value_of(BODY_ARG_NAME).unwrap().to_string(),
+        file: matches.value_of(FILE_ARG_NAME).map(str::to_string),
+    }
+}
+
+/// Send the message and report on stdout that the server accepted it
+pub fn run(options: Options) -> Result<(), Error> {
+    let data = match &options.file {
+        Some(path) => fs::read(path)?,
+        None => format!(
+            "From: <{}>\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            options.sender,
+            options
+                .recipients
+                .iter()
+                .map(|recipient| format!("<{}>", recipient))
+                .collect::<Vec<_>>()
+                .join(", "),
+            options.subject,
+            options.body
+        )
+        .into_bytes(),
+    };
+
+    let mut client = Client::connect(&options.target, "localhost")?;
+    client.send(&options.sender, &options.recipients, &data)?;
+    client.quit()?;
+
+    println!("Message accepted by {}", options.target);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn send_composed_message() {
+        // Given
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            Connection::handle(&mut reader, &mut stream).unwrap()
+        });
+        let matches = subcommand().get_matches_from(vec![
+            SUBCOMMAND_NAME,
+            "--target",
+            &address,
+            "--to",
+            "first@localhost",
+            "--to",
+            "second@localhost",
+            "--subject",
+            "Hello",
+        ]);
+
+        // When
+        run(options(&matches)).unwrap();
+
+        // Then
+        let result = server.join().unwrap();
+        let message = &result.get_messages().unwrap()[0];
+        assert_eq!(message.get_sender(), "<send@localhost>");
+        assert_eq!(
+            message.get_recipients().join(", "),
+            "<first@localhost>, <second@localhost>"
+        );
+        assert_eq!(
+            message.get_data(),
+            "From: <send@localhost>\n\
+             To: <first@localhost>, <second@localhost>\n\
+             Subject: Hello\n\
+             \n\
+             This is a test message."
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Waiting for unix signals on a regular thread.
+//!
+//! Signal handlers only write the signal number to a pipe, the actual handling is done by the
+//! thread reading from it.
+
+use std::fs::File;
+use std::io::{Error, Read, Result};
+use std::mem;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Write end of the pipe the signal handler reports to
+static PIPE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn report_signal(signal: libc::c_int) {
+    let byte = signal as u8;
+    unsafe {
+        libc::write(
+            PIPE_WRITE_FD.load(Ordering::SeqCst),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+    }
+}
+
+/// Signals delivered to the process
+pub struct Signals {
+    pipe: File,
+}
+
+impl Signals {
+    /// Install handlers for the given signals. Must only be called once.
+    pub fn install(signals: &[libc::c_int]) -> Result<Signals> {
+        let mut fds = [0 as RawFd; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        for fd in &fds {
+            set_close_on_exec(*fd)?;
+        }
+        PIPE_WRITE_FD.store(fds[1], Ordering::SeqCst);
+
+        for signal in signals {
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction =
+                    report_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                // Restart system calls interrupted in other threads, such as accept
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(*signal, &action, ptr::null_mut()) == -1 {
+                    return Err(Error::last_os_error());
+                }
+            }
+        }
+
+        Ok(Signals {
+            pipe: unsafe { File::from_raw_fd(fds[0]) },
+        })
+    }
+
+    /// Block until a signal arrives and return its number
+    pub fn wait(&mut self) -> Result<libc::c_int> {
+        let mut byte = [0u8];
+        self.pipe.read_exact(&mut byte)?;
+        Ok(libc::c_int::from(byte[0]))
+    }
+}
+
+/// Make sure a file descriptor is not inherited by child processes
+pub fn set_close_on_exec(fd: RawFd) -> Result<()> {
+    set_fd_flags(fd, |flags| flags | libc::FD_CLOEXEC)
+}
+
+/// Let a child process inherit a file descriptor
+pub fn clear_close_on_exec(fd: RawFd) -> Result<()> {
+    set_fd_flags(fd, |flags| flags & !libc::FD_CLOEXEC)
+}
+
+fn set_fd_flags(fd: RawFd, update: impl Fn(libc::c_int) -> libc::c_int) -> Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags == -1 || libc::fcntl(fd, libc::F_SETFD, update(flags)) == -1 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn wait_for_signals() {
+        // Given
+        let mut signals = Signals::install(&[libc::SIGUSR1]).unwrap();
+
+        // When
+        unsafe { libc::raise(libc::SIGUSR1) };
+
+        // Then
+        assert_eq!(signals.wait().unwrap(), libc::SIGUSR1);
+    }
+
+    #[test]
+    fn toggle_close_on_exec() {
+        // Given
+        let file = File::open("/dev/null").unwrap();
+        let fd = file.as_raw_fd();
+        let close_on_exec = || unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC != 0;
+
+        // When
+        clear_close_on_exec(fd).unwrap();
+        let cleared = close_on_exec();
+        set_close_on_exec(fd).unwrap();
+
+        // Then
+        assert!(!cleared);
+        assert!(close_on_exec());
+    }
+}
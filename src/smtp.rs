@@ -1,27 +1,80 @@
-use std::io::{BufRead, Error, Write};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, Error, ErrorKind, Read, Write};
+use std::mem;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::data::DataReader;
+use crate::mime::decode_base64;
 
 // Client commands
 const HELO_START: &str = "HELO ";
-const MAIL_START: &str = "MAIL FROM:";
+const EHLO_START: &str = "EHLO ";
+const LHLO_START: &str = "LHLO ";
+const STARTTLS_LINE: &str = "STARTTLS";
+const AUTH_START: &str = "AUTH ";
+pub const MAIL_START: &str = "MAIL FROM:";
 const RCPT_START: &str = "RCPT TO:";
 const DATA_LINE: &str = "DATA";
+const BDAT_START: &str = "BDAT ";
 const QUIT_LINE: &str = "QUIT";
+const RSET_LINE: &str = "RSET";
 
 // Server responses
 const MSG_READY: &str = "220 ready";
 const MSG_OK: &str = "250 OK";
+/// Extensions offered in reply to EHLO besides SIZE and STARTTLS
+const EXTENSIONS: [&str; 5] = [
+    "PIPELINING",
+    "8BITMIME",
+    "CHUNKING",
+    "BINARYMIME",
+    "AUTH PLAIN LOGIN",
+];
+const MSG_READY_TO_START_TLS: &str = "220 Ready to start TLS";
+const MSG_AUTH_SUCCEEDED: &str = "235 Authentication succeeded";
+const MSG_AUTH_FAILED: &str = "535 Authentication credentials invalid";
+const MSG_AUTH_CANCELLED: &str = "501 Authentication cancelled";
+const MSG_AUTH_MALFORMED: &str = "501 Malformed authentication response";
+const MSG_AUTH_UNSUPPORTED: &str = "504 Unrecognized authentication type";
+const MSG_ALREADY_AUTHENTICATED: &str = "503 Already authenticated";
+const MSG_AUTH_REQUIRED: &str = "530 Authentication required";
+/// Challenges of AUTH LOGIN, "Username:" and "Password:" in base64
+const MSG_LOGIN_USERNAME: &str = "334 VXNlcm5hbWU6";
+const MSG_LOGIN_PASSWORD: &str = "334 UGFzc3dvcmQ6";
+/// Challenge of AUTH PLAIN without an initial response, which is empty
+const MSG_PLAIN_CHALLENGE: &str = "334 ";
 const MSG_SEND_MESSAGE_CONTENT: &str = "354 Send message content";
 const MSG_BYE: &str = "221 Bye";
 const MSG_SYNTAX_ERROR: &str = "500 unexpected line";
+const MSG_INVALID_CHUNK: &str = "501 Chunk size expected, optionally followed by LAST";
+const MSG_BAD_SEQUENCE: &str = "503 Bad sequence of commands";
+const MSG_SERVICE_NOT_AVAILABLE: &str = "421 Service not available, try again later";
+const MSG_IDLE_TIMEOUT: &str = "421 Idle timeout, closing connection";
+const MSG_MESSAGE_TOO_BIG: &str = "552 Message size exceeds fixed maximum message size";
+
+/// Most content kept of BDAT chunks without a size limit, as clients announce any chunk size
+const MAX_CHUNKED_SIZE: usize = 64 * 1024 * 1024;
 
 /// An Email message
 pub struct Message {
+    /// Random UUID, so the message can be told apart from others with the same envelope
+    id: String,
     sender: String,
     recipients: Vec<String>,
-    data: Vec<String>,
+    data: Vec<u8>,
+    /// Labels given by a policy, e.g. to tell test cases apart
+    tags: Vec<String>,
+    /// Results of checking the authenticity of the message by method, e.g. `("spf", "pass")`
+    auth_results: Vec<(String, String)>,
 }
 
 impl Message {
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
     pub fn get_sender(&self) -> &str {
         &self.sender
     }
@@ -30,8 +83,261 @@ impl Message {
         &self.recipients
     }
 
+    /// Get the size of the message content in bytes as received, after removing dot-stuffing
+    pub fn get_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn add_tag(&mut self, tag: &str) {
+        self.tags.push(tag.to_string());
+    }
+
+    pub fn get_auth_results(&self) -> &[(String, String)] {
+        &self.auth_results
+    }
+
+    pub fn add_auth_result(&mut self, method: &str, result: &str) {
+        self.auth_results
+            .push((method.to_string(), result.to_string()));
+    }
+
+    /// Get the message content as received, with its original line endings
+    pub fn get_content(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Get the message content as text with LF line endings and without the final line ending
     pub fn get_data(&self) -> String {
-        self.data.join("\n")
+        let text = String::from_utf8_lossy(&self.data).replace("\r\n", "\n");
+        match text.strip_suffix('\n') {
+            Some(stripped) => stripped.to_string(),
+            None => text,
+        }
+    }
+}
+
+/// Get the unfolded value of the first header field with a name, ignoring case
+pub fn header_field(content: &[u8], name: &str) -> Option<String> {
+    let header = String::from_utf8_lossy(content);
+    let mut value: Option<String> = None;
+    for line in header.lines() {
+        if line.is_empty() {
+            break;
+        }
+        match &mut value {
+            Some(value) if line.starts_with([' ', '\t']) => *value += line,
+            Some(_) => break,
+            None => {
+                if let Some((field_name, field_value)) = line.split_once(':') {
+                    if field_name.eq_ignore_ascii_case(name) {
+                        value = Some(field_value.to_string());
+                    }
+                }
+            }
+        }
+    }
+    value.map(|value| value.trim().to_string())
+}
+
+/// Get the size of a BDAT chunk and whether it is the last one from the arguments
+fn chunk_arguments(arguments: &str) -> Option<(u64, bool)> {
+    match arguments.split_whitespace().collect::<Vec<&str>>()[..] {
+        [size] => Some((size.parse().ok()?, false)),
+        [size, last] if last.eq_ignore_ascii_case("LAST") => Some((size.parse().ok()?, true)),
+        _ => None,
+    }
+}
+
+/// The error when the connection closes before the end of a BDAT chunk
+fn chunk_cut_off() -> Error {
+    Error::new(ErrorKind::UnexpectedEof, "connection closed during BDAT")
+}
+
+/// Make a random version 4 UUID.
+/// The randomness comes from the hash keys of the standard library, which are seeded by the
+/// operating system, so no random number generator is needed.
+pub fn new_uuid() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos());
+    let mut bytes = [0u8; 16];
+    for half in bytes.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        half.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// What a policy decides about a received message
+pub enum Verdict {
+    Accept,
+    /// Reply as if the message was accepted, but do not keep it
+    Drop,
+    /// Reject the message with a reply
+    Reject(String),
+}
+
+/// Points of a session where a policy can interfere
+pub enum Stage<'a> {
+    /// Before the greeting
+    Greeting,
+    /// Before a command line is handled
+    Command(&'a str),
+    /// After the reply to DATA, before the content is read, or before the reply to the last
+    /// BDAT chunk
+    Data,
+}
+
+/// How a policy interferes with a session
+pub enum Interference {
+    Proceed,
+    /// Wait before going on
+    Delay(Duration),
+    /// Close the connection without a reply
+    Disconnect,
+    /// Close the connection after a reply, e.g. a 421
+    Close(String),
+    /// Answer with a reply instead of handling the command, instead of the usual greeting, or
+    /// instead of accepting the content
+    Reply(String),
+}
+
+/// Decisions about a session beyond the protocol, e.g. by a script
+pub trait Policy {
+    /// Check a recipient before accepting it, returning the reply to reject it with
+    fn check_recipient(&mut self, sender: &str, recipient: &str) -> Option<String>;
+
+    /// Check a message before accepting it, possibly tagging it
+    fn check_message(&mut self, message: &mut Message) -> Verdict;
+
+    /// Check the credentials a client logged in with, any are accepted by default
+    fn check_credentials(&mut self, _user: &str, _password: &str) -> bool {
+        true
+    }
+
+    /// Interfere with the session at a stage, e.g. to test clients against a flaky server.
+    /// Sessions proceed by default.
+    fn interfere(&mut self, _stage: &Stage) -> Interference {
+        Interference::Proceed
+    }
+}
+
+/// Policies that decide one after the other, where the first rejection has the final say
+impl Policy for Vec<Box<dyn Policy>> {
+    fn check_recipient(&mut self, sender: &str, recipient: &str) -> Option<String> {
+        self.iter_mut()
+            .find_map(|policy| policy.check_recipient(sender, recipient))
+    }
+
+    fn check_message(&mut self, message: &mut Message) -> Verdict {
+        for policy in self.iter_mut() {
+            match policy.check_message(message) {
+                Verdict::Accept => continue,
+                verdict => return verdict,
+            }
+        }
+        Verdict::Accept
+    }
+
+    fn check_credentials(&mut self, user: &str, password: &str) -> bool {
+        self.iter_mut()
+            .all(|policy| policy.check_credentials(user, password))
+    }
+
+    fn interfere(&mut self, stage: &Stage) -> Interference {
+        // Every policy sees every stage, e.g. to count commands, but the first one to interfere
+        // decides how
+        let mut result = Interference::Proceed;
+        for policy in self.iter_mut() {
+            let interference = policy.interfere(stage);
+            if let Interference::Proceed = result {
+                result = interference;
+            }
+        }
+        result
+    }
+}
+
+/// The client end of a session
+pub trait Transport {
+    fn reader(&mut self) -> &mut dyn BufRead;
+
+    fn writer(&mut self) -> &mut dyn Write;
+
+    /// Whether the connection can switch to TLS, which is then offered to the client
+    fn can_start_tls(&self) -> bool {
+        false
+    }
+
+    /// Switch to TLS after the client was told to go ahead
+    fn start_tls(&mut self) -> Result<(), Error> {
+        Err(Error::new(ErrorKind::Unsupported, "TLS is not available"))
+    }
+
+    /// Size in bytes of the largest message accepted, which is announced to the client
+    fn max_message_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether the connection keeps a transcript of the commands and replies
+    fn keeps_transcript(&self) -> bool {
+        false
+    }
+
+    /// Whether the client speaks LMTP, [RFC 2033](https://tools.ietf.org/html/rfc2033), rather
+    /// than SMTP
+    fn speaks_lmtp(&self) -> bool {
+        false
+    }
+
+    /// Whether the client has to log in with AUTH before it can send mail
+    fn requires_auth(&self) -> bool {
+        false
+    }
+}
+
+/// A transport without TLS
+struct Plain<'a> {
+    reader: &'a mut dyn BufRead,
+    writer: &'a mut dyn Write,
+}
+
+impl Transport for Plain<'_> {
+    fn reader(&mut self) -> &mut dyn BufRead {
+        self.reader
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        self.writer
+    }
+}
+
+/// Accepts everything
+pub(crate) struct AcceptAll;
+
+impl Policy for AcceptAll {
+    fn check_recipient(&mut self, _sender: &str, _recipient: &str) -> Option<String> {
+        None
+    }
+
+    fn check_message(&mut self, _message: &mut Message) -> Verdict {
+        Verdict::Accept
     }
 }
 
@@ -44,6 +350,10 @@ enum State {
     Rcpt,
     RcptOrData,
     Dot,
+    /// More chunks of the content after BDAT without LAST
+    Bdat,
+    /// The TLS handshake, after the reply to STARTTLS
+    Handshake,
     MailOrQuit,
     Done,
 }
@@ -55,7 +365,26 @@ pub struct Connection {
     messages: Vec<Message>,
     next_sender: String,
     next_recipients: Vec<String>,
-    next_data: Vec<String>,
+    /// Whether STARTTLS is offered
+    tls_available: bool,
+    /// Whether the client switched to TLS
+    encrypted: bool,
+    /// Whether the client greeted with EHLO rather than HELO, or with LHLO
+    extended: bool,
+    /// Whether the session is LMTP, which greets with LHLO and replies for every recipient
+    /// after the content
+    lmtp: bool,
+    max_message_size: Option<usize>,
+    /// The user the client logged in as with AUTH
+    user: Option<String>,
+    /// Whether MAIL FROM is rejected until the client logged in
+    auth_required: bool,
+    /// The content received with BDAT so far, none once it exceeds the size limit
+    chunks: Option<Vec<u8>>,
+    /// Number of replies with a 4xx or 5xx code
+    rejected: usize,
+    /// The lines of the client and the server so far, with `C: ` and `S: ` in front, if kept
+    transcript: Option<Vec<String>>,
 }
 
 impl Connection {
@@ -66,30 +395,146 @@ impl Connection {
             messages: Vec::new(),
             next_sender: "".to_string(),
             next_recipients: Vec::new(),
-            next_data: Vec::new(),
+            tls_available: false,
+            encrypted: false,
+            extended: false,
+            lmtp: false,
+            max_message_size: None,
+            user: None,
+            auth_required: false,
+            chunks: Some(Vec::new()),
+            rejected: 0,
+            transcript: None,
         }
     }
 
     /// Handle an incoming connection
-    pub fn handle(reader: &mut BufRead, writer: &mut Write) -> Result<Connection, Error> {
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn handle(reader: &mut dyn BufRead, writer: &mut dyn Write) -> Result<Connection, Error> {
+        Connection::handle_with_policy(reader, writer, &mut AcceptAll)
+    }
+
+    /// Handle an incoming connection, letting a policy decide about recipients and messages
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn handle_with_policy(
+        reader: &mut dyn BufRead,
+        writer: &mut dyn Write,
+        policy: &mut dyn Policy,
+    ) -> Result<Connection, Error> {
+        Connection::handle_transport(&mut Plain { reader, writer }, policy)
+    }
+
+    /// Handle an incoming connection that may switch to TLS
+    pub fn handle_transport(
+        transport: &mut dyn Transport,
+        policy: &mut dyn Policy,
+    ) -> Result<Connection, Error> {
         let mut result = Connection::new();
+        result.tls_available = transport.can_start_tls();
+        result.max_message_size = transport.max_message_size();
+        result.lmtp = transport.speaks_lmtp();
+        result.auth_required = transport.requires_auth();
+        if transport.keeps_transcript() {
+            result.transcript = Some(Vec::new());
+        }
 
-        writeln!(writer, "{}", MSG_READY)?;
+        let greeting = result.interfere(policy, &Stage::Greeting, transport.writer())?;
+        result.reply(transport.writer(), greeting.as_deref().unwrap_or(MSG_READY))?;
 
         loop {
             let mut line = String::new();
-            reader.read_line(&mut line)?;
+            if transport.reader().read_line(&mut line)? == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed before QUIT",
+                ));
+            }
             // read_line will leave trailing newlines which must be removed
-            match result.feed_line(line.trim_right_matches(|c: char| c == '\n' || c == '\r')) {
+            let line = line.trim_end_matches(['\n', '\r']);
+            result.note_command(line);
+            if let Some(reply) =
+                result.interfere(policy, &Stage::Command(line), transport.writer())?
+            {
+                // The chunk is read in any case, so its content is not taken for commands
+                if let Some(arguments) = line.strip_prefix(BDAT_START) {
+                    Connection::skip_chunk(arguments, transport)?;
+                }
+                result.reply(transport.writer(), &reply)?;
+                continue;
+            }
+            if let (State::Rcpt | State::RcptOrData, Some(recipient)) =
+                (&result.state, line.strip_prefix(RCPT_START))
+            {
+                if let Some(reply) = policy.check_recipient(&result.next_sender, recipient.trim()) {
+                    result.reply(transport.writer(), &reply)?;
+                    continue;
+                }
+            }
+            let extended_greeting = if result.lmtp { LHLO_START } else { EHLO_START };
+            if let (State::Helo, Some(domain)) =
+                (&result.state, line.strip_prefix(extended_greeting))
+            {
+                let reply = result.greet_extended(domain);
+                result.reply(transport.writer(), &reply)?;
+                continue;
+            }
+            if let Some(arguments) = line.strip_prefix(BDAT_START) {
+                let reply = match result.state {
+                    State::RcptOrData | State::Bdat => {
+                        result.receive_chunk(arguments, transport, policy)?
+                    }
+                    _ => Connection::skip_chunk(arguments, transport)?.to_string(),
+                };
+                result.reply(transport.writer(), &reply)?;
+                continue;
+            }
+            if matches!(result.state, State::Mail)
+                && result.auth_required
+                && result.user.is_none()
+                && line.starts_with(MAIL_START)
+            {
+                result.reply(transport.writer(), MSG_AUTH_REQUIRED)?;
+                continue;
+            }
+            if let (State::Mail, Some(arguments)) = (&result.state, line.strip_prefix(AUTH_START)) {
+                let reply = result.authenticate(arguments, transport, policy)?;
+                result.reply(transport.writer(), reply)?;
+                continue;
+            }
+            match result.feed_line(line) {
                 Ok("") => {}
                 Ok(s) => {
-                    writeln!(writer, "{}", s)?;
+                    result.reply(transport.writer(), s)?;
                     if s.starts_with("221") {
                         break;
                     }
+                    match result.state {
+                        State::Dot => {
+                            let answer =
+                                result.interfere(policy, &Stage::Data, transport.writer())?;
+                            let reader = match result.max_message_size {
+                                Some(limit) => DataReader::limited(limit),
+                                None => DataReader::new(),
+                            };
+                            // The content is read in any case, so the client gets the reply
+                            let data = reader.read(transport.reader())?;
+                            let reply = result.complete_message(data, answer, policy);
+                            result.reply(transport.writer(), &reply)?;
+                        }
+                        State::Handshake => {
+                            transport.start_tls()?;
+                            // The client starts over, as if it had just connected
+                            result.encrypted = true;
+                            result.extended = false;
+                            result.sender_domain.clear();
+                            result.user = None;
+                            result.state = State::Helo;
+                        }
+                        _ => {}
+                    }
                 }
                 Err(e) => {
-                    writeln!(writer, "{}", e)?;
+                    result.reply(transport.writer(), e)?;
                 }
             }
         }
@@ -97,6 +542,102 @@ impl Connection {
         Ok(result)
     }
 
+    /// Turn away a client without starting a session
+    pub fn reject(writer: &mut dyn Write) -> Result<(), Error> {
+        writeln!(writer, "{}", MSG_SERVICE_NOT_AVAILABLE)
+    }
+
+    /// Tell a client that kept the session waiting for too long that the connection closes
+    pub fn time_out(writer: &mut dyn Write) -> Result<(), Error> {
+        writeln!(writer, "{}", MSG_IDLE_TIMEOUT)
+    }
+
+    /// Send a reply, which may have several lines, and keep it in the transcript
+    fn reply(&mut self, writer: &mut dyn Write, reply: &str) -> Result<(), Error> {
+        if reply.starts_with(['4', '5']) {
+            self.rejected += 1;
+        }
+        for line in reply.lines() {
+            tracing::debug!("S: {}", line);
+        }
+        if let Some(transcript) = &mut self.transcript {
+            transcript.extend(reply.lines().map(|line| format!("S: {}", line)));
+        }
+        writeln!(writer, "{}", reply)
+    }
+
+    /// Keep a line of the client in the transcript, without the credentials of AUTH
+    fn note_command(&mut self, line: &str) {
+        let line = match line.strip_prefix(AUTH_START) {
+            Some(arguments) if arguments.trim().contains(' ') => {
+                let mechanism = arguments.split_whitespace().next().unwrap_or_default();
+                format!("{}{} ********", AUTH_START, mechanism)
+            }
+            _ => line.to_string(),
+        };
+        tracing::debug!("C: {}", line);
+        if let Some(transcript) = &mut self.transcript {
+            transcript.push(format!("C: {}", line));
+        }
+    }
+
+    /// Let a policy interfere with the session at a stage, which ends the session if the policy
+    /// closes the connection. Returns the reply if the policy answers instead of the session.
+    fn interfere(
+        &mut self,
+        policy: &mut dyn Policy,
+        stage: &Stage,
+        writer: &mut dyn Write,
+    ) -> Result<Option<String>, Error> {
+        match policy.interfere(stage) {
+            Interference::Proceed => Ok(None),
+            Interference::Delay(duration) => {
+                thread::sleep(duration);
+                Ok(None)
+            }
+            Interference::Reply(reply) => Ok(Some(reply)),
+            Interference::Disconnect => Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                "connection dropped by the policy",
+            )),
+            Interference::Close(reply) => {
+                self.reply(writer, &reply)?;
+                Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    format!("connection closed by the policy with {}", reply),
+                ))
+            }
+        }
+    }
+
+    /// Send an AUTH challenge and read the response, which is None if the client cancelled
+    fn challenge(
+        &mut self,
+        transport: &mut dyn Transport,
+        challenge: &str,
+    ) -> Result<Option<String>, Error> {
+        self.reply(transport.writer(), challenge)?;
+        let mut line = String::new();
+        if transport.reader().read_line(&mut line)? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed during AUTH",
+            ));
+        }
+        let response = line.trim_end_matches(['\n', '\r']);
+        self.note_command(if response == "*" { "*" } else { "********" });
+        Ok(if response == "*" {
+            None
+        } else {
+            Some(response.to_string())
+        })
+    }
+
+    /// The commands and replies of the session, if the transport keeps them
+    pub fn get_transcript(&self) -> Option<&[String]> {
+        self.transcript.as_deref()
+    }
+
     fn get_if_done<R, F: FnOnce() -> R>(&self, getter: F) -> Option<R> {
         match self.state {
             State::Done => Some(getter()),
@@ -112,11 +653,243 @@ impl Connection {
         self.get_if_done(|| self.sender_domain.as_str())
     }
 
+    /// Whether the client switched to TLS with STARTTLS
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// The user the client logged in as, if it did
+    pub fn get_user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Whether the client greeted with EHLO and so may use extensions
+    pub fn is_extended(&self) -> bool {
+        self.extended
+    }
+
+    /// Whether the session is LMTP rather than SMTP
+    pub fn is_lmtp(&self) -> bool {
+        self.lmtp
+    }
+
+    /// The number of commands and contents the server refused with a 4xx or 5xx reply
+    pub fn get_rejected(&self) -> usize {
+        self.rejected
+    }
+
+    /// Start a session with EHLO or LHLO and get the reply, which lists the extensions
+    fn greet_extended(&mut self, domain: &str) -> String {
+        self.sender_domain = domain.trim().to_string();
+        self.extended = true;
+        self.state = State::Mail;
+        let size = match self.max_message_size {
+            Some(limit) => format!("SIZE {}", limit),
+            None => "SIZE".to_string(),
+        };
+        let mut lines = vec!["OK", &size];
+        lines.extend(EXTENSIONS);
+        if self.tls_available && !self.encrypted {
+            lines.push("STARTTLS");
+        }
+        let last = lines.len() - 1;
+        lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| format!("250{}{}", if i == last { ' ' } else { '-' }, line))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// Start a mail transaction, unless the client announced a message that is too large
+    fn start_message(&mut self, arguments: &str) -> Result<&'static str, &'static str> {
+        // Parameters such as SIZE=1000 or BODY=8BITMIME follow the path
+        let mut parts = arguments.split_whitespace();
+        let sender = parts.next().unwrap_or("");
+        let announced_size = parts.find_map(|parameter| {
+            let (keyword, value) = parameter.split_once('=')?;
+            keyword
+                .eq_ignore_ascii_case("SIZE")
+                .then(|| value.parse::<usize>().ok())?
+        });
+        if let (Some(size), Some(limit)) = (announced_size, self.max_message_size) {
+            if size > limit {
+                return Err(MSG_MESSAGE_TOO_BIG);
+            }
+        }
+        self.next_sender = sender.to_string();
+        self.state = State::Rcpt;
+        Ok(MSG_OK)
+    }
+
+    /// End the current mail transaction without keeping the content, e.g. because it exceeded
+    /// the size limit, and get the reply
+    fn discard_message(&mut self, reply: &str) -> String {
+        self.next_sender.clear();
+        self.next_recipients.clear();
+        self.state = State::MailOrQuit;
+        reply.to_string()
+    }
+
+    /// Read a chunk of the content sent with BDAT and get the reply, which is the one to the
+    /// content after the last chunk
+    fn receive_chunk(
+        &mut self,
+        arguments: &str,
+        transport: &mut dyn Transport,
+        policy: &mut dyn Policy,
+    ) -> Result<String, Error> {
+        // Without a size the end of the chunk is unknown, so the client cannot be followed
+        let Some((size, last)) = chunk_arguments(arguments) else {
+            return Ok(MSG_INVALID_CHUNK.to_string());
+        };
+        let limit = self.max_message_size.unwrap_or(MAX_CHUNKED_SIZE) as u64;
+        let mut chunk = Read::take(transport.reader(), size);
+        // The chunk is read in any case, so the client gets the reply
+        let read = match &mut self.chunks {
+            Some(chunks)
+                if (chunks.len() as u64)
+                    .checked_add(size)
+                    .is_some_and(|total| total <= limit) =>
+            {
+                chunk.read_to_end(chunks)? as u64
+            }
+            _ => {
+                self.chunks = None;
+                io::copy(&mut chunk, &mut io::sink())?
+            }
+        };
+        if read < size {
+            return Err(chunk_cut_off());
+        }
+        if !last {
+            self.state = State::Bdat;
+            return Ok(MSG_OK.to_string());
+        }
+        let data = self.chunks.replace(Vec::new());
+        let answer = self.interfere(policy, &Stage::Data, transport.writer())?;
+        Ok(self.complete_message(data, answer, policy))
+    }
+
+    /// Read and discard a chunk sent with BDAT outside of a mail transaction or turned away by a
+    /// policy, and get the reply
+    fn skip_chunk(arguments: &str, transport: &mut dyn Transport) -> Result<&'static str, Error> {
+        let Some((size, _)) = chunk_arguments(arguments) else {
+            return Ok(MSG_INVALID_CHUNK);
+        };
+        if io::copy(&mut Read::take(transport.reader(), size), &mut io::sink())? < size {
+            return Err(chunk_cut_off());
+        }
+        Ok(MSG_BAD_SEQUENCE)
+    }
+
+    /// End the current mail transaction with its content, or None if the content exceeded the
+    /// size limit, unless a policy answered instead, and get the reply
+    fn complete_message(
+        &mut self,
+        data: Option<Vec<u8>>,
+        answer: Option<String>,
+        policy: &mut dyn Policy,
+    ) -> String {
+        let recipients = self.next_recipients.len();
+        let reply = match (data, answer) {
+            (_, Some(answer)) => self.discard_message(&answer),
+            (Some(data), None) => self.finish_message(data, policy),
+            (None, None) => self.discard_message(MSG_MESSAGE_TOO_BIG),
+        };
+        // LMTP has a reply for every recipient, in their order
+        if self.lmtp {
+            vec![reply; recipients].join("\n")
+        } else {
+            reply
+        }
+    }
+
+    /// Log in with AUTH PLAIN or LOGIN and get the reply, asking the client for what did not come
+    /// with the command
+    fn authenticate(
+        &mut self,
+        arguments: &str,
+        transport: &mut dyn Transport,
+        policy: &mut dyn Policy,
+    ) -> Result<&'static str, Error> {
+        if self.user.is_some() {
+            return Ok(MSG_ALREADY_AUTHENTICATED);
+        }
+        let (mechanism, initial_response) = match arguments.trim().split_once(' ') {
+            Some((mechanism, response)) => (mechanism, Some(response.trim().to_string())),
+            None => (arguments.trim(), None),
+        };
+        let (user, password) = if mechanism.eq_ignore_ascii_case("PLAIN") {
+            let response = match initial_response {
+                Some(response) => response,
+                None => match self.challenge(transport, MSG_PLAIN_CHALLENGE)? {
+                    Some(response) => response,
+                    None => return Ok(MSG_AUTH_CANCELLED),
+                },
+            };
+            // The authorization identity comes first and is ignored
+            let decoded = decode_base64(response.as_bytes());
+            let parts: Vec<&[u8]> = decoded.split(|&b| b == 0).collect();
+            let [_, user, password] = parts[..] else {
+                return Ok(MSG_AUTH_MALFORMED);
+            };
+            (user.to_vec(), password.to_vec())
+        } else if mechanism.eq_ignore_ascii_case("LOGIN") {
+            let user = match initial_response {
+                Some(response) => response,
+                None => match self.challenge(transport, MSG_LOGIN_USERNAME)? {
+                    Some(response) => response,
+                    None => return Ok(MSG_AUTH_CANCELLED),
+                },
+            };
+            let Some(password) = self.challenge(transport, MSG_LOGIN_PASSWORD)? else {
+                return Ok(MSG_AUTH_CANCELLED);
+            };
+            (
+                decode_base64(user.as_bytes()),
+                decode_base64(password.as_bytes()),
+            )
+        } else {
+            return Ok(MSG_AUTH_UNSUPPORTED);
+        };
+
+        let user = String::from_utf8_lossy(&user);
+        if !policy.check_credentials(&user, &String::from_utf8_lossy(&password)) {
+            return Ok(MSG_AUTH_FAILED);
+        }
+        self.user = Some(user.into_owned());
+        Ok(MSG_AUTH_SUCCEEDED)
+    }
+
+    /// Complete the current mail transaction with the message content, unless the policy
+    /// drops or rejects the message
+    fn finish_message(&mut self, data: Vec<u8>, policy: &mut dyn Policy) -> String {
+        let mut message = Message {
+            id: new_uuid(),
+            sender: mem::take(&mut self.next_sender),
+            recipients: mem::take(&mut self.next_recipients),
+            data,
+            tags: Vec::new(),
+            auth_results: Vec::new(),
+        };
+        self.state = State::MailOrQuit;
+        match policy.check_message(&mut message) {
+            Verdict::Accept => {
+                self.messages.push(message);
+                MSG_OK.to_string()
+            }
+            Verdict::Drop => MSG_OK.to_string(),
+            Verdict::Reject(reply) => reply,
+        }
+    }
+
     fn feed_line<'a>(&mut self, line: &'a str) -> Result<&'a str, &'a str> {
         match self.state {
             State::Helo => {
-                if line.starts_with(HELO_START) {
-                    self.sender_domain = line[HELO_START.len()..].trim().to_string();
+                // EHLO is handled with its extensions before lines get here, and LMTP has no HELO
+                if let Some(domain) = line.strip_prefix(HELO_START).filter(|_| !self.lmtp) {
+                    self.sender_domain = domain.trim().to_string();
                     self.state = State::Mail;
                     Ok(MSG_OK)
                 } else {
@@ -124,18 +897,18 @@ impl Connection {
                 }
             }
             State::Mail => {
-                if line.starts_with(MAIL_START) {
-                    self.next_sender = line[MAIL_START.len()..].trim().to_string();
-                    self.state = State::Rcpt;
-                    Ok(MSG_OK)
+                if let Some(arguments) = line.strip_prefix(MAIL_START) {
+                    self.start_message(arguments)
+                } else if line == STARTTLS_LINE && self.tls_available && !self.encrypted {
+                    self.state = State::Handshake;
+                    Ok(MSG_READY_TO_START_TLS)
                 } else {
                     Err(MSG_SYNTAX_ERROR)
                 }
             }
             State::Rcpt => {
-                if line.starts_with(RCPT_START) {
-                    self.next_recipients
-                        .push(line[RCPT_START.len()..].trim().to_string());
+                if let Some(recipient) = line.strip_prefix(RCPT_START) {
+                    self.next_recipients.push(recipient.trim().to_string());
                     self.state = State::RcptOrData;
                     Ok(MSG_OK)
                 } else {
@@ -143,9 +916,8 @@ impl Connection {
                 }
             }
             State::RcptOrData => {
-                if line.starts_with(RCPT_START) {
-                    self.next_recipients
-                        .push(line[RCPT_START.len()..].trim().to_string());
+                if let Some(recipient) = line.strip_prefix(RCPT_START) {
+                    self.next_recipients.push(recipient.trim().to_string());
                     Ok(MSG_OK)
                 } else if line == DATA_LINE {
                     self.state = State::Dot;
@@ -154,28 +926,25 @@ impl Connection {
                     Err(MSG_SYNTAX_ERROR)
                 }
             }
-            State::Dot => {
-                if line == "." {
-                    self.messages.push(Message {
-                        sender: self.next_sender.clone(),
-                        recipients: self.next_recipients.clone(),
-                        data: self.next_data.clone(),
-                    });
-                    self.next_sender = "".to_string();
-                    self.next_recipients = Vec::new();
-                    self.next_data = Vec::new();
-                    self.state = State::MailOrQuit;
+            // Message content is read by the DataReader and the handshake is done by the
+            // transport, so no lines arrive here
+            State::Dot | State::Handshake => Err(MSG_SYNTAX_ERROR),
+            // BDAT is handled with its chunk before lines get here, and DATA cannot follow it
+            State::Bdat => {
+                if line == RSET_LINE {
+                    self.chunks = Some(Vec::new());
+                    self.discard_message(MSG_OK);
                     Ok(MSG_OK)
+                } else if line == QUIT_LINE {
+                    self.state = State::Done;
+                    Ok(MSG_BYE)
                 } else {
-                    self.next_data.push(line.to_string());
-                    Ok("")
+                    Err(MSG_SYNTAX_ERROR)
                 }
             }
             State::MailOrQuit => {
-                if line.starts_with(MAIL_START) {
-                    self.next_sender = line[MAIL_START.len()..].trim().to_string();
-                    self.state = State::Rcpt;
-                    Ok(MSG_OK)
+                if let Some(arguments) = line.strip_prefix(MAIL_START) {
+                    self.start_message(arguments)
                 } else if line == QUIT_LINE {
                     self.state = State::Done;
                     Ok(MSG_BYE)
@@ -232,4 +1001,379 @@ mod tests {
              221 Bye\n"
         )
     }
+
+    #[test]
+    fn fail_on_eof_before_quit() {
+        // Given
+        let mut reader = BufReader::new("HELO localhost\n".as_bytes());
+
+        // When
+        let error = Connection::handle(&mut reader, &mut Vec::new())
+            .err()
+            .unwrap();
+
+        // Then
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    /// Transport with the capabilities a test asks for, which pretends to switch to TLS and
+    /// counts the switches
+    struct TestTransport<'a> {
+        reader: BufReader<&'a [u8]>,
+        writer: Vec<u8>,
+        tls: bool,
+        switches: u32,
+        max_message_size: Option<usize>,
+        transcript: bool,
+        lmtp: bool,
+        auth_required: bool,
+    }
+
+    impl TestTransport<'_> {
+        /// A plain SMTP transport that reads a request
+        fn new(request: &str) -> TestTransport<'_> {
+            TestTransport {
+                reader: BufReader::new(request.as_bytes()),
+                writer: Vec::new(),
+                tls: false,
+                switches: 0,
+                max_message_size: None,
+                transcript: false,
+                lmtp: false,
+                auth_required: false,
+            }
+        }
+    }
+
+    impl Transport for TestTransport<'_> {
+        fn reader(&mut self) -> &mut dyn BufRead {
+            &mut self.reader
+        }
+
+        fn writer(&mut self) -> &mut dyn Write {
+            &mut self.writer
+        }
+
+        fn can_start_tls(&self) -> bool {
+            self.tls
+        }
+
+        fn start_tls(&mut self) -> Result<(), Error> {
+            self.switches += 1;
+            Ok(())
+        }
+
+        fn max_message_size(&self) -> Option<usize> {
+            self.max_message_size
+        }
+
+        fn keeps_transcript(&self) -> bool {
+            self.transcript
+        }
+
+        fn speaks_lmtp(&self) -> bool {
+            self.lmtp
+        }
+
+        fn requires_auth(&self) -> bool {
+            self.auth_required
+        }
+    }
+
+    #[test]
+    fn switch_to_tls_with_starttls() {
+        // Given
+        let request = "EHLO localhost\n\
+                       STARTTLS\n\
+                       MAIL FROM: tester@localhost\n\
+                       EHLO localhost\n\
+                       MAIL FROM: tester@localhost\n\
+                       RCPT TO: admin@localhost\n\
+                       DATA\n\
+                       Secret\n\
+                       .\n\
+                       QUIT\n";
+        let mut transport = TestTransport {
+            tls: true,
+            ..TestTransport::new(request)
+        };
+
+        // When
+        let result = Connection::handle_transport(&mut transport, &mut AcceptAll).unwrap();
+
+        // Then
+        assert_eq!(
+            String::from_utf8(transport.writer).unwrap(),
+            "220 ready\n\
+             250-OK\n\
+             250-SIZE\n\
+             250-PIPELINING\n\
+             250-8BITMIME\n\
+             250-CHUNKING\n\
+             250-BINARYMIME\n\
+             250-AUTH PLAIN LOGIN\n\
+             250 STARTTLS\n\
+             220 Ready to start TLS\n\
+             500 unexpected line\n\
+             250-OK\n\
+             250-SIZE\n\
+             250-PIPELINING\n\
+             250-8BITMIME\n\
+             250-CHUNKING\n\
+             250-BINARYMIME\n\
+             250 AUTH PLAIN LOGIN\n\
+             250 OK\n\
+             250 OK\n\
+             354 Send message content\n\
+             250 OK\n\
+             221 Bye\n"
+        );
+        assert_eq!(transport.switches, 1);
+        assert!(result.is_encrypted());
+    }
+
+    #[test]
+    fn reject_messages_over_size_limit() {
+        // Given
+        let request = "EHLO localhost\n\
+                       MAIL FROM:<tester@localhost> SIZE=11\n\
+                       MAIL FROM:<tester@localhost> SIZE=5 BODY=8BITMIME\n\
+                       RCPT TO:<admin@localhost>\n\
+                       DATA\n\
+                       More than ten bytes\n\
+                       .\n\
+                       MAIL FROM:<tester@localhost>\n\
+                       RCPT TO:<admin@localhost>\n\
+                       DATA\n\
+                       Short\n\
+                       .\n\
+                       QUIT\n";
+        let mut transport = TestTransport {
+            max_message_size: Some(10),
+            ..TestTransport::new(request)
+        };
+
+        // When
+        let result = Connection::handle_transport(&mut transport, &mut AcceptAll).unwrap();
+
+        // Then
+        let response = String::from_utf8(transport.writer).unwrap();
+        let replies: Vec<&str> = response.lines().collect();
+        assert_eq!(replies[1..3], ["250-OK", "250-SIZE 10"]);
+        assert_eq!(replies[8], MSG_MESSAGE_TOO_BIG);
+        assert_eq!(replies[9], MSG_OK);
+        assert_eq!(replies[12], MSG_MESSAGE_TOO_BIG);
+        assert_eq!(result.get_rejected(), 2);
+        assert!(result.is_extended());
+        let messages = result.get_messages().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_sender(), "<tester@localhost>");
+        assert_eq!(messages[0].get_data(), "Short");
+    }
+
+    #[test]
+    fn receive_binary_chunks_with_bdat() {
+        // Given
+        let content = b"\x00\xff\r\n.\r\nrest\r";
+        let mut request = b"EHLO localhost\r\n\
+                            MAIL FROM:<tester@localhost>\r\n\
+                            RCPT TO:<admin@localhost>\r\n\
+                            BDAT 4\r\n"
+            .to_vec();
+        request.extend(&content[..4]);
+        request.extend(b"BDAT some\r\nBDAT 8 LAST\r\n");
+        request.extend(&content[4..]);
+        request.extend(b"QUIT\r\n");
+        let mut response = Vec::new();
+
+        // When
+        let result = Connection::handle(&mut BufReader::new(&request[..]), &mut response).unwrap();
+
+        // Then
+        let response = String::from_utf8(response).unwrap();
+        let replies: Vec<&str> = response.lines().collect();
+        assert_eq!(replies[10..], [MSG_OK, MSG_INVALID_CHUNK, MSG_OK, MSG_BYE]);
+        let messages = result.get_messages().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_content(), content);
+    }
+
+    #[test]
+    fn skip_chunks_out_of_sequence() {
+        // Given
+        let request = b"EHLO localhost\r\n\
+                        BDAT 12 LAST\r\n\
+                        QUIT\r\nRSET\r\n\
+                        MAIL FROM:<tester@localhost>\r\n\
+                        RCPT TO:<admin@localhost>\r\n\
+                        BDAT 3\r\n\
+                        abcRSET\r\n\
+                        MAIL FROM:<tester@localhost>\r\n\
+                        RCPT TO:<admin@localhost>\r\n\
+                        BDAT 3\r\n\
+                        abcQUIT\r\n";
+        let mut response = Vec::new();
+
+        // When
+        let result = Connection::handle(&mut BufReader::new(&request[..]), &mut response).unwrap();
+
+        // Then
+        let response = String::from_utf8(response).unwrap();
+        let replies: Vec<&str> = response.lines().collect();
+        assert_eq!(
+            replies[replies.len() - 9..],
+            [
+                MSG_BAD_SEQUENCE,
+                MSG_OK,
+                MSG_OK,
+                MSG_OK,
+                MSG_OK,
+                MSG_OK,
+                MSG_OK,
+                MSG_OK,
+                MSG_BYE
+            ]
+        );
+        assert!(result.get_messages().unwrap().is_empty());
+    }
+
+    #[test]
+    fn skip_huge_chunks() {
+        // Given
+        let request = b"EHLO localhost\r\n\
+                        MAIL FROM:<tester@localhost>\r\n\
+                        RCPT TO:<admin@localhost>\r\n\
+                        BDAT 4\r\n\
+                        abcdBDAT 18446744073709551615 LAST\r\n\
+                        QUIT\r\n";
+        let mut response = Vec::new();
+
+        // When
+        let result = Connection::handle(&mut BufReader::new(&request[..]), &mut response);
+
+        // Then
+        // The rest of the session is taken for the chunk, which is not kept
+        let error = result.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn keep_transcript() {
+        // Given
+        let request = "HELO localhost\n\
+                       AUTH PLAIN AHRlc3RlcgBzZWNyZXQ=\n\
+                       MAIL FROM:<tester@localhost>\n\
+                       RCPT TO:<admin@localhost>\n\
+                       DATA\n\
+                       Hello\n\
+                       .\n\
+                       QUIT\n";
+        let mut transport = TestTransport {
+            transcript: true,
+            ..TestTransport::new(request)
+        };
+
+        // When
+        let result = Connection::handle_transport(&mut transport, &mut AcceptAll).unwrap();
+
+        // Then
+        assert_eq!(
+            result.get_transcript().unwrap(),
+            [
+                "S: 220 ready",
+                "C: HELO localhost",
+                "S: 250 OK",
+                "C: AUTH PLAIN ********",
+                "S: 235 Authentication succeeded",
+                "C: MAIL FROM:<tester@localhost>",
+                "S: 250 OK",
+                "C: RCPT TO:<admin@localhost>",
+                "S: 250 OK",
+                "C: DATA",
+                "S: 354 Send message content",
+                "S: 250 OK",
+                "C: QUIT",
+                "S: 221 Bye",
+            ]
+        );
+    }
+
+    #[test]
+    fn require_auth_before_mail() {
+        // Given
+        let request = "HELO localhost\n\
+                       MAIL FROM:<tester@localhost>\n\
+                       AUTH PLAIN AHRlc3RlcgBzZWNyZXQ=\n\
+                       MAIL FROM:<tester@localhost>\n\
+                       RCPT TO:<admin@localhost>\n\
+                       DATA\n\
+                       Hello\n\
+                       .\n\
+                       QUIT\n";
+        let mut transport = TestTransport {
+            auth_required: true,
+            ..TestTransport::new(request)
+        };
+
+        // When
+        let result = Connection::handle_transport(&mut transport, &mut AcceptAll).unwrap();
+
+        // Then
+        assert_eq!(result.get_messages().map(Vec::len), Some(1));
+        assert_eq!(
+            String::from_utf8(transport.writer).unwrap(),
+            "220 ready\n\
+             250 OK\n\
+             530 Authentication required\n\
+             235 Authentication succeeded\n\
+             250 OK\n\
+             250 OK\n\
+             354 Send message content\n\
+             250 OK\n\
+             221 Bye\n"
+        );
+    }
+
+    #[test]
+    fn reply_for_every_recipient_with_lmtp() {
+        // Given
+        let request = "HELO localhost\n\
+                       LHLO localhost\n\
+                       MAIL FROM:<tester@localhost>\n\
+                       RCPT TO:<admin@localhost>\n\
+                       RCPT TO:<postmaster@localhost>\n\
+                       DATA\n\
+                       Hello\n\
+                       .\n\
+                       QUIT\n";
+        let mut transport = TestTransport {
+            lmtp: true,
+            ..TestTransport::new(request)
+        };
+
+        // When
+        let result = Connection::handle_transport(&mut transport, &mut AcceptAll).unwrap();
+
+        // Then
+        let response = String::from_utf8(transport.writer).unwrap();
+        let replies: Vec<&str> = response.lines().collect();
+        assert_eq!(replies[1], MSG_SYNTAX_ERROR);
+        assert_eq!(replies[2], "250-OK");
+        assert_eq!(
+            replies[replies.len() - 4..],
+            [MSG_SEND_MESSAGE_CONTENT, MSG_OK, MSG_OK, MSG_BYE]
+        );
+        assert!(result.is_lmtp());
+        assert_eq!(result.get_messages().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn make_random_uuids() {
+        let (first, second) = (new_uuid(), new_uuid());
+
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 36);
+        assert_eq!(&first[14..15], "4");
+        assert!(matches!(&first[19..20], "8" | "9" | "a" | "b"));
+    }
 }
@@ -0,0 +1,374 @@
+//! Evaluating SPF ([RFC 7208](https://tools.ietf.org/html/rfc7208)), whether a client may send
+//! mail for the domain of a sender.
+//!
+//! Mechanisms and modifiers are supported with their macros, except `ptr`, which never matches
+//! as the RFC advises, and `exp`, which only explains failures. A lookup that fails is a
+//! temperror, and names that do not exist have no records.
+
+use std::net::IpAddr;
+
+use crate::dns::Lookup;
+use crate::verify::Outcome;
+
+/// Mechanisms and modifiers that cause DNS lookups, beyond which a record is a permerror
+const MAX_LOOKUPS: usize = 10;
+/// Mail exchangers of an `mx` mechanism that are looked up at most
+const MAX_EXCHANGES: usize = 10;
+
+/// Check whether a client may send mail from a sender, the address from MAIL FROM without angle
+/// brackets. The null sender is checked as the postmaster of the HELO domain.
+pub fn check(lookup: &dyn Lookup, ip: IpAddr, sender: &str, helo: &str) -> Outcome {
+    let sender = match sender.rsplit_once('@') {
+        Some((_, _)) => sender.to_string(),
+        None if sender.is_empty() => format!("postmaster@{}", helo),
+        None => format!("postmaster@{}", sender),
+    };
+    let domain = sender.rsplit_once('@').unwrap().1.to_string();
+    Evaluation {
+        lookup,
+        ip,
+        sender: &sender,
+        helo,
+        lookups: 0,
+    }
+    .check_host(&domain)
+}
+
+/// The state of a check, which spans included records
+struct Evaluation<'a> {
+    lookup: &'a dyn Lookup,
+    ip: IpAddr,
+    sender: &'a str,
+    helo: &'a str,
+    /// Lookups so far
+    lookups: usize,
+}
+
+impl Evaluation<'_> {
+    /// Evaluate the record of a domain
+    fn check_host(&mut self, domain: &str) -> Outcome {
+        let Ok(records) = self.lookup.txt(domain) else {
+            return Outcome::TempError;
+        };
+        let mut records = records.iter().filter(|record| {
+            let record = record.to_ascii_lowercase();
+            record == "v=spf1" || record.starts_with("v=spf1 ")
+        });
+        let record = match (records.next(), records.next()) {
+            (None, _) => return Outcome::None,
+            (Some(record), None) => record,
+            _ => return Outcome::PermError,
+        };
+
+        let mut redirect = None;
+        for term in record.split_whitespace().skip(1) {
+            if let Some((name, value)) = term.split_once('=') {
+                if !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+                {
+                    return Outcome::PermError;
+                }
+                if name.eq_ignore_ascii_case("redirect") {
+                    redirect = Some(value);
+                }
+                continue;
+            }
+            let (qualifier, mechanism) = match term.strip_prefix(['+', '-', '~', '?']) {
+                Some(mechanism) => (&term[..1], mechanism),
+                None => ("+", term),
+            };
+            match self.matches(mechanism, domain) {
+                Ok(false) => {}
+                Ok(true) => {
+                    return match qualifier {
+                        "+" => Outcome::Pass,
+                        "-" => Outcome::Fail,
+                        "~" => Outcome::SoftFail,
+                        _ => Outcome::Neutral,
+                    }
+                }
+                Err(outcome) => return outcome,
+            }
+        }
+
+        // Redirects only apply if no mechanism matched
+        let Some(target) = redirect else {
+            return Outcome::Neutral;
+        };
+        let target = match self
+            .count_lookup()
+            .and_then(|()| self.expand(target, domain))
+        {
+            Ok(target) => target,
+            Err(outcome) => return outcome,
+        };
+        match self.check_host(&target) {
+            Outcome::None => Outcome::PermError,
+            outcome => outcome,
+        }
+    }
+
+    /// Whether a mechanism matches the client, or the outcome of the whole check if it cannot be
+    /// evaluated
+    fn matches(&mut self, mechanism: &str, domain: &str) -> Result<bool, Outcome> {
+        let name_length = mechanism.find([':', '/']).unwrap_or(mechanism.len());
+        let (name, argument) = mechanism.split_at(name_length);
+        // The argument is a domain or network after a colon, then prefix lengths after a slash
+        let (target, prefixes) = argument.split_once('/').unwrap_or((argument, ""));
+        let target = match target.strip_prefix(':') {
+            Some(target) => Some(target),
+            None if target.is_empty() => None,
+            None => return Err(Outcome::PermError),
+        };
+        let name = name.to_ascii_lowercase();
+        // The only prefix length of ip6 is that of IPv6
+        let prefixes = match name.as_str() {
+            "ip6" => prefix_length(prefixes, 128).map(|length| (32, length)),
+            _ => prefix_lengths(prefixes),
+        };
+        let (ipv4_prefix, ipv6_prefix) = prefixes.ok_or(Outcome::PermError)?;
+        let target_domain = |evaluation: &Self| match target {
+            Some(target) => evaluation.expand(target, domain),
+            None => Ok(domain.to_string()),
+        };
+        let ip = self.ip;
+        let covers = move |network: IpAddr| in_network(ip, network, ipv4_prefix, ipv6_prefix);
+
+        match name.as_str() {
+            "all" => Ok(true),
+            "include" => {
+                self.count_lookup()?;
+                let target = self.expand(target.ok_or(Outcome::PermError)?, domain)?;
+                match self.check_host(&target) {
+                    Outcome::Pass => Ok(true),
+                    Outcome::Fail | Outcome::SoftFail | Outcome::Neutral => Ok(false),
+                    Outcome::TempError => Err(Outcome::TempError),
+                    _ => Err(Outcome::PermError),
+                }
+            }
+            "a" => {
+                self.count_lookup()?;
+                let addresses = self.lookup.addresses(&target_domain(self)?);
+                Ok(addresses
+                    .map_err(|_| Outcome::TempError)?
+                    .into_iter()
+                    .any(covers))
+            }
+            "mx" => {
+                self.count_lookup()?;
+                let exchanges = self.lookup.mx(&target_domain(self)?);
+                let exchanges = exchanges.map_err(|_| Outcome::TempError)?;
+                for exchange in exchanges.iter().take(MAX_EXCHANGES) {
+                    let addresses = self.lookup.addresses(exchange);
+                    if addresses
+                        .map_err(|_| Outcome::TempError)?
+                        .into_iter()
+                        .any(covers)
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            "exists" => {
+                self.count_lookup()?;
+                let target = self.expand(target.ok_or(Outcome::PermError)?, domain)?;
+                let addresses = self.lookup.addresses(&target);
+                Ok(!addresses.map_err(|_| Outcome::TempError)?.is_empty())
+            }
+            "ptr" => {
+                self.count_lookup()?;
+                Ok(false)
+            }
+            "ip4" | "ip6" => {
+                let network: IpAddr = target
+                    .and_then(|network| network.parse().ok())
+                    .filter(|network: &IpAddr| network.is_ipv4() == (name == "ip4"))
+                    .ok_or(Outcome::PermError)?;
+                Ok(covers(network))
+            }
+            _ => Err(Outcome::PermError),
+        }
+    }
+
+    /// Count a lookup against the limit
+    fn count_lookup(&mut self) -> Result<(), Outcome> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(Outcome::PermError);
+        }
+        Ok(())
+    }
+
+    /// Expand the macros of a domain spec, like `%{ir}.%{v}._spf.%{d}`
+    fn expand(&self, spec: &str, domain: &str) -> Result<String, Outcome> {
+        let mut result = String::new();
+        let mut chars = spec.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                result.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => result.push('%'),
+                Some('_') => result.push(' '),
+                Some('-') => result += "%20",
+                Some('{') => {
+                    let mut body = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => body.push(c),
+                            None => return Err(Outcome::PermError),
+                        }
+                    }
+                    result += &self.expand_macro(&body, domain)?;
+                }
+                _ => return Err(Outcome::PermError),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Expand the inside of a macro: a letter, then how many parts to keep from the right, `r` to
+    /// reverse them and the delimiters to split at
+    fn expand_macro(&self, body: &str, domain: &str) -> Result<String, Outcome> {
+        let mut chars = body.chars();
+        let letter = chars.next().ok_or(Outcome::PermError)?;
+        let (local_part, sender_domain) = self.sender.rsplit_once('@').unwrap_or(("", ""));
+        let value = match letter.to_ascii_lowercase() {
+            's' => self.sender.to_string(),
+            'l' => local_part.to_string(),
+            'o' => sender_domain.to_string(),
+            'd' => domain.to_string(),
+            'h' => self.helo.to_string(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                // Nibbles in dotted form
+                IpAddr::V6(ip) => ip
+                    .octets()
+                    .iter()
+                    .flat_map(|octet| [octet >> 4, octet & 0xf])
+                    .map(|nibble| format!("{:x}", nibble))
+                    .collect::<Vec<_>>()
+                    .join("."),
+            },
+            'v' if self.ip.is_ipv4() => "in-addr".to_string(),
+            'v' => "ip6".to_string(),
+            _ => return Err(Outcome::PermError),
+        };
+        let rest = chars.as_str();
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let (keep, rest) = rest.split_at(digits);
+        let (reverse, delimiters) = match rest.strip_prefix(['r', 'R']) {
+            Some(delimiters) => (true, delimiters),
+            None => (false, rest),
+        };
+        if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+            return Err(Outcome::PermError);
+        }
+        let delimiters = if delimiters.is_empty() {
+            "."
+        } else {
+            delimiters
+        };
+        let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+        if reverse {
+            parts.reverse();
+        }
+        if !keep.is_empty() {
+            let keep: usize = keep.parse().map_err(|_| Outcome::PermError)?;
+            if keep == 0 {
+                return Err(Outcome::PermError);
+            }
+            parts.drain(..parts.len().saturating_sub(keep));
+        }
+        Ok(parts.join("."))
+    }
+}
+
+/// Parse the IPv4 and IPv6 prefix lengths after the slash of a mechanism, like `24`, `24//64`
+/// or `/64`
+fn prefix_lengths(prefixes: &str) -> Option<(u32, u32)> {
+    let (ipv4, ipv6) = match prefixes.strip_prefix('/') {
+        Some(ipv6) => ("", ipv6),
+        None => prefixes.split_once("//").unwrap_or((prefixes, "")),
+    };
+    Some((prefix_length(ipv4, 32)?, prefix_length(ipv6, 128)?))
+}
+
+/// Parse a prefix length up to a maximum, which is also the length if there is none
+fn prefix_length(length: &str, max: u32) -> Option<u32> {
+    match length {
+        "" => Some(max),
+        length => length.parse().ok().filter(|&length| length <= max),
+    }
+}
+
+/// Whether an address is in the network of another one with a prefix length for its family
+fn in_network(ip: IpAddr, network: IpAddr, ipv4_prefix: u32, ipv6_prefix: u32) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - ipv4_prefix).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - ipv6_prefix).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::Answers;
+
+    #[test]
+    fn evaluate_records() {
+        // Given
+        let mut answers = Answers::default();
+        let records = [
+            (
+                "example.com",
+                "v=spf1 ip4:192.0.2.0/24 include:_spf.example.net -all",
+            ),
+            ("_spf.example.net", "v=spf1 mx:example.net/28 ~all"),
+            (
+                "example.org",
+                "v=spf1 exists:%{ir}.%{l1r+}.allowed.example.org ?all",
+            ),
+            ("example.edu", "v=spf1 redirect=example.com"),
+        ];
+        for (name, record) in records {
+            answers
+                .txt
+                .insert(name.to_string(), vec![record.to_string()]);
+        }
+        answers.mx.insert(
+            "example.net".to_string(),
+            vec!["mail.example.net".to_string()],
+        );
+        answers.addresses.insert(
+            "mail.example.net".to_string(),
+            vec!["198.51.100.1".parse().unwrap()],
+        );
+        answers.addresses.insert(
+            "1.0.0.10.tester.allowed.example.org".to_string(),
+            vec!["127.0.0.2".parse().unwrap()],
+        );
+        let check = |ip: &str, sender: &str| check(&answers, ip.parse().unwrap(), sender, "helo");
+
+        // Then
+        assert_eq!(check("192.0.2.7", "a@example.com"), Outcome::Pass);
+        assert_eq!(check("198.51.100.14", "a@example.com"), Outcome::Pass);
+        assert_eq!(check("198.51.100.20", "a@example.com"), Outcome::Fail);
+        assert_eq!(check("10.0.0.1", "tester+tag@example.org"), Outcome::Pass);
+        assert_eq!(check("10.0.0.2", "tester@example.org"), Outcome::Neutral);
+        assert_eq!(check("192.0.2.7", "a@example.edu"), Outcome::Pass);
+        assert_eq!(check("192.0.2.7", "a@example.test"), Outcome::None);
+        assert_eq!(check("2001:db8::1", "a@example.com"), Outcome::Fail);
+    }
+}
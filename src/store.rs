@@ -0,0 +1,714 @@
+//! Keeping received messages after their session, so they can be retrieved later.
+//!
+//! Messages are kept in memory, or in a directory where they survive a restart. Like in the relay
+//! queue, each message in a directory is a pair of files named after its ID: the content as
+//! received in `<id>.eml` and the summary as JSON in `<id>.json`. Several servers can share a
+//! directory, e.g. on a volume that all instances behind a load balancer mount, and each of them
+//! lists the messages that any of them received. Files that another server removes while they are
+//! read count as removed.
+//!
+//! Retention limits on the number, total size and age of the messages keep a long running server
+//! from filling its memory or disk: the oldest messages are removed first when a message arrives
+//! or the messages are listed.
+
+use std::fs;
+use std::io::{self, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_json::Value;
+
+use crate::clock::Clock;
+use crate::queue::write_atomically;
+use crate::relay::{self, Relay};
+use crate::zip;
+use crate::{Config, PrintFormat, Session};
+
+/// Name of the subcommand
+pub const SUBCOMMAND_NAME: &str = "messages";
+
+const SHOW_ARG_NAME: &str = "show";
+const DELETE_ARG_NAME: &str = "delete";
+const RELEASE_ARG_NAME: &str = "release";
+const ZIP_ARG_NAME: &str = "zip";
+
+/// A kept message without its content
+#[derive(Clone)]
+pub struct Entry {
+    pub id: String,
+    /// Seconds since the epoch when the message was received
+    pub received: u64,
+    /// Size of the content in bytes
+    pub size: usize,
+    /// The envelope and session, like the lines of `--print-format jsonl`
+    pub summary: Value,
+}
+
+impl Entry {
+    pub fn to_json(&self) -> Value {
+        let mut object = self.summary.clone();
+        object["received"] = self.received.into();
+        object
+    }
+
+    fn from_json(value: Value) -> Option<Entry> {
+        Some(Entry {
+            id: value["id"].as_str()?.to_string(),
+            received: value["received"].as_u64()?,
+            size: value["size"].as_u64()? as usize,
+            summary: value,
+        })
+    }
+}
+
+/// Where received messages are kept
+pub trait MessageStore: Send + Sync {
+    /// Keep a message
+    fn add(&self, entry: Entry, content: &[u8]) -> Result<(), Error>;
+
+    /// The kept messages, oldest first
+    fn entries(&self) -> Result<Vec<Entry>, Error>;
+
+    /// Read the content of a message, if it is kept
+    fn content(&self, id: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Remove a message. Returns whether it was kept.
+    fn remove(&self, id: &str) -> Result<bool, Error>;
+
+    /// Replace the summary of a message. Returns whether it was kept.
+    fn update(&self, entry: Entry) -> Result<bool, Error>;
+
+    /// The directory the messages are kept in, if they are kept on disk
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// The limits on the kept messages, if there are any
+    fn retention(&self) -> Option<&Retention> {
+        None
+    }
+}
+
+/// Messages kept until the server stops
+#[derive(Default)]
+pub struct Memory(Mutex<Vec<(Entry, Arc<[u8]>)>>);
+
+impl MessageStore for Memory {
+    fn add(&self, entry: Entry, content: &[u8]) -> Result<(), Error> {
+        self.0.lock().unwrap().push((entry, content.into()));
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>, Error> {
+        let messages = self.0.lock().unwrap();
+        Ok(messages.iter().map(|(entry, _)| entry.clone()).collect())
+    }
+
+    fn content(&self, id: &str) -> Result<Option<Vec<u8>>, Error> {
+        let messages = self.0.lock().unwrap();
+        Ok(messages
+            .iter()
+            .find(|(entry, _)| entry.id == id)
+            .map(|(_, content)| content.to_vec()))
+    }
+
+    fn remove(&self, id: &str) -> Result<bool, Error> {
+        let mut messages = self.0.lock().unwrap();
+        let count = messages.len();
+        messages.retain(|(entry, _)| entry.id != id);
+        Ok(messages.len() < count)
+    }
+
+    fn update(&self, entry: Entry) -> Result<bool, Error> {
+        let mut messages = self.0.lock().unwrap();
+        match messages.iter_mut().find(|(kept, _)| kept.id == entry.id) {
+            Some((kept, _)) => *kept = entry,
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// Messages kept in a directory
+pub struct Directory {
+    pub path: PathBuf,
+}
+
+impl Directory {
+    fn file(&self, id: &str, extension: &str) -> Result<PathBuf, Error> {
+        // IDs come from clients of the store, so they must not reach outside the directory
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid message ID {}", id),
+            ));
+        }
+        Ok(self.path.join(format!("{}.{}", id, extension)))
+    }
+}
+
+impl MessageStore for Directory {
+    fn add(&self, entry: Entry, content: &[u8]) -> Result<(), Error> {
+        fs::create_dir_all(&self.path)?;
+        // The summary is written last, so a message is only seen once its content is complete
+        write_atomically(&self.file(&entry.id, "eml")?, content)?;
+        write_atomically(
+            &self.file(&entry.id, "json")?,
+            entry.to_json().to_string().as_bytes(),
+        )
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>, Error> {
+        let dir = match fs::read_dir(&self.path) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for file in dir {
+            let path = file?.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let summary = match fs::read(&path) {
+                Ok(summary) => summary,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let entry = serde_json::from_slice(&summary)
+                .ok()
+                .and_then(Entry::from_json)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid message file {}", path.display()),
+                    )
+                })?;
+            entries.push(entry);
+        }
+        entries.sort_by(|a, b| (a.received, &a.id).cmp(&(b.received, &b.id)));
+        Ok(entries)
+    }
+
+    fn content(&self, id: &str) -> Result<Option<Vec<u8>>, Error> {
+        if !self.file(id, "json")?.exists() {
+            return Ok(None);
+        }
+        match fs::read(self.file(id, "eml")?) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove(&self, id: &str) -> Result<bool, Error> {
+        // Without its summary a message is no longer seen, so the content can go second
+        match fs::remove_file(self.file(id, "json")?) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        fs::remove_file(self.file(id, "eml")?)?;
+        Ok(true)
+    }
+
+    fn update(&self, entry: Entry) -> Result<bool, Error> {
+        let path = self.file(&entry.id, "json")?;
+        if !path.exists() {
+            return Ok(false);
+        }
+        write_atomically(&path, entry.to_json().to_string().as_bytes())?;
+        Ok(true)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/// Limits on the kept messages, beyond which the oldest are removed
+#[derive(Clone, Default)]
+pub struct Retention {
+    pub max_messages: Option<usize>,
+    /// Total size of the contents
+    pub max_bytes: Option<usize>,
+    /// Seconds after receiving a message
+    pub max_age: Option<u64>,
+}
+
+impl Retention {
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "max_messages": self.max_messages,
+            "max_bytes": self.max_bytes,
+            "max_age": self.max_age,
+        })
+    }
+}
+
+/// A store that removes its oldest messages while they exceed the limits
+pub struct Retained {
+    pub store: Box<dyn MessageStore>,
+    pub retention: Retention,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Retained {
+    /// Remove the oldest messages until the others are within the limits. Returns the others.
+    fn evict(&self) -> Result<Vec<Entry>, Error> {
+        let retention = &self.retention;
+        let mut entries = self.store.entries()?;
+        let mut bytes: usize = entries.iter().map(|entry| entry.size).sum();
+        let oldest = retention
+            .max_age
+            .map(|age| self.clock.unix_time().saturating_sub(age));
+        let mut evicted = 0;
+        for entry in &entries {
+            if retention
+                .max_messages
+                .is_none_or(|max| entries.len() - evicted <= max)
+                && retention.max_bytes.is_none_or(|max| bytes <= max)
+                && oldest.is_none_or(|oldest| entry.received >= oldest)
+            {
+                break;
+            }
+            self.store.remove(&entry.id)?;
+            bytes -= entry.size;
+            evicted += 1;
+        }
+        Ok(entries.split_off(evicted))
+    }
+}
+
+impl MessageStore for Retained {
+    fn add(&self, entry: Entry, content: &[u8]) -> Result<(), Error> {
+        self.store.add(entry, content)?;
+        self.evict().map(drop)
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>, Error> {
+        self.evict()
+    }
+
+    fn content(&self, id: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.store.content(id)
+    }
+
+    fn remove(&self, id: &str) -> Result<bool, Error> {
+        self.store.remove(id)
+    }
+
+    fn update(&self, entry: Entry) -> Result<bool, Error> {
+        self.store.update(entry)
+    }
+
+    fn path(&self) -> Option<&Path> {
+        self.store.path()
+    }
+
+    fn retention(&self) -> Option<&Retention> {
+        Some(&self.retention)
+    }
+}
+
+/// Keep the messages of every session received until the channel closes.
+/// Messages that fail to be kept are logged and dropped.
+pub fn keep(
+    store: Arc<dyn MessageStore>,
+    clock: Arc<dyn Clock>,
+    server: Option<String>,
+    sessions: Receiver<Arc<Session>>,
+) {
+    for session in sessions {
+        let connection = &session.connection;
+        let (Some(sender_domain), Some(messages)) =
+            (connection.get_sender_domain(), connection.get_messages())
+        else {
+            continue;
+        };
+        for message in messages {
+            let entry = Entry {
+                id: message.get_id().to_string(),
+                received: clock.unix_time(),
+                size: message.get_size(),
+                summary: crate::message_json(&session, sender_domain, message, server.as_deref()),
+            };
+            if let Err(e) = store.add(entry, message.get_content()) {
+                tracing::error!("Keeping message {} failed: {}", message.get_id(), e);
+            }
+        }
+    }
+}
+
+/// What the subcommand does with the kept messages
+pub enum Action {
+    List,
+    /// Print the content of a message
+    Show(String),
+    Delete(String),
+    /// Relay a message to the upstream server of `--relay`
+    Release(String),
+    /// Write all messages as .eml files into a zip archive at a path
+    Zip(String),
+}
+
+/// The command line definition of the subcommand, taking the same settings as the server
+pub fn subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name(SUBCOMMAND_NAME)
+        .about("List the messages kept in the storage directories of the servers, then exit")
+        .args(&crate::server_args())
+        .arg(
+            Arg::with_name(SHOW_ARG_NAME)
+                .long(SHOW_ARG_NAME)
+                .help("ID of a message to print the content of instead")
+                .takes_value(true)
+                .conflicts_with_all(&[DELETE_ARG_NAME, RELEASE_ARG_NAME, ZIP_ARG_NAME]),
+        )
+        .arg(
+            Arg::with_name(DELETE_ARG_NAME)
+                .long(DELETE_ARG_NAME)
+                .help("ID of a message to remove instead")
+                .takes_value(true)
+                .conflicts_with_all(&[RELEASE_ARG_NAME, ZIP_ARG_NAME]),
+        )
+        .arg(
+            Arg::with_name(RELEASE_ARG_NAME)
+                .long(RELEASE_ARG_NAME)
+                .help("ID of a message to relay to the --relay server instead")
+                .takes_value(true)
+                .conflicts_with(ZIP_ARG_NAME),
+        )
+        .arg(
+            Arg::with_name(ZIP_ARG_NAME)
+                .long(ZIP_ARG_NAME)
+                .help("File to write all messages to as .eml files in a zip archive instead")
+                .takes_value(true),
+        )
+}
+
+/// Get the action from the matches of the subcommand
+pub fn action(matches: &ArgMatches) -> Action {
+    if let Some(id) = matches.value_of(SHOW_ARG_NAME) {
+        Action::Show(id.to_string())
+    } else if let Some(id) = matches.value_of(DELETE_ARG_NAME) {
+        Action::Delete(id.to_string())
+    } else if let Some(id) = matches.value_of(RELEASE_ARG_NAME) {
+        Action::Release(id.to_string())
+    } else if let Some(path) = matches.value_of(ZIP_ARG_NAME) {
+        Action::Zip(path.to_string())
+    } else {
+        Action::List
+    }
+}
+
+/// Check the settings that an action needs, once they are merged from all sources, so that
+/// e.g. `--relay` may come from the configuration file
+pub fn check_action(configs: &[Config], action: &Action) -> Result<(), crate::error::Error> {
+    if matches!(action, Action::Release(_)) && configs.iter().all(|config| config.relay.is_none()) {
+        return Err(crate::settings_error(
+            "--release needs --relay",
+            clap::ErrorKind::MissingRequiredArgument,
+        ));
+    }
+    Ok(())
+}
+
+/// List, print, remove, release or archive the kept messages of the main server and the virtual
+/// servers
+pub fn run(configs: &[Config], action: &Action) -> Result<(), Error> {
+    if let Action::Zip(path) = action {
+        return archive(configs, Path::new(path));
+    }
+    let mut out = io::stdout().lock();
+    for config in configs {
+        let Some(store) = &config.store else {
+            continue;
+        };
+        match action {
+            Action::List => {
+                let now = config.clock.unix_time();
+                for entry in store.entries()? {
+                    print_entry(&mut out, config, &entry, now)?;
+                }
+            }
+            Action::Show(id) => {
+                if let Some(content) = store.content(id)? {
+                    return out.write_all(&content);
+                }
+            }
+            Action::Delete(id) => {
+                if store.remove(id)? {
+                    return writeln!(out, "Removed {}", id);
+                }
+            }
+            Action::Release(id) => {
+                if let Some(relay) = &config.relay {
+                    let released = release(store.as_ref(), relay, config.clock.unix_time(), id);
+                    relay.pool.close();
+                    if let Some(released) = released? {
+                        return match released["error"].as_str() {
+                            Some(error) => Err(Error::other(error.to_string())),
+                            None => writeln!(out, "Released {} to {}", id, relay.address),
+                        };
+                    }
+                }
+            }
+            Action::Zip(_) => unreachable!("archives are written before"),
+        }
+    }
+    match action {
+        Action::List | Action::Zip(_) => Ok(()),
+        Action::Show(id) | Action::Delete(id) | Action::Release(id) => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no message {}", id),
+        )),
+    }
+}
+
+/// Write the kept messages into a zip archive, in a directory per virtual server
+fn archive(configs: &[Config], path: &Path) -> Result<(), Error> {
+    let stores: Vec<_> = configs
+        .iter()
+        .filter_map(|config| Some((config.name.as_deref(), config.store.as_deref()?)))
+        .collect();
+    let (_, count) = write_archive(&stores, io::BufWriter::new(fs::File::create(path)?))?;
+    println!("Wrote {} messages to {}", count, path.display());
+    Ok(())
+}
+
+/// Write the messages of stores into a zip archive, in a directory for each store with a name.
+/// Returns the output and the number of messages.
+pub fn write_archive<W: Write>(
+    stores: &[(Option<&str>, &dyn MessageStore)],
+    out: W,
+) -> Result<(W, usize), Error> {
+    let mut writer = zip::Writer::new(out);
+    let mut count = 0;
+    for (name, store) in stores {
+        let directory = name.map_or_else(String::new, |name| format!("{}/", name));
+        for entry in store.entries()? {
+            // A message removed in the meantime is left out
+            if let Some(content) = store.content(&entry.id)? {
+                let name = format!("{}{}.eml", directory, entry.id);
+                writer.add(&name, entry.received, &content)?;
+                count += 1;
+            }
+        }
+    }
+    Ok((writer.finish()?, count))
+}
+
+/// Relay a kept message and record the outcome in its summary as `released`.
+/// Returns the outcome, with the reason if relaying failed, or None if there is no such message.
+pub fn release(
+    store: &dyn MessageStore,
+    relay: &Relay,
+    now: u64,
+    id: &str,
+) -> Result<Option<Value>, Error> {
+    let Some(mut entry) = store.entries()?.into_iter().find(|entry| entry.id == id) else {
+        return Ok(None);
+    };
+    let Some(content) = store.content(id)? else {
+        return Ok(None);
+    };
+    let summary = &entry.summary;
+    let recipients: Vec<String> = summary["to"].as_array().map_or_else(Vec::new, |to| {
+        to.iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect()
+    });
+    let outcome = relay::release(
+        relay,
+        summary["sender_domain"].as_str().unwrap_or("localhost"),
+        summary["from"].as_str().unwrap_or(""),
+        &recipients,
+        &content,
+    );
+    let released = serde_json::json!({
+        "at": now,
+        "error": outcome.err().as_ref().map(Error::to_string),
+    });
+    entry.summary["released"] = released.clone();
+    store.update(entry)?;
+    Ok(Some(released))
+}
+
+fn print_entry(out: &mut dyn Write, config: &Config, entry: &Entry, now: u64) -> io::Result<()> {
+    if config.print_format == PrintFormat::Jsonl {
+        return writeln!(out, "{}", entry.to_json());
+    }
+    if let Some(name) = &config.name {
+        write!(out, "[{}] ", name)?;
+    }
+    let recipients: Vec<&str> = entry.summary["to"]
+        .as_array()
+        .map_or_else(Vec::new, |to| to.iter().filter_map(Value::as_str).collect());
+    writeln!(
+        out,
+        "{} {} -> {} ({} bytes), received {}s ago",
+        entry.id,
+        entry.summary["from"].as_str().unwrap_or(""),
+        recipients.join(", "),
+        entry.size,
+        now.saturating_sub(entry.received)
+    )?;
+    let released = &entry.summary["released"];
+    match (released["at"].as_u64(), released["error"].as_str()) {
+        (Some(_), Some(error)) => writeln!(out, "  release failed: {}", error),
+        (Some(at), None) => writeln!(out, "  released {}s ago", now.saturating_sub(at)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FrozenClock;
+    use crate::smtp::new_uuid;
+
+    fn entry(id: &str, received: u64) -> Entry {
+        Entry {
+            id: id.to_string(),
+            received,
+            size: 5,
+            summary: serde_json::json!({"id": id, "size": 5, "to": ["admin@localhost"]}),
+        }
+    }
+
+    #[test]
+    fn keep_and_remove_messages() {
+        let path = std::env::temp_dir().join(format!("smtp-store-test-{}", new_uuid()));
+        let stores: [Box<dyn MessageStore>; 2] = [
+            Box::new(Memory::default()),
+            Box::new(Directory { path: path.clone() }),
+        ];
+        for store in &stores {
+            // Given
+            store.add(entry("first", 1_700_000_000), b"Hello").unwrap();
+            store.add(entry("second", 1_700_000_001), b"World").unwrap();
+
+            // When
+            let removed = store.remove("first").unwrap();
+
+            // Then
+            assert!(removed);
+            assert!(!store.remove("first").unwrap());
+            let entries = store.entries().unwrap();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].id, "second");
+            assert_eq!(entries[0].received, 1_700_000_001);
+            assert_eq!(entries[0].summary["to"][0], "admin@localhost");
+            assert_eq!(store.content("second").unwrap().unwrap(), b"World");
+            assert_eq!(store.content("first").unwrap(), None);
+            let mut updated = entries[0].clone();
+            updated.summary["released"] = serde_json::json!({"at": 1_700_000_002});
+            assert!(store.update(updated).unwrap());
+            assert_eq!(
+                store.entries().unwrap()[0].summary["released"]["at"],
+                1_700_000_002
+            );
+            assert!(!store.update(entry("first", 1_700_000_000)).unwrap());
+        }
+        assert!(stores[1].content("../second").is_err());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn share_a_directory() {
+        // Given
+        let path = std::env::temp_dir().join(format!("smtp-store-test-{}", new_uuid()));
+        let (first, second) = (
+            Directory { path: path.clone() },
+            Directory { path: path.clone() },
+        );
+        first.add(entry("kept", 1_700_000_000), b"Hello").unwrap();
+        first
+            .add(entry("removed", 1_700_000_001), b"World")
+            .unwrap();
+        // As when another server removes a message between reading its summary and its content
+        fs::remove_file(path.join("removed.eml")).unwrap();
+
+        // When
+        let entries = second.entries().unwrap();
+        let removed = second.remove("kept").unwrap();
+
+        // Then
+        assert_eq!(entries.len(), 2);
+        assert_eq!(second.content("removed").unwrap(), None);
+        assert!(removed);
+        assert_eq!(first.content("kept").unwrap(), None);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn remove_oldest_messages() {
+        // Given
+        let store = Retained {
+            store: Box::new(Memory::default()),
+            retention: Retention {
+                max_messages: Some(3),
+                max_bytes: Some(20),
+                max_age: Some(60),
+            },
+            clock: Arc::new(FrozenClock::at(1_700_000_100)),
+        };
+        let ids = |store: &Retained| -> Vec<String> {
+            let entries = store.entries().unwrap();
+            entries.into_iter().map(|entry| entry.id).collect()
+        };
+
+        // When
+        store.add(entry("1", 1_700_000_000), b"Hello").unwrap();
+        for id in ["2", "3", "4"] {
+            store.add(entry(id, 1_700_000_100), b"Hello").unwrap();
+        }
+        let by_age = ids(&store);
+        store.add(entry("5", 1_700_000_100), b"Hello").unwrap();
+        let by_count = ids(&store);
+        let large = Entry {
+            size: 15,
+            ..entry("6", 1_700_000_100)
+        };
+        store.add(large, b"Hello, large World").unwrap();
+        let by_size = ids(&store);
+
+        // Then
+        assert_eq!(by_age, ["2", "3", "4"]);
+        assert_eq!(by_count, ["3", "4", "5"]);
+        assert_eq!(by_size, ["5", "6"]);
+    }
+
+    #[test]
+    fn release_with_relay_from_file() {
+        // Given
+        let path = std::env::temp_dir().join(format!("smtp-store-test-{}.toml", new_uuid()));
+        fs::write(&path, "relay = \"127.0.0.1:25\"\n").unwrap();
+        let configs = |file: bool| {
+            let mut args = vec![SUBCOMMAND_NAME.to_string(), "--release=1".to_string()];
+            if file {
+                args.push(format!("--config={}", path.display()));
+            }
+            let matches = subcommand().get_matches_from_safe(args).unwrap();
+            (
+                crate::load_config(subcommand, matches.clone()).unwrap(),
+                action(&matches),
+            )
+        };
+
+        // When
+        let (with_file, release) = configs(true);
+        let (without_file, _) = configs(false);
+        fs::remove_file(path).unwrap();
+
+        // Then
+        assert!(check_action(&with_file, &release).is_ok());
+        assert!(matches!(
+            check_action(&without_file, &release),
+            Err(crate::error::Error::Settings(_))
+        ));
+    }
+}
@@ -0,0 +1,36 @@
+//! Support for the systemd socket activation protocol.
+//!
+//! See [sd_listen_fds(3)](https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html).
+
+use std::env;
+use std::os::unix::io::RawFd;
+use std::process;
+
+use crate::Listener;
+
+/// File descriptor of the first passed socket
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the listening sockets passed in by the service manager.
+///
+/// Returns an empty list if the process was not socket activated. The environment variables
+/// are removed so that they are not inherited by child processes.
+pub fn take_listeners() -> Vec<Listener> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let for_us = pid.and_then(|pid| pid.parse::<u32>().ok()) == Some(process::id());
+    let count = fds.and_then(|fds| fds.parse::<RawFd>().ok()).unwrap_or(0);
+    if !for_us {
+        return Vec::new();
+    }
+
+    // The service manager hands over ownership of the descriptors
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| unsafe { Listener::from_raw_fd(fd) })
+        .collect()
+}
@@ -0,0 +1,119 @@
+//! Failing the first delivery attempts of every message temporarily and accepting the retries, to
+//! test that clients retry and do not deliver a message twice.
+//!
+//! Attempts are told apart by a fingerprint of the sender, the recipients and the Message-ID
+//! header field, or the content for messages without one. Accepted messages are tagged with the
+//! number of their attempt as `attempt:<n>`, so a message that was delivered again after being
+//! accepted shows up with a higher number.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use crate::relay::path_address;
+use crate::smtp::{self, header_field, Message, Verdict};
+
+/// Reply to the attempts that fail
+const MSG_TEMPORARY_FAILURE: &str = "451 Temporary failure, try again later";
+
+/// The attempts of all messages of a server
+pub struct Attempts {
+    /// Number of attempts of a message that fail
+    pub failures: u32,
+    /// Attempts so far by fingerprint
+    counts: Mutex<HashMap<u64, u32>>,
+}
+
+impl Attempts {
+    pub fn new(failures: u32) -> Attempts {
+        Attempts {
+            failures,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count an attempt to deliver a message and get its number
+    fn count(&self, message: &Message) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        path_address(message.get_sender())
+            .to_lowercase()
+            .hash(&mut hasher);
+        for recipient in message.get_recipients() {
+            path_address(recipient).to_lowercase().hash(&mut hasher);
+        }
+        match header_field(message.get_content(), "message-id") {
+            Some(id) => id.hash(&mut hasher),
+            None => message.get_content().hash(&mut hasher),
+        }
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(hasher.finish()).or_insert(0);
+        *count += 1;
+        *count
+    }
+}
+
+/// Fails the first attempts of messages in a session
+pub struct Policy(pub Arc<Attempts>);
+
+impl smtp::Policy for Policy {
+    fn check_recipient(&mut self, _sender: &str, _recipient: &str) -> Option<String> {
+        None
+    }
+
+    fn check_message(&mut self, message: &mut Message) -> Verdict {
+        let attempt = self.0.count(message);
+        if attempt <= self.0.failures {
+            return Verdict::Reject(MSG_TEMPORARY_FAILURE.to_string());
+        }
+        message.add_tag(&format!("attempt:{}", attempt));
+        Verdict::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp::Connection;
+    use std::io::BufReader;
+
+    #[test]
+    fn fail_first_attempts() {
+        // Given
+        let attempt = "MAIL FROM: <tester@localhost>\n\
+                       RCPT TO: <admin@localhost>\n\
+                       DATA\n\
+                       Message-ID: <1@localhost>\n\
+                       \n\
+                       Hello\n\
+                       .\n";
+        let request = format!(
+            "HELO localhost\n{}{}{}{}QUIT\n",
+            attempt,
+            attempt.replace("<1@", "<2@"),
+            attempt,
+            attempt
+        );
+        let mut policy = Policy(Arc::new(Attempts::new(2)));
+        let mut response = Vec::new();
+
+        // When
+        let connection = Connection::handle_with_policy(
+            &mut BufReader::new(request.as_bytes()),
+            &mut response,
+            &mut policy,
+        )
+        .unwrap();
+
+        // Then
+        let response = String::from_utf8(response).unwrap();
+        let replies: Vec<&str> = response.lines().collect();
+        assert_eq!(replies[5], MSG_TEMPORARY_FAILURE);
+        assert_eq!(replies[9], MSG_TEMPORARY_FAILURE);
+        assert_eq!(replies[13], MSG_TEMPORARY_FAILURE);
+        assert_eq!(replies[17], "250 OK");
+        let messages = connection.get_messages().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].get_tags(), ["attempt:3"]);
+    }
+}
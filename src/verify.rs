@@ -0,0 +1,187 @@
+//! Checking the authenticity of received messages with SPF and DKIM, to test that an application
+//! sends mail that other servers will trust.
+//!
+//! The results are attached to the messages like tags, e.g. `spf=pass` and `dkim=fail`, and never
+//! reject them. The lookups happen while the client waits for the reply to the content.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::dkim;
+use crate::dns::Resolver;
+use crate::relay::path_address;
+use crate::smtp::{self, Message, Stage, Verdict};
+use crate::spf;
+
+/// The result of a check, named as in
+/// [RFC 8601](https://tools.ietf.org/html/rfc8601#section-2.7)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// There is nothing to check, e.g. no SPF record or DKIM signature
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    /// A lookup failed, so a later check may succeed
+    TempError,
+    /// A record or signature is broken
+    PermError,
+    /// Not checked because lookups are disabled
+    Skipped,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::None => "none",
+            Outcome::Neutral => "neutral",
+            Outcome::Pass => "pass",
+            Outcome::Fail => "fail",
+            Outcome::SoftFail => "softfail",
+            Outcome::TempError => "temperror",
+            Outcome::PermError => "permerror",
+            Outcome::Skipped => "skipped",
+        }
+    }
+}
+
+/// Checks the messages of a server
+pub struct Verifier {
+    /// The resolver of the lookups, none to skip the checks, e.g. without network access
+    pub resolver: Option<Resolver>,
+}
+
+/// Checks the messages of a session
+pub struct Policy {
+    verifier: Arc<Verifier>,
+    /// Address of the client, which SPF checks
+    ip: Option<IpAddr>,
+    /// Domain the client greeted with
+    helo: String,
+}
+
+impl Policy {
+    pub fn new(verifier: Arc<Verifier>, ip: Option<IpAddr>) -> Policy {
+        Policy {
+            verifier,
+            ip,
+            helo: String::new(),
+        }
+    }
+}
+
+impl smtp::Policy for Policy {
+    fn check_recipient(&mut self, _sender: &str, _recipient: &str) -> Option<String> {
+        None
+    }
+
+    fn check_message(&mut self, message: &mut Message) -> Verdict {
+        let (spf, dkim) = match &self.verifier.resolver {
+            None => (Outcome::Skipped, Outcome::Skipped),
+            Some(resolver) => {
+                let sender = path_address(message.get_sender());
+                let spf = match self.ip {
+                    Some(ip) => spf::check(resolver, ip, sender, &self.helo),
+                    // E.g. a client on a Unix socket
+                    None => Outcome::None,
+                };
+                (spf, dkim::verify(resolver, message.get_content()))
+            }
+        };
+        message.add_auth_result("spf", spf.as_str());
+        message.add_auth_result("dkim", dkim.as_str());
+        Verdict::Accept
+    }
+
+    fn interfere(&mut self, stage: &Stage) -> smtp::Interference {
+        if let Stage::Command(line) = stage {
+            let command = line.get(..5).unwrap_or_default();
+            if ["HELO ", "EHLO ", "LHLO "]
+                .iter()
+                .any(|greeting| command.eq_ignore_ascii_case(greeting))
+            {
+                self.helo = line[5..].trim().to_string();
+            }
+        }
+        smtp::Interference::Proceed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use crate::smtp::Connection;
+
+    /// Answer a single TXT query with a record
+    fn answer_txt(server: UdpSocket, record: &'static [u8]) {
+        let mut query = [0; 512];
+        let (length, client) = server.recv_from(&mut query).unwrap();
+        // The question without the OPT record, which is 11 bytes
+        let question = &query[12..length - 11];
+        let mut response = query[..2].to_vec();
+        for value in [0x8180, 1, 1, 0, 0] {
+            response.extend_from_slice(&u16::to_be_bytes(value));
+        }
+        response.extend_from_slice(question);
+        response.extend_from_slice(&[0xc0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0]);
+        response.push(record.len() as u8 + 1);
+        response.push(record.len() as u8);
+        response.extend_from_slice(record);
+        server.send_to(&response, client).unwrap();
+    }
+
+    /// The authentication results of the single message of a session from 127.0.0.1
+    fn results(verifier: Verifier) -> Vec<(String, String)> {
+        let request = "EHLO client.example.com\n\
+                       MAIL FROM:<app@example.com>\n\
+                       RCPT TO:<admin@localhost>\n\
+                       DATA\n\
+                       Subject: Hello\n\
+                       \n\
+                       Hi\n\
+                       .\n\
+                       QUIT\n";
+        let mut policy = Policy::new(Arc::new(verifier), Some("127.0.0.1".parse().unwrap()));
+        let connection = Connection::handle_with_policy(
+            &mut BufReader::new(request.as_bytes()),
+            &mut Vec::new(),
+            &mut policy,
+        )
+        .unwrap();
+        connection.get_messages().unwrap()[0]
+            .get_auth_results()
+            .to_vec()
+    }
+
+    #[test]
+    fn attach_results_to_messages() {
+        // Given
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let online = Verifier {
+            resolver: Some(Resolver {
+                server: server.local_addr().unwrap(),
+            }),
+        };
+        let answering = thread::spawn(move || answer_txt(server, b"v=spf1 ip4:127.0.0.1 -all"));
+
+        // When
+        let checked = results(online);
+        let skipped = results(Verifier { resolver: None });
+
+        // Then
+        answering.join().unwrap();
+        let pairs = |results: &[(&str, &str)]| {
+            results
+                .iter()
+                .map(|(method, result)| (method.to_string(), result.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(checked, pairs(&[("spf", "pass"), ("dkim", "none")]));
+        assert_eq!(skipped, pairs(&[("spf", "skipped"), ("dkim", "skipped")]));
+    }
+}
@@ -0,0 +1,126 @@
+//! Posting every received message as JSON to an HTTP endpoint, so that other services can react
+//! to mail without polling.
+//!
+//! The body is the JSON object that `--print-format jsonl` prints with `--print full`. Posts run
+//! on a pool of their own, and a post that fails is retried with a doubling delay.
+
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use threadpool::ThreadPool;
+
+use crate::http::{self, Url};
+use crate::Session;
+
+/// Delay before the first retry, doubled for every further one
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Longest delay between retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Where to post and how
+#[derive(Clone)]
+pub struct Webhook {
+    /// URL as given
+    pub url: String,
+    /// Number of posts that may be in progress at the same time
+    pub concurrency: usize,
+    /// Number of attempts to post a message before giving up on it
+    pub attempts: u32,
+}
+
+/// Post the messages of every session received until the channel closes.
+/// Messages that still fail to post after all attempts are logged and dropped.
+pub fn run(webhook: Webhook, server: Option<String>, sessions: Receiver<Arc<Session>>) {
+    // The URL is validated with the settings
+    let url = Url::parse(&webhook.url).unwrap();
+    let pool = ThreadPool::new(webhook.concurrency);
+    for session in sessions {
+        let connection = &session.connection;
+        let (Some(sender_domain), Some(messages)) =
+            (connection.get_sender_domain(), connection.get_messages())
+        else {
+            continue;
+        };
+        for message in messages {
+            let mut object =
+                crate::message_json(&session, sender_domain, message, server.as_deref());
+            object["data"] = message.get_data().into();
+            let (url, id, attempts) = (url.clone(), message.get_id().to_string(), webhook.attempts);
+            pool.execute(move || {
+                if let Err(e) = post(
+                    &url,
+                    object.to_string().as_bytes(),
+                    attempts,
+                    MIN_RETRY_DELAY,
+                ) {
+                    tracing::error!("Posting message {} to the webhook failed: {}", id, e);
+                }
+            });
+        }
+    }
+    pool.join();
+}
+
+/// Post a body, retrying with a doubling delay, and get the error of the last attempt
+fn post(url: &Url, body: &[u8], attempts: u32, delay: Duration) -> Result<(), std::io::Error> {
+    let mut delay = delay;
+    let mut attempt = 1;
+    loop {
+        match http::post(url, "application/json", body) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= attempts => return Err(e),
+            Err(_) => {
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn retry_failed_posts() {
+        // Given
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/mail", listener.local_addr().unwrap())).unwrap();
+        let server = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in ["503 Service Unavailable", "200 OK"] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            bodies
+        });
+
+        // When
+        let result = post(&url, br#"{"id":"1"}"#, 3, Duration::from_millis(10));
+
+        // Then
+        assert!(result.is_ok());
+        assert_eq!(server.join().unwrap(), [r#"{"id":"1"}"#, r#"{"id":"1"}"#]);
+    }
+}
@@ -0,0 +1,245 @@
+//! Running the server as a Windows service.
+//!
+//! The service is registered with the same command line it was installed with, plus a flag
+//! telling the server that it has been started by the service control manager.
+
+use std::ffi::{OsStr, OsString};
+use std::iter;
+use std::os::windows::ffi::OsStrExt;
+use std::panic;
+use std::ptr;
+use std::sync::{mpsc, Mutex, PoisonError};
+use std::time::Duration;
+
+use clap::{Arg, ArgMatches};
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    EVENTLOG_INFORMATION_TYPE, REPORT_EVENT_TYPE,
+};
+
+use crate::error::Error;
+use crate::Config;
+
+const SERVICE_NAME: &str = "rust-smtp-server";
+const SERVICE_DISPLAY_NAME: &str = "Rust SMTP server";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+const RUN_ARG_NAME: &str = "service";
+const INSTALL_ARG_NAME: &str = "install-service";
+const UNINSTALL_ARG_NAME: &str = "uninstall-service";
+
+/// What to do with the Windows service
+#[derive(Clone, Copy)]
+pub enum Command {
+    Run,
+    Install,
+    Uninstall,
+}
+
+/// Hands the configurations of all servers from `main` to the service entry point, which the
+/// service control manager calls without arguments of our choosing
+static SERVICE_CONFIGS: Mutex<Option<Vec<Config>>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// The command line arguments for managing the service
+pub fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name(RUN_ARG_NAME)
+            .long(RUN_ARG_NAME)
+            .help("Run as a Windows service, used by the service control manager")
+            .hidden(true),
+        Arg::with_name(INSTALL_ARG_NAME)
+            .long(INSTALL_ARG_NAME)
+            .help("Register a Windows service running with the other given arguments")
+            .conflicts_with_all(&[RUN_ARG_NAME, UNINSTALL_ARG_NAME]),
+        Arg::with_name(UNINSTALL_ARG_NAME)
+            .long(UNINSTALL_ARG_NAME)
+            .help("Remove the registered Windows service")
+            .conflicts_with(RUN_ARG_NAME),
+    ]
+}
+
+/// Get the service command given on the command line, if any
+pub fn command(matches: &ArgMatches) -> Option<Command> {
+    if matches.is_present(RUN_ARG_NAME) {
+        Some(Command::Run)
+    } else if matches.is_present(INSTALL_ARG_NAME) {
+        Some(Command::Install)
+    } else if matches.is_present(UNINSTALL_ARG_NAME) {
+        Some(Command::Uninstall)
+    } else {
+        None
+    }
+}
+
+pub fn execute(command: Command, configs: Vec<Config>) -> Result<(), Error> {
+    let result = match command {
+        Command::Run => {
+            *SERVICE_CONFIGS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(configs);
+            service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        }
+        Command::Install => install(),
+        Command::Uninstall => uninstall(),
+    };
+    result.map_err(Error::from)
+}
+
+fn install() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    // The flags belong to the serve subcommand, so the run flag goes after the other arguments
+    let install_flag = format!("--{}", INSTALL_ARG_NAME);
+    let launch_arguments = std::env::args_os()
+        .skip(1)
+        .filter(|arg| *arg != *install_flag)
+        .chain(iter::once(OsString::from(format!("--{}", RUN_ARG_NAME))))
+        .collect();
+
+    let service_info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_DISPLAY_NAME),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().map_err(windows_service::Error::Winapi)?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description("Simple SMTP server that captures received messages")
+}
+
+fn uninstall() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(SERVICE_NAME, ServiceAccess::DELETE)?;
+    service.delete()
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    // Standard error goes nowhere in a service, so failures have to end up in the event log
+    panic::set_hook(Box::new(|info| {
+        report_event(EVENTLOG_ERROR_TYPE, &info.to_string())
+    }));
+
+    let configs = SERVICE_CONFIGS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    let Some(configs) = configs else {
+        report_event(
+            EVENTLOG_ERROR_TYPE,
+            "Service started without a configuration",
+        );
+        return;
+    };
+    match run_service(configs) {
+        Ok(()) => report_event(EVENTLOG_INFORMATION_TYPE, "Service stopped"),
+        Err(e) => report_event(EVENTLOG_ERROR_TYPE, &format!("Service failed: {}", e)),
+    }
+}
+
+fn run_service(configs: Vec<Config>) -> Result<(), Error> {
+    let (stop_sender, stop_receiver) = mpsc::channel();
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = stop_sender.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+
+    let listeners = crate::bind_servers(&configs)?;
+    // The acceptor threads never finish on their own and end with the process
+    let servers: Vec<_> = configs
+        .iter()
+        .zip(listeners)
+        .map(|(config, listeners)| crate::start(config, listeners))
+        .collect();
+
+    set_state(
+        &status_handle,
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    )?;
+    report_event(EVENTLOG_INFORMATION_TYPE, "Service started");
+
+    let _ = stop_receiver.recv();
+    set_state(
+        &status_handle,
+        ServiceState::StopPending,
+        ServiceControlAccept::empty(),
+    )?;
+    for server in &servers {
+        server.drain();
+    }
+    set_state(
+        &status_handle,
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+    )?;
+    Ok(())
+}
+
+fn set_state(
+    status_handle: &ServiceStatusHandle,
+    state: ServiceState,
+    controls_accepted: ServiceControlAccept,
+) -> windows_service::Result<()> {
+    status_handle.set_service_status(ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })
+}
+
+/// Write a message to the Windows application event log
+fn report_event(event_type: REPORT_EVENT_TYPE, message: &str) {
+    let source = to_wide(OsStr::new(SERVICE_NAME));
+    let message = to_wide(OsStr::new(message));
+    let strings = [message.as_ptr()];
+
+    unsafe {
+        let event_log = RegisterEventSourceW(ptr::null(), source.as_ptr());
+        if !event_log.is_null() {
+            ReportEventW(
+                event_log,
+                event_type,
+                0,
+                0,
+                ptr::null_mut(),
+                strings.len() as u16,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            );
+            DeregisterEventSource(event_log);
+        }
+    }
+}
+
+fn to_wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(iter::once(0)).collect()
+}
@@ -0,0 +1,154 @@
+//! Writing zip archives, e.g. to hand kept messages to someone who opens them in a mail client.
+//!
+//! Files are stored without compression, which every zip reader understands. There is no zip64,
+//! so archives are limited to 65535 files and 4 GiB.
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Write};
+
+use crate::clock;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+/// Version 2.0, the first with directories, which is what readers expect at least
+const VERSION: u16 = 20;
+/// Bit 11: names are UTF-8
+const FLAGS: u16 = 1 << 11;
+
+/// Writes files into a zip archive one after the other
+pub struct Writer<W: Write> {
+    out: W,
+    /// Number of bytes written so far
+    offset: u64,
+    /// The central directory, written at the end
+    central: Vec<u8>,
+    count: u16,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W) -> Writer<W> {
+        Writer {
+            out,
+            offset: 0,
+            central: Vec::new(),
+            count: 0,
+        }
+    }
+
+    /// Add a file, modified at the given seconds since the epoch
+    pub fn add(&mut self, name: &str, modified: u64, content: &[u8]) -> Result<(), Error> {
+        let (offset, size) = match (u32::try_from(self.offset), u32::try_from(content.len())) {
+            (Ok(offset), Ok(size)) if self.count < u16::MAX => (offset, size),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "zip archive too large")),
+        };
+        let (time, date) = dos_time(modified);
+        let crc = crc32(content);
+        // The fields from the version needed to the name length are the same in both headers
+        let mut common = Vec::new();
+        for value in [VERSION, FLAGS, 0, time, date] {
+            common.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [crc, size, size] {
+            common.extend_from_slice(&value.to_le_bytes());
+        }
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+        let mut local = LOCAL_HEADER_SIGNATURE.to_le_bytes().to_vec();
+        local.extend_from_slice(&common);
+        // No extra field
+        local.extend_from_slice(&0u16.to_le_bytes());
+        local.extend_from_slice(name.as_bytes());
+        self.out.write_all(&local)?;
+        self.out.write_all(content)?;
+        self.offset += (local.len() + content.len()) as u64;
+
+        self.central
+            .extend_from_slice(&CENTRAL_HEADER_SIGNATURE.to_le_bytes());
+        // Made by the same version as needed
+        self.central.extend_from_slice(&VERSION.to_le_bytes());
+        self.central.extend_from_slice(&common);
+        // No extra field, comment, disk number, internal or external attributes
+        self.central.extend_from_slice(&[0; 12]);
+        self.central.extend_from_slice(&offset.to_le_bytes());
+        self.central.extend_from_slice(name.as_bytes());
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Write the central directory and get the output back
+    pub fn finish(mut self) -> Result<W, Error> {
+        let offset = u32::try_from(self.offset)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "zip archive too large"))?;
+        self.out.write_all(&self.central)?;
+        let mut end = END_SIGNATURE.to_le_bytes().to_vec();
+        // Disk numbers, then the count for this disk and in total
+        for value in [0, 0, self.count, self.count] {
+            end.extend_from_slice(&value.to_le_bytes());
+        }
+        end.extend_from_slice(&(self.central.len() as u32).to_le_bytes());
+        end.extend_from_slice(&offset.to_le_bytes());
+        // No comment
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.out.write_all(&end)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// The CRC-32 of zip, computed bit by bit instead of with a table
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Convert seconds since the epoch into the MS-DOS time and date of zip headers, in UTC.
+/// Times before 1980, which MS-DOS cannot represent, become 1980-01-01.
+fn dos_time(seconds: u64) -> (u16, u16) {
+    let (year, month, day) = clock::civil_date(seconds / 86400);
+    let seconds = seconds % 86400;
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let time = ((seconds / 3600) << 11) | ((seconds % 3600 / 60) << 5) | (seconds % 60 / 2);
+    let date = ((year.min(2107) - 1980) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_stored_files() {
+        // Given
+        let mut writer = Writer::new(Vec::new());
+
+        // When
+        writer
+            .add("first.eml", 1_700_000_000, b"123456789")
+            .unwrap();
+        writer.add("second.eml", 0, b"").unwrap();
+        let archive = writer.finish().unwrap();
+
+        // Then
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        // 2023-11-14 22:13:20
+        assert_eq!(
+            dos_time(1_700_000_000),
+            ((22 << 11) | (13 << 5) | 10, (43 << 9) | (11 << 5) | 14)
+        );
+        assert_eq!(archive[..4], LOCAL_HEADER_SIGNATURE.to_le_bytes());
+        assert_eq!(&archive[30..39], b"first.eml");
+        assert_eq!(&archive[39..48], b"123456789");
+        let end = &archive[archive.len() - 22..];
+        assert_eq!(end[..4], END_SIGNATURE.to_le_bytes());
+        assert_eq!(end[10..12], 2u16.to_le_bytes());
+    }
+}